@@ -0,0 +1,136 @@
+use std::time::Duration;
+
+/// The state of a [`CircuitBreaker`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CircuitState {
+    /// Connection attempts follow the retry policy.
+    Closed,
+    /// Connection attempts are suspended for the cool-down period.
+    Open,
+    /// A single probe connection is allowed; it closes the circuit on success
+    /// and re-opens it on failure.
+    HalfOpen,
+}
+
+/// Suspends reconnecting after too many consecutive failures.
+///
+/// After `failure_threshold` consecutive failed attempts the circuit opens for
+/// `cool_down`, then a single probe connection decides whether to resume. A connection
+/// that drops before staying up for the minimum uptime counts as a failed attempt.
+#[derive(Clone, Debug)]
+pub struct CircuitBreaker {
+    failure_threshold: u32,
+    cool_down: Duration,
+    min_uptime: Duration,
+    state: CircuitState,
+    failures: u32,
+}
+
+impl CircuitBreaker {
+    pub fn new(failure_threshold: u32, cool_down: Duration) -> Self {
+        Self {
+            failure_threshold: failure_threshold.max(1),
+            cool_down,
+            min_uptime: Duration::from_secs(10),
+            state: CircuitState::Closed,
+            failures: 0,
+        }
+    }
+
+    /// Sets how long a connection must stay up to count as a success. Defaults to 10s.
+    pub fn with_min_uptime(mut self, min_uptime: Duration) -> Self {
+        self.min_uptime = min_uptime;
+        self
+    }
+
+    pub fn state(&self) -> CircuitState {
+        self.state
+    }
+
+    pub fn cool_down(&self) -> Duration {
+        self.cool_down
+    }
+
+    /// Records an established connection, closing the circuit.
+    pub fn record_success(&mut self) {
+        self.state = CircuitState::Closed;
+        self.failures = 0;
+    }
+
+    /// Records a failed attempt. Returns `true` if the circuit has just opened.
+    pub fn record_failure(&mut self) -> bool {
+        self.failures = self.failures.saturating_add(1);
+        let trip = match self.state {
+            CircuitState::Closed => self.failures >= self.failure_threshold,
+            CircuitState::HalfOpen => true,
+            CircuitState::Open => false,
+        };
+        if trip {
+            self.state = CircuitState::Open;
+        }
+        trip
+    }
+
+    /// Records the end of an attempt, with how long the connection stayed up if it was
+    /// established. Returns `true` if the circuit has just opened.
+    pub fn record_attempt(&mut self, uptime: Option<Duration>) -> bool {
+        match uptime {
+            Some(uptime) if uptime >= self.min_uptime => {
+                self.record_success();
+                false
+            }
+            _ => self.record_failure(),
+        }
+    }
+
+    /// Ends the cool-down, allowing a single probe connection.
+    pub fn half_open(&mut self) {
+        if self.state == CircuitState::Open {
+            self.state = CircuitState::HalfOpen;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::circuit_breaker::{CircuitBreaker, CircuitState};
+    use std::time::Duration;
+
+    #[test]
+    fn test_transitions() {
+        let mut breaker = CircuitBreaker::new(3, Duration::from_secs(60));
+        assert!(!breaker.record_failure());
+        assert!(!breaker.record_failure());
+        assert!(breaker.record_failure());
+        assert_eq!(breaker.state(), CircuitState::Open);
+
+        // a failed probe re-opens the circuit
+        breaker.half_open();
+        assert_eq!(breaker.state(), CircuitState::HalfOpen);
+        assert!(breaker.record_failure());
+        assert_eq!(breaker.state(), CircuitState::Open);
+
+        // a successful probe closes it and resets the failure count
+        breaker.half_open();
+        breaker.record_success();
+        assert_eq!(breaker.state(), CircuitState::Closed);
+        assert!(!breaker.record_failure());
+    }
+
+    #[test]
+    fn test_record_attempt() {
+        let mut breaker =
+            CircuitBreaker::new(1, Duration::from_secs(60)).with_min_uptime(Duration::from_secs(5));
+        // a drop after a long uptime is not a failure
+        assert!(!breaker.record_attempt(Some(Duration::from_secs(3600))));
+        assert_eq!(breaker.state(), CircuitState::Closed);
+
+        // a connection dropping right away is
+        assert!(breaker.record_attempt(Some(Duration::from_secs(1))));
+        breaker.half_open();
+        assert!(breaker.record_attempt(None));
+        breaker.half_open();
+        assert!(!breaker.record_attempt(Some(Duration::from_secs(5))));
+        assert_eq!(breaker.state(), CircuitState::Closed);
+    }
+}
@@ -1,5 +1,9 @@
+use crate::circuit_breaker::CircuitBreaker;
+use crate::coordinator::ReconnectCoordinator;
+use crate::decompression::Decompression;
 use crate::handshake::{NonHandshake, StreamHandshake};
-use crate::strategies::{DurationIterator, ExpBackoffStrategy};
+use crate::retry::{RetryAction, RetryClassifier};
+use crate::strategies::{ExpBackoffStrategy, RetryPolicyFn};
 use std::sync::Arc;
 use std::time::Duration;
 
@@ -16,8 +20,8 @@ impl Default for ReconnectOptions {
 }
 
 impl ReconnectOptions {
-    pub(crate) fn retries_to_attempt_fn(&self) -> &Arc<dyn Fn() -> DurationIterator + Send + Sync> {
-        &self.inner.retries_to_attempt_fn
+    pub(crate) fn retry_policy_fn(&self) -> &RetryPolicyFn {
+        &self.inner.retry_policy_fn
     }
 
     #[allow(dead_code)]
@@ -32,6 +36,22 @@ impl ReconnectOptions {
     pub(crate) fn handshake(&self) -> &Arc<dyn StreamHandshake + Send + Sync> {
         &self.inner.handshake
     }
+
+    pub(crate) fn retry_classifier(&self) -> &RetryClassifier {
+        &self.inner.retry_classifier
+    }
+
+    pub(crate) fn circuit_breaker(&self) -> Option<&CircuitBreaker> {
+        self.inner.circuit_breaker.as_ref()
+    }
+
+    pub(crate) fn coordinator(&self) -> Option<&Arc<ReconnectCoordinator>> {
+        self.inner.coordinator.as_ref()
+    }
+
+    pub(crate) fn decompression(&self) -> Option<&Decompression> {
+        self.inner.decompression.as_ref()
+    }
 }
 
 impl ReconnectOptions {
@@ -43,6 +63,59 @@ impl ReconnectOptions {
         self
     }
 
+    /// Sets the factory creating the [`RetryPolicy`](crate::strategies::RetryPolicy) used by
+    /// each `run`. Defaults to [`ExpBackoffStrategy::default`].
+    pub fn with_retry_policy_fn(&mut self, retry_policy_fn: RetryPolicyFn) -> &mut Self {
+        self.inner.retry_policy_fn = retry_policy_fn;
+        self
+    }
+
+    /// Sets the callback classifying tungstenite errors before the retry policy is consulted.
+    ///
+    /// Defaults to [`RetryAction::classify`], which honors `Retry-After` on 429/503.
+    pub fn with_retry_classifier(&mut self, retry_classifier: RetryClassifier) -> &mut Self {
+        self.inner.retry_classifier = retry_classifier;
+        self
+    }
+
+    /// Opens the circuit for `cool_down` after `failure_threshold` consecutive failures,
+    /// then allows a single probe connection before resuming the retry policy. A
+    /// connection dropping within 10s counts as a failure.
+    pub fn with_circuit_breaker(
+        &mut self,
+        failure_threshold: u32,
+        cool_down: Duration,
+    ) -> &mut Self {
+        self.inner.circuit_breaker = Some(CircuitBreaker::new(failure_threshold, cool_down));
+        self
+    }
+
+    /// Uses `circuit_breaker`, e.g. with a custom
+    /// [`min_uptime`](CircuitBreaker::with_min_uptime).
+    pub fn with_circuit_breaker_config(&mut self, circuit_breaker: CircuitBreaker) -> &mut Self {
+        self.inner.circuit_breaker = Some(circuit_breaker);
+        self
+    }
+
+    /// Shares a coordinator limiting connection attempts across `ReconnectT` instances.
+    pub fn with_coordinator(&mut self, coordinator: Arc<ReconnectCoordinator>) -> &mut Self {
+        self.inner.coordinator = Some(coordinator);
+        self
+    }
+
+    /// Inflates compressed binary frames before they reach the listeners, including the
+    /// frames a handshake forwards. Handshakes themselves see the frames as received.
+    /// A frame that is not compressed is delivered unchanged unless
+    /// [`Decompression::with_pass_through`] is turned off; a frame failing to inflate then,
+    /// or inflating past the max size, drops the connection with
+    /// [`ReconnectTError::Decompression`].
+    ///
+    /// [`ReconnectTError::Decompression`]: crate::errors::ReconnectTError::Decompression
+    pub fn with_decompression(&mut self, decompression: Decompression) -> &mut Self {
+        self.inner.decompression = Some(decompression);
+        self
+    }
+
     pub fn with_receive_timeout(&mut self, receive_timeout: Duration) -> &mut Self {
         self.inner.receive_timeout = receive_timeout;
         self
@@ -57,21 +130,27 @@ impl ReconnectOptions {
 
 #[derive(Clone)]
 struct Inner {
-    retries_to_attempt_fn: Arc<dyn Fn() -> DurationIterator + Send + Sync>,
+    retry_policy_fn: RetryPolicyFn,
     exit_if_first_connect_fails: bool,
     receive_timeout: Duration,
     handshake: Arc<dyn StreamHandshake + Send + Sync>,
+    retry_classifier: RetryClassifier,
+    circuit_breaker: Option<CircuitBreaker>,
+    coordinator: Option<Arc<ReconnectCoordinator>>,
+    decompression: Option<Decompression>,
 }
 
 impl Default for Inner {
     fn default() -> Self {
         Self {
-            retries_to_attempt_fn: Arc::new(move || {
-                Box::new(ExpBackoffStrategy::default().into_iter())
-            }),
+            retry_policy_fn: Arc::new(move || Box::new(ExpBackoffStrategy::default().into_iter())),
             exit_if_first_connect_fails: false,
             receive_timeout: Duration::from_secs(20),
             handshake: Arc::new(NonHandshake),
+            retry_classifier: Arc::new(RetryAction::classify),
+            circuit_breaker: None,
+            coordinator: None,
+            decompression: None,
         }
     }
 }
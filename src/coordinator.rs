@@ -0,0 +1,121 @@
+use std::time::Duration;
+use tokio::sync::{Mutex, Semaphore, SemaphorePermit};
+use tokio::time::Instant;
+
+/// Coordinates connection attempts across many `ReconnectT` instances.
+///
+/// Share one `Arc<ReconnectCoordinator>` between the `ReconnectOptions` of every
+/// instance to bound how many connection attempts (connect + handshake) run at once,
+/// and optionally how fast new attempts may start.
+pub struct ReconnectCoordinator {
+    attempts: Semaphore,
+    bucket: Option<Mutex<TokenBucket>>,
+}
+
+impl ReconnectCoordinator {
+    /// Creates a coordinator allowing at most `max_concurrent_attempts` attempts at once.
+    pub fn new(max_concurrent_attempts: usize) -> Self {
+        Self {
+            attempts: Semaphore::new(max_concurrent_attempts.max(1)),
+            bucket: None,
+        }
+    }
+
+    /// Limits new attempts with a token bucket holding up to `burst` tokens
+    /// and refilling one token every `refill_interval`.
+    pub fn with_rate_limit(mut self, burst: u32, refill_interval: Duration) -> Self {
+        self.bucket = Some(Mutex::new(TokenBucket::new(burst, refill_interval)));
+        self
+    }
+
+    /// Waits until a connection attempt may start. The attempt ends when the permit is dropped.
+    pub async fn acquire(&self) -> ConnectPermit<'_> {
+        let permit = self
+            .attempts
+            .acquire()
+            .await
+            .expect("coordinator semaphore is never closed");
+        if let Some(bucket) = &self.bucket {
+            loop {
+                let wait = bucket.lock().await.try_take();
+                match wait {
+                    None => break,
+                    Some(wait) => tokio::time::sleep(wait).await,
+                }
+            }
+        }
+        ConnectPermit { _permit: permit }
+    }
+
+    /// Returns the number of attempts that may currently start without waiting
+    /// for another attempt to finish.
+    pub fn available_attempts(&self) -> usize {
+        self.attempts.available_permits()
+    }
+}
+
+/// Held for the duration of a coordinated connection attempt.
+pub struct ConnectPermit<'a> {
+    _permit: SemaphorePermit<'a>,
+}
+
+struct TokenBucket {
+    capacity: f64,
+    tokens: f64,
+    refill_interval: Duration,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(burst: u32, refill_interval: Duration) -> Self {
+        let capacity = f64::from(burst.max(1));
+        Self {
+            capacity,
+            tokens: capacity,
+            refill_interval,
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// Takes a token, or returns how long to wait until one is available.
+    fn try_take(&mut self) -> Option<Duration> {
+        if self.refill_interval.is_zero() {
+            return None;
+        }
+        let now = Instant::now();
+        let refilled = (now - self.last_refill).as_secs_f64() / self.refill_interval.as_secs_f64();
+        self.tokens = (self.tokens + refilled).min(self.capacity);
+        self.last_refill = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            None
+        } else {
+            Some(self.refill_interval.mul_f64(1.0 - self.tokens))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::coordinator::ReconnectCoordinator;
+    use std::time::Duration;
+    use tokio::time::Instant;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_rate_limit() {
+        let coordinator = ReconnectCoordinator::new(2).with_rate_limit(2, Duration::from_secs(1));
+        let start = Instant::now();
+
+        drop(coordinator.acquire().await);
+        drop(coordinator.acquire().await);
+        assert_eq!(start.elapsed(), Duration::ZERO);
+
+        // the burst is spent, the next attempt waits for a refill
+        let permit = coordinator.acquire().await;
+        assert_eq!(start.elapsed(), Duration::from_secs(1));
+        assert_eq!(coordinator.available_attempts(), 1);
+        drop(permit);
+        assert_eq!(coordinator.available_attempts(), 2);
+    }
+}
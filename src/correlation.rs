@@ -0,0 +1,298 @@
+use crate::errors::ReconnectTError;
+use crate::event_listeners::ShareListener;
+use crate::extension::StreamEvent;
+use crate::maybe_sender::MaybePSTSender;
+use crate::prelude::WsStreamStatus;
+use crate::tungstenite::ReconnectT;
+use eyre::Result as EResult;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{oneshot, Mutex};
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use tokio_stream::StreamExt;
+use tungstenite::client::IntoClientRequest;
+use tungstenite::Message;
+
+pub type IdExtractor = Arc<dyn Fn(&Message) -> Option<String> + Send + Sync>;
+
+/// The waiting requests by id, with the generation of the connection they were sent on.
+type Pending = Arc<Mutex<HashMap<String, (u64, oneshot::Sender<Message>)>>>;
+
+/// Correlates requests with their responses by an id carried in both.
+///
+/// Inbound messages whose extracted id matches a pending request complete it; all other
+/// messages are delivered to the listeners created with
+/// [`create_receive_stream`](Self::create_receive_stream). Pending requests fail with
+/// [`ReconnectTError::Disconnected`] when the connection they were sent on drops.
+pub struct Correlator {
+    sender: Arc<MaybePSTSender>,
+    extract_id: IdExtractor,
+    timeout: Duration,
+    pending: Pending,
+    uncorrelated: Arc<ShareListener<Message>>,
+}
+
+impl Correlator {
+    pub fn new<F>(sender: Arc<MaybePSTSender>, extract_id: F) -> Self
+    where
+        F: Fn(&Message) -> Option<String> + Send + Sync + 'static,
+    {
+        Self {
+            sender,
+            extract_id: Arc::new(extract_id),
+            timeout: Duration::from_secs(30),
+            pending: Arc::new(Mutex::new(HashMap::new())),
+            uncorrelated: Arc::new(ShareListener::default()),
+        }
+    }
+
+    /// Sets the default timeout of [`request`](Self::request).
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Starts correlating the messages of `reconnect`.
+    pub async fn attach<R: IntoClientRequest + Send + Sync>(
+        self,
+        reconnect: &ReconnectT<R>,
+    ) -> EResult<Arc<Self>> {
+        let this = Arc::new(self);
+        this.spawn_receive(reconnect.create_event_stream().await);
+        Ok(this)
+    }
+
+    /// Sends `msg` and waits for the inbound message carrying `id`. Fails with
+    /// [`ReconnectTError::DuplicateRequestId`] if a request with `id` is already pending.
+    pub async fn request(
+        &self,
+        msg: Message,
+        id: impl Into<String>,
+    ) -> EResult<Message, ReconnectTError> {
+        self.request_with_timeout(msg, id, self.timeout).await
+    }
+
+    pub async fn request_with_timeout(
+        &self,
+        msg: Message,
+        id: impl Into<String>,
+        timeout: Duration,
+    ) -> EResult<Message, ReconnectTError> {
+        let id = id.into();
+        let (tx, rx) = oneshot::channel();
+        {
+            let mut sender = self.sender.lock().await;
+            let generation = sender
+                .generation()
+                .ok_or(ReconnectTError::SenderNotConnected)?;
+            {
+                let mut pending = self.pending.lock().await;
+                if pending.contains_key(&id) {
+                    return Err(ReconnectTError::DuplicateRequestId(id));
+                }
+                pending.insert(id.clone(), (generation, tx));
+            }
+            if let Err(e) = sender.send(msg).await {
+                self.pending.lock().await.remove(&id);
+                return Err(e);
+            }
+        }
+        match tokio::time::timeout(timeout, rx).await {
+            Ok(Ok(response)) => Ok(response),
+            Ok(Err(_)) => Err(ReconnectTError::Disconnected),
+            Err(_) => {
+                self.pending.lock().await.remove(&id);
+                Err(ReconnectTError::RequestTimeout(timeout))
+            }
+        }
+    }
+
+    /// Sends `msg` without waiting for a response.
+    pub async fn send(&self, msg: Message) -> EResult<(), ReconnectTError> {
+        self.sender.send(msg).await
+    }
+
+    /// Completes the only pending request with `msg`, for responses that lost their id
+    /// (e.g. a JSON-RPC parse error). Returns `msg` back if none or several are pending.
+    pub async fn complete_only_pending(&self, msg: Message) -> Result<(), Message> {
+        let waiter = {
+            let mut pending = self.pending.lock().await;
+            if pending.len() != 1 {
+                return Err(msg);
+            }
+            let id = pending.keys().next().cloned().expect("one pending request");
+            pending.remove(&id)
+        };
+        match waiter {
+            Some((_, waiter)) => waiter.send(msg),
+            None => Err(msg),
+        }
+    }
+
+    /// Returns the number of requests waiting for a response.
+    pub async fn pending_len(&self) -> usize {
+        self.pending.lock().await.len()
+    }
+
+    /// Creates a stream of the inbound messages that did not complete a request.
+    pub async fn create_receive_stream(&self) -> UnboundedReceiverStream<Message> {
+        self.uncorrelated.new_listener().await
+    }
+
+    fn spawn_receive(&self, mut events: UnboundedReceiverStream<StreamEvent>) {
+        let extract_id = self.extract_id.clone();
+        let pending = self.pending.clone();
+        let uncorrelated = self.uncorrelated.clone();
+        tokio::spawn(async move {
+            let mut generation = 0;
+            while let Some(event) = events.next().await {
+                match event {
+                    StreamEvent::Message(msg) => {
+                        let waiter = match extract_id(&msg) {
+                            Some(id) => pending.lock().await.remove(&id),
+                            None => None,
+                        };
+                        match waiter {
+                            Some((_, waiter)) => {
+                                let _ = waiter.send(msg);
+                            }
+                            None => uncorrelated.notify(msg).await,
+                        }
+                    }
+                    StreamEvent::Status(WsStreamStatus::Connected(ctx)) => {
+                        generation = ctx.generation();
+                    }
+                    StreamEvent::Status(WsStreamStatus::Disconnected) => {
+                        // dropping the senders fails the requests sent on the dropped
+                        // connection; those already sent on the next one keep waiting
+                        pending
+                            .lock()
+                            .await
+                            .retain(|_, (sent, _)| *sent > generation);
+                    }
+                    StreamEvent::Status(_) => {}
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::correlation::Correlator;
+    use crate::prelude::*;
+    use crate::test_util::mock_server;
+    use futures_util::{SinkExt, StreamExt};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tungstenite::Message;
+
+    #[tokio::test]
+    async fn test_request() {
+        let url = mock_server(|mut ws| async move {
+            ws.send(Message::text("hello")).await.unwrap();
+            while let Some(Ok(msg)) = ws.next().await {
+                match msg.to_text().unwrap().split_once(' ') {
+                    Some((id, "ping")) => {
+                        ws.send(Message::text(format!("{id} pong"))).await.unwrap()
+                    }
+                    // never answered; the connection is dropped instead
+                    Some((_, "close")) => return,
+                    _ => {}
+                }
+            }
+        })
+        .await;
+
+        let mut options = ReconnectOptions::default();
+        options.with_retry_policy_fn(Arc::new(|| {
+            Box::new(ConstantStrategy::new(Duration::from_secs(60)).into_iter())
+        }));
+        let reconnect = Arc::new(ReconnectT::new(url, Some(options)));
+        let correlator = Correlator::new(reconnect.sender.clone(), |msg| {
+            Some(msg.to_text().ok()?.split_once(' ')?.0.to_string())
+        })
+        .attach(&reconnect)
+        .await
+        .unwrap();
+        let mut uncorrelated = correlator.create_receive_stream().await;
+        let mut status = reconnect.create_status_stream().await;
+        reconnect.spawn_run();
+        assert!(matches!(
+            status.next().await,
+            Some(WsStreamStatus::Connected(_))
+        ));
+
+        let response = correlator
+            .request(Message::text("1 ping"), "1")
+            .await
+            .unwrap();
+        assert_eq!(response, Message::text("1 pong"));
+        assert_eq!(uncorrelated.next().await, Some(Message::text("hello")));
+
+        let timeout = correlator
+            .request_with_timeout(Message::text("2 noop"), "2", Duration::from_millis(20))
+            .await;
+        assert!(matches!(timeout, Err(ReconnectTError::RequestTimeout(_))));
+
+        let lost = correlator.request(Message::text("3 close"), "3").await;
+        assert!(matches!(lost, Err(ReconnectTError::Disconnected)));
+        assert_eq!(correlator.pending_len().await, 0);
+    }
+
+    #[tokio::test]
+    async fn test_reply_before_disconnect() {
+        let url = mock_server(|mut ws| async move {
+            while let Some(Ok(msg)) = ws.next().await {
+                if let Some((id, "last")) = msg.to_text().unwrap().split_once(' ') {
+                    // answered right before the connection drops
+                    ws.send(Message::text(format!("{id} pong"))).await.unwrap();
+                    return;
+                }
+            }
+        })
+        .await;
+
+        let mut options = ReconnectOptions::default();
+        options.with_retry_policy_fn(Arc::new(|| {
+            Box::new(ConstantStrategy::new(Duration::from_secs(60)).into_iter())
+        }));
+        let reconnect = Arc::new(ReconnectT::new(url, Some(options)));
+        let correlator = Correlator::new(reconnect.sender.clone(), |msg| {
+            Some(msg.to_text().ok()?.split_once(' ')?.0.to_string())
+        })
+        .attach(&reconnect)
+        .await
+        .unwrap();
+        let mut status = reconnect.create_status_stream().await;
+        reconnect.spawn_run();
+        assert!(matches!(
+            status.next().await,
+            Some(WsStreamStatus::Connected(_))
+        ));
+
+        let waiting = tokio::spawn({
+            let correlator = correlator.clone();
+            async move { correlator.request(Message::text("1 noop"), "1").await }
+        });
+        while correlator.pending_len().await == 0 {
+            tokio::task::yield_now().await;
+        }
+        let duplicate = correlator.request(Message::text("1 again"), "1").await;
+        assert!(matches!(
+            duplicate,
+            Err(ReconnectTError::DuplicateRequestId(id)) if id == "1"
+        ));
+
+        let response = correlator
+            .request(Message::text("2 last"), "2")
+            .await
+            .unwrap();
+        assert_eq!(response, Message::text("2 pong"));
+        assert!(matches!(
+            waiting.await.unwrap(),
+            Err(ReconnectTError::Disconnected)
+        ));
+    }
+}
@@ -0,0 +1,249 @@
+use flate2::read::{DeflateDecoder, GzDecoder, ZlibDecoder};
+use std::io::Read;
+use tungstenite::Message;
+
+/// The format of compressed binary frames.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    Gzip,
+    /// Raw deflate, without header.
+    Deflate,
+    Zlib,
+    /// Detects gzip and zlib by their header, falling back to raw deflate.
+    Auto,
+}
+
+impl Compression {
+    fn detect(data: &[u8]) -> Self {
+        match data {
+            [0x1f, 0x8b, ..] => Compression::Gzip,
+            // deflate method with a valid header checksum
+            [cmf, flg, ..]
+                if cmf & 0x0f == 8 && (u16::from(*cmf) << 8 | u16::from(*flg)) % 31 == 0 =>
+            {
+                Compression::Zlib
+            }
+            _ => Compression::Deflate,
+        }
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum DecompressionError {
+    #[error("invalid compressed frame: {0}")]
+    Invalid(#[from] std::io::Error),
+    #[error("decompressed frame exceeds {0} bytes")]
+    TooLarge(usize),
+}
+
+/// Inflates compressed binary frames before they reach the listeners.
+///
+/// Frames inflating to valid UTF-8 become text frames, others stay binary. Text and
+/// control frames pass unchanged, as do binary frames that are not compressed unless
+/// [`with_pass_through`](Self::with_pass_through) is turned off.
+#[derive(Clone, Debug)]
+pub struct Decompression {
+    compression: Compression,
+    max_size: usize,
+    pass_through: bool,
+}
+
+impl Decompression {
+    pub fn new(compression: Compression) -> Self {
+        Self {
+            compression,
+            max_size: 16 * 1024 * 1024,
+            pass_through: true,
+        }
+    }
+
+    /// Sets the largest decompressed frame accepted, protecting against zip bombs.
+    /// Defaults to 16 MiB.
+    pub fn with_max_size(mut self, max_size: usize) -> Self {
+        self.max_size = max_size;
+        self
+    }
+
+    /// Sets whether binary frames failing to inflate, e.g. a server sending some frames
+    /// uncompressed, are delivered unchanged. Otherwise they fail with
+    /// [`DecompressionError::Invalid`]. Frames over the max size always fail. Defaults to
+    /// `true`.
+    pub fn with_pass_through(mut self, pass_through: bool) -> Self {
+        self.pass_through = pass_through;
+        self
+    }
+
+    pub fn decompress(&self, data: &[u8]) -> Result<Vec<u8>, DecompressionError> {
+        let compression = match self.compression {
+            Compression::Auto => Compression::detect(data),
+            compression => compression,
+        };
+        let decoder: Box<dyn Read + '_> = match compression {
+            Compression::Gzip => Box::new(GzDecoder::new(data)),
+            Compression::Zlib => Box::new(ZlibDecoder::new(data)),
+            Compression::Deflate | Compression::Auto => Box::new(DeflateDecoder::new(data)),
+        };
+        // reads one byte past the limit to tell an exact fit from an overflow
+        let mut inflated = Vec::new();
+        decoder
+            .take(self.max_size as u64 + 1)
+            .read_to_end(&mut inflated)?;
+        if inflated.len() > self.max_size {
+            return Err(DecompressionError::TooLarge(self.max_size));
+        }
+        Ok(inflated)
+    }
+
+    pub fn apply(&self, msg: Message) -> Result<Message, DecompressionError> {
+        let Message::Binary(data) = msg else {
+            return Ok(msg);
+        };
+        let inflated = match self.decompress(&data) {
+            Ok(inflated) => inflated,
+            Err(DecompressionError::Invalid(e)) if self.pass_through => {
+                tracing::debug!(error=?e, "decompression::pass_through");
+                return Ok(Message::Binary(data));
+            }
+            Err(e) => return Err(e),
+        };
+        Ok(match String::from_utf8(inflated) {
+            Ok(text) => Message::text(text),
+            Err(e) => Message::binary(e.into_bytes()),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::decompression::{Compression, Decompression, DecompressionError};
+    use crate::handshake::SingleHandshake;
+    use crate::prelude::*;
+    use crate::test_util::mock_server;
+    use flate2::write::{DeflateEncoder, GzEncoder, ZlibEncoder};
+    use futures_util::{SinkExt, StreamExt};
+    use std::io::Write;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tungstenite::Message;
+
+    fn compress(compression: Compression, data: &[u8]) -> Vec<u8> {
+        let level = flate2::Compression::default();
+        match compression {
+            Compression::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), level);
+                encoder.write_all(data).unwrap();
+                encoder.finish().unwrap()
+            }
+            Compression::Zlib => {
+                let mut encoder = ZlibEncoder::new(Vec::new(), level);
+                encoder.write_all(data).unwrap();
+                encoder.finish().unwrap()
+            }
+            _ => {
+                let mut encoder = DeflateEncoder::new(Vec::new(), level);
+                encoder.write_all(data).unwrap();
+                encoder.finish().unwrap()
+            }
+        }
+    }
+
+    #[test]
+    fn test_decompression() {
+        let text = r#"{"ch":"market.btcusdt.trade.detail"}"#;
+        for compression in [Compression::Gzip, Compression::Deflate, Compression::Zlib] {
+            let compressed = Message::binary(compress(compression, text.as_bytes()));
+            for decompression in [compression, Compression::Auto] {
+                let msg = Decompression::new(decompression)
+                    .apply(compressed.clone())
+                    .unwrap();
+                assert_eq!(msg, Message::text(text));
+            }
+        }
+
+        let inflated = Decompression::new(Compression::Gzip)
+            .apply(Message::binary(compress(Compression::Gzip, &[0xff, 0])))
+            .unwrap();
+        assert_eq!(inflated, Message::binary(vec![0xff, 0]));
+        let text_frame = Message::text("plain");
+        let decompression = Decompression::new(Compression::Gzip);
+        assert_eq!(decompression.apply(text_frame.clone()).unwrap(), text_frame);
+        let uncompressed = Message::binary(vec![1, 2, 3]);
+        assert_eq!(
+            decompression.apply(uncompressed.clone()).unwrap(),
+            uncompressed
+        );
+        assert!(matches!(
+            decompression.with_pass_through(false).apply(uncompressed),
+            Err(DecompressionError::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn test_max_size() {
+        let bomb = compress(Compression::Zlib, &vec![0; 1024 * 1024]);
+        assert!(bomb.len() < 2048);
+        let decompression = Decompression::new(Compression::Zlib).with_max_size(1024);
+        assert!(matches!(
+            decompression.decompress(&bomb),
+            Err(DecompressionError::TooLarge(1024))
+        ));
+        let exact = compress(Compression::Zlib, &[7; 1024]);
+        assert_eq!(decompression.decompress(&exact).unwrap().len(), 1024);
+    }
+
+    #[tokio::test]
+    async fn test_receive_path() {
+        let url = mock_server(|mut ws| async move {
+            ws.next().await.unwrap().unwrap();
+            // the handshake's reply, forwarded once connected
+            let reply = compress(Compression::Zlib, b"{\"welcome\":1}");
+            ws.send(Message::binary(reply)).await.unwrap();
+            let frame = compress(Compression::Gzip, b"{\"ticker\":1}");
+            ws.send(Message::binary(frame)).await.unwrap();
+            ws.send(Message::binary(vec![1, 2, 3])).await.unwrap();
+            let bomb = compress(Compression::Gzip, &vec![b' '; 64 * 1024]);
+            ws.send(Message::binary(bomb)).await.unwrap();
+            while ws.next().await.is_some() {}
+        })
+        .await;
+
+        let mut options = ReconnectOptions::default();
+        options
+            .with_handshake(Arc::new(SingleHandshake::new(true)))
+            .with_decompression(Decompression::new(Compression::Auto).with_max_size(1024))
+            .with_retry_policy_fn(Arc::new(|| {
+                Box::new(ConstantStrategy::new(Duration::from_millis(10)).into_iter())
+            }));
+        let reconnect = Arc::new(ReconnectT::new(url, Some(options)));
+        let mut messages = reconnect.create_receive_stream().await;
+        let mut status = reconnect.create_status_stream().await;
+        reconnect.spawn_run();
+
+        assert_eq!(
+            messages.next().await.unwrap(),
+            Message::text("{\"welcome\":1}")
+        );
+        assert_eq!(
+            messages.next().await.unwrap(),
+            Message::text("{\"ticker\":1}")
+        );
+        // not compressed, so delivered as is
+        assert_eq!(
+            messages.next().await.unwrap(),
+            Message::binary(vec![1, 2, 3])
+        );
+        // the oversized frame is not delivered and drops the connection
+        assert!(matches!(
+            status.next().await,
+            Some(WsStreamStatus::Connected(_))
+        ));
+        assert!(matches!(
+            status.next().await,
+            Some(WsStreamStatus::Disconnected)
+        ));
+        assert_eq!(
+            messages.next().await.unwrap(),
+            Message::text("{\"welcome\":1}")
+        );
+    }
+}
@@ -4,10 +4,28 @@ use core::time::Duration;
 pub enum ReconnectTError {
     #[error("receive timeout: {0:?}")]
     ReceiveTimeout(Duration),
-    #[error("handshake failed")]
-    HandshakeFailed,
+    #[error("handshake failed: {0}")]
+    HandshakeFailed(eyre::Report),
+    #[error("connection closed")]
+    ConnectionClosed,
     #[error("sender not connected")]
     SenderNotConnected,
+    #[error("request timed out after {0:?}")]
+    RequestTimeout(Duration),
+    #[error("disconnected before a response arrived")]
+    Disconnected,
+    #[error("a request with id {0} is already pending")]
+    DuplicateRequestId(String),
+    #[error("subscription not confirmed: {0}")]
+    SubscriptionNotConfirmed(String),
+    #[error(transparent)]
+    Decompression(#[from] crate::decompression::DecompressionError),
     #[error("tokio_tungstenite error: {0}")]
     TokioTungsteniteError(#[from] tokio_tungstenite::tungstenite::Error),
+    /// Reconnecting was stopped with a [`StopHandle`](crate::tungstenite::StopHandle), or
+    /// by a handshake failing with this error.
+    #[error("stopped reconnecting: {0}")]
+    Stopped(String),
+    #[error("gave up reconnecting: {0}")]
+    GaveUp(#[source] Box<ReconnectTError>),
 }
@@ -0,0 +1,11 @@
+use crate::prelude::WsStreamStatus;
+use tungstenite::Message;
+
+/// An inbound message or a status change of the connection, as yielded by
+/// [`ReconnectT::create_event_stream`](crate::tungstenite::ReconnectT::create_event_stream)
+/// in the order they happened.
+#[derive(Clone)]
+pub(crate) enum StreamEvent {
+    Message(Message),
+    Status(WsStreamStatus),
+}
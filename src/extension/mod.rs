@@ -1,3 +1,4 @@
+mod events;
 mod status_viewer;
 
 pub mod interface {
@@ -34,5 +35,6 @@ pub mod interface {
     }
 }
 
+pub(crate) use crate::extension::events::*;
 pub use crate::extension::interface::*;
 pub use crate::extension::status_viewer::*;
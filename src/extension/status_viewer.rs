@@ -11,6 +11,12 @@ pub struct StatusViewer {
     current: Arc<Mutex<WsStreamStatus>>,
 }
 
+impl Default for StatusViewer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl StatusViewer {
     pub fn new() -> Self {
         Self {
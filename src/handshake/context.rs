@@ -0,0 +1,174 @@
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+use tungstenite::Message;
+
+/// Values learned during a handshake (session id, server-assigned heartbeat interval,
+/// initial snapshot, ...), keyed by type.
+///
+/// The context returned by a successful handshake is stamped with the connection
+/// generation, kept by `ReconnectT` and included in [`WsStreamStatus::Connected`].
+///
+/// [`WsStreamStatus::Connected`]: crate::prelude::WsStreamStatus::Connected
+#[derive(Clone, Default)]
+pub struct HandshakeContext {
+    generation: u64,
+    receive_timeout: Option<Duration>,
+    subprotocol: Option<String>,
+    values: HashMap<TypeId, Arc<dyn Any + Send + Sync>>,
+    forwarded: Vec<Message>,
+}
+
+impl HandshakeContext {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds `value`, replacing any previous value of the same type.
+    pub fn with<T: Any + Send + Sync>(mut self, value: T) -> Self {
+        self.insert(value);
+        self
+    }
+
+    /// Inserts `value`, replacing any previous value of the same type.
+    pub fn insert<T: Any + Send + Sync>(&mut self, value: T) {
+        self.values.insert(TypeId::of::<T>(), Arc::new(value));
+    }
+
+    pub fn get<T: Any + Send + Sync>(&self) -> Option<&T> {
+        self.values
+            .get(&TypeId::of::<T>())
+            .and_then(|value| value.downcast_ref())
+    }
+
+    /// Queues a message consumed during the handshake (e.g. an initial snapshot) for
+    /// delivery to the receive stream listeners once connected.
+    pub fn forward(&mut self, msg: Message) {
+        self.forwarded.push(msg);
+    }
+
+    pub(crate) fn take_forwarded(&mut self) -> Vec<Message> {
+        std::mem::take(&mut self.forwarded)
+    }
+
+    /// The connection generation this context belongs to, starting at 1 for the first
+    /// successful handshake of a `ReconnectT`.
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Overrides the receive timeout of the options for this connection, e.g. with a
+    /// heart-beat interval negotiated during the handshake.
+    pub fn set_receive_timeout(&mut self, receive_timeout: Duration) {
+        self.receive_timeout = Some(receive_timeout);
+    }
+
+    pub fn receive_timeout(&self) -> Option<Duration> {
+        self.receive_timeout
+    }
+
+    /// The subprotocol the server selected in its `Sec-WebSocket-Protocol` header.
+    pub fn subprotocol(&self) -> Option<&str> {
+        self.subprotocol.as_deref()
+    }
+
+    pub(crate) fn set_subprotocol(&mut self, subprotocol: Option<String>) {
+        self.subprotocol = subprotocol;
+    }
+
+    pub(crate) fn set_generation(&mut self, generation: u64) {
+        self.generation = generation;
+    }
+}
+
+impl fmt::Debug for HandshakeContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HandshakeContext")
+            .field("generation", &self.generation)
+            .field("receive_timeout", &self.receive_timeout)
+            .field("subprotocol", &self.subprotocol)
+            .field("values", &self.values.len())
+            .field("forwarded", &self.forwarded.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::handshake::{HandshakeContext, StreamHandshake};
+    use crate::prelude::*;
+    use crate::test_util::mock_server;
+    use crate::types::{PSTReceiver, PSTSender};
+    use async_trait::async_trait;
+    use eyre::Result as EResult;
+    use futures_util::{SinkExt, StreamExt};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tungstenite::Message;
+
+    struct Session(String);
+
+    struct SessionHandshake;
+
+    #[async_trait]
+    impl StreamHandshake for SessionHandshake {
+        async fn handshake(
+            &self,
+            _writer: &mut PSTSender,
+            reader: &mut PSTReceiver,
+        ) -> EResult<HandshakeContext> {
+            let msg = reader.next().await.ok_or_else(|| eyre::eyre!("closed"))??;
+            Ok(HandshakeContext::new().with(Session(msg.into_text()?.to_string())))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_context_per_connection() {
+        let connections = Arc::new(AtomicUsize::new(0));
+        let url = mock_server(move |mut ws| {
+            let connection = connections.fetch_add(1, Ordering::SeqCst);
+            async move {
+                ws.send(Message::text(format!("session-{connection}")))
+                    .await
+                    .unwrap();
+                if connection > 0 {
+                    while ws.next().await.is_some() {}
+                }
+            }
+        })
+        .await;
+
+        let mut options = ReconnectOptions::default();
+        options
+            .with_handshake(Arc::new(SessionHandshake))
+            .with_retry_policy_fn(Arc::new(|| {
+                Box::new(ConstantStrategy::new(Duration::from_millis(10)).into_iter())
+            }));
+        let reconnect = Arc::new(ReconnectT::new(url, Some(options)));
+        let mut status = reconnect.create_status_stream().await;
+        assert!(reconnect.handshake_context().await.is_none());
+        reconnect.spawn_run();
+
+        let Some(WsStreamStatus::Connected(first)) = status.next().await else {
+            panic!("not connected");
+        };
+        assert_eq!(first.generation(), 1);
+        assert_eq!(first.get::<Session>().unwrap().0, "session-0");
+        assert!(matches!(
+            status.next().await,
+            Some(WsStreamStatus::Disconnected)
+        ));
+
+        let Some(WsStreamStatus::Connected(second)) = status.next().await else {
+            panic!("not reconnected");
+        };
+        assert_eq!(second.generation(), 2);
+        assert_eq!(second.get::<Session>().unwrap().0, "session-1");
+        let current = reconnect.handshake_context().await.unwrap();
+        assert!(Arc::ptr_eq(&current, &second));
+        assert_eq!(first.get::<Session>().unwrap().0, "session-0");
+    }
+}
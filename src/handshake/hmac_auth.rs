@@ -0,0 +1,260 @@
+use crate::handshake::{receive_until, HandshakeContext, StreamHandshake};
+use crate::types::{PSTReceiver, PSTSender};
+use async_trait::async_trait;
+use base64::Engine;
+use eyre::{eyre, Result as EResult};
+use futures_util::SinkExt;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tungstenite::Message;
+
+type NonceFn = Arc<dyn Fn(&Message) -> Option<String> + Send + Sync>;
+type MatchFn = Arc<dyn Fn(&Message) -> bool + Send + Sync>;
+
+/// Where the value being signed comes from.
+#[derive(Clone)]
+pub enum Challenge {
+    /// The current Unix time in milliseconds.
+    TimestampMillis,
+    /// The current Unix time in seconds.
+    TimestampSeconds,
+    /// A nonce extracted from the first server message the extractor returns `Some` for.
+    ServerNonce(NonceFn),
+}
+
+impl Challenge {
+    pub fn server_nonce<F>(extract: F) -> Self
+    where
+        F: Fn(&Message) -> Option<String> + Send + Sync + 'static,
+    {
+        Challenge::ServerNonce(Arc::new(extract))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SignatureEncoding {
+    Hex,
+    Base64,
+}
+
+/// The values available to the auth message template.
+#[derive(Clone, Debug)]
+pub struct AuthParams {
+    pub api_key: String,
+    /// The timestamp or server nonce.
+    pub nonce: String,
+    /// The string that was signed.
+    pub payload: String,
+    /// The encoded HMAC-SHA256 signature of `payload`.
+    pub signature: String,
+}
+
+/// Authenticates by signing a timestamp or a server-provided nonce with HMAC-SHA256.
+///
+/// The handshake waits for the challenge (if any), sends the message built by the
+/// template and waits for a reply accepted by the success or failure matcher. Other
+/// messages received meanwhile are forwarded to the listeners. The [`AuthParams`] are
+/// available in the returned context.
+#[derive(Clone)]
+pub struct HmacAuthHandshake {
+    api_key: String,
+    secret: Vec<u8>,
+    challenge: Challenge,
+    payload: Arc<dyn Fn(&str) -> String + Send + Sync>,
+    encoding: SignatureEncoding,
+    template: Arc<dyn Fn(&AuthParams) -> Message + Send + Sync>,
+    success: MatchFn,
+    failure: MatchFn,
+    timeout: Duration,
+}
+
+impl HmacAuthHandshake {
+    /// Creates the handshake with the auth message `template`. By default the current
+    /// timestamp in milliseconds is signed, the signature is hex encoded and the first
+    /// reply is taken as success.
+    pub fn new<F>(api_key: impl Into<String>, secret: impl AsRef<[u8]>, template: F) -> Self
+    where
+        F: Fn(&AuthParams) -> Message + Send + Sync + 'static,
+    {
+        Self {
+            api_key: api_key.into(),
+            secret: secret.as_ref().to_vec(),
+            challenge: Challenge::TimestampMillis,
+            payload: Arc::new(str::to_string),
+            encoding: SignatureEncoding::Hex,
+            template: Arc::new(template),
+            success: Arc::new(|_| true),
+            failure: Arc::new(|_| false),
+            timeout: Duration::from_secs(10),
+        }
+    }
+
+    pub fn with_challenge(mut self, challenge: Challenge) -> Self {
+        self.challenge = challenge;
+        self
+    }
+
+    /// Sets how the signed string is built from the nonce, e.g. `GET/realtime{nonce}`.
+    pub fn with_payload<F>(mut self, payload: F) -> Self
+    where
+        F: Fn(&str) -> String + Send + Sync + 'static,
+    {
+        self.payload = Arc::new(payload);
+        self
+    }
+
+    pub fn with_encoding(mut self, encoding: SignatureEncoding) -> Self {
+        self.encoding = encoding;
+        self
+    }
+
+    /// Sets the matchers for the server's reply to the auth message.
+    /// Failure is checked first; messages matching neither are forwarded.
+    pub fn with_matchers<S, F>(mut self, success: S, failure: F) -> Self
+    where
+        S: Fn(&Message) -> bool + Send + Sync + 'static,
+        F: Fn(&Message) -> bool + Send + Sync + 'static,
+    {
+        self.success = Arc::new(success);
+        self.failure = Arc::new(failure);
+        self
+    }
+
+    /// Sets how long to wait for the challenge and for the auth reply.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Signs `payload` with the secret.
+    pub fn sign(&self, payload: &str) -> String {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.secret).expect("HMAC accepts keys of any length");
+        mac.update(payload.as_bytes());
+        let signature = mac.finalize().into_bytes();
+        match self.encoding {
+            SignatureEncoding::Hex => hex::encode(signature),
+            SignatureEncoding::Base64 => {
+                base64::engine::general_purpose::STANDARD.encode(signature)
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl StreamHandshake for HmacAuthHandshake {
+    async fn handshake(
+        &self,
+        writer: &mut PSTSender,
+        reader: &mut PSTReceiver,
+    ) -> EResult<HandshakeContext> {
+        let mut ctx = HandshakeContext::new();
+        let nonce = match &self.challenge {
+            Challenge::TimestampMillis => unix_time()?.as_millis().to_string(),
+            Challenge::TimestampSeconds => unix_time()?.as_secs().to_string(),
+            Challenge::ServerNonce(extract) => {
+                receive_until(reader, &mut ctx, self.timeout, |msg| extract(msg).map(Ok)).await?
+            }
+        };
+
+        let payload = (self.payload)(&nonce);
+        let params = AuthParams {
+            api_key: self.api_key.clone(),
+            signature: self.sign(&payload),
+            nonce,
+            payload,
+        };
+        writer.send((self.template)(&params)).await?;
+
+        receive_until(reader, &mut ctx, self.timeout, |msg| {
+            if (self.failure)(msg) {
+                Some(Err(eyre!("authentication rejected: {msg}")))
+            } else if (self.success)(msg) {
+                Some(Ok(()))
+            } else {
+                None
+            }
+        })
+        .await?;
+
+        ctx.insert(params);
+        Ok(ctx)
+    }
+}
+
+fn unix_time() -> EResult<Duration> {
+    Ok(SystemTime::now().duration_since(UNIX_EPOCH)?)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::handshake::{
+        AuthParams, Challenge, HmacAuthHandshake, SignatureEncoding, StreamHandshake,
+    };
+    use crate::test_util::mock_server;
+    use futures_util::{SinkExt, StreamExt};
+    use tungstenite::Message;
+
+    fn handshake(secret: &str) -> HmacAuthHandshake {
+        HmacAuthHandshake::new("key", secret, |p| {
+            Message::text(format!("auth {} {}", p.api_key, p.signature))
+        })
+        .with_challenge(Challenge::server_nonce(|msg| {
+            Some(msg.to_text().ok()?.strip_prefix("nonce ")?.to_string())
+        }))
+        .with_payload(|nonce| format!("login{nonce}"))
+        .with_matchers(
+            |msg| msg.to_text().is_ok_and(|t| t == "ok"),
+            |msg| msg.to_text().is_ok_and(|t| t == "denied"),
+        )
+    }
+
+    #[test]
+    fn test_sign() {
+        // RFC 4231, test case 2
+        let handshake = HmacAuthHandshake::new("", "Jefe", |_| Message::text(""));
+        assert_eq!(
+            handshake.sign("what do ya want for nothing?"),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+        let handshake = handshake.with_encoding(SignatureEncoding::Base64);
+        assert_eq!(
+            handshake.sign("what do ya want for nothing?"),
+            "W9zBRr9gdU5qBCQmCJV1x1oAPwidJzmDnexYuWTsOEM="
+        );
+    }
+
+    #[tokio::test]
+    async fn test_hmac_handshake() {
+        let url = mock_server(|mut ws| async move {
+            ws.send(Message::text("welcome")).await.unwrap();
+            ws.send(Message::text("nonce n0nce")).await.unwrap();
+            let expected = format!("auth key {}", handshake("secret").sign("loginn0nce"));
+            while let Some(Ok(msg)) = ws.next().await {
+                let reply = if msg.to_text().unwrap() == expected {
+                    "ok"
+                } else {
+                    "denied"
+                };
+                ws.send(Message::text(reply)).await.unwrap();
+            }
+        })
+        .await;
+
+        let (ws, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+        let (mut writer, mut reader) = ws.split();
+        let mut ctx = handshake("secret")
+            .handshake(&mut writer, &mut reader)
+            .await
+            .unwrap();
+        assert_eq!(ctx.get::<AuthParams>().unwrap().nonce, "n0nce");
+        assert_eq!(ctx.take_forwarded(), [Message::text("welcome")]);
+
+        let (ws, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+        let (mut writer, mut reader) = ws.split();
+        let result = handshake("wrong").handshake(&mut writer, &mut reader).await;
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,134 @@
+mod context;
+mod hmac_auth;
+mod scripted;
+
+use crate::types::{PSTReceiver, PSTSender};
+use async_trait::async_trait;
+use eyre::{eyre, Result as EResult};
+use futures_util::SinkExt;
+use std::time::Duration;
+use tokio_stream::StreamExt;
+use tungstenite::protocol::frame::Utf8Bytes;
+use tungstenite::Message;
+
+#[async_trait]
+pub trait StreamHandshake {
+    /// Performs the handshake on a freshly connected stream and returns what it learned.
+    async fn handshake(
+        &self,
+        writer: &mut PSTSender,
+        reader: &mut PSTReceiver,
+    ) -> EResult<HandshakeContext>;
+}
+
+pub struct NonHandshake;
+
+#[async_trait]
+impl StreamHandshake for NonHandshake {
+    async fn handshake(
+        &self,
+        _writer: &mut PSTSender,
+        _reader: &mut PSTReceiver,
+    ) -> EResult<HandshakeContext> {
+        Ok(HandshakeContext::new())
+    }
+}
+
+/// Sends a greeting and waits for one reply.
+#[derive(Default)]
+pub struct SingleHandshake {
+    forward_reply: bool,
+}
+
+impl SingleHandshake {
+    /// With `forward_reply`, the reply is delivered to the receive stream listeners once
+    /// connected and a read error fails the handshake. Otherwise the reply is discarded.
+    pub fn new(forward_reply: bool) -> Self {
+        Self { forward_reply }
+    }
+}
+
+#[async_trait]
+impl StreamHandshake for SingleHandshake {
+    async fn handshake(
+        &self,
+        writer: &mut PSTSender,
+        reader: &mut PSTReceiver,
+    ) -> EResult<HandshakeContext> {
+        let _ = writer
+            .send(Message::Text(Utf8Bytes::from("hello world")))
+            .await?;
+        let mut ctx = HandshakeContext::new();
+        let reply = reader.next().await;
+        if self.forward_reply {
+            if let Some(reply) = reply {
+                ctx.forward(reply?);
+            }
+        }
+        Ok(ctx)
+    }
+}
+
+/// Reads handshake replies until `accept` returns a result. Messages it returns `None`
+/// for are forwarded to the listeners once connected.
+pub(crate) async fn receive_until<T>(
+    reader: &mut PSTReceiver,
+    ctx: &mut HandshakeContext,
+    timeout: Duration,
+    mut accept: impl FnMut(&Message) -> Option<EResult<T>>,
+) -> EResult<T> {
+    let wait = async {
+        loop {
+            match reader.next().await {
+                Some(Ok(msg)) => match accept(&msg) {
+                    Some(result) => return result,
+                    None => ctx.forward(msg),
+                },
+                Some(Err(e)) => return Err(e.into()),
+                None => return Err(eyre!("connection closed during the handshake")),
+            }
+        }
+    };
+    tokio::time::timeout(timeout, wait)
+        .await
+        .map_err(|_| eyre!("no handshake reply within {timeout:?}"))?
+}
+
+pub use crate::handshake::context::*;
+pub use crate::handshake::hmac_auth::*;
+pub use crate::handshake::scripted::*;
+
+#[cfg(test)]
+mod test {
+    use crate::handshake::SingleHandshake;
+    use crate::prelude::*;
+    use crate::test_util::mock_server;
+    use futures_util::{SinkExt, StreamExt};
+    use std::sync::Arc;
+    use tungstenite::Message;
+
+    #[tokio::test]
+    async fn test_single_handshake_forwards_reply() {
+        let url = mock_server(|mut ws| async move {
+            let hello = ws.next().await.unwrap().unwrap();
+            ws.send(Message::text(format!("re: {}", hello.to_text().unwrap())))
+                .await
+                .unwrap();
+            ws.send(Message::text("update")).await.unwrap();
+            while ws.next().await.is_some() {}
+        })
+        .await;
+
+        let mut options = ReconnectOptions::default();
+        options.with_handshake(Arc::new(SingleHandshake::new(true)));
+        let reconnect = Arc::new(ReconnectT::new(url, Some(options)));
+        let mut messages = reconnect.create_receive_stream().await;
+        reconnect.spawn_run();
+
+        assert_eq!(
+            messages.next().await.unwrap(),
+            Message::text("re: hello world")
+        );
+        assert_eq!(messages.next().await.unwrap(), Message::text("update"));
+    }
+}
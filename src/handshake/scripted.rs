@@ -0,0 +1,250 @@
+use crate::handshake::{HandshakeContext, StreamHandshake};
+use crate::types::{PSTReceiver, PSTSender};
+use async_trait::async_trait;
+use eyre::{eyre, Result as EResult};
+use futures_util::SinkExt;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_stream::StreamExt;
+use tungstenite::Message;
+
+/// Values extracted by the steps of a [`ScriptedHandshake`], e.g. a session id.
+#[derive(Clone, Debug, Default)]
+pub struct ScriptContext {
+    values: HashMap<String, String>,
+}
+
+impl ScriptContext {
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.values.get(key).map(String::as_str)
+    }
+
+    pub fn insert(&mut self, key: impl Into<String>, value: impl Into<String>) {
+        self.values.insert(key.into(), value.into());
+    }
+}
+
+type SendFn = Arc<dyn Fn(&ScriptContext) -> EResult<Message> + Send + Sync>;
+type ExpectFn = Arc<dyn Fn(&Message, &mut ScriptContext) -> EResult<bool> + Send + Sync>;
+type ExtractFn = Arc<dyn Fn(&Message) -> Option<String> + Send + Sync>;
+
+#[derive(Clone)]
+enum Step {
+    Send(SendFn),
+    Expect {
+        timeout: Duration,
+        matcher: ExpectFn,
+    },
+    Extract {
+        key: String,
+        extractor: ExtractFn,
+    },
+}
+
+/// A handshake assembled from steps, covering common login/subscribe flows
+/// without a custom [`StreamHandshake`] implementation.
+///
+/// ```ignore
+/// let handshake = ScriptedHandshake::new()
+///     .send(Message::text(r#"{"op":"login","token":"..."}"#))
+///     .expect(Duration::from_secs(5), |msg| msg.to_text().is_ok_and(|t| t.contains("session")))
+///     .extract("session", |msg| parse_session_id(msg))
+///     .send_with(|ctx| Ok(Message::text(format!(r#"{{"op":"subscribe","session":"{}"}}"#, ctx.get("session").unwrap()))));
+/// ```
+#[derive(Clone, Default)]
+pub struct ScriptedHandshake {
+    steps: Vec<Step>,
+    forward_consumed: bool,
+}
+
+impl ScriptedHandshake {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sends `msg`.
+    pub fn send(self, msg: Message) -> Self {
+        self.send_with(move |_| Ok(msg.clone()))
+    }
+
+    /// Sends the message built from the values extracted so far.
+    pub fn send_with<F>(mut self, build: F) -> Self
+    where
+        F: Fn(&ScriptContext) -> EResult<Message> + Send + Sync + 'static,
+    {
+        self.steps.push(Step::Send(Arc::new(build)));
+        self
+    }
+
+    /// Waits up to `timeout` for a message matching `predicate`, skipping others.
+    pub fn expect<F>(self, timeout: Duration, predicate: F) -> Self
+    where
+        F: Fn(&Message) -> bool + Send + Sync + 'static,
+    {
+        self.expect_with(timeout, move |msg, _| Ok(predicate(msg)))
+    }
+
+    /// Waits up to `timeout` for a message accepted by `matcher`, skipping messages it
+    /// returns `Ok(false)` for. An error from `matcher` fails the handshake, e.g. on an
+    /// explicit rejection from the server.
+    pub fn expect_with<F>(mut self, timeout: Duration, matcher: F) -> Self
+    where
+        F: Fn(&Message, &mut ScriptContext) -> EResult<bool> + Send + Sync + 'static,
+    {
+        self.steps.push(Step::Expect {
+            timeout,
+            matcher: Arc::new(matcher),
+        });
+        self
+    }
+
+    /// Stores a value taken from the message matched by the preceding `expect` under `key`.
+    /// Fails the handshake if `extractor` returns `None`.
+    pub fn extract<F>(mut self, key: impl Into<String>, extractor: F) -> Self
+    where
+        F: Fn(&Message) -> Option<String> + Send + Sync + 'static,
+    {
+        self.steps.push(Step::Extract {
+            key: key.into(),
+            extractor: Arc::new(extractor),
+        });
+        self
+    }
+
+    /// Appends the steps of `next`.
+    pub fn then(mut self, next: ScriptedHandshake) -> Self {
+        self.steps.extend(next.steps);
+        self.forward_consumed |= next.forward_consumed;
+        self
+    }
+
+    /// Forwards every message consumed by `expect` steps, matched or skipped,
+    /// to the receive stream listeners once connected.
+    pub fn forward_consumed(mut self) -> Self {
+        self.forward_consumed = true;
+        self
+    }
+}
+
+#[async_trait]
+impl StreamHandshake for ScriptedHandshake {
+    /// The extracted values are available as `ScriptContext` in the returned context.
+    async fn handshake(
+        &self,
+        writer: &mut PSTSender,
+        reader: &mut PSTReceiver,
+    ) -> EResult<HandshakeContext> {
+        let mut ctx = ScriptContext::default();
+        let mut consumed = Vec::new();
+        let mut matched: Option<Message> = None;
+        for step in &self.steps {
+            match step {
+                Step::Send(build) => writer.send(build(&ctx)?).await?,
+                Step::Expect { timeout, matcher } => {
+                    let wait = async {
+                        loop {
+                            match reader.next().await {
+                                Some(Ok(msg)) => {
+                                    let is_match = matcher(&msg, &mut ctx)?;
+                                    if self.forward_consumed {
+                                        consumed.push(msg.clone());
+                                    }
+                                    if is_match {
+                                        return Ok(msg);
+                                    }
+                                    tracing::debug!(msg=?msg, "handshake::skip");
+                                }
+                                Some(Err(e)) => return Err(e.into()),
+                                None => return Err(eyre!("connection closed during handshake")),
+                            }
+                        }
+                    };
+                    let msg = tokio::time::timeout(*timeout, wait)
+                        .await
+                        .map_err(|_| eyre!("no handshake reply within {timeout:?}"))??;
+                    matched = Some(msg);
+                }
+                Step::Extract { key, extractor } => {
+                    let msg = matched
+                        .as_ref()
+                        .ok_or_else(|| eyre!("extract `{key}` has no preceding expect"))?;
+                    let value = extractor(msg).ok_or_else(|| eyre!("failed to extract `{key}`"))?;
+                    ctx.insert(key.clone(), value);
+                }
+            }
+        }
+
+        let mut handshake_ctx = HandshakeContext::new().with(ctx);
+        for msg in consumed {
+            handshake_ctx.forward(msg);
+        }
+        Ok(handshake_ctx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::handshake::{ScriptContext, ScriptedHandshake, StreamHandshake};
+    use crate::test_util::mock_server;
+    use futures_util::{SinkExt, StreamExt};
+    use std::time::Duration;
+    use tungstenite::Message;
+
+    #[tokio::test]
+    async fn test_scripted_handshake() {
+        let url = mock_server(|mut ws| async move {
+            while let Some(Ok(msg)) = ws.next().await {
+                let reply = match msg.to_text().unwrap() {
+                    "login" => "session=abc",
+                    "subscribe abc" => "subscribed",
+                    _ => continue,
+                };
+                ws.send(Message::text("noise")).await.unwrap();
+                ws.send(Message::text(reply)).await.unwrap();
+            }
+        })
+        .await;
+
+        let login = ScriptedHandshake::new()
+            .forward_consumed()
+            .send(Message::text("login"))
+            .expect(Duration::from_secs(1), |msg| {
+                msg.to_text().is_ok_and(|t| t.starts_with("session="))
+            })
+            .extract("session", |msg| {
+                Some(msg.to_text().ok()?.strip_prefix("session=")?.to_string())
+            });
+        let subscribe = ScriptedHandshake::new()
+            .send_with(|ctx| {
+                Ok(Message::text(format!(
+                    "subscribe {}",
+                    ctx.get("session").unwrap()
+                )))
+            })
+            .expect(Duration::from_secs(1), |msg| {
+                msg.to_text().is_ok_and(|t| t == "subscribed")
+            });
+
+        let (ws, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+        let (mut writer, mut reader) = ws.split();
+        let mut ctx = login
+            .then(subscribe)
+            .handshake(&mut writer, &mut reader)
+            .await
+            .unwrap();
+        let script = ctx.get::<ScriptContext>().unwrap();
+        assert_eq!(script.get("session"), Some("abc"));
+        let forwarded: Vec<_> = ctx
+            .take_forwarded()
+            .into_iter()
+            .map(|msg| msg.into_text().unwrap().to_string())
+            .collect();
+        assert_eq!(forwarded, ["noise", "session=abc", "noise", "subscribed"]);
+
+        let timeout = ScriptedHandshake::new()
+            .send(Message::text("unknown"))
+            .expect(Duration::from_millis(50), |_| true);
+        assert!(timeout.handshake(&mut writer, &mut reader).await.is_err());
+    }
+}
@@ -1,6 +1,13 @@
+pub mod circuit_breaker;
 pub mod config;
+pub mod coordinator;
+pub mod correlation;
+pub mod decompression;
 pub mod handshake;
+pub mod protocols;
+pub mod retry;
 pub mod strategies;
+pub mod subscriptions;
 
 pub mod tungstenite;
 
@@ -12,6 +19,8 @@ pub mod errors;
 pub(crate) mod event_listeners;
 pub mod extension;
 pub(crate) mod maybe_sender;
+#[cfg(test)]
+pub(crate) mod test_util;
 
 /// Contains type aliases for WebSocket stream components.
 pub(crate) mod types {
@@ -29,23 +38,33 @@ pub(crate) mod types {
 pub(crate) mod status {
     #[derive(Clone)]
     pub enum WsStreamStatus {
-        /// The WebSocket stream is connected.
-        Connected,
+        /// The WebSocket stream is connected; carries what the handshake learned.
+        Connected(std::sync::Arc<crate::handshake::HandshakeContext>),
         /// The WebSocket stream is disconnected.
         Disconnected,
+        /// Too many consecutive failures; reconnecting is suspended for the given cool-down.
+        CircuitOpen(std::time::Duration),
+        /// The cool-down has elapsed; a single probe connection is being attempted.
+        CircuitHalfOpen,
     }
 }
 
 /// A prelude module for convenient imports of commonly used items.
 pub mod prelude {
+    pub use super::circuit_breaker::*;
     pub use super::config::*;
+    pub use super::coordinator::*;
+    pub use super::correlation::*;
+    pub use super::decompression::*;
     pub use super::errors::*;
     pub use super::event_listeners::*;
     pub use super::extension::*;
     pub use super::handshake::*;
     pub use super::maybe_sender::*;
+    pub use super::retry::*;
     pub use super::status::*;
     pub use super::strategies::*;
+    pub use super::subscriptions::*;
     pub use super::tungstenite::*;
     pub use super::types::*;
 }
@@ -2,8 +2,9 @@ use crate::errors::ReconnectTError;
 use crate::types::PSTSender;
 use eyre::Result as EResult;
 use futures_util::SinkExt;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
-use tokio::sync::Mutex;
+use tokio::sync::{watch, Mutex, MutexGuard};
 use tungstenite::Message;
 
 pub enum OptPSTSender {
@@ -13,31 +14,76 @@ pub enum OptPSTSender {
 
 pub struct MaybePSTSender {
     inner: Arc<Mutex<OptPSTSender>>,
+    connected: watch::Sender<bool>,
+    generation: AtomicU64,
+}
+
+/// The locked sender. No reconnect can happen while it is held.
+pub(crate) struct SenderGuard<'a> {
+    inner: MutexGuard<'a, OptPSTSender>,
+    generation: u64,
+}
+
+impl SenderGuard<'_> {
+    /// Returns the generation of the connection, or `None` if not connected.
+    pub(crate) fn generation(&self) -> Option<u64> {
+        match *self.inner {
+            OptPSTSender::Some(_) => Some(self.generation),
+            OptPSTSender::None => None,
+        }
+    }
+
+    pub(crate) async fn send(&mut self, msg: Message) -> EResult<(), ReconnectTError> {
+        self.inner.send(msg).await
+    }
 }
 
 impl Default for MaybePSTSender {
     fn default() -> Self {
         Self {
             inner: Arc::new(Mutex::new(OptPSTSender::None)),
+            connected: watch::Sender::new(false),
+            generation: AtomicU64::new(0),
         }
     }
 }
 
 impl MaybePSTSender {
-    pub(crate) async fn set_sender(&self, sender: PSTSender) {
+    pub(crate) async fn set_sender(&self, sender: PSTSender, generation: u64) {
         let mut lock = self.inner.lock().await;
         *lock = OptPSTSender::Some(sender);
+        self.generation.store(generation, Ordering::SeqCst);
+        self.connected.send_replace(true);
     }
 
     pub(crate) async fn reset_sender(&self) {
         let mut lock = self.inner.lock().await;
         *lock = OptPSTSender::None;
+        self.connected.send_replace(false);
+    }
+
+    pub fn is_connected(&self) -> bool {
+        *self.connected.borrow()
+    }
+
+    /// Waits until a sender is connected.
+    pub async fn wait_connected(&self) {
+        let mut connected = self.connected.subscribe();
+        let _ = connected.wait_for(|connected| *connected).await;
     }
 
     pub async fn send(&self, msg: Message) -> EResult<(), ReconnectTError> {
         let mut lock = self.inner.lock().await;
         lock.send(msg).await
     }
+
+    pub(crate) async fn lock(&self) -> SenderGuard<'_> {
+        let inner = self.inner.lock().await;
+        SenderGuard {
+            inner,
+            generation: self.generation.load(Ordering::SeqCst),
+        }
+    }
 }
 
 impl OptPSTSender {
@@ -46,7 +92,7 @@ impl OptPSTSender {
             OptPSTSender::Some(sender) => sender
                 .send(msg)
                 .await
-                .map_err(|e| ReconnectTError::TokioTungsteniteError(e)),
+                .map_err(ReconnectTError::TokioTungsteniteError),
             OptPSTSender::None => Err(ReconnectTError::SenderNotConnected),
         }
     }
@@ -0,0 +1,524 @@
+//! A Chrome DevTools Protocol client, with flattened target sessions.
+
+use crate::correlation::Correlator;
+use crate::errors::ReconnectTError;
+use crate::handshake::HandshakeContext;
+use crate::maybe_sender::MaybePSTSender;
+use crate::protocols::{Driver, ProtocolClient};
+use crate::tungstenite::ReconnectT;
+use async_trait::async_trait;
+use eyre::Result as EResult;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::sync::{mpsc, Mutex};
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use tokio_stream::Stream;
+use tungstenite::client::IntoClientRequest;
+use tungstenite::Message;
+
+/// The error of a failed command.
+#[derive(thiserror::Error, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[error("CDP error {code}: {message}")]
+pub struct ProtocolError {
+    pub code: i64,
+    pub message: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data: Option<Value>,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum CdpError {
+    #[error(transparent)]
+    Transport(#[from] ReconnectTError),
+    #[error(transparent)]
+    Protocol(#[from] ProtocolError),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    /// The session was detached, or lost with the connection.
+    #[error("session {0} is closed")]
+    SessionClosed(String),
+}
+
+/// An event, from the browser or from the session it carries.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CdpEvent {
+    pub method: String,
+    #[serde(default)]
+    pub params: Value,
+    #[serde(default)]
+    pub session_id: Option<String>,
+}
+
+/// Event listeners by session and method.
+type Listeners = HashMap<(Option<String>, String), Vec<mpsc::UnboundedSender<CdpEvent>>>;
+
+/// Configures and attaches a [`CdpClient`].
+pub struct CdpClientBuilder {
+    timeout: Duration,
+}
+
+impl CdpClientBuilder {
+    /// Sets how long a command may take. Defaults to 30s.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub async fn attach<R: IntoClientRequest + Send + Sync>(
+        self,
+        reconnect: &ReconnectT<R>,
+    ) -> EResult<Arc<CdpClient>> {
+        let correlator = Correlator::new(reconnect.sender.clone(), |msg| {
+            let value: Value = serde_json::from_str(msg.to_text().ok()?).ok()?;
+            value.get("id")?.as_u64().map(|id| id.to_string())
+        })
+        .with_timeout(self.timeout)
+        .attach(reconnect)
+        .await?;
+        let client = Arc::new(CdpClient {
+            sender: reconnect.sender.clone(),
+            correlator,
+            next_id: AtomicU64::new(1),
+            generation: AtomicU64::new(0),
+            listeners: Mutex::new(HashMap::new()),
+            sessions: Mutex::new(Sessions::default()),
+            persistent: Mutex::new(Vec::new()),
+        });
+        Driver::attach(&client, reconnect).await?;
+        Ok(client)
+    }
+}
+
+/// The open sessions, by id, with their target id and the generation of the connection
+/// they were attached on.
+#[derive(Default)]
+struct Sessions {
+    open: HashMap<String, (String, u64)>,
+    /// The generation of the last connection that dropped.
+    closed: u64,
+}
+
+/// A CDP client over a [`ReconnectT`].
+///
+/// Sessions attached with [`attach_to_target`](Self::attach_to_target) end when the
+/// connection drops, while browser-level event streams continue after a reconnect.
+/// Commands sent with [`send_persistent`](Self::send_persistent), such as
+/// `Target.setDiscoverTargets`, are re-sent after every reconnect until removed with
+/// [`remove_persistent`](Self::remove_persistent).
+pub struct CdpClient {
+    sender: Arc<MaybePSTSender>,
+    correlator: Arc<Correlator>,
+    next_id: AtomicU64,
+    generation: AtomicU64,
+    listeners: Mutex<Listeners>,
+    sessions: Mutex<Sessions>,
+    persistent: Mutex<Vec<(String, Value)>>,
+}
+
+impl CdpClient {
+    pub fn builder() -> CdpClientBuilder {
+        CdpClientBuilder {
+            timeout: Duration::from_secs(30),
+        }
+    }
+
+    /// Sends the browser-level command `method` and deserializes its result.
+    pub async fn send<P: Serialize, T: DeserializeOwned>(
+        &self,
+        method: &str,
+        params: P,
+    ) -> Result<T, CdpError> {
+        let result = self
+            .send_value(None, method, serde_json::to_value(params)?)
+            .await?;
+        Ok(serde_json::from_value(result)?)
+    }
+
+    /// Sends `method`, on `session_id` if given, and returns its raw result.
+    pub async fn send_value(
+        &self,
+        session_id: Option<&str>,
+        method: &str,
+        params: Value,
+    ) -> Result<Value, CdpError> {
+        if let Some(session_id) = session_id {
+            if !self.sessions.lock().await.open.contains_key(session_id) {
+                return Err(CdpError::SessionClosed(session_id.to_string()));
+            }
+        }
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let mut command = json!({"id": id, "method": method, "params": params});
+        if let Some(session_id) = session_id {
+            command["sessionId"] = json!(session_id);
+        }
+        let reply = self
+            .correlator
+            .request(Message::text(command.to_string()), id.to_string())
+            .await?;
+        let mut reply: Value = serde_json::from_str(reply.to_text().unwrap_or_default())?;
+        if let Some(error) = reply.get_mut("error") {
+            return Err(CdpError::Protocol(serde_json::from_value(error.take())?));
+        }
+        Ok(reply.get_mut("result").map(Value::take).unwrap_or_default())
+    }
+
+    /// Sends the browser-level command `method` now and after every reconnect, replacing
+    /// the params of an earlier persistent `method`.
+    pub async fn send_persistent(&self, method: &str, params: Value) -> Result<Value, CdpError> {
+        {
+            let mut persistent = self.persistent.lock().await;
+            match persistent.iter_mut().find(|(m, _)| m == method) {
+                Some((_, p)) => *p = params.clone(),
+                None => persistent.push((method.to_string(), params.clone())),
+            }
+        }
+        self.send_value(None, method, params).await
+    }
+
+    /// Stops re-sending the persistent command `method`. Returns whether there was one.
+    pub async fn remove_persistent(&self, method: &str) -> bool {
+        let mut persistent = self.persistent.lock().await;
+        let len = persistent.len();
+        persistent.retain(|(m, _)| m != method);
+        persistent.len() != len
+    }
+
+    /// Creates a stream of the browser-level events `method`, e.g.
+    /// `Target.targetCreated`.
+    pub async fn events(&self, method: &str) -> CdpEvents {
+        self.listen(None, method).await
+    }
+
+    /// Attaches to `target_id` in flattened mode and returns the session.
+    pub async fn attach_to_target(
+        self: &Arc<Self>,
+        target_id: &str,
+    ) -> Result<CdpSession, CdpError> {
+        #[derive(Deserialize)]
+        #[serde(rename_all = "camelCase")]
+        struct Attached {
+            session_id: String,
+        }
+        let generation = self.sender.lock().await.generation().unwrap_or_default();
+        let attached: Attached = self
+            .send(
+                "Target.attachToTarget",
+                json!({"targetId": target_id, "flatten": true}),
+            )
+            .await?;
+        {
+            let mut sessions = self.sessions.lock().await;
+            if sessions.closed >= generation {
+                // the connection dropped before the reply was handled
+                return Err(CdpError::SessionClosed(attached.session_id));
+            }
+            sessions.open.insert(
+                attached.session_id.clone(),
+                (target_id.to_string(), generation),
+            );
+        }
+        Ok(CdpSession {
+            client: self.clone(),
+            session_id: attached.session_id,
+        })
+    }
+
+    /// Returns the number of open sessions.
+    pub async fn sessions_len(&self) -> usize {
+        self.sessions.lock().await.open.len()
+    }
+
+    async fn listen(&self, session_id: Option<&str>, method: &str) -> CdpEvents {
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.listeners
+            .lock()
+            .await
+            .entry((session_id.map(str::to_string), method.to_string()))
+            .or_default()
+            .push(tx);
+        CdpEvents {
+            stream: UnboundedReceiverStream::new(rx),
+        }
+    }
+
+    async fn dispatch(&self, event: CdpEvent) {
+        if event.method == "Target.detachedFromTarget" {
+            if let Some(session_id) = event.params["sessionId"].as_str() {
+                self.sessions.lock().await.open.remove(session_id);
+                self.close_session(session_id).await;
+            }
+        }
+        let mut listeners = self.listeners.lock().await;
+        let key = (event.session_id.clone(), event.method.clone());
+        if let Some(senders) = listeners.get_mut(&key) {
+            senders.retain(|tx| tx.send(event.clone()).is_ok());
+            if senders.is_empty() {
+                listeners.remove(&key);
+            }
+        }
+    }
+
+    /// Ends the event streams of `session_id`.
+    async fn close_session(&self, session_id: &str) {
+        let mut listeners = self.listeners.lock().await;
+        listeners.retain(|(session, _), _| session.as_deref() != Some(session_id));
+    }
+
+    /// Re-sends the persistent commands while connection `generation` is current.
+    async fn replay(&self, generation: u64) {
+        let persistent = self.persistent.lock().await.clone();
+        for (method, params) in persistent {
+            if self.generation.load(Ordering::SeqCst) != generation {
+                return;
+            }
+            if let Err(e) = self.send_value(None, &method, params).await {
+                tracing::warn!(method=method, error=?e, "cdp::replay");
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl ProtocolClient for CdpClient {
+    async fn on_message(self: Arc<Self>, msg: Message) {
+        let Ok(text) = msg.to_text() else {
+            return;
+        };
+        let Ok(value) = serde_json::from_str::<Value>(text) else {
+            tracing::debug!(msg = text, "cdp::unhandled");
+            return;
+        };
+        if value.get("id").is_some() {
+            // a reply, handled by the correlator
+            return;
+        }
+        match serde_json::from_value::<CdpEvent>(value) {
+            Ok(event) => self.dispatch(event).await,
+            Err(e) => tracing::debug!(error=?e, msg=text, "cdp::unhandled"),
+        }
+    }
+
+    async fn on_connected(self: Arc<Self>, ctx: Arc<HandshakeContext>) {
+        self.generation.store(ctx.generation(), Ordering::SeqCst);
+        // awaits the replies, which must not hold back the connection's messages
+        tokio::spawn(async move { self.replay(ctx.generation()).await });
+    }
+
+    async fn on_disconnected(self: Arc<Self>) {
+        // sessions do not survive the connection; those already attached on the next
+        // one are left alone
+        let sessions: Vec<String> = {
+            let mut sessions = self.sessions.lock().await;
+            let generation = self.generation.load(Ordering::SeqCst);
+            sessions.closed = generation;
+            let closed: Vec<String> = sessions
+                .open
+                .iter()
+                .filter(|(_, (_, attached))| *attached <= generation)
+                .map(|(id, _)| id.clone())
+                .collect();
+            for id in &closed {
+                sessions.open.remove(id);
+            }
+            closed
+        };
+        for session_id in sessions {
+            self.close_session(&session_id).await;
+        }
+    }
+}
+
+/// A flattened session attached to a target, sharing the client's connection.
+pub struct CdpSession {
+    client: Arc<CdpClient>,
+    session_id: String,
+}
+
+impl CdpSession {
+    pub fn session_id(&self) -> &str {
+        &self.session_id
+    }
+
+    /// Sends `method` to the target, e.g. `Page.navigate`.
+    pub async fn send<P: Serialize, T: DeserializeOwned>(
+        &self,
+        method: &str,
+        params: P,
+    ) -> Result<T, CdpError> {
+        let result = self
+            .client
+            .send_value(
+                Some(&self.session_id),
+                method,
+                serde_json::to_value(params)?,
+            )
+            .await?;
+        Ok(serde_json::from_value(result)?)
+    }
+
+    /// Creates a stream of the target's events `method`. It ends with the session.
+    pub async fn events(&self, method: &str) -> CdpEvents {
+        self.client.listen(Some(&self.session_id), method).await
+    }
+
+    pub async fn detach(self) -> Result<(), CdpError> {
+        self.client
+            .send_value(
+                None,
+                "Target.detachFromTarget",
+                json!({"sessionId": self.session_id}),
+            )
+            .await?;
+        self.client
+            .sessions
+            .lock()
+            .await
+            .open
+            .remove(&self.session_id);
+        self.client.close_session(&self.session_id).await;
+        Ok(())
+    }
+}
+
+/// A stream of events of one method.
+pub struct CdpEvents {
+    stream: UnboundedReceiverStream<CdpEvent>,
+}
+
+impl Stream for CdpEvents {
+    type Item = CdpEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<CdpEvent>> {
+        Pin::new(&mut self.stream).poll_next(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::prelude::*;
+    use crate::protocols::cdp::{CdpClient, CdpError};
+    use crate::test_util::mock_server;
+    use futures_util::{SinkExt, StreamExt};
+    use serde_json::{json, Value};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::sync::mpsc;
+    use tungstenite::Message;
+
+    #[tokio::test]
+    async fn test_cdp() {
+        let (seen_tx, mut seen_rx) = mpsc::unbounded_channel();
+        let connections = Arc::new(AtomicUsize::new(0));
+        let url = mock_server(move |mut ws| {
+            let seen_tx = seen_tx.clone();
+            let connection = connections.fetch_add(1, Ordering::SeqCst);
+            async move {
+                while let Some(Ok(msg)) = ws.next().await {
+                    let command: Value = serde_json::from_str(msg.to_text().unwrap()).unwrap();
+                    let method = command["method"].as_str().unwrap().to_string();
+                    seen_tx.send((connection, method.clone())).unwrap();
+                    let id = &command["id"];
+                    let session = &command["sessionId"];
+                    let replies = match method.as_str() {
+                        "Target.setDiscoverTargets" => vec![
+                            json!({"id": id, "result": {}}),
+                            json!({"method": "Target.targetCreated", "params": {"connection": connection}}),
+                        ],
+                        "Target.attachToTarget" => vec![
+                            json!({"id": id, "result": {"sessionId": format!("S{connection}")}}),
+                        ],
+                        "Page.navigate" => vec![
+                            json!({"method": "Page.frameNavigated", "params": {}, "sessionId": session}),
+                            json!({"id": id, "result": {"frameId": "F"}, "sessionId": session}),
+                        ],
+                        "Page.crash" => return,
+                        _ => vec![json!({
+                            "id": id,
+                            "error": {"code": -32601, "message": format!("'{method}' wasn't found")},
+                        })],
+                    };
+                    for reply in replies {
+                        ws.send(Message::text(reply.to_string())).await.unwrap();
+                    }
+                }
+            }
+        })
+        .await;
+
+        let mut options = ReconnectOptions::default();
+        options.with_retry_policy_fn(Arc::new(|| {
+            Box::new(ConstantStrategy::new(Duration::from_millis(10)).into_iter())
+        }));
+        let reconnect = Arc::new(ReconnectT::new(url, Some(options)));
+        let client = CdpClient::builder().attach(&reconnect).await.unwrap();
+        let mut created = client.events("Target.targetCreated").await;
+        let mut status = reconnect.create_status_stream().await;
+        reconnect.spawn_run();
+        assert!(matches!(
+            status.next().await,
+            Some(WsStreamStatus::Connected(_))
+        ));
+
+        client
+            .send_persistent("Target.setDiscoverTargets", json!({"discover": true}))
+            .await
+            .unwrap();
+        assert_eq!(
+            created.next().await.unwrap().params,
+            json!({"connection": 0})
+        );
+        client
+            .send_persistent("Target.setDiscoverTargets", json!({"discover": true}))
+            .await
+            .unwrap();
+        assert_eq!(
+            created.next().await.unwrap().params,
+            json!({"connection": 0})
+        );
+        assert_eq!(client.persistent.lock().await.len(), 1);
+        assert!(client
+            .send_persistent("Foo.persist", json!({}))
+            .await
+            .is_err());
+        assert!(client.remove_persistent("Foo.persist").await);
+        assert!(!client.remove_persistent("Foo.persist").await);
+        assert_eq!(client.persistent.lock().await.len(), 1);
+
+        let missing = client.send::<_, Value>("Foo.bar", json!({})).await;
+        assert!(matches!(missing, Err(CdpError::Protocol(e)) if e.code == -32601));
+
+        let session = client.attach_to_target("T1").await.unwrap();
+        assert_eq!(session.session_id(), "S0");
+        let mut navigated = session.events("Page.frameNavigated").await;
+        let result: Value = session
+            .send("Page.navigate", json!({"url": "about:blank"}))
+            .await
+            .unwrap();
+        assert_eq!(result, json!({"frameId": "F"}));
+        let event = navigated.next().await.unwrap();
+        assert_eq!(event.session_id.as_deref(), Some("S0"));
+
+        // the session is lost with the connection; browser events continue
+        let crashed = session.send::<_, Value>("Page.crash", json!({})).await;
+        assert!(matches!(crashed, Err(CdpError::Transport(_))));
+        assert!(navigated.next().await.is_none());
+        assert_eq!(
+            created.next().await.unwrap().params,
+            json!({"connection": 1})
+        );
+        let closed = session.send::<_, Value>("Page.navigate", json!({})).await;
+        assert!(matches!(closed, Err(CdpError::SessionClosed(id)) if id == "S0"));
+        assert_eq!(client.sessions_len().await, 0);
+
+        while seen_rx.recv().await != Some((1, "Target.setDiscoverTargets".to_string())) {}
+    }
+}
@@ -0,0 +1,714 @@
+//! A client of the Centrifugo JSON protocol, e.g. `ws://host/connection/websocket`.
+//!
+//! Commands and replies are JSON objects correlated by id, several of them sharing a
+//! frame separated by newlines.
+
+use crate::errors::ReconnectTError;
+use crate::handshake::{receive_until, HandshakeContext, StreamHandshake};
+use crate::maybe_sender::MaybePSTSender;
+use crate::protocols::{Driver, ProtocolClient};
+use crate::tungstenite::{ReconnectT, StopHandle};
+use crate::types::{PSTReceiver, PSTSender};
+use async_trait::async_trait;
+use eyre::{eyre, Result as EResult};
+use futures_util::SinkExt;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot, Mutex};
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use tokio_stream::Stream;
+use tungstenite::client::IntoClientRequest;
+use tungstenite::Message;
+
+/// The id of the connect command sent by the handshake.
+const CONNECT_ID: u64 = 1;
+
+/// Splits a frame into its non-empty JSON lines.
+fn lines(msg: &Message) -> impl Iterator<Item = &str> {
+    msg.to_text()
+        .unwrap_or_default()
+        .split('\n')
+        .filter(|line| !line.trim().is_empty())
+}
+
+/// The error of a command.
+#[derive(thiserror::Error, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[error("centrifugo error {code}: {message}")]
+pub struct ReplyError {
+    pub code: u32,
+    pub message: String,
+    /// Whether the command may succeed when retried.
+    #[serde(default)]
+    pub temporary: bool,
+}
+
+/// The connection details from the connect reply.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CentrifugoSession {
+    pub client: String,
+    pub version: String,
+    /// How often the server pings, if it does.
+    pub ping: Option<Duration>,
+    /// Whether the server expects the pings to be answered.
+    pub pong: bool,
+}
+
+/// Sends the connect command and stores the [`CentrifugoSession`] in the context.
+pub struct CentrifugoHandshake {
+    token: Option<String>,
+    name: Option<String>,
+    data: Option<Value>,
+    max_ping_delay: Duration,
+    timeout: Duration,
+}
+
+impl Default for CentrifugoHandshake {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CentrifugoHandshake {
+    pub fn new() -> Self {
+        Self {
+            token: None,
+            name: None,
+            data: None,
+            max_ping_delay: Duration::from_secs(10),
+            timeout: Duration::from_secs(10),
+        }
+    }
+
+    /// Sets the connection JWT.
+    pub fn with_token(mut self, token: impl Into<String>) -> Self {
+        self.token = Some(token.into());
+        self
+    }
+
+    /// Sets the client name reported to the server.
+    pub fn with_name(mut self, name: impl Into<String>) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    /// Sets the custom data passed to the connect proxy.
+    pub fn with_data(mut self, data: Value) -> Self {
+        self.data = Some(data);
+        self
+    }
+
+    /// Sets how late a server ping may be. The receive timeout is the ping interval
+    /// plus this. Defaults to 10s.
+    pub fn with_max_ping_delay(mut self, max_ping_delay: Duration) -> Self {
+        self.max_ping_delay = max_ping_delay;
+        self
+    }
+
+    /// Sets how long to wait for the connect reply. Defaults to 10s.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+}
+
+#[async_trait]
+impl StreamHandshake for CentrifugoHandshake {
+    async fn handshake(
+        &self,
+        writer: &mut PSTSender,
+        reader: &mut PSTReceiver,
+    ) -> EResult<HandshakeContext> {
+        let mut connect = Map::new();
+        if let Some(token) = &self.token {
+            connect.insert("token".to_string(), json!(token));
+        }
+        if let Some(name) = &self.name {
+            connect.insert("name".to_string(), json!(name));
+        }
+        if let Some(data) = &self.data {
+            connect.insert("data".to_string(), data.clone());
+        }
+        let command = json!({"id": CONNECT_ID, "connect": connect});
+        writer.send(Message::text(command.to_string())).await?;
+
+        let mut ctx = HandshakeContext::new();
+        let (session, rest) = receive_until(reader, &mut ctx, self.timeout, |msg| {
+            let text = msg.to_text().ok()?;
+            let (reply, rest) = text.split_once('\n').unwrap_or((text, ""));
+            let mut reply: Value = serde_json::from_str(reply).ok()?;
+            if reply["id"] != CONNECT_ID {
+                return None;
+            }
+            if let Some(error) = reply.get_mut("error") {
+                return match serde_json::from_value::<ReplyError>(error.take()) {
+                    Ok(error) => Some(Err(eyre!("connect rejected: {error}"))),
+                    Err(e) => Some(Err(e.into())),
+                };
+            }
+            let connect = &reply["connect"];
+            let session = CentrifugoSession {
+                client: connect["client"].as_str().unwrap_or_default().to_string(),
+                version: connect["version"].as_str().unwrap_or_default().to_string(),
+                ping: connect["ping"].as_u64().map(Duration::from_secs),
+                pong: connect["pong"].as_bool().unwrap_or_default(),
+            };
+            Some(Ok((session, rest.to_string())))
+        })
+        .await?;
+        if !rest.trim().is_empty() {
+            // pushes the server sent right after the reply
+            ctx.forward(Message::text(rest));
+        }
+        if let Some(ping) = session.ping {
+            ctx.set_receive_timeout(ping + self.max_ping_delay);
+        }
+        ctx.insert(session);
+        Ok(ctx)
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum CentrifugoError {
+    #[error(transparent)]
+    Transport(#[from] ReconnectTError),
+    #[error(transparent)]
+    Reply(#[from] ReplyError),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    #[error("not subscribed to {0}")]
+    NotSubscribed(String),
+    #[error("already subscribed to {0}")]
+    AlreadySubscribed(String),
+}
+
+/// A message published to a channel.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Publication {
+    #[serde(default)]
+    pub data: Value,
+    /// The position in the channel history, for recoverable channels.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub offset: Option<u64>,
+    /// The publisher, when known.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub info: Option<Value>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ChannelEvent {
+    Publication(Publication),
+    /// A client joined, with its info.
+    Join(Value),
+    Leave(Value),
+}
+
+struct ChannelState {
+    token: Option<String>,
+    /// The stream position, set once the server reported the channel recoverable.
+    position: Option<(String, u64)>,
+    tx: mpsc::UnboundedSender<ChannelEvent>,
+}
+
+type PendingReply = oneshot::Sender<Result<Value, CentrifugoError>>;
+
+/// Configures and attaches a [`CentrifugoClient`].
+pub struct CentrifugoClientBuilder {
+    timeout: Duration,
+}
+
+impl CentrifugoClientBuilder {
+    /// Sets how long a command may take. Defaults to 10s.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub async fn attach<R: IntoClientRequest + Send + Sync>(
+        self,
+        reconnect: &ReconnectT<R>,
+    ) -> EResult<Arc<CentrifugoClient>> {
+        let client = Arc::new(CentrifugoClient {
+            sender: reconnect.sender.clone(),
+            timeout: self.timeout,
+            next_id: AtomicU64::new(CONNECT_ID + 1),
+            stop: reconnect.stop_handle(),
+            ctx: Mutex::new(None),
+            channels: Mutex::new(HashMap::new()),
+            pending: Mutex::new(HashMap::new()),
+        });
+        Driver::attach(&client, reconnect).await?;
+        Ok(client)
+    }
+}
+
+/// A Centrifugo client over a [`ReconnectT`] configured with [`CentrifugoHandshake`].
+///
+/// Channels are subscribed again after every reconnect. Recoverable channels ask for
+/// the publications missed meanwhile, which the stream receives before new ones. A
+/// `disconnect` push forbidding to reconnect stops the reconnects.
+pub struct CentrifugoClient {
+    sender: Arc<MaybePSTSender>,
+    timeout: Duration,
+    next_id: AtomicU64,
+    stop: StopHandle,
+    /// The handshake context of the current connection.
+    ctx: Mutex<Option<Arc<HandshakeContext>>>,
+    channels: Mutex<HashMap<String, ChannelState>>,
+    pending: Mutex<HashMap<u64, PendingReply>>,
+}
+
+impl CentrifugoClient {
+    pub fn builder() -> CentrifugoClientBuilder {
+        CentrifugoClientBuilder {
+            timeout: Duration::from_secs(10),
+        }
+    }
+
+    /// Sends the command `method` with `params` and returns its reply.
+    pub async fn command(&self, method: &str, params: Value) -> Result<Value, CentrifugoError> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = oneshot::channel();
+        self.pending.lock().await.insert(id, tx);
+        let command = json!({"id": id, method: params});
+        if let Err(e) = self.sender.send(Message::text(command.to_string())).await {
+            self.pending.lock().await.remove(&id);
+            return Err(e.into());
+        }
+        let mut reply = match tokio::time::timeout(self.timeout, rx).await {
+            Ok(Ok(reply)) => reply?,
+            Ok(Err(_)) => return Err(ReconnectTError::Disconnected.into()),
+            Err(_) => {
+                self.pending.lock().await.remove(&id);
+                return Err(ReconnectTError::RequestTimeout(self.timeout).into());
+            }
+        };
+        Ok(reply.get_mut(method).map(Value::take).unwrap_or_default())
+    }
+
+    /// Subscribes to `channel`, waiting for the reply when connected. The `token` is
+    /// needed by channels that require a subscription JWT.
+    pub async fn subscribe(
+        &self,
+        channel: &str,
+        token: Option<String>,
+    ) -> Result<CentrifugoSubscription, CentrifugoError> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        {
+            let mut channels = self.channels.lock().await;
+            if channels.contains_key(channel) {
+                return Err(CentrifugoError::AlreadySubscribed(channel.to_string()));
+            }
+            let state = ChannelState {
+                token,
+                position: None,
+                tx,
+            };
+            channels.insert(channel.to_string(), state);
+        }
+
+        if self.sender.is_connected() {
+            match self.send_subscribe(channel).await {
+                Ok(()) => {}
+                Err(e @ CentrifugoError::Reply(_)) => {
+                    self.channels.lock().await.remove(channel);
+                    return Err(e);
+                }
+                // subscribed again once reconnected
+                Err(e) => tracing::warn!(channel=channel, error=?e, "centrifugo::subscribe"),
+            }
+        }
+        Ok(CentrifugoSubscription {
+            channel: channel.to_string(),
+            stream: UnboundedReceiverStream::new(rx),
+        })
+    }
+
+    /// Unsubscribes from `channel` and ends its stream.
+    pub async fn unsubscribe(&self, channel: &str) -> Result<(), CentrifugoError> {
+        if self.channels.lock().await.remove(channel).is_none() {
+            return Err(CentrifugoError::NotSubscribed(channel.to_string()));
+        }
+        match self
+            .command("unsubscribe", json!({"channel": channel}))
+            .await
+        {
+            Ok(_) | Err(CentrifugoError::Transport(_)) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub async fn publish(&self, channel: &str, data: Value) -> Result<(), CentrifugoError> {
+        self.command("publish", json!({"channel": channel, "data": data}))
+            .await?;
+        Ok(())
+    }
+
+    /// Calls the RPC `method` and returns its data.
+    pub async fn rpc(&self, method: &str, data: Value) -> Result<Value, CentrifugoError> {
+        let mut reply = self
+            .command("rpc", json!({"method": method, "data": data}))
+            .await?;
+        Ok(reply.get_mut("data").map(Value::take).unwrap_or_default())
+    }
+
+    async fn send_subscribe(&self, channel: &str) -> Result<(), CentrifugoError> {
+        let mut params = json!({"channel": channel});
+        {
+            let channels = self.channels.lock().await;
+            let state = channels
+                .get(channel)
+                .ok_or_else(|| CentrifugoError::NotSubscribed(channel.to_string()))?;
+            if let Some(token) = &state.token {
+                params["token"] = json!(token);
+            }
+            if let Some((epoch, offset)) = &state.position {
+                params["recover"] = json!(true);
+                params["epoch"] = json!(epoch);
+                params["offset"] = json!(offset);
+            }
+        }
+        let reply = self.command("subscribe", params).await?;
+
+        let recovered: Vec<Publication> = match reply.get("publications") {
+            Some(publications) => serde_json::from_value(publications.clone())?,
+            None => Vec::new(),
+        };
+        let mut channels = self.channels.lock().await;
+        let Some(state) = channels.get_mut(channel) else {
+            return Ok(());
+        };
+        if reply["recoverable"].as_bool().unwrap_or_default() {
+            let epoch = reply["epoch"].as_str().unwrap_or_default().to_string();
+            let offset = reply["offset"].as_u64().unwrap_or_default();
+            state.position = Some((epoch, offset));
+        }
+        for publication in recovered {
+            Self::deliver(state, publication);
+        }
+        Ok(())
+    }
+
+    fn deliver(state: &mut ChannelState, publication: Publication) {
+        if let (Some((_, position)), Some(offset)) = (&mut state.position, publication.offset) {
+            *position = offset.max(*position);
+        }
+        let _ = state.tx.send(ChannelEvent::Publication(publication));
+    }
+
+    async fn on_line(&self, line: &str) {
+        let mut reply: Value = match serde_json::from_str(line) {
+            Ok(reply) => reply,
+            Err(e) => {
+                tracing::debug!(error=?e, line=line, "centrifugo::invalid_reply");
+                return;
+            }
+        };
+        if reply.as_object().is_some_and(Map::is_empty) {
+            // the server's ping
+            let pong = self
+                .ctx
+                .lock()
+                .await
+                .as_ref()
+                .and_then(|ctx| ctx.get::<CentrifugoSession>())
+                .is_some_and(|session| session.pong);
+            if pong {
+                if let Err(e) = self.sender.send(Message::text("{}")).await {
+                    tracing::warn!(error=?e, "centrifugo::pong");
+                }
+            }
+            return;
+        }
+        if let Some(id) = reply["id"].as_u64() {
+            let Some(tx) = self.pending.lock().await.remove(&id) else {
+                return;
+            };
+            let result = match reply.get_mut("error") {
+                Some(error) => match serde_json::from_value::<ReplyError>(error.take()) {
+                    Ok(error) => Err(error.into()),
+                    Err(e) => Err(e.into()),
+                },
+                None => Ok(reply),
+            };
+            let _ = tx.send(result);
+            return;
+        }
+        if let Some(push) = reply.get_mut("push") {
+            self.on_push(push.take()).await;
+        }
+    }
+
+    async fn on_push(&self, mut push: Value) {
+        let channel = push["channel"].as_str().unwrap_or_default().to_string();
+        let mut channels = self.channels.lock().await;
+        if let Some(publication) = push.get_mut("pub") {
+            match serde_json::from_value(publication.take()) {
+                Ok(publication) => {
+                    if let Some(state) = channels.get_mut(&channel) {
+                        Self::deliver(state, publication);
+                    }
+                }
+                Err(e) => tracing::debug!(error=?e, "centrifugo::invalid_publication"),
+            }
+        } else if let Some(join) = push.get_mut("join") {
+            if let Some(state) = channels.get(&channel) {
+                let _ = state.tx.send(ChannelEvent::Join(join["info"].take()));
+            }
+        } else if let Some(leave) = push.get_mut("leave") {
+            if let Some(state) = channels.get(&channel) {
+                let _ = state.tx.send(ChannelEvent::Leave(leave["info"].take()));
+            }
+        } else if push.get("unsubscribe").is_some() {
+            // unsubscribed by the server, which ends the stream
+            tracing::warn!(channel = channel, "centrifugo::unsubscribed");
+            channels.remove(&channel);
+        } else if let Some(disconnect) = push.get("disconnect") {
+            tracing::warn!(disconnect=?disconnect, "centrifugo::disconnect");
+            // without the `reconnect` flag, codes 3500-3999 are terminal
+            let reconnect = match disconnect["reconnect"].as_bool() {
+                Some(reconnect) => reconnect,
+                None => !disconnect["code"]
+                    .as_u64()
+                    .is_some_and(|code| (3500..4000).contains(&code)),
+            };
+            if !reconnect {
+                self.stop.stop(format!(
+                    "centrifugo disconnect {}: {}",
+                    disconnect["code"],
+                    disconnect["reason"].as_str().unwrap_or_default()
+                ));
+            }
+        } else {
+            tracing::debug!(push=?push, "centrifugo::unhandled");
+        }
+    }
+}
+
+#[async_trait]
+impl ProtocolClient for CentrifugoClient {
+    async fn on_message(self: Arc<Self>, msg: Message) {
+        for line in lines(&msg) {
+            self.on_line(line).await;
+        }
+    }
+
+    async fn on_connected(self: Arc<Self>, ctx: Arc<HandshakeContext>) {
+        *self.ctx.lock().await = Some(ctx);
+        let channels: Vec<String> = self.channels.lock().await.keys().cloned().collect();
+        for channel in channels {
+            let this = self.clone();
+            tokio::spawn(async move {
+                match this.send_subscribe(&channel).await {
+                    Ok(()) | Err(CentrifugoError::NotSubscribed(_)) => {}
+                    Err(e @ CentrifugoError::Reply(_)) => {
+                        // refused, which ends the stream
+                        tracing::warn!(channel=channel, error=?e, "centrifugo::resubscribe");
+                        this.channels.lock().await.remove(&channel);
+                    }
+                    Err(e) => tracing::warn!(channel=channel, error=?e, "centrifugo::resubscribe"),
+                }
+            });
+        }
+    }
+
+    async fn on_disconnected(self: Arc<Self>) {
+        *self.ctx.lock().await = None;
+        // dropping the senders fails the waiting commands
+        self.pending.lock().await.clear();
+    }
+}
+
+/// The events of a subscribed channel, across reconnects.
+pub struct CentrifugoSubscription {
+    channel: String,
+    stream: UnboundedReceiverStream<ChannelEvent>,
+}
+
+impl CentrifugoSubscription {
+    pub fn channel(&self) -> &str {
+        &self.channel
+    }
+}
+
+impl Stream for CentrifugoSubscription {
+    type Item = ChannelEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.stream).poll_next(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::prelude::*;
+    use crate::protocols::centrifugo::{
+        CentrifugoClient, CentrifugoError, CentrifugoHandshake, CentrifugoSession, ChannelEvent,
+    };
+    use crate::test_util::mock_server;
+    use futures_util::{SinkExt, StreamExt};
+    use serde_json::{json, Value};
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::sync::mpsc;
+    use tungstenite::Message;
+
+    fn publication(offset: u64) -> Value {
+        json!({"data": {"n": offset}, "offset": offset})
+    }
+
+    #[tokio::test]
+    async fn test_centrifugo() {
+        let connections = Arc::new(AtomicU64::new(0));
+        let (seen_tx, mut seen_rx) = mpsc::unbounded_channel();
+        let counter = connections.clone();
+        let url = mock_server(move |mut ws| {
+            let seen_tx = seen_tx.clone();
+            let connection = counter.fetch_add(1, Ordering::SeqCst);
+            async move {
+                let connect: Value =
+                    serde_json::from_str(ws.next().await.unwrap().unwrap().to_text().unwrap())
+                        .unwrap();
+                assert_eq!(connect["connect"]["token"], "jwt");
+                let reply = json!({"id": 1, "connect": {"client": "c", "version": "5", "ping": 25, "pong": true}});
+                // the server's ping shares the frame with the reply
+                ws.send(Message::text(format!("{reply}\n{{}}")))
+                    .await
+                    .unwrap();
+
+                while let Some(Ok(msg)) = ws.next().await {
+                    let command: Value = serde_json::from_str(msg.to_text().unwrap()).unwrap();
+                    seen_tx.send((connection, command.clone())).unwrap();
+                    let id = &command["id"];
+                    let reply = if let Some(subscribe) = command.get("subscribe") {
+                        match (subscribe["channel"].as_str().unwrap(), connection) {
+                            ("secret", _) => {
+                                json!({"id": id, "error": {"code": 103, "message": "permission denied"}})
+                            }
+                            (_, 0) => json!({"id": id, "subscribe": {"recoverable": true, "epoch": "e", "offset": 1}}),
+                            (_, _) => json!({"id": id, "subscribe": {
+                                "recoverable": true, "epoch": "e", "offset": 3, "recovered": true,
+                                "publications": [publication(3)],
+                            }}),
+                        }
+                    } else if command.get("publish").is_some() {
+                        let push = json!({"push": {"channel": "news", "pub": publication(2)}});
+                        let reply = json!({"id": id, "publish": {}});
+                        ws.send(Message::text(format!("{reply}\n{push}")))
+                            .await
+                            .unwrap();
+                        // drop the connection, missing the third publication
+                        return;
+                    } else if command.get("rpc").is_some() {
+                        json!({"id": id, "rpc": {"data": command["rpc"]["data"]}})
+                    } else {
+                        continue;
+                    };
+                    ws.send(Message::text(reply.to_string())).await.unwrap();
+                }
+            }
+        })
+        .await;
+
+        let mut options = ReconnectOptions::default();
+        options
+            .with_handshake(Arc::new(CentrifugoHandshake::new().with_token("jwt")))
+            .with_retry_policy_fn(Arc::new(|| {
+                Box::new(ConstantStrategy::new(Duration::from_millis(10)).into_iter())
+            }));
+        let reconnect = Arc::new(ReconnectT::new(url, Some(options)));
+        let client = CentrifugoClient::builder()
+            .attach(&reconnect)
+            .await
+            .unwrap();
+        let mut status = reconnect.create_status_stream().await;
+        reconnect.spawn_run();
+        let Some(WsStreamStatus::Connected(ctx)) = status.next().await else {
+            panic!("not connected");
+        };
+        assert_eq!(ctx.get::<CentrifugoSession>().unwrap().client, "c");
+        assert_eq!(ctx.receive_timeout(), Some(Duration::from_secs(35)));
+
+        // the ping is answered
+        assert_eq!(seen_rx.recv().await.unwrap().1, json!({}));
+        assert_eq!(
+            client.rpc("echo", json!({"a": 1})).await.unwrap(),
+            json!({"a": 1})
+        );
+        assert!(matches!(
+            client.subscribe("secret", None).await,
+            Err(CentrifugoError::Reply(e)) if e.code == 103
+        ));
+        let mut news = client.subscribe("news", None).await.unwrap();
+
+        client.publish("news", json!({"n": 2})).await.unwrap();
+        let Some(ChannelEvent::Publication(second)) = news.next().await else {
+            panic!("no publication");
+        };
+        assert_eq!(second.offset, Some(2));
+
+        // recovers from the last offset after the reconnect
+        let Some(ChannelEvent::Publication(third)) = news.next().await else {
+            panic!("no recovered publication");
+        };
+        assert_eq!(third.offset, Some(3));
+        loop {
+            let (connection, command) = seen_rx.recv().await.unwrap();
+            if connection == 1 && command.get("subscribe").is_some() {
+                assert_eq!(command["subscribe"]["recover"], true);
+                assert_eq!(command["subscribe"]["offset"], 2);
+                break;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_disconnect_without_reconnect() {
+        let connections = Arc::new(AtomicU64::new(0));
+        let counter = connections.clone();
+        let url = mock_server(move |mut ws| {
+            let connection = counter.fetch_add(1, Ordering::SeqCst);
+            async move {
+                ws.next().await.unwrap().unwrap();
+                let reply = json!({"id": 1, "connect": {"client": "c", "version": "5"}});
+                ws.send(Message::text(reply.to_string())).await.unwrap();
+                let disconnect = match connection {
+                    0 => json!({"code": 3001, "reason": "shutdown", "reconnect": true}),
+                    _ => json!({"code": 3501, "reason": "bad request", "reconnect": false}),
+                };
+                let push = json!({"push": {"disconnect": disconnect}});
+                ws.send(Message::text(push.to_string())).await.unwrap();
+                // left open; the client drops it
+                while ws.next().await.is_some() {}
+            }
+        })
+        .await;
+
+        let mut options = ReconnectOptions::default();
+        options
+            .with_handshake(Arc::new(CentrifugoHandshake::new()))
+            .with_receive_timeout(Duration::from_millis(200))
+            .with_retry_policy_fn(Arc::new(|| {
+                Box::new(ConstantStrategy::new(Duration::from_millis(10)).into_iter())
+            }));
+        let reconnect = Arc::new(ReconnectT::new(url, Some(options)));
+        let _client = CentrifugoClient::builder()
+            .attach(&reconnect)
+            .await
+            .unwrap();
+        let result = tokio::time::timeout(Duration::from_secs(5), reconnect.run())
+            .await
+            .unwrap();
+        assert!(matches!(
+            result,
+            Err(ReconnectTError::Stopped(reason)) if reason == "centrifugo disconnect 3501: bad request"
+        ));
+        assert_eq!(connections.load(Ordering::SeqCst), 2);
+    }
+}
@@ -0,0 +1,371 @@
+//! `eth_subscribe` subscriptions that survive reconnects.
+
+use crate::errors::ReconnectTError;
+use crate::maybe_sender::MaybePSTSender;
+use crate::prelude::WsStreamStatus;
+use crate::protocols::jsonrpc::{JsonRpcClient, JsonRpcError};
+use crate::tungstenite::ReconnectT;
+use eyre::Result as EResult;
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::sync::{mpsc, Mutex};
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use tokio_stream::{Stream, StreamExt};
+use tungstenite::client::IntoClientRequest;
+
+/// Notifications received for a server id before its `eth_subscribe` call returned are
+/// buffered, up to this many per id.
+const MAX_EARLY_NOTIFICATIONS: usize = 256;
+
+/// At most this many unknown server ids are buffered at once.
+const MAX_EARLY_IDS: usize = 64;
+
+/// A client-side subscription handle, stable across reconnects.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct SubscriptionId(u64);
+
+#[derive(Deserialize)]
+struct SubscriptionParams {
+    subscription: String,
+    #[serde(default)]
+    result: Value,
+}
+
+struct Entry {
+    params: Value,
+    server_id: Option<String>,
+    /// Incremented on every `eth_subscribe` issued for the entry; only the latest counts.
+    issued: u64,
+    tx: mpsc::UnboundedSender<Value>,
+}
+
+#[derive(Default)]
+struct State {
+    entries: HashMap<SubscriptionId, Entry>,
+    by_server_id: HashMap<String, SubscriptionId>,
+    /// Notifications for unknown server ids, kept only while `eth_subscribe` calls are
+    /// pending.
+    early: HashMap<String, Vec<Value>>,
+    /// The number of pending `eth_subscribe` calls.
+    issuing: usize,
+}
+
+impl State {
+    /// Forgets all server ids; they are only valid for the connection that issued them.
+    fn reset(&mut self) {
+        self.by_server_id.clear();
+        self.early.clear();
+        for entry in self.entries.values_mut() {
+            entry.server_id = None;
+        }
+    }
+}
+
+/// Subscriptions over the `eth_subscribe`/`eth_unsubscribe` pubsub API.
+///
+/// Servers assign a new subscription id on every connection, so each subscription is
+/// re-issued after every handshake and its notifications keep arriving on the same
+/// [`EthSubscription`] stream.
+pub struct EthSubscriptions {
+    client: Arc<JsonRpcClient>,
+    sender: Arc<MaybePSTSender>,
+    next_id: AtomicU64,
+    state: Arc<Mutex<State>>,
+}
+
+impl EthSubscriptions {
+    /// Creates the subscriptions of `client`, which must be attached to `reconnect`.
+    pub async fn attach<R: IntoClientRequest + Send + Sync>(
+        reconnect: &ReconnectT<R>,
+        client: Arc<JsonRpcClient>,
+    ) -> EResult<Arc<Self>> {
+        let this = Arc::new(Self {
+            client: client.clone(),
+            sender: reconnect.sender.clone(),
+            next_id: AtomicU64::new(1),
+            state: Arc::new(Mutex::new(State::default())),
+        });
+
+        let mut notifications = client.notifications().await;
+        let state = this.state.clone();
+        tokio::spawn(async move {
+            while let Some(notification) = notifications.next().await {
+                if notification.method != "eth_subscription" {
+                    continue;
+                }
+                let params: SubscriptionParams = match serde_json::from_value(notification.params) {
+                    Ok(params) => params,
+                    Err(e) => {
+                        tracing::debug!(error=?e, "eth_subscribe::invalid_notification");
+                        continue;
+                    }
+                };
+                let mut state = state.lock().await;
+                let entry = match state.by_server_id.get(&params.subscription) {
+                    Some(id) => state.entries.get(id),
+                    None => None,
+                };
+                match entry {
+                    Some(entry) => {
+                        let _ = entry.tx.send(params.result);
+                    }
+                    None if state.issuing == 0 => {}
+                    None => {
+                        let known = state.early.contains_key(&params.subscription);
+                        if !known && state.early.len() >= MAX_EARLY_IDS {
+                            continue;
+                        }
+                        let early = state.early.entry(params.subscription).or_default();
+                        if early.len() < MAX_EARLY_NOTIFICATIONS {
+                            early.push(params.result);
+                        }
+                    }
+                }
+            }
+        });
+
+        let mut status = reconnect.create_status_stream().await;
+        let weak = Arc::downgrade(&this);
+        tokio::spawn(async move {
+            while let Some(status) = status.next().await {
+                let Some(this) = weak.upgrade() else {
+                    return;
+                };
+                // server ids are kept until the next handshake so that notifications
+                // received just before a disconnect are still delivered
+                if let WsStreamStatus::Connected(_) = status {
+                    this.resubscribe().await;
+                }
+            }
+        });
+
+        Ok(this)
+    }
+
+    /// Subscribes with `params`, e.g. `["newHeads"]`, and returns the stream of the
+    /// `result` of its notifications.
+    ///
+    /// When not connected, or if the connection drops before the server replies, the
+    /// subscription is issued after the next handshake.
+    pub async fn subscribe(&self, params: Value) -> Result<EthSubscription, JsonRpcError> {
+        let id = SubscriptionId(self.next_id.fetch_add(1, Ordering::Relaxed));
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.state.lock().await.entries.insert(
+            id,
+            Entry {
+                params,
+                server_id: None,
+                issued: 0,
+                tx,
+            },
+        );
+
+        if self.sender.is_connected() {
+            match self.issue(id).await {
+                Ok(()) | Err(JsonRpcError::Transport(_)) => {}
+                Err(e) => {
+                    self.state.lock().await.entries.remove(&id);
+                    return Err(e);
+                }
+            }
+        }
+        Ok(EthSubscription {
+            id,
+            stream: UnboundedReceiverStream::new(rx),
+        })
+    }
+
+    /// Removes the subscription, ending its stream, and unsubscribes if connected.
+    pub async fn unsubscribe(&self, id: SubscriptionId) -> Result<(), JsonRpcError> {
+        let server_id = {
+            let mut state = self.state.lock().await;
+            let server_id = state.entries.remove(&id).and_then(|entry| entry.server_id);
+            if let Some(server_id) = &server_id {
+                state.by_server_id.remove(server_id);
+            }
+            server_id
+        };
+        match server_id {
+            Some(server_id) => self.unsubscribe_server_id(server_id).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Returns the id the server assigned to the subscription on the current connection.
+    pub async fn server_id(&self, id: SubscriptionId) -> Option<String> {
+        let state = self.state.lock().await;
+        state.entries.get(&id)?.server_id.clone()
+    }
+
+    /// Returns the number of subscriptions.
+    pub async fn len(&self) -> usize {
+        self.state.lock().await.entries.len()
+    }
+
+    pub async fn is_empty(&self) -> bool {
+        self.len().await == 0
+    }
+
+    async fn resubscribe(&self) {
+        let ids: Vec<SubscriptionId> = {
+            let mut state = self.state.lock().await;
+            state.reset();
+            state.entries.keys().copied().collect()
+        };
+        for id in ids {
+            if let Err(e) = self.issue(id).await {
+                tracing::warn!(id=id.0, error=?e, "eth_subscribe::resubscribe");
+            }
+        }
+    }
+
+    /// Sends `eth_subscribe` for the entry and maps the returned server id to it.
+    async fn issue(&self, id: SubscriptionId) -> Result<(), JsonRpcError> {
+        let (params, issued) = {
+            let mut state = self.state.lock().await;
+            let Some(entry) = state.entries.get_mut(&id) else {
+                return Ok(());
+            };
+            entry.issued += 1;
+            let issued = entry.issued;
+            let params = entry.params.clone();
+            state.issuing += 1;
+            (params, issued)
+        };
+
+        let result = self.client.call::<_, String>("eth_subscribe", params).await;
+
+        let server_id = {
+            let mut state = self.state.lock().await;
+            let state = &mut *state;
+            state.issuing -= 1;
+            let early = match &result {
+                Ok(server_id) => state.early.remove(server_id).unwrap_or_default(),
+                Err(_) => Vec::new(),
+            };
+            if state.issuing == 0 {
+                // no call is left to claim them
+                state.early.clear();
+            }
+            let server_id = result?;
+            if let Some(entry) = state.entries.get_mut(&id) {
+                if entry.issued == issued {
+                    for result in early {
+                        let _ = entry.tx.send(result);
+                    }
+                    entry.server_id = Some(server_id.clone());
+                    state.by_server_id.insert(server_id, id);
+                    return Ok(());
+                }
+            }
+            server_id
+        };
+        // unsubscribed, or superseded by a resubscribe, while the call was pending
+        self.unsubscribe_server_id(server_id).await
+    }
+
+    async fn unsubscribe_server_id(&self, server_id: String) -> Result<(), JsonRpcError> {
+        match self
+            .client
+            .call::<_, Value>("eth_unsubscribe", [server_id])
+            .await
+        {
+            Ok(_)
+            | Err(JsonRpcError::Transport(
+                ReconnectTError::SenderNotConnected | ReconnectTError::Disconnected,
+            )) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// The notifications of a subscription. The stream ends when it is unsubscribed.
+pub struct EthSubscription {
+    id: SubscriptionId,
+    stream: UnboundedReceiverStream<Value>,
+}
+
+impl EthSubscription {
+    pub fn id(&self) -> SubscriptionId {
+        self.id
+    }
+}
+
+impl Stream for EthSubscription {
+    type Item = Value;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Value>> {
+        Pin::new(&mut self.stream).poll_next(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::prelude::*;
+    use crate::protocols::eth_subscribe::EthSubscriptions;
+    use crate::protocols::jsonrpc::JsonRpcClient;
+    use crate::test_util::mock_server;
+    use futures_util::{SinkExt, StreamExt};
+    use serde_json::{json, Value};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tungstenite::Message;
+
+    #[tokio::test]
+    async fn test_resubscribe_after_reconnect() {
+        let connections = Arc::new(AtomicUsize::new(0));
+        let url = mock_server(move |mut ws| {
+            let connection = connections.fetch_add(1, Ordering::SeqCst);
+            async move {
+                while let Some(Ok(msg)) = ws.next().await {
+                    let request: Value = serde_json::from_str(msg.to_text().unwrap()).unwrap();
+                    let server_id = format!("0x{connection}{}", request["params"][0]);
+                    let reply = json!({"jsonrpc": "2.0", "id": request["id"], "result": server_id});
+                    ws.send(Message::text(reply.to_string())).await.unwrap();
+                    let notification = json!({
+                        "jsonrpc": "2.0",
+                        "method": "eth_subscription",
+                        "params": {"subscription": server_id, "result": connection},
+                    });
+                    ws.send(Message::text(notification.to_string()))
+                        .await
+                        .unwrap();
+                    if connection == 0 {
+                        return;
+                    }
+                }
+            }
+        })
+        .await;
+
+        let mut options = ReconnectOptions::default();
+        options.with_retry_policy_fn(Arc::new(|| {
+            Box::new(ConstantStrategy::new(Duration::from_millis(10)).into_iter())
+        }));
+        let reconnect = Arc::new(ReconnectT::new(url, Some(options)));
+        let client = JsonRpcClient::builder().attach(&reconnect).await.unwrap();
+        let subscriptions = EthSubscriptions::attach(&reconnect, client).await.unwrap();
+
+        // issued after the first handshake
+        let mut heads = subscriptions.subscribe(json!(["newHeads"])).await.unwrap();
+        reconnect.spawn_run();
+
+        assert_eq!(heads.next().await, Some(json!(0)));
+        // the first connection is dropped after the notification
+        assert_eq!(heads.next().await, Some(json!(1)));
+        assert_eq!(
+            subscriptions.server_id(heads.id()).await.as_deref(),
+            Some("0x1\"newHeads\"")
+        );
+
+        subscriptions.unsubscribe(heads.id()).await.unwrap();
+        assert!(subscriptions.is_empty().await);
+        assert_eq!(heads.next().await, None);
+    }
+}
@@ -0,0 +1,509 @@
+//! A client for the `graphql-transport-ws` protocol.
+
+use crate::errors::ReconnectTError;
+use crate::extension::StreamEvent;
+use crate::handshake::{receive_until, HandshakeContext, StreamHandshake};
+use crate::maybe_sender::MaybePSTSender;
+use crate::prelude::WsStreamStatus;
+use crate::tungstenite::ReconnectT;
+use crate::types::{PSTReceiver, PSTSender};
+use async_trait::async_trait;
+use eyre::{eyre, Result as EResult};
+use futures_util::SinkExt;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::sync::{mpsc, Mutex};
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use tokio_stream::{Stream, StreamExt};
+use tungstenite::client::IntoClientRequest;
+use tungstenite::handshake::client::Request;
+use tungstenite::http::HeaderValue;
+use tungstenite::Message;
+
+pub const SUBPROTOCOL: &str = "graphql-transport-ws";
+
+/// Builds the connect request for `url` with the `graphql-transport-ws` subprotocol.
+#[allow(clippy::result_large_err)]
+pub fn request(url: &str) -> Result<Request, tungstenite::Error> {
+    let mut request = url.into_client_request()?;
+    request.headers_mut().insert(
+        "Sec-WebSocket-Protocol",
+        HeaderValue::from_static(SUBPROTOCOL),
+    );
+    Ok(request)
+}
+
+#[derive(Serialize, Deserialize)]
+struct ProtocolMessage {
+    #[serde(rename = "type")]
+    kind: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    payload: Option<Value>,
+}
+
+impl ProtocolMessage {
+    fn new(kind: &str, id: Option<&str>, payload: Option<Value>) -> Self {
+        Self {
+            kind: kind.to_string(),
+            id: id.map(str::to_string),
+            payload,
+        }
+    }
+
+    fn parse(msg: &Message) -> Option<Self> {
+        serde_json::from_str(msg.to_text().ok()?).ok()
+    }
+
+    fn to_message(&self) -> Message {
+        Message::text(serde_json::to_string(self).expect("protocol messages serialize"))
+    }
+}
+
+/// The payload of the server's `connection_ack`, available in the handshake context.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ConnectionAck {
+    pub payload: Option<Value>,
+}
+
+/// Sends `connection_init` and waits for `connection_ack`.
+#[derive(Clone)]
+pub struct GraphqlWsHandshake {
+    payload: Option<Value>,
+    timeout: Duration,
+}
+
+impl GraphqlWsHandshake {
+    /// Creates the handshake; `payload` is sent with `connection_init`, e.g. auth tokens.
+    pub fn new(payload: Option<Value>) -> Self {
+        Self {
+            payload,
+            timeout: Duration::from_secs(10),
+        }
+    }
+
+    /// Sets how long to wait for `connection_ack`.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+}
+
+#[async_trait]
+impl StreamHandshake for GraphqlWsHandshake {
+    async fn handshake(
+        &self,
+        writer: &mut PSTSender,
+        reader: &mut PSTReceiver,
+    ) -> EResult<HandshakeContext> {
+        let init = ProtocolMessage::new("connection_init", None, self.payload.clone());
+        writer.send(init.to_message()).await?;
+
+        let mut ctx = HandshakeContext::new();
+        let ack = receive_until(reader, &mut ctx, self.timeout, |msg| {
+            if let Message::Close(frame) = msg {
+                return Some(Err(eyre!("connection_init rejected: {frame:?}")));
+            }
+            // pings are forwarded and answered by the client once connected
+            match ProtocolMessage::parse(msg)? {
+                ack if ack.kind == "connection_ack" => Some(Ok(ConnectionAck {
+                    payload: ack.payload,
+                })),
+                _ => None,
+            }
+        })
+        .await?;
+        ctx.insert(ack);
+        Ok(ctx)
+    }
+}
+
+/// A GraphQL operation.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GraphqlRequest {
+    pub query: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub variables: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub operation_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub extensions: Option<Value>,
+}
+
+impl GraphqlRequest {
+    pub fn new(query: impl Into<String>) -> Self {
+        Self {
+            query: query.into(),
+            ..Default::default()
+        }
+    }
+
+    pub fn with_variables(mut self, variables: Value) -> Self {
+        self.variables = Some(variables);
+        self
+    }
+
+    pub fn with_operation_name(mut self, operation_name: impl Into<String>) -> Self {
+        self.operation_name = Some(operation_name.into());
+        self
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum GraphqlWsError {
+    #[error(transparent)]
+    Transport(#[from] ReconnectTError),
+    /// The operation failed with an `error` message; holds its GraphQL errors.
+    #[error("graphql errors: {0:?}")]
+    Graphql(Vec<Value>),
+    #[error("operation completed without a result")]
+    Completed,
+}
+
+struct Operation {
+    payload: Value,
+    /// Re-sent after a reconnect; queries and mutations fail instead.
+    resubscribe: bool,
+    /// The generation of the connection it was last sent on.
+    sent: Option<u64>,
+    tx: mpsc::UnboundedSender<Result<Value, GraphqlWsError>>,
+}
+
+type Operations = Arc<Mutex<HashMap<String, Operation>>>;
+
+/// Multiplexes GraphQL operations over a [`ReconnectT`] configured with
+/// [`GraphqlWsHandshake`].
+///
+/// Subscriptions are re-sent with their id after every reconnect and keep delivering
+/// on the same stream. Server pings are answered with pongs.
+pub struct GraphqlWsClient {
+    sender: Arc<MaybePSTSender>,
+    next_id: AtomicU64,
+    operations: Operations,
+}
+
+impl GraphqlWsClient {
+    /// Creates the client and starts handling the events of `reconnect`.
+    pub async fn attach<R: IntoClientRequest + Send + Sync>(
+        reconnect: &ReconnectT<R>,
+    ) -> EResult<Arc<Self>> {
+        let this = Arc::new(Self {
+            sender: reconnect.sender.clone(),
+            next_id: AtomicU64::new(1),
+            operations: Arc::new(Mutex::new(HashMap::new())),
+        });
+        this.spawn_receive(reconnect.create_event_stream().await);
+        Ok(this)
+    }
+
+    /// Starts a subscription. It is sent once connected and re-sent after every reconnect
+    /// until the server completes it or it is [`complete`](Self::complete)d.
+    pub async fn subscribe(
+        &self,
+        request: GraphqlRequest,
+    ) -> EResult<GraphqlSubscription, ReconnectTError> {
+        self.start(request, true).await
+    }
+
+    /// Executes a query or mutation and returns the payload of its single result.
+    /// It fails with [`ReconnectTError::Disconnected`] if the connection drops first.
+    pub async fn execute(&self, request: GraphqlRequest) -> Result<Value, GraphqlWsError> {
+        let mut operation = self.start(request, false).await?;
+        let result = operation.next().await;
+        self.complete(&operation.id).await?;
+        result.unwrap_or(Err(GraphqlWsError::Completed))
+    }
+
+    /// Stops the operation `id` and ends its stream.
+    pub async fn complete(&self, id: &str) -> EResult<(), ReconnectTError> {
+        if self.operations.lock().await.remove(id).is_none() {
+            return Ok(());
+        }
+        let complete = ProtocolMessage::new("complete", Some(id), None);
+        match self.sender.send(complete.to_message()).await {
+            Err(ReconnectTError::SenderNotConnected) => Ok(()),
+            result => result,
+        }
+    }
+
+    /// Returns the number of running operations.
+    pub async fn len(&self) -> usize {
+        self.operations.lock().await.len()
+    }
+
+    pub async fn is_empty(&self) -> bool {
+        self.len().await == 0
+    }
+
+    async fn start(
+        &self,
+        request: GraphqlRequest,
+        resubscribe: bool,
+    ) -> EResult<GraphqlSubscription, ReconnectTError> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed).to_string();
+        let payload = serde_json::to_value(request).expect("requests serialize");
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.operations.lock().await.insert(
+            id.clone(),
+            Operation {
+                payload: payload.clone(),
+                resubscribe,
+                sent: None,
+                tx,
+            },
+        );
+
+        // holding the sender keeps a resubscribe from sending the operation again
+        let mut sender = self.sender.lock().await;
+        let subscribe = ProtocolMessage::new("subscribe", Some(&id), Some(payload));
+        let result = match sender.generation() {
+            Some(generation) => sender
+                .send(subscribe.to_message())
+                .await
+                .map(|()| Some(generation)),
+            None => Err(ReconnectTError::SenderNotConnected),
+        };
+        match result {
+            Ok(sent) => {
+                if let Some(operation) = self.operations.lock().await.get_mut(&id) {
+                    operation.sent = sent;
+                }
+            }
+            Err(ReconnectTError::SenderNotConnected) if resubscribe => {}
+            Err(e) => {
+                self.operations.lock().await.remove(&id);
+                return Err(e);
+            }
+        }
+        Ok(GraphqlSubscription {
+            id,
+            stream: UnboundedReceiverStream::new(rx),
+        })
+    }
+}
+
+impl GraphqlWsClient {
+    fn spawn_receive(&self, mut events: UnboundedReceiverStream<StreamEvent>) {
+        let sender = self.sender.clone();
+        let operations = self.operations.clone();
+        tokio::spawn(async move {
+            let mut generation = 0;
+            while let Some(event) = events.next().await {
+                let msg = match event {
+                    StreamEvent::Message(msg) => msg,
+                    StreamEvent::Status(WsStreamStatus::Connected(ctx)) => {
+                        generation = ctx.generation();
+                        resubscribe(&sender, &operations, generation).await;
+                        continue;
+                    }
+                    StreamEvent::Status(WsStreamStatus::Disconnected) => {
+                        // operations already sent on the next connection are left alone
+                        operations.lock().await.retain(|_, op| {
+                            if op.sent.is_some_and(|sent| sent > generation) {
+                                return true;
+                            }
+                            if !op.resubscribe {
+                                let _ = op.tx.send(Err(ReconnectTError::Disconnected.into()));
+                            }
+                            op.resubscribe
+                        });
+                        continue;
+                    }
+                    StreamEvent::Status(_) => continue,
+                };
+                let Some(msg) = ProtocolMessage::parse(&msg) else {
+                    tracing::debug!(msg=?msg, "graphql_ws::unhandled");
+                    continue;
+                };
+                match (msg.kind.as_str(), msg.id) {
+                    ("ping", _) => {
+                        let pong = ProtocolMessage::new("pong", None, None);
+                        if let Err(e) = sender.send(pong.to_message()).await {
+                            tracing::debug!(error=?e, "graphql_ws::pong");
+                        }
+                    }
+                    ("next", Some(id)) => {
+                        if let Some(operation) = operations.lock().await.get(&id) {
+                            let _ = operation.tx.send(Ok(msg.payload.unwrap_or_default()));
+                        }
+                    }
+                    ("error", Some(id)) => {
+                        if let Some(operation) = operations.lock().await.remove(&id) {
+                            let errors = match msg.payload {
+                                Some(Value::Array(errors)) => errors,
+                                payload => payload.into_iter().collect(),
+                            };
+                            let _ = operation.tx.send(Err(GraphqlWsError::Graphql(errors)));
+                        }
+                    }
+                    ("complete", Some(id)) => {
+                        operations.lock().await.remove(&id);
+                    }
+                    _ => {}
+                }
+            }
+        });
+    }
+}
+
+/// Re-sends the operations not yet sent on the connection `generation`.
+async fn resubscribe(sender: &MaybePSTSender, operations: &Operations, generation: u64) {
+    let mut sender = sender.lock().await;
+    if sender.generation() != Some(generation) {
+        // disconnected again
+        return;
+    }
+    let resubscribe: Vec<Message> = {
+        let mut operations = operations.lock().await;
+        operations
+            .iter_mut()
+            .filter(|(_, op)| op.sent != Some(generation))
+            .map(|(id, op)| {
+                op.sent = Some(generation);
+                ProtocolMessage::new("subscribe", Some(id), Some(op.payload.clone())).to_message()
+            })
+            .collect()
+    };
+    for msg in resubscribe {
+        if let Err(e) = sender.send(msg).await {
+            tracing::warn!(error=?e, "graphql_ws::resubscribe");
+        }
+    }
+}
+
+/// The results of an operation. The stream ends when the operation completes.
+pub struct GraphqlSubscription {
+    id: String,
+    stream: UnboundedReceiverStream<Result<Value, GraphqlWsError>>,
+}
+
+impl GraphqlSubscription {
+    /// The operation id, also used to [`complete`](GraphqlWsClient::complete) it.
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+}
+
+impl Stream for GraphqlSubscription {
+    type Item = Result<Value, GraphqlWsError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.stream).poll_next(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::prelude::*;
+    use crate::protocols::graphql_ws::{
+        ConnectionAck, GraphqlRequest, GraphqlWsClient, GraphqlWsError, GraphqlWsHandshake,
+    };
+    use crate::test_util::mock_server;
+    use futures_util::{SinkExt, StreamExt};
+    use serde_json::{json, Value};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::sync::mpsc;
+    use tungstenite::Message;
+
+    fn text(value: Value) -> Message {
+        Message::text(value.to_string())
+    }
+
+    #[tokio::test]
+    async fn test_graphql_ws() {
+        let (pong_tx, mut pong_rx) = mpsc::unbounded_channel();
+        let connections = Arc::new(AtomicUsize::new(0));
+        let url = mock_server(move |mut ws| {
+            let pong_tx = pong_tx.clone();
+            let connection = connections.fetch_add(1, Ordering::SeqCst);
+            async move {
+                let init: Value =
+                    serde_json::from_str(ws.next().await.unwrap().unwrap().to_text().unwrap())
+                        .unwrap();
+                assert_eq!(init, json!({"type": "connection_init", "payload": {"token": "t"}}));
+                ws.send(text(json!({"type": "ping"}))).await.unwrap();
+                ws.send(text(json!({"type": "connection_ack", "payload": {"v": 1}})))
+                    .await
+                    .unwrap();
+
+                while let Some(Ok(msg)) = ws.next().await {
+                    let msg: Value = serde_json::from_str(msg.to_text().unwrap()).unwrap();
+                    let id = msg["id"].clone();
+                    match (msg["type"].as_str().unwrap(), msg["payload"]["query"].as_str()) {
+                        ("pong", _) => pong_tx.send(connection).unwrap(),
+                        ("subscribe", Some("subscription { ticks }")) => {
+                            let next = json!({"type": "next", "id": id, "payload": {"data": {"ticks": connection}}});
+                            ws.send(text(next)).await.unwrap();
+                            if connection == 0 {
+                                return;
+                            }
+                        }
+                        ("subscribe", Some("{ hello }")) => {
+                            let next = json!({"type": "next", "id": id, "payload": {"data": {"hello": "world"}}});
+                            ws.send(text(next)).await.unwrap();
+                            ws.send(text(json!({"type": "complete", "id": id})))
+                                .await
+                                .unwrap();
+                        }
+                        ("subscribe", _) => {
+                            let error = json!({"type": "error", "id": id, "payload": [{"message": "invalid"}]});
+                            ws.send(text(error)).await.unwrap();
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        })
+        .await;
+
+        let mut options = ReconnectOptions::default();
+        options
+            .with_handshake(Arc::new(GraphqlWsHandshake::new(Some(
+                json!({"token": "t"}),
+            ))))
+            .with_retry_policy_fn(Arc::new(|| {
+                Box::new(ConstantStrategy::new(Duration::from_millis(10)).into_iter())
+            }));
+        let reconnect = Arc::new(ReconnectT::new(url, Some(options)));
+        let client = GraphqlWsClient::attach(&reconnect).await.unwrap();
+        let mut ticks = client
+            .subscribe(GraphqlRequest::new("subscription { ticks }"))
+            .await
+            .unwrap();
+        reconnect.spawn_run();
+
+        let data = |value: Value| value["data"]["ticks"].clone();
+        assert_eq!(ticks.next().await.map(|r| data(r.unwrap())), Some(json!(0)));
+        // resubscribed with the same id after the server dropped the first connection
+        assert_eq!(ticks.next().await.map(|r| data(r.unwrap())), Some(json!(1)));
+        // the ping sent during the handshake is answered once connected
+        assert!(pong_rx.recv().await.is_some());
+        let ctx = reconnect.handshake_context().await.unwrap();
+        assert_eq!(
+            ctx.get::<ConnectionAck>().unwrap().payload,
+            Some(json!({"v": 1}))
+        );
+
+        let hello = client
+            .execute(GraphqlRequest::new("{ hello }"))
+            .await
+            .unwrap();
+        assert_eq!(hello, json!({"data": {"hello": "world"}}));
+        let error = client.execute(GraphqlRequest::new("{ nope }")).await;
+        assert!(matches!(error, Err(GraphqlWsError::Graphql(errors)) if errors.len() == 1));
+
+        client.complete(ticks.id()).await.unwrap();
+        assert!(client.is_empty().await);
+        assert!(ticks.next().await.is_none());
+    }
+}
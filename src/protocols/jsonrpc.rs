@@ -0,0 +1,533 @@
+//! A JSON-RPC 2.0 client.
+
+use crate::correlation::Correlator;
+use crate::errors::ReconnectTError;
+use crate::event_listeners::ShareListener;
+use crate::maybe_sender::MaybePSTSender;
+use crate::tungstenite::ReconnectT;
+use eyre::Result as EResult;
+use serde::de::{DeserializeOwned, IgnoredAny};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use tokio_stream::StreamExt;
+use tungstenite::client::IntoClientRequest;
+use tungstenite::Message;
+
+/// The error object of a failed call.
+#[derive(thiserror::Error, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[error("JSON-RPC error {code}: {message}")]
+pub struct ErrorObject {
+    pub code: i64,
+    pub message: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data: Option<Value>,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum JsonRpcError {
+    #[error(transparent)]
+    Transport(#[from] ReconnectTError),
+    #[error(transparent)]
+    Rpc(#[from] ErrorObject),
+    #[error("json error: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("invalid response: {0}")]
+    InvalidResponse(String),
+}
+
+/// A server-initiated notification.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct Notification {
+    pub method: String,
+    #[serde(default)]
+    pub params: Value,
+}
+
+#[derive(Deserialize)]
+struct Response {
+    id: Option<Value>,
+    #[serde(default)]
+    result: Option<Value>,
+    #[serde(default)]
+    error: Option<ErrorObject>,
+}
+
+impl Response {
+    fn into_result(self) -> Result<Value, ErrorObject> {
+        match self.error {
+            Some(error) => Err(error),
+            None => Ok(self.result.unwrap_or(Value::Null)),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct IdOnly {
+    id: Option<Value>,
+    method: Option<IgnoredAny>,
+}
+
+/// The correlation key of an inbound response: its id, or the sorted ids of a batch.
+fn response_key(msg: &Message) -> Option<String> {
+    let text = msg.to_text().ok()?;
+    if text.trim_start().starts_with('[') {
+        let items: Vec<IdOnly> = serde_json::from_str(text).ok()?;
+        let ids: Vec<u64> = items
+            .iter()
+            .filter(|item| item.method.is_none())
+            .filter_map(|item| item.id.as_ref()?.as_u64())
+            .collect();
+        (!ids.is_empty()).then(|| batch_key(ids))
+    } else {
+        let item: IdOnly = serde_json::from_str(text).ok()?;
+        match item.method {
+            Some(_) => None,
+            None => item.id?.as_u64().map(|id| id.to_string()),
+        }
+    }
+}
+
+/// Builds a request, or a notification without `id`. Params serializing to `null`, like
+/// `()`, are omitted as the specification requires params to be structured.
+fn request(id: Option<u64>, method: &str, params: Value) -> Value {
+    let mut request = json!({"jsonrpc": "2.0", "method": method});
+    if let Some(id) = id {
+        request["id"] = id.into();
+    }
+    if !params.is_null() {
+        request["params"] = params;
+    }
+    request
+}
+
+fn batch_key(mut ids: Vec<u64>) -> String {
+    ids.sort_unstable();
+    let ids: Vec<String> = ids.iter().map(u64::to_string).collect();
+    format!("batch:{}", ids.join(","))
+}
+
+/// A JSON-RPC 2.0 client over a [`ReconnectT`].
+///
+/// Calls pending when the connection drops fail with [`ReconnectTError::Disconnected`],
+/// or are re-sent once reconnected if [`with_retry_on_reconnect`](Self::with_retry_on_reconnect)
+/// is set. Server notifications are delivered on [`notifications`](Self::notifications).
+///
+/// An error response with a `null` id, sent when the server could not read the request id,
+/// fails the call if it is the only one pending. Otherwise it is delivered on
+/// [`errors`](Self::errors).
+pub struct JsonRpcClient {
+    sender: Arc<MaybePSTSender>,
+    correlator: Arc<Correlator>,
+    next_id: AtomicU64,
+    timeout: Duration,
+    retry_on_reconnect: bool,
+    notifications: Arc<ShareListener<Notification>>,
+    errors: Arc<ShareListener<ErrorObject>>,
+}
+
+/// Configures and attaches a [`JsonRpcClient`].
+pub struct JsonRpcClientBuilder {
+    timeout: Duration,
+    retry_on_reconnect: bool,
+}
+
+impl JsonRpcClientBuilder {
+    /// Sets how long a call may take, including waiting for a reconnect. Defaults to 30s.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Re-sends calls interrupted by a disconnect once reconnected, within the call timeout.
+    pub fn with_retry_on_reconnect(mut self, retry_on_reconnect: bool) -> Self {
+        self.retry_on_reconnect = retry_on_reconnect;
+        self
+    }
+
+    pub async fn attach<R: IntoClientRequest + Send + Sync>(
+        self,
+        reconnect: &ReconnectT<R>,
+    ) -> EResult<Arc<JsonRpcClient>> {
+        let correlator = Correlator::new(reconnect.sender.clone(), response_key)
+            .attach(reconnect)
+            .await?;
+        let notifications = Arc::new(ShareListener::default());
+        let errors = Arc::new(ShareListener::default());
+
+        let mut uncorrelated = correlator.create_receive_stream().await;
+        let listener = notifications.clone();
+        let error_listener = errors.clone();
+        let requests = correlator.clone();
+        tokio::spawn(async move {
+            while let Some(msg) = uncorrelated.next().await {
+                let Ok(text) = msg.to_text() else {
+                    continue;
+                };
+                if let Ok(Response {
+                    id: None,
+                    error: Some(error),
+                    ..
+                }) = serde_json::from_str(text)
+                {
+                    if let Err(msg) = requests.complete_only_pending(msg.clone()).await {
+                        tracing::warn!(error=?error, msg=?msg, "jsonrpc::error_without_id");
+                        error_listener.notify(error).await;
+                    }
+                    continue;
+                }
+                match serde_json::from_str::<Notification>(text) {
+                    Ok(notification) => listener.notify(notification).await,
+                    Err(e) => tracing::debug!(error=?e, msg=text, "jsonrpc::unhandled"),
+                }
+            }
+        });
+
+        Ok(Arc::new(JsonRpcClient {
+            sender: reconnect.sender.clone(),
+            correlator,
+            next_id: AtomicU64::new(1),
+            timeout: self.timeout,
+            retry_on_reconnect: self.retry_on_reconnect,
+            notifications,
+            errors,
+        }))
+    }
+}
+
+impl JsonRpcClient {
+    pub fn builder() -> JsonRpcClientBuilder {
+        JsonRpcClientBuilder {
+            timeout: Duration::from_secs(30),
+            retry_on_reconnect: false,
+        }
+    }
+
+    fn next_id(&self) -> u64 {
+        self.next_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Calls `method` and decodes its result.
+    pub async fn call<P: Serialize, T: DeserializeOwned>(
+        &self,
+        method: &str,
+        params: P,
+    ) -> Result<T, JsonRpcError> {
+        let params = serde_json::to_value(params)?;
+        let result = self.call_value(method, params).await?;
+        Ok(serde_json::from_value(result)?)
+    }
+
+    /// Calls `method` and returns its raw result.
+    pub async fn call_value(&self, method: &str, params: Value) -> Result<Value, JsonRpcError> {
+        let deadline = Instant::now() + self.timeout;
+        loop {
+            let id = self.next_id();
+            let request = request(Some(id), method, params.clone());
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let result = self
+                .correlator
+                .request_with_timeout(
+                    Message::text(request.to_string()),
+                    id.to_string(),
+                    remaining,
+                )
+                .await;
+
+            match result {
+                Ok(response) => {
+                    let response: Response =
+                        serde_json::from_str(response.to_text().map_err(ReconnectTError::from)?)?;
+                    return Ok(response.into_result()?);
+                }
+                Err(
+                    ReconnectTError::Disconnected
+                    | ReconnectTError::SenderNotConnected
+                    | ReconnectTError::TokioTungsteniteError(_),
+                ) if self.retry_on_reconnect => {
+                    tracing::debug!(method = method, id = id, "jsonrpc::retry");
+                    if tokio::time::timeout_at(deadline, self.sender.wait_connected())
+                        .await
+                        .is_err()
+                    {
+                        return Err(ReconnectTError::RequestTimeout(self.timeout).into());
+                    }
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    /// Sends a notification, which has no response.
+    pub async fn notify<P: Serialize>(&self, method: &str, params: P) -> Result<(), JsonRpcError> {
+        let request = request(None, method, serde_json::to_value(params)?);
+        self.correlator
+            .send(Message::text(request.to_string()))
+            .await?;
+        Ok(())
+    }
+
+    /// Starts a batch of calls and notifications sent as one message.
+    pub fn batch(&self) -> Batch<'_> {
+        Batch {
+            client: self,
+            requests: Vec::new(),
+            ids: Vec::new(),
+        }
+    }
+
+    /// Creates a stream of server notifications.
+    pub async fn notifications(&self) -> UnboundedReceiverStream<Notification> {
+        self.notifications.new_listener().await
+    }
+
+    /// Creates a stream of the error responses with a `null` id that could not be matched
+    /// to a call.
+    pub async fn errors(&self) -> UnboundedReceiverStream<ErrorObject> {
+        self.errors.new_listener().await
+    }
+}
+
+/// Identifies a call within a [`Batch`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct BatchId(u64);
+
+/// A batch request. Batches are not retried across reconnects.
+pub struct Batch<'a> {
+    client: &'a JsonRpcClient,
+    requests: Vec<Value>,
+    ids: Vec<u64>,
+}
+
+#[allow(clippy::result_large_err)]
+impl Batch<'_> {
+    pub fn call<P: Serialize>(&mut self, method: &str, params: P) -> Result<BatchId, JsonRpcError> {
+        let id = self.client.next_id();
+        self.requests
+            .push(request(Some(id), method, serde_json::to_value(params)?));
+        self.ids.push(id);
+        Ok(BatchId(id))
+    }
+
+    pub fn notify<P: Serialize>(&mut self, method: &str, params: P) -> Result<(), JsonRpcError> {
+        self.requests
+            .push(request(None, method, serde_json::to_value(params)?));
+        Ok(())
+    }
+
+    /// Sends the batch and waits for the responses to all of its calls.
+    pub async fn send(self) -> Result<BatchResponse, JsonRpcError> {
+        let msg = Message::text(Value::Array(self.requests).to_string());
+        if self.ids.is_empty() {
+            self.client.correlator.send(msg).await?;
+            return Ok(BatchResponse::default());
+        }
+
+        let key = batch_key(self.ids);
+        let response = self
+            .client
+            .correlator
+            .request_with_timeout(msg, key, self.client.timeout)
+            .await?;
+        let text = response.to_text().map_err(ReconnectTError::from)?;
+        let responses: Vec<Response> = match serde_json::from_str(text) {
+            Ok(responses) => responses,
+            // a batch the server could not read is answered with a single error
+            Err(e) => match serde_json::from_str::<Response>(text) {
+                Ok(Response {
+                    error: Some(error), ..
+                }) => return Err(error.into()),
+                _ => return Err(e.into()),
+            },
+        };
+
+        let mut results = HashMap::new();
+        for response in responses {
+            let Some(id) = response.id.as_ref().and_then(Value::as_u64) else {
+                continue;
+            };
+            results.insert(id, response.into_result());
+        }
+        Ok(BatchResponse { results })
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct BatchResponse {
+    results: HashMap<u64, Result<Value, ErrorObject>>,
+}
+
+#[allow(clippy::result_large_err)]
+impl BatchResponse {
+    /// Decodes the result of the call identified by `id`.
+    pub fn get<T: DeserializeOwned>(&self, id: BatchId) -> Result<T, JsonRpcError> {
+        match self.results.get(&id.0) {
+            Some(Ok(result)) => Ok(serde_json::from_value(result.clone())?),
+            Some(Err(error)) => Err(error.clone().into()),
+            None => Err(JsonRpcError::InvalidResponse(format!(
+                "no response for id {}",
+                id.0
+            ))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::prelude::*;
+    use crate::protocols::jsonrpc::{JsonRpcClient, JsonRpcError, Notification};
+    use crate::test_util::mock_server;
+    use futures_util::{SinkExt, StreamExt};
+    use serde_json::{json, Value};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::sync::mpsc;
+    use tungstenite::Message;
+
+    fn handle(request: &Value) -> Option<Value> {
+        let id = request.get("id")?.clone();
+        let params = &request["params"];
+        Some(match request["method"].as_str()? {
+            "add" => {
+                json!({"jsonrpc": "2.0", "id": id, "result": params[0].as_i64()? + params[1].as_i64()?})
+            }
+            _ => {
+                json!({"jsonrpc": "2.0", "id": id, "error": {"code": -32601, "message": "Method not found"}})
+            }
+        })
+    }
+
+    #[tokio::test]
+    async fn test_jsonrpc() {
+        let connections = Arc::new(AtomicUsize::new(0));
+        let url = mock_server(move |mut ws| {
+            let connection = connections.fetch_add(1, Ordering::SeqCst);
+            async move {
+                while let Some(Ok(msg)) = ws.next().await {
+                    let request: Value = serde_json::from_str(msg.to_text().unwrap()).unwrap();
+                    let reply = match &request {
+                        Value::Array(requests) => {
+                            Value::Array(requests.iter().filter_map(handle).rev().collect())
+                        }
+                        _ if request["method"] == "flaky" && connection == 0 => return,
+                        _ if request["method"] == "flaky" => {
+                            json!({"jsonrpc": "2.0", "id": request["id"], "result": "recovered"})
+                        }
+                        _ if request["method"] == "subscribe" => {
+                            json!({"jsonrpc": "2.0", "method": "tick", "params": [1]})
+                        }
+                        _ => handle(&request).unwrap(),
+                    };
+                    ws.send(Message::text(reply.to_string())).await.unwrap();
+                }
+            }
+        })
+        .await;
+
+        let mut options = ReconnectOptions::default();
+        options.with_retry_policy_fn(Arc::new(|| {
+            Box::new(ConstantStrategy::new(Duration::from_millis(10)).into_iter())
+        }));
+        let reconnect = Arc::new(ReconnectT::new(url, Some(options)));
+        let client = JsonRpcClient::builder()
+            .with_timeout(Duration::from_secs(5))
+            .with_retry_on_reconnect(true)
+            .attach(&reconnect)
+            .await
+            .unwrap();
+        let mut notifications = client.notifications().await;
+        reconnect.spawn_run();
+        reconnect.sender.wait_connected().await;
+
+        let sum: i64 = client.call("add", [1, 2]).await.unwrap();
+        assert_eq!(sum, 3);
+
+        let error = client.call::<_, Value>("missing", ()).await.unwrap_err();
+        assert!(matches!(error, JsonRpcError::Rpc(e) if e.code == -32601));
+
+        let mut batch = client.batch();
+        let a = batch.call("add", [2, 3]).unwrap();
+        let b = batch.call("missing", ()).unwrap();
+        batch.notify("log", ["ignored"]).unwrap();
+        let responses = batch.send().await.unwrap();
+        assert_eq!(responses.get::<i64>(a).unwrap(), 5);
+        assert!(responses.get::<i64>(b).is_err());
+
+        client.notify("subscribe", ()).await.unwrap();
+        assert_eq!(
+            notifications.next().await,
+            Some(Notification {
+                method: "tick".to_string(),
+                params: json!([1]),
+            })
+        );
+
+        // the first connection drops without answering; the call is re-sent after reconnecting
+        let recovered: String = client.call("flaky", ()).await.unwrap();
+        assert_eq!(recovered, "recovered");
+    }
+
+    #[tokio::test]
+    async fn test_wire_format() {
+        let (seen_tx, mut seen_rx) = mpsc::unbounded_channel();
+        let url = mock_server(move |mut ws| {
+            let seen_tx = seen_tx.clone();
+            async move {
+                while let Some(Ok(msg)) = ws.next().await {
+                    let request: Value = serde_json::from_str(msg.to_text().unwrap()).unwrap();
+                    seen_tx.send(request.clone()).unwrap();
+                    let reply = match request["method"].as_str() {
+                        Some("garbled") => json!({
+                            "jsonrpc": "2.0",
+                            "id": null,
+                            "error": {"code": -32700, "message": "Parse error"},
+                        }),
+                        _ => json!({"jsonrpc": "2.0", "id": request["id"], "result": true}),
+                    };
+                    ws.send(Message::text(reply.to_string())).await.unwrap();
+                }
+            }
+        })
+        .await;
+
+        let reconnect = Arc::new(ReconnectT::new(url, None));
+        let client = JsonRpcClient::builder()
+            .with_timeout(Duration::from_secs(5))
+            .attach(&reconnect)
+            .await
+            .unwrap();
+        reconnect.spawn_run();
+        reconnect.sender.wait_connected().await;
+
+        let _: bool = client.call("no_params", ()).await.unwrap();
+        assert_eq!(
+            seen_rx.recv().await.unwrap(),
+            json!({"jsonrpc": "2.0", "id": 1, "method": "no_params"})
+        );
+        let _: bool = client.call("positional", [1]).await.unwrap();
+        assert_eq!(
+            seen_rx.recv().await.unwrap(),
+            json!({"jsonrpc": "2.0", "id": 2, "method": "positional", "params": [1]})
+        );
+        client.notify("notification", ()).await.unwrap();
+        assert_eq!(
+            seen_rx.recv().await.unwrap(),
+            json!({"jsonrpc": "2.0", "method": "notification"})
+        );
+
+        // an error without id fails the only pending call instead of timing out
+        let error = client.call::<_, Value>("garbled", ()).await.unwrap_err();
+        assert!(matches!(error, JsonRpcError::Rpc(e) if e.code == -32700));
+
+        // with no call pending it is surfaced on the error stream
+        let mut errors = client.errors().await;
+        client.notify("garbled", ()).await.unwrap();
+        assert_eq!(errors.next().await.unwrap().code, -32700);
+    }
+}
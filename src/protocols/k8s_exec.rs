@@ -0,0 +1,380 @@
+//! The Kubernetes exec/attach channel protocol (`v4.channel.k8s.io` and
+//! `v5.channel.k8s.io`).
+//!
+//! Every reconnect starts a new exec session, running the command again; use a retry
+//! policy that gives up for commands that must run once.
+
+use crate::errors::ReconnectTError;
+use crate::event_listeners::ShareListener;
+use crate::handshake::HandshakeContext;
+use crate::maybe_sender::MaybePSTSender;
+use crate::protocols::{Driver, ProtocolClient};
+use crate::tungstenite::ReconnectT;
+use async_trait::async_trait;
+use eyre::Result as EResult;
+use serde_json::{json, Value};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use tokio_stream::StreamExt;
+use tungstenite::client::IntoClientRequest;
+use tungstenite::handshake::client::Request;
+use tungstenite::http::HeaderValue;
+use tungstenite::Message;
+
+pub const V4_PROTOCOL: &str = "v4.channel.k8s.io";
+/// Adds closing a channel, letting the command see the end of stdin.
+pub const V5_PROTOCOL: &str = "v5.channel.k8s.io";
+
+/// The channel byte closing the channel in the next byte, with [`V5_PROTOCOL`].
+const CLOSE_CHANNEL: u8 = 255;
+
+/// Builds the request for an exec or attach `url`, offering both protocol versions.
+///
+/// The url carries the command and the streams to open, e.g.
+/// `wss://host/api/v1/namespaces/ns/pods/pod/exec?command=sh&stdin=true&stdout=true`.
+#[allow(clippy::result_large_err)]
+pub fn request(url: &str) -> Result<Request, tungstenite::Error> {
+    let mut request = url.into_client_request()?;
+    request.headers_mut().insert(
+        "Sec-WebSocket-Protocol",
+        HeaderValue::from_static("v5.channel.k8s.io, v4.channel.k8s.io"),
+    );
+    Ok(request)
+}
+
+/// The channel a frame belongs to, given by its first byte.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Channel {
+    Stdin = 0,
+    Stdout = 1,
+    Stderr = 2,
+    /// Carries the exit status.
+    Error = 3,
+    Resize = 4,
+}
+
+impl Channel {
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(Channel::Stdin),
+            1 => Some(Channel::Stdout),
+            2 => Some(Channel::Stderr),
+            3 => Some(Channel::Error),
+            4 => Some(Channel::Resize),
+            _ => None,
+        }
+    }
+
+    /// Prefixes `data` with the channel byte.
+    pub fn frame(self, data: &[u8]) -> Message {
+        let mut frame = Vec::with_capacity(data.len() + 1);
+        frame.push(self as u8);
+        frame.extend_from_slice(data);
+        Message::binary(frame)
+    }
+}
+
+/// How the command ended, from the `Status` sent on the error channel.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ExitStatus {
+    Success,
+    Failure {
+        /// Set when the command exited with a non-zero code.
+        exit_code: Option<i32>,
+        reason: String,
+        message: String,
+    },
+}
+
+impl ExitStatus {
+    pub fn from_status(status: &Value) -> Self {
+        if status["status"] == "Success" {
+            return ExitStatus::Success;
+        }
+        let exit_code = status["details"]["causes"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .find(|cause| cause["reason"] == "ExitCode")
+            .and_then(|cause| cause["message"].as_str()?.parse().ok());
+        ExitStatus::Failure {
+            exit_code,
+            reason: status["reason"].as_str().unwrap_or_default().to_string(),
+            message: status["message"].as_str().unwrap_or_default().to_string(),
+        }
+    }
+
+    /// Returns the exit code, unknown when the exec itself failed.
+    pub fn code(&self) -> Option<i32> {
+        match self {
+            ExitStatus::Success => Some(0),
+            ExitStatus::Failure { exit_code, .. } => *exit_code,
+        }
+    }
+
+    pub fn success(&self) -> bool {
+        *self == ExitStatus::Success
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum ExecError {
+    #[error(transparent)]
+    Transport(#[from] ReconnectTError),
+    /// The operation needs a protocol version the server did not select.
+    #[error("{0} is not supported by the negotiated protocol")]
+    Unsupported(&'static str),
+}
+
+/// An exec or attach session over a [`ReconnectT`] built with [`request`].
+///
+/// Output frames are demultiplexed into the [`stdout`](Self::stdout) and
+/// [`stderr`](Self::stderr) streams, which continue across reconnects.
+pub struct ExecClient {
+    sender: Arc<MaybePSTSender>,
+    stdout: ShareListener<Vec<u8>>,
+    stderr: ShareListener<Vec<u8>>,
+    statuses: ShareListener<ExitStatus>,
+    /// The exit status of the current connection's session.
+    status: Mutex<Option<ExitStatus>>,
+    /// The protocol the server selected for the current connection.
+    protocol: Mutex<Option<String>>,
+}
+
+impl ExecClient {
+    /// Creates the client and starts feeding it the frames of `reconnect`.
+    pub async fn attach<R: IntoClientRequest + Send + Sync>(
+        reconnect: &ReconnectT<R>,
+    ) -> EResult<Arc<Self>> {
+        let this = Arc::new(Self {
+            sender: reconnect.sender.clone(),
+            stdout: ShareListener::default(),
+            stderr: ShareListener::default(),
+            statuses: ShareListener::default(),
+            status: Mutex::new(None),
+            protocol: Mutex::new(None),
+        });
+        Driver::attach(&this, reconnect).await?;
+        Ok(this)
+    }
+
+    /// Creates a stream of the command's standard output.
+    pub async fn stdout(&self) -> UnboundedReceiverStream<Vec<u8>> {
+        self.stdout.new_listener().await
+    }
+
+    pub async fn stderr(&self) -> UnboundedReceiverStream<Vec<u8>> {
+        self.stderr.new_listener().await
+    }
+
+    /// Creates a stream of the exit statuses, one per session.
+    pub async fn statuses(&self) -> UnboundedReceiverStream<ExitStatus> {
+        self.statuses.new_listener().await
+    }
+
+    /// Waits for the exit status of the current session, or of the next one if the
+    /// connection dropped before it ended.
+    ///
+    /// Only a status frame resolves it, so it waits forever if the server never sends
+    /// one or the connection stops; see [`wait_timeout`](Self::wait_timeout).
+    pub async fn wait(&self) -> ExitStatus {
+        let mut statuses = self.statuses.new_listener().await;
+        if let Some(status) = self.status.lock().await.clone() {
+            return status;
+        }
+        statuses
+            .next()
+            .await
+            .expect("the listener outlives the call")
+    }
+
+    /// Like [`wait`](Self::wait), but fails with
+    /// [`RequestTimeout`](ReconnectTError::RequestTimeout) after `timeout`.
+    pub async fn wait_timeout(&self, timeout: Duration) -> Result<ExitStatus, ExecError> {
+        tokio::time::timeout(timeout, self.wait())
+            .await
+            .map_err(|_| ReconnectTError::RequestTimeout(timeout).into())
+    }
+
+    pub async fn write_stdin(&self, data: &[u8]) -> Result<(), ExecError> {
+        Ok(self.sender.send(Channel::Stdin.frame(data)).await?)
+    }
+
+    /// Closes stdin so the command sees its end. Needs [`V5_PROTOCOL`].
+    pub async fn close_stdin(&self) -> Result<(), ExecError> {
+        if self.protocol.lock().await.as_deref() != Some(V5_PROTOCOL) {
+            return Err(ExecError::Unsupported("closing stdin"));
+        }
+        let frame = Message::binary(vec![CLOSE_CHANNEL, Channel::Stdin as u8]);
+        Ok(self.sender.send(frame).await?)
+    }
+
+    /// Resizes the terminal of a `tty` session.
+    pub async fn resize(&self, width: u16, height: u16) -> Result<(), ExecError> {
+        let size = json!({"Width": width, "Height": height}).to_string();
+        Ok(self
+            .sender
+            .send(Channel::Resize.frame(size.as_bytes()))
+            .await?)
+    }
+}
+
+#[async_trait]
+impl ProtocolClient for ExecClient {
+    async fn on_message(self: Arc<Self>, msg: Message) {
+        let Message::Binary(data) = msg else {
+            return;
+        };
+        let Some((&channel, payload)) = data.split_first() else {
+            return;
+        };
+        match Channel::from_byte(channel) {
+            // the server opens each channel with an empty frame
+            _ if payload.is_empty() => {}
+            Some(Channel::Stdout) => self.stdout.notify(payload.to_vec()).await,
+            Some(Channel::Stderr) => self.stderr.notify(payload.to_vec()).await,
+            Some(Channel::Error) => match serde_json::from_slice(payload) {
+                Ok(value) => {
+                    let exit = ExitStatus::from_status(&value);
+                    *self.status.lock().await = Some(exit.clone());
+                    self.statuses.notify(exit).await;
+                }
+                Err(e) => tracing::warn!(error=?e, "k8s_exec::invalid_status"),
+            },
+            _ => tracing::debug!(channel = channel, "k8s_exec::unhandled"),
+        }
+    }
+
+    async fn on_connected(self: Arc<Self>, ctx: Arc<HandshakeContext>) {
+        *self.protocol.lock().await = ctx.subprotocol().map(str::to_string);
+    }
+
+    async fn on_disconnected(self: Arc<Self>) {
+        // handled after the session's last frame, so its status is never mistaken for
+        // the next session's and the next one's is never cleared
+        *self.status.lock().await = None;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::prelude::*;
+    use crate::protocols::k8s_exec::{request, Channel, ExecClient, ExitStatus, V5_PROTOCOL};
+    use crate::test_util::mock_server_with_subprotocol;
+    use futures_util::{SinkExt, StreamExt};
+    use serde_json::{json, Value};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::sync::mpsc;
+    use tungstenite::Message;
+
+    #[test]
+    fn test_exit_status() {
+        let success = json!({"metadata": {}, "status": "Success"});
+        assert!(ExitStatus::from_status(&success).success());
+        let failure = json!({
+            "status": "Failure",
+            "reason": "InternalError",
+            "message": "pod not found",
+        });
+        assert_eq!(ExitStatus::from_status(&failure).code(), None);
+    }
+
+    #[tokio::test]
+    async fn test_exec() {
+        let (resize_tx, mut resize_rx) = mpsc::unbounded_channel();
+        let connections = Arc::new(AtomicUsize::new(0));
+        let url = mock_server_with_subprotocol(V5_PROTOCOL, move |mut ws| {
+            let resize_tx = resize_tx.clone();
+            let connection = connections.fetch_add(1, Ordering::SeqCst);
+            async move {
+                for channel in [Channel::Stdout, Channel::Stderr, Channel::Error] {
+                    ws.send(channel.frame(b"")).await.unwrap();
+                }
+                if connection > 0 {
+                    // the command run again exits right away
+                    let status = json!({"status": "Success"}).to_string();
+                    ws.send(Channel::Error.frame(status.as_bytes()))
+                        .await
+                        .unwrap();
+                    while ws.next().await.is_some() {}
+                    return;
+                }
+                ws.send(Channel::Stderr.frame(b"warn")).await.unwrap();
+                while let Some(Ok(Message::Binary(data))) = ws.next().await {
+                    match data[0] {
+                        0 => ws.send(Channel::Stdout.frame(&data[1..])).await.unwrap(),
+                        4 => {
+                            let size: Value = serde_json::from_slice(&data[1..]).unwrap();
+                            resize_tx.send(size).unwrap();
+                        }
+                        255 => {
+                            let status = json!({
+                                "status": "Failure",
+                                "reason": "NonZeroExitCode",
+                                "message": "command terminated with non-zero exit code",
+                                "details": {"causes": [{"reason": "ExitCode", "message": "3"}]},
+                            });
+                            let status = status.to_string();
+                            ws.send(Channel::Error.frame(status.as_bytes()))
+                                .await
+                                .unwrap();
+                            return;
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        })
+        .await;
+
+        let mut options = ReconnectOptions::default();
+        options.with_retry_policy_fn(Arc::new(|| {
+            Box::new(ConstantStrategy::new(Duration::from_millis(10)).into_iter())
+        }));
+        let reconnect = Arc::new(ReconnectT::new(request(&url).unwrap(), Some(options)));
+        let client = ExecClient::attach(&reconnect).await.unwrap();
+        let mut stdout = client.stdout().await;
+        let mut stderr = client.stderr().await;
+        let mut status = reconnect.create_status_stream().await;
+        reconnect.spawn_run();
+        let Some(WsStreamStatus::Connected(ctx)) = status.next().await else {
+            panic!("not connected");
+        };
+        assert_eq!(ctx.subprotocol(), Some(V5_PROTOCOL));
+
+        assert_eq!(stderr.next().await.unwrap(), b"warn");
+        client.write_stdin(b"echo").await.unwrap();
+        assert_eq!(stdout.next().await.unwrap(), b"echo");
+        client.resize(80, 24).await.unwrap();
+        assert_eq!(
+            resize_rx.recv().await.unwrap(),
+            json!({"Width": 80, "Height": 24})
+        );
+
+        client.close_stdin().await.unwrap();
+        let exit = client.wait().await;
+        assert_eq!(exit.code(), Some(3));
+        assert!(!exit.success());
+
+        // the next session's status is kept, whenever its `Connected` is handled
+        assert!(matches!(
+            status.next().await,
+            Some(WsStreamStatus::Disconnected)
+        ));
+        assert!(matches!(
+            status.next().await,
+            Some(WsStreamStatus::Connected(_))
+        ));
+        loop {
+            let exit = client.wait_timeout(Duration::from_secs(5)).await.unwrap();
+            if exit.success() {
+                break;
+            }
+            tokio::task::yield_now().await;
+        }
+    }
+}
@@ -0,0 +1,110 @@
+//! Protocol clients built on top of [`ReconnectT`](crate::tungstenite::ReconnectT).
+
+pub mod cdp;
+pub mod centrifugo;
+pub mod eth_subscribe;
+pub mod graphql_ws;
+pub mod jsonrpc;
+pub mod k8s_exec;
+pub mod mqtt;
+pub mod nostr;
+pub mod phoenix;
+pub mod pusher;
+pub mod signalr;
+pub mod socket_io;
+pub mod stomp;
+pub mod wamp;
+
+use crate::extension::StreamEvent;
+use crate::handshake::HandshakeContext;
+use crate::prelude::WsStreamStatus;
+use crate::tungstenite::ReconnectT;
+use async_trait::async_trait;
+use eyre::Result as EResult;
+use std::future::Future;
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+use tokio::time::{interval_at, Instant};
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use tokio_stream::StreamExt;
+use tungstenite::client::IntoClientRequest;
+use tungstenite::Message;
+
+/// A protocol client fed by a [`Driver`].
+#[async_trait]
+pub(crate) trait ProtocolClient: Send + Sync + 'static {
+    async fn on_message(self: Arc<Self>, msg: Message);
+
+    async fn on_connected(self: Arc<Self>, ctx: Arc<HandshakeContext>);
+
+    async fn on_disconnected(self: Arc<Self>);
+}
+
+/// Feeds a client the messages and status changes of its connection without keeping it
+/// alive. Both are handled in order by one task, so a reply received just before a drop
+/// is handled before `on_disconnected`.
+pub(crate) struct Driver<T> {
+    client: Weak<T>,
+}
+
+impl<T: ProtocolClient> Driver<T> {
+    /// Feeds `client` with the events of `reconnect`.
+    pub(crate) async fn attach<R: IntoClientRequest + Send + Sync>(
+        client: &Arc<T>,
+        reconnect: &ReconnectT<R>,
+    ) -> EResult<()> {
+        let driver = Driver {
+            client: Arc::downgrade(client),
+        };
+        tokio::spawn(driver.run(reconnect.create_event_stream().await));
+        Ok(())
+    }
+
+    async fn run(self, mut events: UnboundedReceiverStream<StreamEvent>) {
+        while let Some(event) = events.next().await {
+            let Some(client) = self.client.upgrade() else {
+                return;
+            };
+            match event {
+                StreamEvent::Message(msg) => client.on_message(msg).await,
+                StreamEvent::Status(WsStreamStatus::Connected(ctx)) => {
+                    client.on_connected(ctx).await
+                }
+                StreamEvent::Status(WsStreamStatus::Disconnected) => client.on_disconnected().await,
+                StreamEvent::Status(_) => {}
+            }
+        }
+    }
+}
+
+/// A client that knows the generation of its current connection.
+pub(crate) trait KeepAlive: Send + Sync + 'static {
+    fn generation(&self) -> u64;
+}
+
+/// Calls `tick` every `interval` while connection `generation` is current. Stops when the
+/// client is dropped or `tick` returns `false`.
+pub(crate) fn spawn_keep_alive<T, F, Fut>(
+    client: &Arc<T>,
+    generation: u64,
+    interval: Duration,
+    tick: F,
+) where
+    T: KeepAlive,
+    F: Fn(Arc<T>) -> Fut + Send + 'static,
+    Fut: Future<Output = bool> + Send,
+{
+    let client = Arc::downgrade(client);
+    tokio::spawn(async move {
+        let mut ticker = interval_at(Instant::now() + interval, interval);
+        loop {
+            ticker.tick().await;
+            let Some(client) = client.upgrade() else {
+                return;
+            };
+            if client.generation() != generation || !tick(client).await {
+                return;
+            }
+        }
+    });
+}
@@ -0,0 +1,765 @@
+//! An MQTT 3.1.1 and 5.0 client over WebSocket, using the `mqtt` subprotocol.
+
+mod packet;
+
+use crate::errors::ReconnectTError;
+use crate::handshake::{receive_until, HandshakeContext, StreamHandshake};
+use crate::maybe_sender::MaybePSTSender;
+use crate::protocols::{spawn_keep_alive, Driver, KeepAlive, ProtocolClient};
+use crate::tungstenite::ReconnectT;
+use crate::types::{PSTReceiver, PSTSender};
+use async_trait::async_trait;
+use eyre::{eyre, Result as EResult};
+use futures_util::SinkExt;
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU16, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot, Mutex};
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use tokio_stream::Stream;
+use tungstenite::client::IntoClientRequest;
+use tungstenite::handshake::client::Request;
+use tungstenite::http::HeaderValue;
+use tungstenite::Message;
+
+pub use crate::protocols::mqtt::packet::*;
+
+pub const SUBPROTOCOL: &str = "mqtt";
+
+/// Builds the connect request for `url` with the `mqtt` subprotocol.
+#[allow(clippy::result_large_err)]
+pub fn request(url: &str) -> Result<Request, tungstenite::Error> {
+    let mut request = url.into_client_request()?;
+    request.headers_mut().insert(
+        "Sec-WebSocket-Protocol",
+        HeaderValue::from_static(SUBPROTOCOL),
+    );
+    Ok(request)
+}
+
+/// Returns whether `topic` matches the subscription `filter`, honouring the `+` and `#`
+/// wildcards. Wildcards at the first level do not match topics starting with `$`.
+pub fn topic_matches(filter: &str, topic: &str) -> bool {
+    if topic.starts_with('$') && (filter.starts_with('+') || filter.starts_with('#')) {
+        return false;
+    }
+    let mut levels = topic.split('/');
+    for part in filter.split('/') {
+        match (part, levels.next()) {
+            ("#", _) => return true,
+            ("+", Some(_)) => {}
+            (part, Some(level)) if part == level => {}
+            _ => return false,
+        }
+    }
+    levels.next().is_none()
+}
+
+/// What the broker sent in its CONNACK, available in the handshake context.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MqttSession {
+    pub version: ProtocolVersion,
+    pub session_present: bool,
+    pub keep_alive: Duration,
+}
+
+/// Sends CONNECT and waits for an accepting CONNACK.
+///
+/// With a non-zero keep alive, the connection's receive timeout is set to one and a half
+/// times the keep alive, while [`MqttClient`] sends PINGREQ every keep alive.
+#[derive(Clone)]
+pub struct MqttHandshake {
+    connect: Connect,
+    timeout: Duration,
+}
+
+impl MqttHandshake {
+    /// Creates the handshake for `client_id`, with MQTT 3.1.1, a clean start and a 60s
+    /// keep alive.
+    pub fn new(client_id: impl Into<String>) -> Self {
+        Self {
+            connect: Connect {
+                version: ProtocolVersion::V311,
+                client_id: client_id.into(),
+                clean_start: true,
+                keep_alive: 60,
+                username: None,
+                password: None,
+            },
+            timeout: Duration::from_secs(10),
+        }
+    }
+
+    pub fn with_version(mut self, version: ProtocolVersion) -> Self {
+        self.connect.version = version;
+        self
+    }
+
+    /// Sets the keep alive, rounded up to whole seconds. Zero disables it.
+    pub fn with_keep_alive(mut self, keep_alive: Duration) -> Self {
+        let secs = keep_alive.as_secs() + (keep_alive.subsec_nanos() > 0) as u64;
+        self.connect.keep_alive = secs.min(u16::MAX as u64) as u16;
+        self
+    }
+
+    pub fn with_login(mut self, username: impl Into<String>, password: impl Into<Vec<u8>>) -> Self {
+        self.connect.username = Some(username.into());
+        self.connect.password = Some(password.into());
+        self
+    }
+
+    /// Sets whether the broker discards the previous session. Subscriptions are restored
+    /// by [`MqttClient`] either way.
+    pub fn with_clean_start(mut self, clean_start: bool) -> Self {
+        self.connect.clean_start = clean_start;
+        self
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+}
+
+#[async_trait]
+impl StreamHandshake for MqttHandshake {
+    async fn handshake(
+        &self,
+        writer: &mut PSTSender,
+        reader: &mut PSTReceiver,
+    ) -> EResult<HandshakeContext> {
+        let version = self.connect.version;
+        writer
+            .send(Packet::Connect(self.connect.clone()).to_message(version)?)
+            .await?;
+
+        let mut ctx = HandshakeContext::new();
+        let (ack, rest) = receive_until(reader, &mut ctx, self.timeout, |msg| {
+            let Message::Binary(bytes) = msg else {
+                return None;
+            };
+            match Packet::decode(bytes, version) {
+                Ok(Some((Packet::ConnAck(ack), used))) => Some(Ok((ack, bytes.slice(used..)))),
+                Ok(_) => None,
+                Err(e) => Some(Err(e.into())),
+            }
+        })
+        .await?;
+        if ack.code != 0 {
+            return Err(eyre!("connect refused: code {}", ack.code));
+        }
+        if !rest.is_empty() {
+            // packets the broker sent right after CONNACK
+            ctx.forward(Message::Binary(rest));
+        }
+
+        let keep_alive = Duration::from_secs(self.connect.keep_alive as u64);
+        if !keep_alive.is_zero() {
+            ctx.set_receive_timeout(keep_alive * 3 / 2);
+        }
+        ctx.insert(MqttSession {
+            version,
+            session_present: ack.session_present,
+            keep_alive,
+        });
+        Ok(ctx)
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum MqttError {
+    #[error(transparent)]
+    Transport(#[from] ReconnectTError),
+    #[error(transparent)]
+    Packet(#[from] PacketError),
+    /// The broker refused a subscription with this code.
+    #[error("subscription refused: code {0}")]
+    Refused(u8),
+    /// Every packet id is used by an in-flight publish or a pending request.
+    #[error("no packet id available")]
+    NoPacketId,
+}
+
+struct Subscription {
+    filter: String,
+    qos: QoS,
+    /// The generation of the connection it was last sent on.
+    sent: Option<u64>,
+    tx: mpsc::UnboundedSender<Publish>,
+}
+
+struct InFlight {
+    publish: Publish,
+    /// The generation of the connection it was last sent on.
+    sent: Option<u64>,
+    done: Option<oneshot::Sender<()>>,
+}
+
+/// Configures and attaches an [`MqttClient`].
+pub struct MqttClientBuilder {
+    version: ProtocolVersion,
+    timeout: Duration,
+}
+
+impl MqttClientBuilder {
+    /// Sets the version used to encode packets, which must match the handshake's.
+    /// Defaults to MQTT 3.1.1.
+    pub fn with_version(mut self, version: ProtocolVersion) -> Self {
+        self.version = version;
+        self
+    }
+
+    /// Sets how long [`subscribe`](MqttClient::subscribe) waits for the SUBACK.
+    /// Defaults to 10s.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub async fn attach<R: IntoClientRequest + Send + Sync>(
+        self,
+        reconnect: &ReconnectT<R>,
+    ) -> EResult<Arc<MqttClient>> {
+        let client = Arc::new(MqttClient {
+            sender: reconnect.sender.clone(),
+            version: self.version,
+            timeout: self.timeout,
+            next_packet_id: AtomicU16::new(1),
+            next_subscription_id: AtomicU64::new(1),
+            generation: AtomicU64::new(0),
+            subscriptions: Mutex::new(HashMap::new()),
+            in_flight: Mutex::new(HashMap::new()),
+            pending: Mutex::new(HashMap::new()),
+            buffer: Mutex::new(Vec::new()),
+        });
+        Driver::attach(&client, reconnect).await?;
+        Ok(client)
+    }
+}
+
+/// An MQTT client over a [`ReconnectT`] configured with [`MqttHandshake`] and the
+/// [`request`] carrying the `mqtt` subprotocol.
+///
+/// Subscriptions are restored after every reconnect, and QoS 1 publishes not yet
+/// acknowledged are retransmitted with the DUP flag set.
+pub struct MqttClient {
+    sender: Arc<MaybePSTSender>,
+    version: ProtocolVersion,
+    timeout: Duration,
+    next_packet_id: AtomicU16,
+    next_subscription_id: AtomicU64,
+    generation: AtomicU64,
+    subscriptions: Mutex<HashMap<u64, Subscription>>,
+    in_flight: Mutex<HashMap<u16, InFlight>>,
+    /// SUBACKs and UNSUBACKs waited for.
+    pending: Mutex<HashMap<u16, oneshot::Sender<Packet>>>,
+    /// The start of a packet split across frames.
+    buffer: Mutex<Vec<u8>>,
+}
+
+impl MqttClient {
+    pub fn builder() -> MqttClientBuilder {
+        MqttClientBuilder {
+            version: ProtocolVersion::V311,
+            timeout: Duration::from_secs(10),
+        }
+    }
+
+    /// Returns a packet id not used by an in-flight publish or a pending request.
+    async fn next_packet_id(&self) -> Result<u16, MqttError> {
+        let in_flight = self.in_flight.lock().await;
+        let pending = self.pending.lock().await;
+        for _ in 0..=u16::MAX {
+            let id = self.next_packet_id.fetch_add(1, Ordering::Relaxed);
+            if id != 0 && !in_flight.contains_key(&id) && !pending.contains_key(&id) {
+                return Ok(id);
+            }
+        }
+        Err(MqttError::NoPacketId)
+    }
+
+    /// Publishes `payload` to `topic`.
+    ///
+    /// A QoS 0 publish fails when not connected. A QoS 1 publish is sent once connected
+    /// and returns when the broker acknowledged it, retransmitting it after reconnects.
+    pub async fn publish(
+        &self,
+        topic: &str,
+        payload: impl Into<Vec<u8>>,
+        qos: QoS,
+        retain: bool,
+    ) -> Result<(), MqttError> {
+        let mut publish = Publish {
+            topic: topic.to_string(),
+            payload: payload.into(),
+            qos,
+            retain,
+            dup: false,
+            packet_id: None,
+        };
+        if qos == QoS::AtMostOnce {
+            self.send(Packet::Publish(publish)).await?;
+            return Ok(());
+        }
+
+        let id = self.next_packet_id().await?;
+        publish.packet_id = Some(id);
+        // fails now rather than on every retransmission
+        Packet::Publish(publish.clone()).encode(self.version)?;
+        let (tx, rx) = oneshot::channel();
+        self.in_flight.lock().await.insert(
+            id,
+            InFlight {
+                publish,
+                sent: None,
+                done: Some(tx),
+            },
+        );
+        self.send_in_flight(id).await;
+        let _ = rx.await;
+        Ok(())
+    }
+
+    /// Sends the in-flight publish `id` unless already sent on this connection.
+    async fn send_in_flight(&self, id: u16) {
+        // holding the sender keeps a retransmission from sending the publish again
+        let mut sender = self.sender.lock().await;
+        let Some(generation) = sender.generation() else {
+            tracing::debug!(id = id, "mqtt::publish_deferred");
+            return;
+        };
+        let publish = {
+            let mut in_flight = self.in_flight.lock().await;
+            let Some(entry) = in_flight
+                .get_mut(&id)
+                .filter(|entry| entry.sent != Some(generation))
+            else {
+                return;
+            };
+            entry.sent = Some(generation);
+            let publish = entry.publish.clone();
+            entry.publish.dup = true;
+            publish
+        };
+        let msg = Packet::Publish(publish)
+            .to_message(self.version)
+            .expect("checked by publish");
+        if let Err(e) = sender.send(msg).await {
+            tracing::debug!(id=id, error=?e, "mqtt::publish_deferred");
+        }
+    }
+
+    /// Subscribes to `filter` and returns the stream of matching publishes.
+    ///
+    /// Fails if the broker refuses the subscription. When not connected, or if the
+    /// connection drops before the SUBACK, the subscription is sent once connected.
+    pub async fn subscribe(&self, filter: &str, qos: QoS) -> Result<MqttSubscription, MqttError> {
+        let packet_id = self.next_packet_id().await?;
+        let subscribe = Packet::Subscribe(Subscribe {
+            packet_id,
+            filters: vec![(filter.to_string(), qos)],
+        })
+        .to_message(self.version)?;
+        let id = self.next_subscription_id.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.subscriptions.lock().await.insert(
+            id,
+            Subscription {
+                filter: filter.to_string(),
+                qos,
+                sent: None,
+                tx,
+            },
+        );
+        let subscription = MqttSubscription {
+            id,
+            stream: UnboundedReceiverStream::new(rx),
+        };
+
+        let (tx, rx) = oneshot::channel();
+        {
+            // holding the sender keeps a restore from sending the subscription again
+            let mut sender = self.sender.lock().await;
+            let Some(generation) = sender.generation() else {
+                return Ok(subscription);
+            };
+            self.pending.lock().await.insert(packet_id, tx);
+            if let Err(e) = sender.send(subscribe).await {
+                self.pending.lock().await.remove(&packet_id);
+                tracing::warn!(filter=filter, error=?e, "mqtt::subscribe");
+                return Ok(subscription);
+            }
+            if let Some(entry) = self.subscriptions.lock().await.get_mut(&id) {
+                entry.sent = Some(generation);
+            }
+        }
+        match self.reply(packet_id, rx).await {
+            Ok(Packet::SubAck(ack)) => match ack.codes.first() {
+                Some(&code) if code >= 0x80 => {
+                    self.subscriptions.lock().await.remove(&id);
+                    Err(MqttError::Refused(code))
+                }
+                _ => Ok(subscription),
+            },
+            Ok(_) => Ok(subscription),
+            Err(e) => {
+                tracing::warn!(filter=filter, error=?e, "mqtt::subscribe");
+                Ok(subscription)
+            }
+        }
+    }
+
+    /// Ends the subscription `id`, unsubscribing its filter unless another subscription
+    /// uses it.
+    pub async fn unsubscribe(&self, id: u64) -> Result<(), MqttError> {
+        let filter = {
+            let mut subscriptions = self.subscriptions.lock().await;
+            let Some(removed) = subscriptions.remove(&id) else {
+                return Ok(());
+            };
+            if subscriptions.values().any(|s| s.filter == removed.filter) {
+                return Ok(());
+            }
+            removed.filter
+        };
+        let packet_id = self.next_packet_id().await?;
+        let unsubscribe = Packet::Unsubscribe(Unsubscribe {
+            packet_id,
+            filters: vec![filter],
+        });
+        match self.send(unsubscribe).await {
+            Ok(()) | Err(MqttError::Transport(ReconnectTError::SenderNotConnected)) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Returns the number of QoS 1 publishes waiting for their PUBACK.
+    pub async fn in_flight(&self) -> usize {
+        self.in_flight.lock().await.len()
+    }
+
+    async fn send(&self, packet: Packet) -> Result<(), MqttError> {
+        self.sender.send(packet.to_message(self.version)?).await?;
+        Ok(())
+    }
+
+    /// Waits for the acknowledgement carrying `packet_id`.
+    async fn reply(
+        &self,
+        packet_id: u16,
+        rx: oneshot::Receiver<Packet>,
+    ) -> Result<Packet, ReconnectTError> {
+        match tokio::time::timeout(self.timeout, rx).await {
+            Ok(Ok(reply)) => Ok(reply),
+            Ok(Err(_)) => Err(ReconnectTError::Disconnected),
+            Err(_) => {
+                self.pending.lock().await.remove(&packet_id);
+                Err(ReconnectTError::RequestTimeout(self.timeout))
+            }
+        }
+    }
+
+    async fn on_data(&self, data: &[u8]) {
+        let mut buffer = self.buffer.lock().await;
+        buffer.extend_from_slice(data);
+        let mut start = 0;
+        loop {
+            match Packet::decode(&buffer[start..], self.version) {
+                Ok(Some((packet, used))) => {
+                    start += used;
+                    self.on_packet(packet).await;
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    tracing::warn!(error=?e, "mqtt::invalid_packet");
+                    start = buffer.len();
+                    break;
+                }
+            }
+        }
+        buffer.drain(..start);
+    }
+
+    async fn on_packet(&self, packet: Packet) {
+        match packet {
+            Packet::Publish(publish) => {
+                let ack = publish
+                    .packet_id
+                    .filter(|_| publish.qos == QoS::AtLeastOnce);
+                for subscription in self.subscriptions.lock().await.values() {
+                    if topic_matches(&subscription.filter, &publish.topic) {
+                        let _ = subscription.tx.send(publish.clone());
+                    }
+                }
+                if let Some(id) = ack {
+                    if let Err(e) = self.send(Packet::PubAck(id)).await {
+                        tracing::warn!(id=id, error=?e, "mqtt::puback");
+                    }
+                }
+            }
+            Packet::PubAck(id) => {
+                let entry = self.in_flight.lock().await.remove(&id);
+                if let Some(done) = entry.and_then(|mut entry| entry.done.take()) {
+                    let _ = done.send(());
+                }
+            }
+            Packet::SubAck(SubAck { packet_id: id, .. }) | Packet::UnsubAck(id) => {
+                let waiter = self.pending.lock().await.remove(&id);
+                match (waiter, packet) {
+                    (Some(waiter), packet) => {
+                        let _ = waiter.send(packet);
+                    }
+                    (None, Packet::SubAck(ack)) if ack.codes.iter().any(|&c| c >= 0x80) => {
+                        tracing::warn!(codes=?ack.codes, "mqtt::restore_refused");
+                    }
+                    _ => {}
+                }
+            }
+            Packet::PingResp => {}
+            packet => tracing::debug!(packet=?packet, "mqtt::unhandled"),
+        }
+    }
+
+    /// Re-sends the subscriptions not yet sent on the connection `generation`.
+    async fn restore(&self, generation: u64) {
+        let mut sender = self.sender.lock().await;
+        if sender.generation() != Some(generation) {
+            // disconnected again
+            return;
+        }
+        let filters: Vec<(String, QoS)> = {
+            let mut subscriptions = self.subscriptions.lock().await;
+            subscriptions
+                .values_mut()
+                .filter(|subscription| subscription.sent != Some(generation))
+                .map(|subscription| {
+                    subscription.sent = Some(generation);
+                    (subscription.filter.clone(), subscription.qos)
+                })
+                .collect()
+        };
+        for filter in filters {
+            let result: Result<(), MqttError> = async {
+                let subscribe = Packet::Subscribe(Subscribe {
+                    packet_id: self.next_packet_id().await?,
+                    filters: vec![filter],
+                });
+                Ok(sender.send(subscribe.to_message(self.version)?).await?)
+            }
+            .await;
+            if let Err(e) = result {
+                tracing::warn!(error=?e, "mqtt::restore");
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl ProtocolClient for MqttClient {
+    async fn on_message(self: Arc<Self>, msg: Message) {
+        match msg {
+            Message::Binary(data) => self.on_data(&data).await,
+            msg => tracing::debug!(msg=?msg, "mqtt::unhandled"),
+        }
+    }
+
+    async fn on_connected(self: Arc<Self>, ctx: Arc<HandshakeContext>) {
+        self.generation.store(ctx.generation(), Ordering::SeqCst);
+        if let Some(session) = ctx.get::<MqttSession>() {
+            if !session.keep_alive.is_zero() {
+                spawn_keep_alive(
+                    &self,
+                    ctx.generation(),
+                    session.keep_alive,
+                    |this| async move { this.send(Packet::PingReq).await.is_ok() },
+                );
+            }
+        }
+
+        self.restore(ctx.generation()).await;
+
+        let mut ids: Vec<u16> = self.in_flight.lock().await.keys().copied().collect();
+        ids.sort_unstable();
+        for id in ids {
+            self.send_in_flight(id).await;
+        }
+    }
+
+    async fn on_disconnected(self: Arc<Self>) {
+        self.buffer.lock().await.clear();
+        // dropping the senders fails the waiting requests
+        self.pending.lock().await.clear();
+    }
+}
+
+impl KeepAlive for MqttClient {
+    fn generation(&self) -> u64 {
+        self.generation.load(Ordering::SeqCst)
+    }
+}
+
+/// The publishes matching a subscription. The stream ends when it is unsubscribed.
+pub struct MqttSubscription {
+    id: u64,
+    stream: UnboundedReceiverStream<Publish>,
+}
+
+impl MqttSubscription {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+}
+
+impl Stream for MqttSubscription {
+    type Item = Publish;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Publish>> {
+        Pin::new(&mut self.stream).poll_next(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::prelude::*;
+    use crate::protocols::mqtt::{
+        topic_matches, ConnAck, MqttClient, MqttError, MqttHandshake, MqttSession, Packet,
+        ProtocolVersion, Publish, QoS, SubAck,
+    };
+    use crate::test_util::mock_server;
+    use futures_util::{SinkExt, StreamExt};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::sync::mpsc;
+    use tungstenite::Message;
+
+    #[test]
+    fn test_topic_matches() {
+        assert!(topic_matches("a/+/c", "a/b/c"));
+        assert!(topic_matches("a/#", "a"));
+        assert!(topic_matches("a/#", "a/b/c"));
+        assert!(topic_matches("#", "a/b"));
+        assert!(!topic_matches("a/+", "a/b/c"));
+        assert!(!topic_matches("a/b", "a"));
+        assert!(!topic_matches("#", "$SYS/uptime"));
+        assert!(topic_matches("$SYS/#", "$SYS/uptime"));
+    }
+
+    #[tokio::test]
+    async fn test_mqtt() {
+        let version = ProtocolVersion::V5;
+        let (seen_tx, mut seen_rx) = mpsc::unbounded_channel();
+        let connections = Arc::new(AtomicUsize::new(0));
+        let url = mock_server(move |mut ws| {
+            let seen_tx = seen_tx.clone();
+            let connection = connections.fetch_add(1, Ordering::SeqCst);
+            async move {
+                while let Some(Ok(Message::Binary(data))) = ws.next().await {
+                    let (packet, _) = Packet::decode(&data, version).unwrap().unwrap();
+                    seen_tx.send((connection, packet.clone())).unwrap();
+                    let replies = match packet {
+                        Packet::Connect(_) => vec![Packet::ConnAck(ConnAck {
+                            session_present: false,
+                            code: 0,
+                        })],
+                        Packet::Subscribe(subscribe) if subscribe.filters[0].0 == "denied" => {
+                            vec![Packet::SubAck(SubAck {
+                                packet_id: subscribe.packet_id,
+                                codes: vec![0x87],
+                            })]
+                        }
+                        Packet::Subscribe(subscribe) => vec![
+                            Packet::SubAck(SubAck {
+                                packet_id: subscribe.packet_id,
+                                codes: vec![1],
+                            }),
+                            Packet::Publish(Publish {
+                                topic: "a/b".to_string(),
+                                payload: format!("hello {connection}").into_bytes(),
+                                qos: QoS::AtLeastOnce,
+                                retain: false,
+                                dup: false,
+                                packet_id: Some(7),
+                            }),
+                        ],
+                        Packet::Publish(_) if connection == 0 => return,
+                        Packet::Publish(publish) => {
+                            vec![Packet::PubAck(publish.packet_id.unwrap())]
+                        }
+                        Packet::PingReq => vec![Packet::PingResp],
+                        _ => vec![],
+                    };
+                    // everything in one frame
+                    let frame: Vec<u8> = replies
+                        .iter()
+                        .flat_map(|p| p.encode(version).unwrap())
+                        .collect();
+                    if !frame.is_empty() {
+                        ws.send(Message::binary(frame)).await.unwrap();
+                    }
+                }
+            }
+        })
+        .await;
+
+        let mut options = ReconnectOptions::default();
+        options
+            .with_handshake(Arc::new(
+                MqttHandshake::new("client")
+                    .with_version(version)
+                    .with_keep_alive(Duration::from_secs(1)),
+            ))
+            .with_retry_policy_fn(Arc::new(|| {
+                Box::new(ConstantStrategy::new(Duration::from_millis(10)).into_iter())
+            }));
+        let reconnect = Arc::new(ReconnectT::new(url, Some(options)));
+        let client = MqttClient::builder()
+            .with_version(version)
+            .attach(&reconnect)
+            .await
+            .unwrap();
+        let mut status = reconnect.create_status_stream().await;
+        reconnect.spawn_run();
+        let Some(WsStreamStatus::Connected(ctx)) = status.next().await else {
+            panic!("not connected");
+        };
+        assert_eq!(ctx.get::<MqttSession>().unwrap().version, version);
+        assert_eq!(ctx.receive_timeout(), Some(Duration::from_millis(1500)));
+
+        let refused = client.subscribe("denied", QoS::AtMostOnce).await;
+        assert!(matches!(refused, Err(MqttError::Refused(0x87))));
+
+        let mut messages = client.subscribe("a/+", QoS::AtLeastOnce).await.unwrap();
+        let message = messages.next().await.unwrap();
+        assert_eq!(message.payload, b"hello 0");
+
+        // the broker drops the connection instead of acknowledging
+        client
+            .publish("out", "x", QoS::AtLeastOnce, false)
+            .await
+            .unwrap();
+        assert_eq!(client.in_flight().await, 0);
+
+        // the subscription was restored
+        let message = messages.next().await.unwrap();
+        assert_eq!(message.payload, b"hello 1");
+
+        let mut publishes = Vec::new();
+        let mut pinged = false;
+        while !pinged {
+            let (connection, packet) = seen_rx.recv().await.unwrap();
+            match packet {
+                Packet::Publish(publish) => publishes.push((connection, publish)),
+                Packet::PubAck(id) => assert_eq!(id, 7),
+                Packet::PingReq => pinged = connection == 1,
+                _ => {}
+            }
+        }
+        assert_eq!(publishes.len(), 2);
+        assert_eq!(publishes[0].1.packet_id, publishes[1].1.packet_id);
+        assert!(!publishes[0].1.dup && publishes[1].1.dup);
+    }
+}
@@ -0,0 +1,529 @@
+use tungstenite::Message;
+
+/// The largest remaining length four bytes can encode.
+const MAX_REMAINING_LENGTH: usize = 268_435_455;
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub enum PacketError {
+    #[error("unknown packet type: {0}")]
+    UnknownType(u8),
+    #[error("unsupported protocol level: {0}")]
+    UnsupportedVersion(u8),
+    #[error("unsupported QoS: {0}")]
+    UnsupportedQoS(u8),
+    #[error("malformed packet: {0}")]
+    Malformed(&'static str),
+    /// A field or the whole packet is longer than MQTT can encode.
+    #[error("{0} too long to encode")]
+    TooLong(&'static str),
+}
+
+/// The protocol level sent in CONNECT.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ProtocolVersion {
+    /// MQTT 3.1.1.
+    #[default]
+    V311,
+    /// MQTT 5.0. Properties are sent empty and skipped when received.
+    V5,
+}
+
+impl ProtocolVersion {
+    fn level(&self) -> u8 {
+        match self {
+            ProtocolVersion::V311 => 4,
+            ProtocolVersion::V5 => 5,
+        }
+    }
+
+    fn from_level(level: u8) -> Result<Self, PacketError> {
+        match level {
+            4 => Ok(ProtocolVersion::V311),
+            5 => Ok(ProtocolVersion::V5),
+            level => Err(PacketError::UnsupportedVersion(level)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum QoS {
+    #[default]
+    AtMostOnce,
+    AtLeastOnce,
+}
+
+impl QoS {
+    fn from_bits(bits: u8) -> Result<Self, PacketError> {
+        match bits {
+            0 => Ok(QoS::AtMostOnce),
+            1 => Ok(QoS::AtLeastOnce),
+            bits => Err(PacketError::UnsupportedQoS(bits)),
+        }
+    }
+
+    fn bits(&self) -> u8 {
+        match self {
+            QoS::AtMostOnce => 0,
+            QoS::AtLeastOnce => 1,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Connect {
+    pub version: ProtocolVersion,
+    pub client_id: String,
+    pub clean_start: bool,
+    /// In seconds.
+    pub keep_alive: u16,
+    pub username: Option<String>,
+    pub password: Option<Vec<u8>>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConnAck {
+    pub session_present: bool,
+    /// The return code (3.1.1) or reason code (5); zero means accepted.
+    pub code: u8,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Publish {
+    pub topic: String,
+    pub payload: Vec<u8>,
+    pub qos: QoS,
+    pub retain: bool,
+    pub dup: bool,
+    /// Set for QoS 1.
+    pub packet_id: Option<u16>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Subscribe {
+    pub packet_id: u16,
+    pub filters: Vec<(String, QoS)>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SubAck {
+    pub packet_id: u16,
+    /// The granted QoS per filter, or a failure code of 0x80 and above.
+    pub codes: Vec<u8>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Unsubscribe {
+    pub packet_id: u16,
+    pub filters: Vec<String>,
+}
+
+/// The MQTT control packets used by [`MqttClient`](super::MqttClient).
+///
+/// Packets are carried in binary frames; a frame may hold several packets or a part of
+/// one.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Packet {
+    Connect(Connect),
+    ConnAck(ConnAck),
+    Publish(Publish),
+    PubAck(u16),
+    Subscribe(Subscribe),
+    SubAck(SubAck),
+    Unsubscribe(Unsubscribe),
+    UnsubAck(u16),
+    PingReq,
+    PingResp,
+    Disconnect,
+}
+
+impl Packet {
+    /// Encodes the packet. CONNECT uses its own version.
+    pub fn encode(&self, version: ProtocolVersion) -> Result<Vec<u8>, PacketError> {
+        let v5 = version == ProtocolVersion::V5;
+        let mut body = Vec::new();
+        let header = match self {
+            Packet::Connect(connect) => {
+                put_str(&mut body, "MQTT")?;
+                body.push(connect.version.level());
+                let mut flags = 0;
+                if connect.clean_start {
+                    flags |= 0x02;
+                }
+                if connect.password.is_some() {
+                    flags |= 0x40;
+                }
+                if connect.username.is_some() {
+                    flags |= 0x80;
+                }
+                body.push(flags);
+                body.extend(connect.keep_alive.to_be_bytes());
+                if connect.version == ProtocolVersion::V5 {
+                    body.push(0);
+                }
+                put_str(&mut body, &connect.client_id)?;
+                if let Some(username) = &connect.username {
+                    put_str(&mut body, username)?;
+                }
+                if let Some(password) = &connect.password {
+                    put_bytes(&mut body, password)?;
+                }
+                0x10
+            }
+            Packet::ConnAck(ack) => {
+                body.push(ack.session_present as u8);
+                body.push(ack.code);
+                if v5 {
+                    body.push(0);
+                }
+                0x20
+            }
+            Packet::Publish(publish) => {
+                put_str(&mut body, &publish.topic)?;
+                if let Some(id) = publish.packet_id {
+                    body.extend(id.to_be_bytes());
+                }
+                if v5 {
+                    body.push(0);
+                }
+                body.extend(&publish.payload);
+                0x30 | (publish.dup as u8) << 3 | publish.qos.bits() << 1 | publish.retain as u8
+            }
+            Packet::PubAck(id) => {
+                body.extend(id.to_be_bytes());
+                0x40
+            }
+            Packet::Subscribe(subscribe) => {
+                body.extend(subscribe.packet_id.to_be_bytes());
+                if v5 {
+                    body.push(0);
+                }
+                for (filter, qos) in &subscribe.filters {
+                    put_str(&mut body, filter)?;
+                    body.push(qos.bits());
+                }
+                0x82
+            }
+            Packet::SubAck(ack) => {
+                body.extend(ack.packet_id.to_be_bytes());
+                if v5 {
+                    body.push(0);
+                }
+                body.extend(&ack.codes);
+                0x90
+            }
+            Packet::Unsubscribe(unsubscribe) => {
+                body.extend(unsubscribe.packet_id.to_be_bytes());
+                if v5 {
+                    body.push(0);
+                }
+                for filter in &unsubscribe.filters {
+                    put_str(&mut body, filter)?;
+                }
+                0xa2
+            }
+            Packet::UnsubAck(id) => {
+                body.extend(id.to_be_bytes());
+                0xb0
+            }
+            Packet::PingReq => 0xc0,
+            Packet::PingResp => 0xd0,
+            Packet::Disconnect => 0xe0,
+        };
+
+        if body.len() > MAX_REMAINING_LENGTH {
+            return Err(PacketError::TooLong("packet"));
+        }
+        let mut out = vec![header];
+        let mut len = body.len();
+        loop {
+            let mut byte = (len % 128) as u8;
+            len /= 128;
+            if len > 0 {
+                byte |= 0x80;
+            }
+            out.push(byte);
+            if len == 0 {
+                break;
+            }
+        }
+        out.extend(body);
+        Ok(out)
+    }
+
+    pub fn to_message(&self, version: ProtocolVersion) -> Result<Message, PacketError> {
+        Ok(Message::binary(self.encode(version)?))
+    }
+
+    /// Decodes the first packet of `buf`, returning it with the number of bytes it used,
+    /// or `None` if `buf` does not hold a whole packet yet. CONNECT is decoded with its
+    /// own version.
+    pub fn decode(
+        buf: &[u8],
+        version: ProtocolVersion,
+    ) -> Result<Option<(Packet, usize)>, PacketError> {
+        let Some(&header) = buf.first() else {
+            return Ok(None);
+        };
+        let mut len = 0usize;
+        let mut pos = 1;
+        loop {
+            let Some(&byte) = buf.get(pos) else {
+                return Ok(None);
+            };
+            len += ((byte & 0x7f) as usize) << (7 * (pos - 1));
+            pos += 1;
+            if byte & 0x80 == 0 {
+                break;
+            }
+            if pos > 4 {
+                return Err(PacketError::Malformed("remaining length"));
+            }
+        }
+        let Some(body) = buf.get(pos..pos + len) else {
+            return Ok(None);
+        };
+
+        let v5 = version == ProtocolVersion::V5;
+        let mut r = Reader { buf: body, pos: 0 };
+        let packet = match header >> 4 {
+            1 => {
+                if r.str()? != "MQTT" {
+                    return Err(PacketError::Malformed("protocol name"));
+                }
+                let version = ProtocolVersion::from_level(r.u8()?)?;
+                let flags = r.u8()?;
+                if flags & 0x04 != 0 {
+                    return Err(PacketError::Malformed("will messages are not supported"));
+                }
+                let keep_alive = r.u16()?;
+                if version == ProtocolVersion::V5 {
+                    r.skip_properties()?;
+                }
+                let client_id = r.str()?;
+                let username = match flags & 0x80 {
+                    0 => None,
+                    _ => Some(r.str()?),
+                };
+                let password = match flags & 0x40 {
+                    0 => None,
+                    _ => Some(r.bytes()?.to_vec()),
+                };
+                Packet::Connect(Connect {
+                    version,
+                    client_id,
+                    clean_start: flags & 0x02 != 0,
+                    keep_alive,
+                    username,
+                    password,
+                })
+            }
+            2 => Packet::ConnAck(ConnAck {
+                session_present: r.u8()? & 0x01 != 0,
+                code: r.u8()?,
+            }),
+            3 => {
+                let qos = QoS::from_bits((header >> 1) & 0x03)?;
+                let topic = r.str()?;
+                let packet_id = match qos {
+                    QoS::AtMostOnce => None,
+                    QoS::AtLeastOnce => Some(r.u16()?),
+                };
+                if v5 {
+                    r.skip_properties()?;
+                }
+                Packet::Publish(Publish {
+                    topic,
+                    payload: r.rest().to_vec(),
+                    qos,
+                    retain: header & 0x01 != 0,
+                    dup: header & 0x08 != 0,
+                    packet_id,
+                })
+            }
+            4 => Packet::PubAck(r.u16()?),
+            8 => {
+                let packet_id = r.u16()?;
+                if v5 {
+                    r.skip_properties()?;
+                }
+                let mut filters = Vec::new();
+                while !r.rest().is_empty() {
+                    let filter = r.str()?;
+                    filters.push((filter, QoS::from_bits(r.u8()? & 0x03)?));
+                }
+                Packet::Subscribe(Subscribe { packet_id, filters })
+            }
+            9 => {
+                let packet_id = r.u16()?;
+                if v5 {
+                    r.skip_properties()?;
+                }
+                Packet::SubAck(SubAck {
+                    packet_id,
+                    codes: r.rest().to_vec(),
+                })
+            }
+            10 => {
+                let packet_id = r.u16()?;
+                if v5 {
+                    r.skip_properties()?;
+                }
+                let mut filters = Vec::new();
+                while !r.rest().is_empty() {
+                    filters.push(r.str()?);
+                }
+                Packet::Unsubscribe(Unsubscribe { packet_id, filters })
+            }
+            11 => Packet::UnsubAck(r.u16()?),
+            12 => Packet::PingReq,
+            13 => Packet::PingResp,
+            14 => Packet::Disconnect,
+            kind => return Err(PacketError::UnknownType(kind)),
+        };
+        Ok(Some((packet, pos + len)))
+    }
+}
+
+fn put_str(out: &mut Vec<u8>, s: &str) -> Result<(), PacketError> {
+    put_bytes(out, s.as_bytes())
+}
+
+fn put_bytes(out: &mut Vec<u8>, bytes: &[u8]) -> Result<(), PacketError> {
+    let len = u16::try_from(bytes.len()).map_err(|_| PacketError::TooLong("string"))?;
+    out.extend(len.to_be_bytes());
+    out.extend(bytes);
+    Ok(())
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], PacketError> {
+        let bytes = self
+            .buf
+            .get(self.pos..self.pos + n)
+            .ok_or(PacketError::Malformed("truncated"))?;
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, PacketError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, PacketError> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn bytes(&mut self) -> Result<&'a [u8], PacketError> {
+        let len = self.u16()? as usize;
+        self.take(len)
+    }
+
+    fn str(&mut self) -> Result<String, PacketError> {
+        let bytes = self.bytes()?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| PacketError::Malformed("invalid utf-8"))
+    }
+
+    fn skip_properties(&mut self) -> Result<(), PacketError> {
+        let mut len = 0usize;
+        for shift in 0..4 {
+            let byte = self.u8()?;
+            len += ((byte & 0x7f) as usize) << (7 * shift);
+            if byte & 0x80 == 0 {
+                self.take(len)?;
+                return Ok(());
+            }
+        }
+        Err(PacketError::Malformed("property length"))
+    }
+
+    fn rest(&self) -> &'a [u8] {
+        &self.buf[self.pos..]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::protocols::mqtt::{
+        ConnAck, Connect, Packet, PacketError, ProtocolVersion, Publish, QoS, SubAck, Subscribe,
+        Unsubscribe,
+    };
+
+    #[test]
+    fn test_packet_codec() {
+        let packets = [
+            Packet::ConnAck(ConnAck {
+                session_present: true,
+                code: 0,
+            }),
+            Packet::Publish(Publish {
+                topic: "a/b".to_string(),
+                payload: vec![7; 200],
+                qos: QoS::AtLeastOnce,
+                retain: true,
+                dup: true,
+                packet_id: Some(9),
+            }),
+            Packet::PubAck(9),
+            Packet::Subscribe(Subscribe {
+                packet_id: 1,
+                filters: vec![("a/#".to_string(), QoS::AtLeastOnce)],
+            }),
+            Packet::SubAck(SubAck {
+                packet_id: 1,
+                codes: vec![1, 0x80],
+            }),
+            Packet::Unsubscribe(Unsubscribe {
+                packet_id: 2,
+                filters: vec!["a/#".to_string()],
+            }),
+            Packet::UnsubAck(2),
+            Packet::PingReq,
+            Packet::PingResp,
+            Packet::Disconnect,
+        ];
+        for version in [ProtocolVersion::V311, ProtocolVersion::V5] {
+            for packet in &packets {
+                let bytes = packet.encode(version).unwrap();
+                let decoded = Packet::decode(&bytes, version).unwrap();
+                assert_eq!(decoded, Some((packet.clone(), bytes.len())));
+                assert_eq!(Packet::decode(&bytes[..bytes.len() - 1], version), Ok(None));
+            }
+
+            let connect = Packet::Connect(Connect {
+                version,
+                client_id: "c".to_string(),
+                clean_start: true,
+                keep_alive: 30,
+                username: Some("u".to_string()),
+                password: Some(b"p".to_vec()),
+            });
+            // CONNECT carries its own version
+            let bytes = connect.encode(ProtocolVersion::V311).unwrap();
+            let decoded = Packet::decode(&bytes, ProtocolVersion::V311).unwrap();
+            assert_eq!(decoded, Some((connect, bytes.len())));
+        }
+
+        assert_eq!(
+            Packet::PingReq.encode(ProtocolVersion::V311),
+            Ok(vec![0xc0, 0x00])
+        );
+        let long = Packet::Subscribe(Subscribe {
+            packet_id: 1,
+            filters: vec![("a".repeat(70_000), QoS::AtMostOnce)],
+        });
+        assert_eq!(
+            long.encode(ProtocolVersion::V311),
+            Err(PacketError::TooLong("string"))
+        );
+        assert_eq!(
+            Packet::decode(&[0x34, 0x00], ProtocolVersion::V311),
+            Err(PacketError::UnsupportedQoS(2))
+        );
+    }
+}
@@ -0,0 +1,618 @@
+//! A Nostr (NIP-01) relay pool spanning several connections.
+//!
+//! Events are published as given; signing them is up to the caller.
+
+use crate::errors::ReconnectTError;
+use crate::handshake::HandshakeContext;
+use crate::maybe_sender::MaybePSTSender;
+use crate::protocols::{Driver, ProtocolClient};
+use crate::tungstenite::ReconnectT;
+use async_trait::async_trait;
+use eyre::Result as EResult;
+use futures_util::future::join_all;
+use serde_json::{json, Value};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Weak};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot, Mutex};
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use tokio_stream::Stream;
+use tungstenite::client::IntoClientRequest;
+use tungstenite::Message;
+
+/// A message sent by a relay.
+#[derive(Clone, Debug, PartialEq)]
+pub enum RelayMessage {
+    Event {
+        subscription_id: String,
+        event: Value,
+    },
+    Ok {
+        event_id: String,
+        accepted: bool,
+        message: String,
+    },
+    Eose(String),
+    Closed {
+        subscription_id: String,
+        message: String,
+    },
+    Notice(String),
+}
+
+impl RelayMessage {
+    pub fn decode(msg: &Message) -> Option<Self> {
+        let value: Vec<Value> = serde_json::from_str(msg.to_text().ok()?).ok()?;
+        let text = |i: usize| value.get(i).and_then(Value::as_str).map(str::to_string);
+        Some(match value.first()?.as_str()? {
+            "EVENT" => RelayMessage::Event {
+                subscription_id: text(1)?,
+                event: value.get(2)?.clone(),
+            },
+            "OK" => RelayMessage::Ok {
+                event_id: text(1)?,
+                accepted: value.get(2)?.as_bool()?,
+                message: text(3).unwrap_or_default(),
+            },
+            "EOSE" => RelayMessage::Eose(text(1)?),
+            "CLOSED" => RelayMessage::Closed {
+                subscription_id: text(1)?,
+                message: text(2).unwrap_or_default(),
+            },
+            "NOTICE" => RelayMessage::Notice(text(1)?),
+            _ => return None,
+        })
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum NostrError {
+    #[error(transparent)]
+    Transport(#[from] ReconnectTError),
+    /// The relay answered with an `OK` of `false`.
+    #[error("rejected: {0}")]
+    Rejected(String),
+    #[error("event has no id")]
+    MissingId,
+}
+
+/// What a subscription receives.
+#[derive(Clone, Debug, PartialEq)]
+pub enum SubscriptionItem {
+    /// An event not received before from any relay.
+    Event { relay: String, event: Value },
+    /// The relay sent all its stored events. Sent again after each reconnect.
+    Eose { relay: String },
+    /// The relay ended the subscription, which is not replayed to it.
+    Closed { relay: String, message: String },
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum RelayState {
+    Sent,
+    Closed,
+}
+
+/// The ids of the latest events delivered, forgetting the oldest beyond `capacity`.
+struct SeenIds {
+    capacity: usize,
+    ids: HashSet<String>,
+    order: VecDeque<String>,
+}
+
+impl SeenIds {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            ids: HashSet::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    /// Returns whether `id` was not seen yet.
+    fn insert(&mut self, id: &str) -> bool {
+        if self.ids.contains(id) {
+            return false;
+        }
+        if self.order.len() >= self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.ids.remove(&oldest);
+            }
+        }
+        self.ids.insert(id.to_string());
+        self.order.push_back(id.to_string());
+        true
+    }
+}
+
+struct Subscription {
+    filters: Vec<Value>,
+    /// The relays the REQ was sent to on their current connection, or that closed it.
+    relays: HashMap<String, RelayState>,
+    seen: SeenIds,
+    tx: mpsc::UnboundedSender<SubscriptionItem>,
+}
+
+impl Subscription {
+    fn req(&self, id: &str) -> Message {
+        let mut req = vec![json!("REQ"), json!(id)];
+        req.extend(self.filters.iter().cloned());
+        Message::text(Value::Array(req).to_string())
+    }
+}
+
+type Acks = HashMap<(String, String), oneshot::Sender<(bool, String)>>;
+
+/// Configures a [`RelayPool`].
+pub struct RelayPoolBuilder {
+    timeout: Duration,
+    seen_capacity: usize,
+}
+
+impl RelayPoolBuilder {
+    /// Sets how long [`publish`](RelayPool::publish) waits for each relay's `OK`.
+    /// Defaults to 10s.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Sets how many event ids each subscription remembers to drop duplicates. An event
+    /// received again after that many newer ones is delivered again. Defaults to 10000.
+    pub fn with_seen_capacity(mut self, capacity: usize) -> Self {
+        self.seen_capacity = capacity.max(1);
+        self
+    }
+
+    pub fn build(self) -> Arc<RelayPool> {
+        Arc::new(RelayPool {
+            timeout: self.timeout,
+            seen_capacity: self.seen_capacity,
+            next_id: AtomicU64::new(1),
+            relays: Mutex::new(HashMap::new()),
+            subscriptions: Mutex::new(HashMap::new()),
+            acks: Mutex::new(HashMap::new()),
+        })
+    }
+}
+
+/// A pool of relays, each on its own [`ReconnectT`].
+///
+/// Subscriptions go to every relay and are replayed to a relay after it reconnects.
+/// Events received from several relays, or again after a replay, are delivered once,
+/// among the latest [`with_seen_capacity`](RelayPoolBuilder::with_seen_capacity) ones.
+pub struct RelayPool {
+    timeout: Duration,
+    seen_capacity: usize,
+    next_id: AtomicU64,
+    relays: Mutex<HashMap<String, Arc<Relay>>>,
+    subscriptions: Mutex<HashMap<String, Subscription>>,
+    acks: Mutex<Acks>,
+}
+
+impl RelayPool {
+    pub fn builder() -> RelayPoolBuilder {
+        RelayPoolBuilder {
+            timeout: Duration::from_secs(10),
+            seen_capacity: 10_000,
+        }
+    }
+
+    /// Adds the relay connected by `reconnect`, naming it `name` (usually its URL).
+    pub async fn add_relay<R: IntoClientRequest + Send + Sync>(
+        self: &Arc<Self>,
+        name: impl Into<String>,
+        reconnect: &ReconnectT<R>,
+    ) -> EResult<()> {
+        let name = name.into();
+        let relay = Arc::new(Relay {
+            name: name.clone(),
+            pool: Arc::downgrade(self),
+            sender: reconnect.sender.clone(),
+        });
+        Driver::attach(&relay, reconnect).await?;
+        self.relays.lock().await.insert(name.clone(), relay);
+        if reconnect.sender.is_connected() {
+            self.replay(&name).await;
+        }
+        Ok(())
+    }
+
+    /// Returns the names of the relays.
+    pub async fn relays(&self) -> Vec<String> {
+        let mut relays: Vec<String> = self.relays.lock().await.keys().cloned().collect();
+        relays.sort_unstable();
+        relays
+    }
+
+    /// Subscribes to the events matching any of `filters` on every relay.
+    pub async fn subscribe(&self, filters: Vec<Value>) -> NostrSubscription {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed).to_string();
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.subscriptions.lock().await.insert(
+            id.clone(),
+            Subscription {
+                filters,
+                relays: HashMap::new(),
+                seen: SeenIds::new(self.seen_capacity),
+                tx,
+            },
+        );
+        let relays: Vec<String> = self.relays.lock().await.keys().cloned().collect();
+        for relay in relays {
+            self.replay(&relay).await;
+        }
+        NostrSubscription {
+            id,
+            stream: UnboundedReceiverStream::new(rx),
+        }
+    }
+
+    /// Closes the subscription `id` on every relay and ends its stream.
+    pub async fn unsubscribe(&self, id: &str) {
+        let Some(subscription) = self.subscriptions.lock().await.remove(id) else {
+            return;
+        };
+        let close = Message::text(json!(["CLOSE", id]).to_string());
+        let relays = self.relays.lock().await;
+        for (relay, state) in subscription.relays {
+            let Some(sender) = relays.get(&relay).filter(|_| state == RelayState::Sent) else {
+                continue;
+            };
+            if let Err(e) = sender.sender.send(close.clone()).await {
+                tracing::debug!(relay=relay, error=?e, "nostr::close");
+            }
+        }
+    }
+
+    /// Publishes a signed `event` to every relay and waits for their `OK`, returning
+    /// each relay's message or why it did not accept the event.
+    pub async fn publish(
+        &self,
+        event: Value,
+    ) -> Result<HashMap<String, Result<String, NostrError>>, NostrError> {
+        let event_id = event["id"]
+            .as_str()
+            .ok_or(NostrError::MissingId)?
+            .to_string();
+        let msg = Message::text(json!(["EVENT", event]).to_string());
+        let relays: Vec<(String, Arc<MaybePSTSender>)> = self
+            .relays
+            .lock()
+            .await
+            .iter()
+            .map(|(name, relay)| (name.clone(), relay.sender.clone()))
+            .collect();
+        let results = join_all(relays.into_iter().map(|(relay, sender)| {
+            let msg = msg.clone();
+            let event_id = event_id.clone();
+            async move {
+                let result = self.publish_to(&relay, &sender, msg, event_id).await;
+                (relay, result)
+            }
+        }))
+        .await;
+        Ok(results.into_iter().collect())
+    }
+
+    async fn publish_to(
+        &self,
+        relay: &str,
+        sender: &MaybePSTSender,
+        msg: Message,
+        event_id: String,
+    ) -> Result<String, NostrError> {
+        let key = (relay.to_string(), event_id);
+        let (tx, rx) = oneshot::channel();
+        self.acks.lock().await.insert(key.clone(), tx);
+        if let Err(e) = sender.send(msg).await {
+            self.acks.lock().await.remove(&key);
+            return Err(e.into());
+        }
+        match tokio::time::timeout(self.timeout, rx).await {
+            Ok(Ok((true, message))) => Ok(message),
+            Ok(Ok((false, message))) => Err(NostrError::Rejected(message)),
+            Ok(Err(_)) => Err(ReconnectTError::Disconnected.into()),
+            Err(_) => {
+                self.acks.lock().await.remove(&key);
+                Err(ReconnectTError::RequestTimeout(self.timeout).into())
+            }
+        }
+    }
+
+    /// Sends `relay` the subscriptions it has not received on its current connection.
+    async fn replay(&self, relay: &str) {
+        let Some(sender) = self
+            .relays
+            .lock()
+            .await
+            .get(relay)
+            .map(|relay| relay.sender.clone())
+        else {
+            return;
+        };
+        let reqs: Vec<(String, Message)> = {
+            let mut subscriptions = self.subscriptions.lock().await;
+            subscriptions
+                .iter_mut()
+                .filter(|(_, subscription)| !subscription.relays.contains_key(relay))
+                .map(|(id, subscription)| {
+                    subscription
+                        .relays
+                        .insert(relay.to_string(), RelayState::Sent);
+                    (id.clone(), subscription.req(id))
+                })
+                .collect()
+        };
+        for (id, req) in reqs {
+            if let Err(e) = sender.send(req).await {
+                tracing::debug!(relay=relay, error=?e, "nostr::replay");
+                // sent once the relay connects
+                if let Some(subscription) = self.subscriptions.lock().await.get_mut(&id) {
+                    subscription.relays.remove(relay);
+                }
+            }
+        }
+    }
+
+    async fn on_message(&self, relay: &str, msg: RelayMessage) {
+        match msg {
+            RelayMessage::Event {
+                subscription_id,
+                event,
+            } => {
+                let mut subscriptions = self.subscriptions.lock().await;
+                let Some(subscription) = subscriptions.get_mut(&subscription_id) else {
+                    return;
+                };
+                let Some(id) = event["id"].as_str() else {
+                    return;
+                };
+                if subscription.seen.insert(id) {
+                    let _ = subscription.tx.send(SubscriptionItem::Event {
+                        relay: relay.to_string(),
+                        event,
+                    });
+                }
+            }
+            RelayMessage::Eose(subscription_id) => {
+                let subscriptions = self.subscriptions.lock().await;
+                if let Some(subscription) = subscriptions.get(&subscription_id) {
+                    let _ = subscription.tx.send(SubscriptionItem::Eose {
+                        relay: relay.to_string(),
+                    });
+                }
+            }
+            RelayMessage::Closed {
+                subscription_id,
+                message,
+            } => {
+                let mut subscriptions = self.subscriptions.lock().await;
+                if let Some(subscription) = subscriptions.get_mut(&subscription_id) {
+                    subscription
+                        .relays
+                        .insert(relay.to_string(), RelayState::Closed);
+                    let _ = subscription.tx.send(SubscriptionItem::Closed {
+                        relay: relay.to_string(),
+                        message,
+                    });
+                }
+            }
+            RelayMessage::Ok {
+                event_id,
+                accepted,
+                message,
+            } => {
+                let key = (relay.to_string(), event_id);
+                if let Some(tx) = self.acks.lock().await.remove(&key) {
+                    let _ = tx.send((accepted, message));
+                }
+            }
+            RelayMessage::Notice(notice) => {
+                tracing::info!(relay = relay, notice = notice, "nostr::notice");
+            }
+        }
+    }
+
+    async fn on_disconnected(&self, relay: &str) {
+        for subscription in self.subscriptions.lock().await.values_mut() {
+            if subscription.relays.get(relay) == Some(&RelayState::Sent) {
+                subscription.relays.remove(relay);
+            }
+        }
+        // dropping the senders fails the waiting publishes
+        self.acks.lock().await.retain(|(r, _), _| r != relay);
+    }
+}
+
+/// One relay of a pool, fed by the [`Driver`] of its connection.
+struct Relay {
+    name: String,
+    pool: Weak<RelayPool>,
+    sender: Arc<MaybePSTSender>,
+}
+
+#[async_trait]
+impl ProtocolClient for Relay {
+    async fn on_message(self: Arc<Self>, msg: Message) {
+        let Some(pool) = self.pool.upgrade() else {
+            return;
+        };
+        match RelayMessage::decode(&msg) {
+            Some(msg) => pool.on_message(&self.name, msg).await,
+            None => tracing::debug!(relay=self.name, msg=?msg, "nostr::unhandled"),
+        }
+    }
+
+    async fn on_connected(self: Arc<Self>, _ctx: Arc<HandshakeContext>) {
+        if let Some(pool) = self.pool.upgrade() {
+            pool.replay(&self.name).await;
+        }
+    }
+
+    async fn on_disconnected(self: Arc<Self>) {
+        if let Some(pool) = self.pool.upgrade() {
+            pool.on_disconnected(&self.name).await;
+        }
+    }
+}
+
+/// The items of a subscription. The stream ends when it is unsubscribed.
+pub struct NostrSubscription {
+    id: String,
+    stream: UnboundedReceiverStream<SubscriptionItem>,
+}
+
+impl NostrSubscription {
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+}
+
+impl Stream for NostrSubscription {
+    type Item = SubscriptionItem;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<SubscriptionItem>> {
+        Pin::new(&mut self.stream).poll_next(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::prelude::*;
+    use crate::protocols::nostr::{NostrError, RelayPool, SeenIds, SubscriptionItem};
+    use crate::test_util::mock_server;
+    use futures_util::{SinkExt, StreamExt};
+    use serde_json::{json, Value};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::sync::mpsc;
+    use tungstenite::Message;
+
+    /// A relay storing event "e1", plus "e2" on relay "b" and "e3" on reconnections to
+    /// relay "a". Relay "a" drops its first connection after the stored events; relay "b"
+    /// rejects publishes.
+    async fn relay(
+        name: &'static str,
+        seen_tx: mpsc::UnboundedSender<(&'static str, String)>,
+    ) -> String {
+        let connections = Arc::new(AtomicUsize::new(0));
+        mock_server(move |mut ws| {
+            let seen_tx = seen_tx.clone();
+            let connection = connections.fetch_add(1, Ordering::SeqCst);
+            async move {
+                while let Some(Ok(msg)) = ws.next().await {
+                    let msg: Vec<Value> = serde_json::from_str(msg.to_text().unwrap()).unwrap();
+                    let kind = msg[0].as_str().unwrap().to_string();
+                    seen_tx.send((name, kind.clone())).unwrap();
+                    let replies = match kind.as_str() {
+                        "REQ" => {
+                            let mut ids = vec!["e1"];
+                            match (name, connection) {
+                                ("b", _) => ids.push("e2"),
+                                (_, 1..) => ids.push("e3"),
+                                _ => {}
+                            }
+                            let mut replies: Vec<Value> = ids
+                                .into_iter()
+                                .map(|id| json!(["EVENT", msg[1], {"id": id, "kind": 1}]))
+                                .collect();
+                            replies.push(json!(["EOSE", msg[1]]));
+                            replies
+                        }
+                        "EVENT" => {
+                            let accepted = name == "a";
+                            let message = if accepted { "" } else { "blocked: spam" };
+                            vec![json!(["OK", msg[1]["id"], accepted, message])]
+                        }
+                        _ => vec![],
+                    };
+                    for reply in replies {
+                        ws.send(Message::text(reply.to_string())).await.unwrap();
+                    }
+                    if kind == "REQ" && name == "a" && connection == 0 {
+                        return;
+                    }
+                }
+            }
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_relay_pool() {
+        let (seen_tx, mut seen_rx) = mpsc::unbounded_channel();
+        let pool = RelayPool::builder().build();
+        let mut statuses = Vec::new();
+        for name in ["a", "b"] {
+            let url = relay(name, seen_tx.clone()).await;
+            let mut options = ReconnectOptions::default();
+            options.with_retry_policy_fn(Arc::new(|| {
+                Box::new(ConstantStrategy::new(Duration::from_millis(10)).into_iter())
+            }));
+            let reconnect = Arc::new(ReconnectT::new(url, Some(options)));
+            pool.add_relay(name, &reconnect).await.unwrap();
+            statuses.push(reconnect.create_status_stream().await);
+            reconnect.spawn_run();
+        }
+        assert_eq!(pool.relays().await, ["a", "b"]);
+
+        let mut subscription = pool.subscribe(vec![json!({"kinds": [1]})]).await;
+        let mut events = Vec::new();
+        let mut eose = Vec::new();
+        while events.len() < 3 || !eose.contains(&"b".to_string()) {
+            match subscription.next().await.unwrap() {
+                SubscriptionItem::Event { event, .. } => {
+                    events.push(event["id"].as_str().unwrap().to_string())
+                }
+                SubscriptionItem::Eose { relay } => eose.push(relay),
+                item => panic!("unexpected {item:?}"),
+            }
+        }
+        events.sort();
+        assert_eq!(events, ["e1", "e2", "e3"]);
+
+        // relay "a" is connected again once it replayed the subscription
+        let results = pool
+            .publish(json!({"id": "p1", "kind": 1, "content": "hi"}))
+            .await
+            .unwrap();
+        assert_eq!(results["a"].as_ref().unwrap(), "");
+        assert!(matches!(&results["b"], Err(NostrError::Rejected(m)) if m == "blocked: spam"));
+        assert!(matches!(
+            pool.publish(json!({"kind": 1})).await,
+            Err(NostrError::MissingId)
+        ));
+
+        pool.unsubscribe(subscription.id()).await;
+        while let Some(item) = subscription.next().await {
+            assert!(!matches!(item, SubscriptionItem::Event { .. }));
+        }
+        let mut closed = Vec::new();
+        while closed.len() < 2 {
+            let (name, kind) = seen_rx.recv().await.unwrap();
+            if kind == "CLOSE" {
+                closed.push(name);
+            }
+        }
+        closed.sort();
+        assert_eq!(closed, ["a", "b"]);
+    }
+
+    #[test]
+    fn test_seen_ids_forget_oldest() {
+        let mut seen = SeenIds::new(2);
+        assert!(seen.insert("e1"));
+        assert!(seen.insert("e2"));
+        assert!(!seen.insert("e1"));
+        assert!(seen.insert("e3"));
+        assert!(!seen.insert("e2"));
+        // forgotten after two newer ids
+        assert!(seen.insert("e1"));
+        assert_eq!(seen.ids.len(), 2);
+    }
+}
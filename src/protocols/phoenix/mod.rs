@@ -0,0 +1,655 @@
+//! A Phoenix Channels client, using the V2 JSON serializer.
+
+mod presence;
+
+use crate::errors::ReconnectTError;
+use crate::handshake::HandshakeContext;
+use crate::maybe_sender::MaybePSTSender;
+use crate::protocols::{spawn_keep_alive, Driver, KeepAlive, ProtocolClient};
+use crate::strategies::{DurationIterator, ExpBackoffStrategy};
+use crate::tungstenite::ReconnectT;
+use async_trait::async_trait;
+use eyre::Result as EResult;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot, Mutex};
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use tokio_stream::Stream;
+use tungstenite::client::IntoClientRequest;
+use tungstenite::protocol::frame::coding::CloseCode;
+use tungstenite::protocol::CloseFrame;
+use tungstenite::Message;
+
+pub use crate::protocols::phoenix::presence::*;
+
+/// Returns the WebSocket endpoint of the Phoenix socket mounted at `base`, e.g.
+/// `ws://host:4000/socket`.
+pub fn endpoint(base: &str) -> String {
+    format!("{}/websocket?vsn=2.0.0", base.trim_end_matches('/'))
+}
+
+/// Creates the per-channel rejoin delays.
+pub type RejoinBackoffFn = Arc<dyn Fn() -> DurationIterator + Send + Sync>;
+
+/// A message of the V2 serializer, `[join_ref, ref, topic, event, payload]`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PhoenixMessage {
+    pub join_ref: Option<String>,
+    pub msg_ref: Option<String>,
+    pub topic: String,
+    pub event: String,
+    pub payload: Value,
+}
+
+impl PhoenixMessage {
+    pub fn decode(msg: &Message) -> Option<Self> {
+        let (join_ref, msg_ref, topic, event, payload): (
+            Option<String>,
+            Option<String>,
+            String,
+            String,
+            Value,
+        ) = serde_json::from_str(msg.to_text().ok()?).ok()?;
+        Some(Self {
+            join_ref,
+            msg_ref,
+            topic,
+            event,
+            payload,
+        })
+    }
+
+    pub fn encode(&self) -> Message {
+        let array = json!([
+            self.join_ref,
+            self.msg_ref,
+            self.topic,
+            self.event,
+            self.payload
+        ]);
+        Message::text(array.to_string())
+    }
+}
+
+/// The payload of a `phx_reply`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Reply {
+    pub status: String,
+    pub response: Value,
+}
+
+impl Reply {
+    fn from_payload(payload: Value) -> Self {
+        Self {
+            status: payload["status"].as_str().unwrap_or_default().to_string(),
+            response: payload.get("response").cloned().unwrap_or_default(),
+        }
+    }
+
+    pub fn is_ok(&self) -> bool {
+        self.status == "ok"
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum PhoenixError {
+    #[error(transparent)]
+    Transport(#[from] ReconnectTError),
+    /// The server replied with a non-ok status.
+    #[error("{status}: {response}", status = .0.status, response = .0.response)]
+    Rejected(Reply),
+    #[error("not joined to {0}")]
+    NotJoined(String),
+    #[error("already joined to {0}")]
+    AlreadyJoined(String),
+}
+
+struct ChannelState {
+    join_payload: Value,
+    /// The ref of the latest join; messages for older joins are dropped.
+    join_ref: Option<String>,
+    joined: bool,
+    backoff: DurationIterator,
+    presence: Presence,
+    tx: mpsc::UnboundedSender<PhoenixMessage>,
+}
+
+type Channels = Arc<Mutex<HashMap<String, ChannelState>>>;
+type Replies = Arc<Mutex<HashMap<String, oneshot::Sender<Reply>>>>;
+
+/// Configures and attaches a [`PhoenixClient`].
+pub struct PhoenixClientBuilder {
+    heartbeat_interval: Duration,
+    timeout: Duration,
+    rejoin_backoff: RejoinBackoffFn,
+}
+
+impl PhoenixClientBuilder {
+    /// Sets how often a heartbeat is sent on the `phoenix` topic. Defaults to 30s.
+    pub fn with_heartbeat_interval(mut self, heartbeat_interval: Duration) -> Self {
+        self.heartbeat_interval = heartbeat_interval;
+        self
+    }
+
+    /// Sets how long to wait for replies to joins and pushes. Defaults to 10s.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Sets the delays between attempts to rejoin a channel that failed to join or
+    /// crashed. Each channel has its own delays, reset once it joins.
+    pub fn with_rejoin_backoff(mut self, rejoin_backoff: RejoinBackoffFn) -> Self {
+        self.rejoin_backoff = rejoin_backoff;
+        self
+    }
+
+    pub async fn attach<R: IntoClientRequest + Send + Sync>(
+        self,
+        reconnect: &ReconnectT<R>,
+    ) -> EResult<Arc<PhoenixClient>> {
+        let client = Arc::new(PhoenixClient {
+            sender: reconnect.sender.clone(),
+            next_ref: AtomicU64::new(1),
+            generation: AtomicU64::new(0),
+            heartbeat_interval: self.heartbeat_interval,
+            timeout: self.timeout,
+            rejoin_backoff: self.rejoin_backoff,
+            channels: Arc::new(Mutex::new(HashMap::new())),
+            replies: Arc::new(Mutex::new(HashMap::new())),
+        });
+        Driver::attach(&client, reconnect).await?;
+        Ok(client)
+    }
+}
+
+/// A Phoenix Channels client over a [`ReconnectT`].
+///
+/// All channels are rejoined after every reconnect, and a channel crashing on the
+/// server (`phx_error`) is rejoined after its backoff delay. A heartbeat without a
+/// reply closes the connection, so a dead connection is also detected when
+/// [`with_receive_timeout`](crate::config::ReconnectOptions::with_receive_timeout) is
+/// longer than the heartbeat interval.
+pub struct PhoenixClient {
+    sender: Arc<MaybePSTSender>,
+    next_ref: AtomicU64,
+    generation: AtomicU64,
+    heartbeat_interval: Duration,
+    timeout: Duration,
+    rejoin_backoff: RejoinBackoffFn,
+    channels: Channels,
+    replies: Replies,
+}
+
+impl PhoenixClient {
+    pub fn builder() -> PhoenixClientBuilder {
+        PhoenixClientBuilder {
+            heartbeat_interval: Duration::from_secs(30),
+            timeout: Duration::from_secs(10),
+            rejoin_backoff: Arc::new(|| {
+                Box::new(
+                    ExpBackoffStrategy::new(Duration::from_secs(1), 2.0, 0.1)
+                        .with_max(Duration::from_secs(10))
+                        .into_iter(),
+                )
+            }),
+        }
+    }
+
+    fn next_ref(&self) -> String {
+        self.next_ref.fetch_add(1, Ordering::Relaxed).to_string()
+    }
+
+    /// Joins `topic` and returns the stream of its events.
+    ///
+    /// Fails if the server rejects the join. When not connected, or if the connection
+    /// drops before the reply, the channel is joined once connected.
+    pub async fn join(
+        self: &Arc<Self>,
+        topic: &str,
+        payload: Value,
+    ) -> Result<Channel, PhoenixError> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        {
+            let mut channels = self.channels.lock().await;
+            if channels.contains_key(topic) {
+                return Err(PhoenixError::AlreadyJoined(topic.to_string()));
+            }
+            channels.insert(
+                topic.to_string(),
+                ChannelState {
+                    join_payload: payload,
+                    join_ref: None,
+                    joined: false,
+                    backoff: (self.rejoin_backoff)(),
+                    presence: Presence::default(),
+                    tx,
+                },
+            );
+        }
+
+        if self.sender.is_connected() {
+            match self.send_join(topic).await {
+                Ok(()) => {}
+                Err(PhoenixError::Rejected(reply)) => {
+                    self.channels.lock().await.remove(topic);
+                    return Err(PhoenixError::Rejected(reply));
+                }
+                Err(e) => {
+                    tracing::warn!(topic=topic, error=?e, "phoenix::join");
+                    if self.sender.is_connected() {
+                        self.spawn_rejoin(topic.to_string(), false);
+                    }
+                }
+            }
+        }
+        Ok(Channel {
+            topic: topic.to_string(),
+            stream: UnboundedReceiverStream::new(rx),
+        })
+    }
+
+    /// Leaves `topic` and ends its stream.
+    pub async fn leave(&self, topic: &str) -> Result<(), PhoenixError> {
+        let channel = self.channels.lock().await.remove(topic);
+        let channel = channel.ok_or_else(|| PhoenixError::NotJoined(topic.to_string()))?;
+        if !channel.joined {
+            return Ok(());
+        }
+        let msg = PhoenixMessage {
+            join_ref: channel.join_ref,
+            msg_ref: Some(self.next_ref()),
+            topic: topic.to_string(),
+            event: "phx_leave".to_string(),
+            payload: json!({}),
+        };
+        match self.request(msg).await {
+            Ok(_) | Err(PhoenixError::Transport(_)) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Pushes `event` to the joined channel `topic` and waits for the reply.
+    pub async fn push(
+        &self,
+        topic: &str,
+        event: &str,
+        payload: Value,
+    ) -> Result<Reply, PhoenixError> {
+        let join_ref = {
+            let channels = self.channels.lock().await;
+            match channels.get(topic) {
+                Some(channel) if channel.joined => channel.join_ref.clone(),
+                _ => return Err(PhoenixError::NotJoined(topic.to_string())),
+            }
+        };
+        let msg = PhoenixMessage {
+            join_ref,
+            msg_ref: Some(self.next_ref()),
+            topic: topic.to_string(),
+            event: event.to_string(),
+            payload,
+        };
+        let reply = self.request(msg).await?;
+        if reply.is_ok() {
+            Ok(reply)
+        } else {
+            Err(PhoenixError::Rejected(reply))
+        }
+    }
+
+    /// Returns whether `topic` is currently joined.
+    pub async fn is_joined(&self, topic: &str) -> bool {
+        let channels = self.channels.lock().await;
+        channels.get(topic).is_some_and(|channel| channel.joined)
+    }
+
+    /// Returns the presences of `topic`.
+    pub async fn presence(&self, topic: &str) -> Option<Presence> {
+        let channels = self.channels.lock().await;
+        channels.get(topic).map(|channel| channel.presence.clone())
+    }
+
+    /// Sends `msg` and waits for the `phx_reply` with its ref.
+    async fn request(&self, msg: PhoenixMessage) -> Result<Reply, PhoenixError> {
+        let msg_ref = msg.msg_ref.clone().unwrap_or_default();
+        let (tx, rx) = oneshot::channel();
+        self.replies.lock().await.insert(msg_ref.clone(), tx);
+
+        if let Err(e) = self.sender.send(msg.encode()).await {
+            self.replies.lock().await.remove(&msg_ref);
+            return Err(e.into());
+        }
+        match tokio::time::timeout(self.timeout, rx).await {
+            Ok(Ok(reply)) => Ok(reply),
+            Ok(Err(_)) => Err(ReconnectTError::Disconnected.into()),
+            Err(_) => {
+                self.replies.lock().await.remove(&msg_ref);
+                Err(ReconnectTError::RequestTimeout(self.timeout).into())
+            }
+        }
+    }
+
+    /// Sends `phx_join` for the registered channel `topic`.
+    async fn send_join(&self, topic: &str) -> Result<(), PhoenixError> {
+        let join_ref = self.next_ref();
+        let msg = {
+            let mut channels = self.channels.lock().await;
+            let channel = channels
+                .get_mut(topic)
+                .ok_or_else(|| PhoenixError::NotJoined(topic.to_string()))?;
+            channel.join_ref = Some(join_ref.clone());
+            channel.joined = false;
+            PhoenixMessage {
+                join_ref: Some(join_ref.clone()),
+                msg_ref: Some(join_ref.clone()),
+                topic: topic.to_string(),
+                event: "phx_join".to_string(),
+                payload: channel.join_payload.clone(),
+            }
+        };
+
+        let reply = self.request(msg).await?;
+        let mut channels = self.channels.lock().await;
+        let channel = channels
+            .get_mut(topic)
+            .filter(|channel| channel.join_ref.as_deref() == Some(&join_ref))
+            .ok_or_else(|| PhoenixError::NotJoined(topic.to_string()))?;
+        if !reply.is_ok() {
+            return Err(PhoenixError::Rejected(reply));
+        }
+        channel.joined = true;
+        channel.backoff.reset();
+        Ok(())
+    }
+
+    /// Joins `topic` until it succeeds, the channel is left or the connection changes.
+    fn spawn_rejoin(self: &Arc<Self>, topic: String, delay_first: bool) {
+        let this = self.clone();
+        let generation = self.generation.load(Ordering::SeqCst);
+        tokio::spawn(async move {
+            let mut delay_next = delay_first;
+            loop {
+                if delay_next {
+                    let delay = {
+                        let mut channels = this.channels.lock().await;
+                        match channels.get_mut(&topic) {
+                            Some(channel) => channel.backoff.next(),
+                            None => return,
+                        }
+                    };
+                    let Some(delay) = delay else {
+                        tracing::warn!(topic = topic, "phoenix::rejoin_gave_up");
+                        return;
+                    };
+                    tokio::time::sleep(delay).await;
+                }
+                delay_next = true;
+
+                if this.generation.load(Ordering::SeqCst) != generation {
+                    return;
+                }
+                match this.send_join(&topic).await {
+                    Ok(()) | Err(PhoenixError::NotJoined(_)) => return,
+                    Err(PhoenixError::Transport(
+                        ReconnectTError::Disconnected | ReconnectTError::SenderNotConnected,
+                    )) => return,
+                    Err(e) => tracing::warn!(topic=topic, error=?e, "phoenix::rejoin"),
+                }
+            }
+        });
+    }
+
+    /// Sends a heartbeat, closing the connection if it is not answered. Returns whether
+    /// to keep sending them.
+    async fn heartbeat(&self) -> bool {
+        let heartbeat = PhoenixMessage {
+            join_ref: None,
+            msg_ref: Some(self.next_ref()),
+            topic: "phoenix".to_string(),
+            event: "heartbeat".to_string(),
+            payload: json!({}),
+        };
+        match self.request(heartbeat).await {
+            Ok(_) => true,
+            Err(PhoenixError::Transport(ReconnectTError::RequestTimeout(_))) => {
+                tracing::warn!("phoenix::heartbeat_timeout");
+                let close = Message::Close(Some(CloseFrame {
+                    code: CloseCode::Normal,
+                    reason: "heartbeat timeout".into(),
+                }));
+                let _ = self.sender.send(close).await;
+                false
+            }
+            Err(_) => false,
+        }
+    }
+
+    async fn dispatch(self: &Arc<Self>, msg: PhoenixMessage) {
+        if msg.event == "phx_reply" {
+            let waiter = match &msg.msg_ref {
+                Some(msg_ref) => self.replies.lock().await.remove(msg_ref),
+                None => None,
+            };
+            if let Some(waiter) = waiter {
+                let _ = waiter.send(Reply::from_payload(msg.payload));
+                return;
+            }
+        }
+
+        let mut channels = self.channels.lock().await;
+        let Some(channel) = channels.get_mut(&msg.topic) else {
+            return;
+        };
+        if msg.join_ref.is_some() && msg.join_ref != channel.join_ref {
+            // sent to an earlier join of the channel
+            return;
+        }
+        match msg.event.as_str() {
+            "phx_error" => {
+                tracing::warn!(topic = msg.topic, "phoenix::channel_error");
+                channel.joined = false;
+                drop(channels);
+                self.spawn_rejoin(msg.topic.clone(), true);
+                return;
+            }
+            "phx_close" => {
+                channels.remove(&msg.topic);
+                return;
+            }
+            "presence_state" => channel.presence.sync_state(&msg.payload),
+            "presence_diff" => channel.presence.sync_diff(&msg.payload),
+            _ => {}
+        }
+        let _ = channel.tx.send(msg);
+    }
+}
+
+#[async_trait]
+impl ProtocolClient for PhoenixClient {
+    async fn on_message(self: Arc<Self>, msg: Message) {
+        match PhoenixMessage::decode(&msg) {
+            Some(msg) => self.dispatch(msg).await,
+            None => tracing::debug!(msg=?msg, "phoenix::unhandled"),
+        }
+    }
+
+    async fn on_connected(self: Arc<Self>, ctx: Arc<HandshakeContext>) {
+        let generation = ctx.generation();
+        self.generation.store(generation, Ordering::SeqCst);
+        // a heartbeat without a reply closes the connection
+        spawn_keep_alive(
+            &self,
+            generation,
+            self.heartbeat_interval,
+            |this| async move { this.heartbeat().await },
+        );
+        let topics: Vec<String> = self.channels.lock().await.keys().cloned().collect();
+        for topic in topics {
+            self.spawn_rejoin(topic, false);
+        }
+    }
+
+    async fn on_disconnected(self: Arc<Self>) {
+        // dropping the senders fails the waiting requests
+        self.replies.lock().await.clear();
+        for channel in self.channels.lock().await.values_mut() {
+            channel.joined = false;
+        }
+    }
+}
+
+impl KeepAlive for PhoenixClient {
+    fn generation(&self) -> u64 {
+        self.generation.load(Ordering::SeqCst)
+    }
+}
+
+/// The events of a joined channel. The stream ends when the channel is left or closed.
+pub struct Channel {
+    topic: String,
+    stream: UnboundedReceiverStream<PhoenixMessage>,
+}
+
+impl Channel {
+    pub fn topic(&self) -> &str {
+        &self.topic
+    }
+}
+
+impl Stream for Channel {
+    type Item = PhoenixMessage;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<PhoenixMessage>> {
+        Pin::new(&mut self.stream).poll_next(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::prelude::*;
+    use crate::protocols::phoenix::{Channel, PhoenixClient, PhoenixError, PhoenixMessage};
+    use crate::test_util::mock_server;
+    use futures_util::{SinkExt, StreamExt};
+    use serde_json::json;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::sync::mpsc;
+
+    fn reply(msg: &PhoenixMessage, status: &str) -> PhoenixMessage {
+        PhoenixMessage {
+            event: "phx_reply".to_string(),
+            payload: json!({"status": status, "response": {}}),
+            ..msg.clone()
+        }
+    }
+
+    fn event(msg: &PhoenixMessage, event: &str, payload: serde_json::Value) -> PhoenixMessage {
+        PhoenixMessage {
+            msg_ref: None,
+            event: event.to_string(),
+            payload,
+            ..msg.clone()
+        }
+    }
+
+    async fn next_new_msg(room: &mut Channel) -> PhoenixMessage {
+        loop {
+            let msg = room.next().await.unwrap();
+            if msg.event == "new_msg" {
+                return msg;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_phoenix() {
+        let (seen_tx, mut seen_rx) = mpsc::unbounded_channel();
+        let connections = Arc::new(AtomicUsize::new(0));
+        let url = mock_server(move |mut ws| {
+            let seen_tx = seen_tx.clone();
+            let connection = connections.fetch_add(1, Ordering::SeqCst);
+            async move {
+                let mut joins = 0;
+                while let Some(Ok(msg)) = ws.next().await {
+                    let msg = PhoenixMessage::decode(&msg).unwrap();
+                    seen_tx.send((connection, msg.event.clone())).unwrap();
+                    let replies = match (msg.topic.as_str(), msg.event.as_str()) {
+                        ("phoenix", "heartbeat") => vec![reply(&msg, "ok")],
+                        ("room:bad", "phx_join") => vec![reply(&msg, "error")],
+                        ("room:1", "phx_join") => {
+                            joins += 1;
+                            // the first rejoin after the reconnect fails
+                            if connection == 1 && joins == 1 {
+                                vec![reply(&msg, "error")]
+                            } else {
+                                vec![
+                                    reply(&msg, "ok"),
+                                    event(&msg, "presence_state", json!({"alice": {"metas": [{"phx_ref": "a"}]}})),
+                                    event(&msg, "presence_diff", json!({"joins": {"bob": {"metas": [{"phx_ref": "b"}]}}, "leaves": {}})),
+                                    event(&msg, "new_msg", json!({"connection": connection})),
+                                ]
+                            }
+                        }
+                        ("room:1", "shout") if connection == 0 => return,
+                        _ => vec![reply(&msg, "ok")],
+                    };
+                    for reply in replies {
+                        ws.send(reply.encode()).await.unwrap();
+                    }
+                }
+            }
+        })
+        .await;
+
+        let mut options = ReconnectOptions::default();
+        options.with_retry_policy_fn(Arc::new(|| {
+            Box::new(ConstantStrategy::new(Duration::from_millis(10)).into_iter())
+        }));
+        let reconnect = Arc::new(ReconnectT::new(url, Some(options)));
+        let client = PhoenixClient::builder()
+            .with_heartbeat_interval(Duration::from_millis(20))
+            .with_rejoin_backoff(Arc::new(|| {
+                Box::new(ConstantStrategy::new(Duration::from_millis(10)).into_iter())
+            }))
+            .attach(&reconnect)
+            .await
+            .unwrap();
+        let mut status = reconnect.create_status_stream().await;
+        reconnect.spawn_run();
+        assert!(matches!(
+            status.next().await,
+            Some(WsStreamStatus::Connected(_))
+        ));
+
+        let rejected = client.join("room:bad", json!({})).await;
+        assert!(matches!(rejected, Err(PhoenixError::Rejected(_))));
+
+        let mut room = client.join("room:1", json!({"token": "t"})).await.unwrap();
+        let msg = room.next().await.unwrap();
+        assert_eq!(msg.event, "presence_state");
+        let presence = client.presence("room:1").await.unwrap();
+        assert_eq!(presence.keys(), ["alice", "bob"]);
+        let msg = next_new_msg(&mut room).await;
+        assert_eq!(msg.payload, json!({"connection": 0}));
+
+        // the server drops the connection; the channel is rejoined after one failed attempt
+        let lost = client.push("room:1", "shout", json!({})).await;
+        assert!(matches!(lost, Err(PhoenixError::Transport(_))));
+        let msg = next_new_msg(&mut room).await;
+        assert_eq!(msg.payload, json!({"connection": 1}));
+        assert!(client.is_joined("room:1").await);
+        let reply = client.push("room:1", "shout", json!({})).await.unwrap();
+        assert!(reply.is_ok());
+
+        while seen_rx.recv().await != Some((1, "heartbeat".to_string())) {}
+        client.leave("room:1").await.unwrap();
+        assert!(room.next().await.is_none());
+    }
+}
@@ -0,0 +1,98 @@
+use serde_json::Value;
+use std::collections::HashMap;
+
+/// The presences of a channel, kept in sync from `presence_state` and `presence_diff`
+/// events.
+///
+/// Each key (usually a user id) maps to the metas of its connections, identified by
+/// their `phx_ref`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Presence {
+    state: HashMap<String, Vec<Value>>,
+}
+
+impl Presence {
+    /// Replaces the state with a `presence_state` payload.
+    pub fn sync_state(&mut self, state: &Value) {
+        self.state = metas_by_key(state).collect();
+    }
+
+    /// Applies the `joins` and `leaves` of a `presence_diff` payload.
+    pub fn sync_diff(&mut self, diff: &Value) {
+        for (key, joined) in metas_by_key(&diff["joins"]) {
+            let metas = self.state.entry(key).or_default();
+            for meta in joined {
+                if !metas.iter().any(|m| m["phx_ref"] == meta["phx_ref"]) {
+                    metas.push(meta);
+                }
+            }
+        }
+        for (key, left) in metas_by_key(&diff["leaves"]) {
+            let Some(metas) = self.state.get_mut(&key) else {
+                continue;
+            };
+            metas.retain(|m| !left.iter().any(|l| l["phx_ref"] == m["phx_ref"]));
+            if metas.is_empty() {
+                self.state.remove(&key);
+            }
+        }
+    }
+
+    /// Returns the present keys, sorted.
+    pub fn keys(&self) -> Vec<&str> {
+        let mut keys: Vec<&str> = self.state.keys().map(String::as_str).collect();
+        keys.sort_unstable();
+        keys
+    }
+
+    pub fn metas(&self, key: &str) -> Option<&[Value]> {
+        self.state.get(key).map(Vec::as_slice)
+    }
+
+    pub fn len(&self) -> usize {
+        self.state.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.state.is_empty()
+    }
+}
+
+fn metas_by_key(value: &Value) -> impl Iterator<Item = (String, Vec<Value>)> + '_ {
+    value.as_object().into_iter().flatten().map(|(key, entry)| {
+        let metas = entry["metas"].as_array().cloned().unwrap_or_default();
+        (key.clone(), metas)
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use crate::protocols::phoenix::Presence;
+    use serde_json::json;
+
+    #[test]
+    fn test_presence() {
+        let mut presence = Presence::default();
+        presence.sync_state(&json!({
+            "alice": {"metas": [{"phx_ref": "a1"}]},
+            "bob": {"metas": [{"phx_ref": "b1"}]},
+        }));
+        assert_eq!(presence.keys(), ["alice", "bob"]);
+
+        presence.sync_diff(&json!({
+            "joins": {"alice": {"metas": [{"phx_ref": "a1"}, {"phx_ref": "a2"}]}, "carol": {"metas": [{"phx_ref": "c1"}]}},
+            "leaves": {"bob": {"metas": [{"phx_ref": "b1"}]}},
+        }));
+        assert_eq!(presence.keys(), ["alice", "carol"]);
+        assert_eq!(presence.metas("alice").unwrap().len(), 2);
+
+        presence.sync_diff(&json!({
+            "joins": {},
+            "leaves": {"alice": {"metas": [{"phx_ref": "a1"}]}},
+        }));
+        assert_eq!(
+            presence.metas("alice"),
+            Some(&[json!({"phx_ref": "a2"})][..])
+        );
+    }
+}
@@ -0,0 +1,773 @@
+//! A client of the Pusher Channels protocol, for Pusher and compatible servers such as
+//! Soketi or Laravel Reverb.
+
+use crate::errors::ReconnectTError;
+use crate::handshake::{receive_until, HandshakeContext, StreamHandshake};
+use crate::maybe_sender::MaybePSTSender;
+use crate::protocols::{spawn_keep_alive, Driver, KeepAlive, ProtocolClient};
+use crate::tungstenite::{ReconnectT, StopHandle};
+use crate::types::{PSTReceiver, PSTSender};
+use async_trait::async_trait;
+use eyre::{eyre, Result as EResult};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot, Mutex};
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use tokio_stream::Stream;
+use tungstenite::client::IntoClientRequest;
+use tungstenite::Message;
+
+pub const PROTOCOL_VERSION: u8 = 7;
+
+/// Returns the endpoint of the app `app_key` on `base`, e.g. `wss://ws-eu.pusher.com`.
+pub fn endpoint(base: &str, app_key: &str) -> String {
+    format!(
+        "{}/app/{app_key}?protocol={PROTOCOL_VERSION}&client=stream-tungstenite&version={}",
+        base.trim_end_matches('/'),
+        env!("CARGO_PKG_VERSION")
+    )
+}
+
+/// Returns whether subscribing to `channel` needs an authorization.
+pub fn is_private(channel: &str) -> bool {
+    channel.starts_with("private-") || channel.starts_with("presence-")
+}
+
+/// An event sent or received over the connection.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PusherEvent {
+    pub event: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub channel: Option<String>,
+    #[serde(default)]
+    pub data: Value,
+    /// The sender of a client event on a presence channel.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_id: Option<String>,
+}
+
+impl PusherEvent {
+    pub fn new(event: &str, channel: Option<&str>, data: Value) -> Self {
+        Self {
+            event: event.to_string(),
+            channel: channel.map(str::to_string),
+            data,
+            user_id: None,
+        }
+    }
+
+    pub fn decode(msg: &Message) -> Option<Self> {
+        serde_json::from_str(msg.to_text().ok()?).ok()
+    }
+
+    pub fn to_message(&self) -> Message {
+        Message::text(serde_json::to_string(self).unwrap_or_default())
+    }
+
+    /// Returns the data, parsing it when the server sent it as a JSON encoded string.
+    pub fn data_json(&self) -> Value {
+        match &self.data {
+            Value::String(data) => serde_json::from_str(data).unwrap_or_else(|_| self.data.clone()),
+            data => data.clone(),
+        }
+    }
+}
+
+/// Returns the reason of a `pusher:error` telling the client not to reconnect, i.e. one
+/// with a code in 4000-4099.
+fn fatal_error(data: &Value) -> Option<String> {
+    let code = data["code"].as_u64()?;
+    (4000..4100).contains(&code).then(|| {
+        format!(
+            "pusher error {code}: {}",
+            data["message"].as_str().unwrap_or_default()
+        )
+    })
+}
+
+/// The connection details from `pusher:connection_established`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PusherSession {
+    pub socket_id: String,
+    /// How long the connection may be idle before the client should ping.
+    pub activity_timeout: Duration,
+}
+
+/// Waits for `pusher:connection_established` and stores the [`PusherSession`] in the
+/// context. A `pusher:error` with a code in 4000-4099 stops the reconnects.
+pub struct PusherHandshake {
+    pong_timeout: Duration,
+    timeout: Duration,
+}
+
+impl Default for PusherHandshake {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PusherHandshake {
+    pub fn new() -> Self {
+        Self {
+            pong_timeout: Duration::from_secs(30),
+            timeout: Duration::from_secs(10),
+        }
+    }
+
+    /// Sets how long the server may take to answer a ping. The receive timeout is the
+    /// activity timeout plus this. Defaults to 30s.
+    pub fn with_pong_timeout(mut self, pong_timeout: Duration) -> Self {
+        self.pong_timeout = pong_timeout;
+        self
+    }
+
+    /// Sets how long to wait for the connection to be established. Defaults to 10s.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+}
+
+#[async_trait]
+impl StreamHandshake for PusherHandshake {
+    async fn handshake(
+        &self,
+        _writer: &mut PSTSender,
+        reader: &mut PSTReceiver,
+    ) -> EResult<HandshakeContext> {
+        let mut ctx = HandshakeContext::new();
+        let session = receive_until(reader, &mut ctx, self.timeout, |msg| {
+            let event = PusherEvent::decode(msg)?;
+            let data = event.data_json();
+            match event.event.as_str() {
+                "pusher:connection_established" => {
+                    let Some(socket_id) = data["socket_id"].as_str() else {
+                        return Some(Err(eyre!("missing socket_id: {data}")));
+                    };
+                    let activity_timeout = data["activity_timeout"].as_u64().unwrap_or(120);
+                    Some(Ok(PusherSession {
+                        socket_id: socket_id.to_string(),
+                        activity_timeout: Duration::from_secs(activity_timeout),
+                    }))
+                }
+                "pusher:error" => Some(Err(match fatal_error(&data) {
+                    Some(reason) => ReconnectTError::Stopped(reason).into(),
+                    None => eyre!(
+                        "pusher error {}: {}",
+                        data["code"],
+                        data["message"].as_str().unwrap_or_default()
+                    ),
+                })),
+                _ => None,
+            }
+        })
+        .await?;
+        ctx.set_receive_timeout(session.activity_timeout + self.pong_timeout);
+        ctx.insert(session);
+        Ok(ctx)
+    }
+}
+
+/// The authorization of a private or presence channel subscription.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChannelAuth {
+    /// `<app key>:<signature>`.
+    pub auth: String,
+    /// The user of a presence channel, as a JSON encoded string.
+    pub channel_data: Option<String>,
+}
+
+/// Authorizes private and presence channel subscriptions, usually by asking the
+/// application's auth endpoint.
+#[async_trait]
+pub trait ChannelAuthorizer: Send + Sync {
+    async fn authorize(&self, socket_id: &str, channel: &str) -> EResult<ChannelAuth>;
+}
+
+/// Signs subscriptions locally with the app secret, for trusted clients.
+pub struct SecretAuthorizer {
+    key: String,
+    secret: String,
+    user_data: Option<Value>,
+}
+
+impl SecretAuthorizer {
+    pub fn new(key: impl Into<String>, secret: impl Into<String>) -> Self {
+        Self {
+            key: key.into(),
+            secret: secret.into(),
+            user_data: None,
+        }
+    }
+
+    /// Sets the user joining presence channels, e.g.
+    /// `{"user_id": "1", "user_info": {"name": "a"}}`.
+    pub fn with_user_data(mut self, user_data: Value) -> Self {
+        self.user_data = Some(user_data);
+        self
+    }
+}
+
+#[async_trait]
+impl ChannelAuthorizer for SecretAuthorizer {
+    async fn authorize(&self, socket_id: &str, channel: &str) -> EResult<ChannelAuth> {
+        let channel_data = match (&self.user_data, channel.starts_with("presence-")) {
+            (Some(user_data), true) => Some(user_data.to_string()),
+            (None, true) => return Err(eyre!("presence channels need user data")),
+            (_, false) => None,
+        };
+        let mut payload = format!("{socket_id}:{channel}");
+        if let Some(channel_data) = &channel_data {
+            payload = format!("{payload}:{channel_data}");
+        }
+        let mut mac = Hmac::<Sha256>::new_from_slice(self.secret.as_bytes())?;
+        mac.update(payload.as_bytes());
+        let signature = hex::encode(mac.finalize().into_bytes());
+        Ok(ChannelAuth {
+            auth: format!("{}:{signature}", self.key),
+            channel_data,
+        })
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum PusherError {
+    #[error(transparent)]
+    Transport(#[from] ReconnectTError),
+    #[error("authorization of {channel} failed: {message}")]
+    Auth { channel: String, message: String },
+    /// The server refused the subscription, with the `pusher:subscription_error` data.
+    #[error("subscription to {channel} failed: {data}")]
+    Subscription { channel: String, data: Value },
+    #[error("not subscribed to {0}")]
+    NotSubscribed(String),
+    #[error("already subscribed to {0}")]
+    AlreadySubscribed(String),
+    /// Client events need a `client-` name and a private or presence channel.
+    #[error("invalid client event {0}")]
+    InvalidClientEvent(String),
+}
+
+struct ChannelState {
+    subscribed: bool,
+    tx: mpsc::UnboundedSender<PusherEvent>,
+}
+
+/// Configures and attaches a [`PusherClient`].
+pub struct PusherClientBuilder {
+    authorizer: Option<Arc<dyn ChannelAuthorizer>>,
+    timeout: Duration,
+}
+
+impl PusherClientBuilder {
+    /// Sets the authorizer of private and presence channels.
+    pub fn with_authorizer(mut self, authorizer: Arc<dyn ChannelAuthorizer>) -> Self {
+        self.authorizer = Some(authorizer);
+        self
+    }
+
+    /// Sets how long a subscription may take to succeed. Defaults to 10s.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub async fn attach<R: IntoClientRequest + Send + Sync>(
+        self,
+        reconnect: &ReconnectT<R>,
+    ) -> EResult<Arc<PusherClient>> {
+        let client = Arc::new(PusherClient {
+            sender: reconnect.sender.clone(),
+            stop: reconnect.stop_handle(),
+            authorizer: self.authorizer,
+            timeout: self.timeout,
+            generation: AtomicU64::new(0),
+            socket_id: Mutex::new(None),
+            channels: Mutex::new(HashMap::new()),
+            pending: Mutex::new(HashMap::new()),
+        });
+        Driver::attach(&client, reconnect).await?;
+        Ok(client)
+    }
+}
+
+/// A Pusher client over a [`ReconnectT`] configured with [`PusherHandshake`].
+///
+/// Channels are subscribed again after every reconnect, with a fresh authorization
+/// for the new socket id, and their streams continue. A `pusher:error` with a code in
+/// 4000-4099 stops the reconnects.
+pub struct PusherClient {
+    sender: Arc<MaybePSTSender>,
+    stop: StopHandle,
+    authorizer: Option<Arc<dyn ChannelAuthorizer>>,
+    timeout: Duration,
+    generation: AtomicU64,
+    socket_id: Mutex<Option<String>>,
+    channels: Mutex<HashMap<String, ChannelState>>,
+    /// The subscriptions waiting for their outcome, by channel.
+    pending: Mutex<HashMap<String, oneshot::Sender<Result<(), PusherError>>>>,
+}
+
+impl PusherClient {
+    pub fn builder() -> PusherClientBuilder {
+        PusherClientBuilder {
+            authorizer: None,
+            timeout: Duration::from_secs(10),
+        }
+    }
+
+    /// Returns the socket id of the current connection.
+    pub async fn socket_id(&self) -> Option<String> {
+        self.socket_id.lock().await.clone()
+    }
+
+    /// Subscribes to `channel`, waiting for the subscription to succeed when connected.
+    ///
+    /// The stream receives the channel's events, including
+    /// `pusher_internal:subscription_succeeded` with the members of a presence channel.
+    pub async fn subscribe(self: &Arc<Self>, channel: &str) -> Result<PusherChannel, PusherError> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        {
+            let mut channels = self.channels.lock().await;
+            if channels.contains_key(channel) {
+                return Err(PusherError::AlreadySubscribed(channel.to_string()));
+            }
+            let state = ChannelState {
+                subscribed: false,
+                tx,
+            };
+            channels.insert(channel.to_string(), state);
+        }
+
+        if self.sender.is_connected() && self.socket_id.lock().await.is_some() {
+            match self.send_subscribe(channel).await {
+                Ok(()) => {}
+                Err(e @ (PusherError::Auth { .. } | PusherError::Subscription { .. })) => {
+                    self.channels.lock().await.remove(channel);
+                    return Err(e);
+                }
+                // subscribed again once reconnected
+                Err(e) => tracing::warn!(channel=channel, error=?e, "pusher::subscribe"),
+            }
+        }
+        Ok(PusherChannel {
+            name: channel.to_string(),
+            stream: UnboundedReceiverStream::new(rx),
+        })
+    }
+
+    /// Unsubscribes from `channel` and ends its stream.
+    pub async fn unsubscribe(&self, channel: &str) -> Result<(), PusherError> {
+        let state = self.channels.lock().await.remove(channel);
+        let state = state.ok_or_else(|| PusherError::NotSubscribed(channel.to_string()))?;
+        if !state.subscribed {
+            return Ok(());
+        }
+        let event = PusherEvent::new("pusher:unsubscribe", None, json!({"channel": channel}));
+        match self.sender.send(event.to_message()).await {
+            Ok(()) | Err(ReconnectTError::SenderNotConnected) => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Triggers the client event `event`, named `client-*`, on a subscribed private or
+    /// presence channel.
+    pub async fn trigger(
+        &self,
+        channel: &str,
+        event: &str,
+        data: Value,
+    ) -> Result<(), PusherError> {
+        if !event.starts_with("client-") || !is_private(channel) {
+            return Err(PusherError::InvalidClientEvent(event.to_string()));
+        }
+        if !self.channels.lock().await.contains_key(channel) {
+            return Err(PusherError::NotSubscribed(channel.to_string()));
+        }
+        let event = PusherEvent::new(event, Some(channel), data);
+        Ok(self.sender.send(event.to_message()).await?)
+    }
+
+    async fn send_subscribe(&self, channel: &str) -> Result<(), PusherError> {
+        let mut data = json!({"channel": channel});
+        if is_private(channel) {
+            let auth_error = |message: String| PusherError::Auth {
+                channel: channel.to_string(),
+                message,
+            };
+            let authorizer = self
+                .authorizer
+                .as_ref()
+                .ok_or_else(|| auth_error("no authorizer".to_string()))?;
+            let socket_id = self.socket_id.lock().await.clone();
+            let socket_id = socket_id.ok_or(ReconnectTError::Disconnected)?;
+            let auth = authorizer
+                .authorize(&socket_id, channel)
+                .await
+                .map_err(|e| auth_error(e.to_string()))?;
+            data["auth"] = json!(auth.auth);
+            if let Some(channel_data) = auth.channel_data {
+                data["channel_data"] = json!(channel_data);
+            }
+        }
+
+        let (tx, rx) = oneshot::channel();
+        self.pending.lock().await.insert(channel.to_string(), tx);
+        let event = PusherEvent::new("pusher:subscribe", None, data);
+        if let Err(e) = self.sender.send(event.to_message()).await {
+            self.pending.lock().await.remove(channel);
+            return Err(e.into());
+        }
+        match tokio::time::timeout(self.timeout, rx).await {
+            Ok(Ok(result)) => result,
+            Ok(Err(_)) => Err(ReconnectTError::Disconnected.into()),
+            Err(_) => {
+                self.pending.lock().await.remove(channel);
+                Err(ReconnectTError::RequestTimeout(self.timeout).into())
+            }
+        }
+    }
+
+    async fn dispatch(&self, event: PusherEvent) {
+        match (event.event.as_str(), event.channel.clone()) {
+            ("pusher:ping", _) => {
+                let pong = PusherEvent::new("pusher:pong", None, json!({}));
+                if let Err(e) = self.sender.send(pong.to_message()).await {
+                    tracing::warn!(error=?e, "pusher::pong");
+                }
+            }
+            ("pusher:pong", _) => {}
+            ("pusher:error", _) => match fatal_error(&event.data_json()) {
+                Some(reason) => self.stop.stop(reason),
+                None => tracing::warn!(data=?event.data, "pusher::error"),
+            },
+            ("pusher_internal:subscription_succeeded", Some(channel)) => {
+                if let Some(state) = self.channels.lock().await.get_mut(&channel) {
+                    state.subscribed = true;
+                    let _ = state.tx.send(event);
+                }
+                if let Some(tx) = self.pending.lock().await.remove(&channel) {
+                    let _ = tx.send(Ok(()));
+                }
+            }
+            ("pusher:subscription_error", Some(channel)) => {
+                let error = PusherError::Subscription {
+                    channel: channel.clone(),
+                    data: event.data_json(),
+                };
+                match self.pending.lock().await.remove(&channel) {
+                    Some(tx) => {
+                        let _ = tx.send(Err(error));
+                    }
+                    None => {
+                        // refused when subscribing again, which ends the stream
+                        tracing::warn!(error=?error, "pusher::resubscribe");
+                        self.channels.lock().await.remove(&channel);
+                    }
+                }
+            }
+            (_, Some(channel)) => {
+                if let Some(state) = self.channels.lock().await.get(&channel) {
+                    let _ = state.tx.send(event);
+                }
+            }
+            (_, None) => tracing::debug!(event=?event, "pusher::unhandled"),
+        }
+    }
+}
+
+#[async_trait]
+impl ProtocolClient for PusherClient {
+    async fn on_message(self: Arc<Self>, msg: Message) {
+        match PusherEvent::decode(&msg) {
+            Some(event) => self.dispatch(event).await,
+            None => tracing::debug!(msg=?msg, "pusher::invalid_event"),
+        }
+    }
+
+    async fn on_connected(self: Arc<Self>, ctx: Arc<HandshakeContext>) {
+        self.generation.store(ctx.generation(), Ordering::SeqCst);
+        let Some(session) = ctx.get::<PusherSession>() else {
+            tracing::warn!("pusher::no_session");
+            return;
+        };
+        *self.socket_id.lock().await = Some(session.socket_id.clone());
+        // pings every activity timeout
+        spawn_keep_alive(
+            &self,
+            ctx.generation(),
+            session.activity_timeout,
+            |this| async move {
+                let ping = PusherEvent::new("pusher:ping", None, json!({}));
+                this.sender.send(ping.to_message()).await.is_ok()
+            },
+        );
+        let channels: Vec<String> = self.channels.lock().await.keys().cloned().collect();
+        for channel in channels {
+            let this = self.clone();
+            tokio::spawn(async move {
+                if let Err(e) = this.send_subscribe(&channel).await {
+                    tracing::warn!(channel=channel, error=?e, "pusher::resubscribe");
+                }
+            });
+        }
+    }
+
+    async fn on_disconnected(self: Arc<Self>) {
+        *self.socket_id.lock().await = None;
+        // dropping the senders fails the waiting subscriptions
+        self.pending.lock().await.clear();
+        for state in self.channels.lock().await.values_mut() {
+            state.subscribed = false;
+        }
+    }
+}
+
+impl KeepAlive for PusherClient {
+    fn generation(&self) -> u64 {
+        self.generation.load(Ordering::SeqCst)
+    }
+}
+
+/// The events of a subscribed channel, across reconnects.
+pub struct PusherChannel {
+    name: String,
+    stream: UnboundedReceiverStream<PusherEvent>,
+}
+
+impl PusherChannel {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl Stream for PusherChannel {
+    type Item = PusherEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.stream).poll_next(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::prelude::*;
+    use crate::protocols::pusher::{
+        endpoint, ChannelAuthorizer, PusherClient, PusherError, PusherEvent, PusherHandshake,
+        SecretAuthorizer,
+    };
+    use crate::test_util::mock_server;
+    use futures_util::{SinkExt, StreamExt};
+    use serde_json::json;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::sync::mpsc;
+
+    #[tokio::test]
+    async fn test_secret_authorizer() {
+        // the example of the Pusher authentication docs
+        let authorizer = SecretAuthorizer::new("278d425bdf160c739803", "7ad3773142a6692b25b8");
+        let auth = authorizer
+            .authorize("1234.1234", "private-foobar")
+            .await
+            .unwrap();
+        assert_eq!(
+            auth.auth,
+            "278d425bdf160c739803:58df8b0c36d6982b82c3ecf6b4662e34fe8c25bba48f5369f135bf843651c3a4"
+        );
+        assert!(authorizer.authorize("1.1", "presence-room").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_pusher() {
+        let connections = Arc::new(AtomicU64::new(0));
+        let (seen_tx, mut seen_rx) = mpsc::unbounded_channel();
+        let counter = connections.clone();
+        let url = mock_server(move |mut ws| {
+            let seen_tx = seen_tx.clone();
+            let connection = counter.fetch_add(1, Ordering::SeqCst);
+            async move {
+                let data = json!({"socket_id": format!("{connection}.1"), "activity_timeout": 1});
+                let established = PusherEvent::new(
+                    "pusher:connection_established",
+                    None,
+                    json!(data.to_string()),
+                );
+                ws.send(established.to_message()).await.unwrap();
+                while let Some(Ok(msg)) = ws.next().await {
+                    let event = PusherEvent::decode(&msg).unwrap();
+                    seen_tx.send((connection, event.clone())).unwrap();
+                    let channel = event.data["channel"].as_str().unwrap_or_default();
+                    let reply = match event.event.as_str() {
+                        "pusher:subscribe" if channel == "private-denied" => PusherEvent::new(
+                            "pusher:subscription_error",
+                            Some(channel),
+                            json!({"type": "AuthError", "status": 403}),
+                        ),
+                        "pusher:subscribe" => PusherEvent::new(
+                            "pusher_internal:subscription_succeeded",
+                            Some(channel),
+                            json!("{}"),
+                        ),
+                        "client-hello" if connection == 0 => return,
+                        "client-hello" => PusherEvent::new(
+                            "news",
+                            Some("private-news"),
+                            json!("{\"title\":\"again\"}"),
+                        ),
+                        _ => continue,
+                    };
+                    ws.send(reply.to_message()).await.unwrap();
+                }
+            }
+        })
+        .await;
+
+        let mut options = ReconnectOptions::default();
+        options
+            .with_handshake(Arc::new(PusherHandshake::new()))
+            .with_retry_policy_fn(Arc::new(|| {
+                Box::new(ConstantStrategy::new(Duration::from_millis(10)).into_iter())
+            }));
+        let reconnect = Arc::new(ReconnectT::new(endpoint(&url, "key"), Some(options)));
+        let client = PusherClient::builder()
+            .with_authorizer(Arc::new(SecretAuthorizer::new("key", "secret")))
+            .attach(&reconnect)
+            .await
+            .unwrap();
+        let mut status = reconnect.create_status_stream().await;
+        reconnect.spawn_run();
+        assert!(matches!(
+            status.next().await,
+            Some(WsStreamStatus::Connected(_))
+        ));
+        assert_eq!(client.socket_id().await.unwrap(), "0.1");
+
+        let mut news = client.subscribe("private-news").await.unwrap();
+        let succeeded = news.next().await.unwrap();
+        assert_eq!(succeeded.event, "pusher_internal:subscription_succeeded");
+        let (_, subscribe) = seen_rx.recv().await.unwrap();
+        assert!(subscribe.data["auth"].as_str().unwrap().starts_with("key:"));
+        assert!(matches!(
+            client.subscribe("private-denied").await,
+            Err(PusherError::Subscription { .. })
+        ));
+        assert!(matches!(
+            client.trigger("public", "client-hello", json!({})).await,
+            Err(PusherError::InvalidClientEvent(_))
+        ));
+
+        // the first server closes on the client event, the channel is subscribed again
+        client
+            .trigger("private-news", "client-hello", json!({}))
+            .await
+            .unwrap();
+        assert_eq!(
+            news.next().await.unwrap().event,
+            "pusher_internal:subscription_succeeded"
+        );
+        assert_eq!(client.socket_id().await.unwrap(), "1.1");
+        client
+            .trigger("private-news", "client-hello", json!({}))
+            .await
+            .unwrap();
+        let event = news.next().await.unwrap();
+        assert_eq!(event.data_json(), json!({"title": "again"}));
+
+        // pings after the activity timeout of a second
+        tokio::time::timeout(Duration::from_secs(3), async {
+            while let Some((_, event)) = seen_rx.recv().await {
+                if event.event == "pusher:ping" {
+                    return;
+                }
+            }
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_fatal_error() {
+        let connections = Arc::new(AtomicU64::new(0));
+        let counter = connections.clone();
+        let url = mock_server(move |mut ws| {
+            let connection = counter.fetch_add(1, Ordering::SeqCst);
+            async move {
+                let error = |code: u64, message: &str| {
+                    PusherEvent::new(
+                        "pusher:error",
+                        None,
+                        json!({"code": code, "message": message}),
+                    )
+                    .to_message()
+                };
+                if connection < 2 {
+                    let data = json!({"socket_id": format!("{connection}.1")});
+                    let established = PusherEvent::new(
+                        "pusher:connection_established",
+                        None,
+                        json!(data.to_string()),
+                    );
+                    ws.send(established.to_message()).await.unwrap();
+                }
+                match connection {
+                    // reconnecting after a 4200 error is fine
+                    0 => ws
+                        .send(error(4200, "Generic reconnect immediately"))
+                        .await
+                        .unwrap(),
+                    1 => {
+                        ws.send(error(4009, "Connection not authorized"))
+                            .await
+                            .unwrap();
+                        // left open; the client drops it
+                        while ws.next().await.is_some() {}
+                    }
+                    _ => ws.send(error(4001, "App does not exist")).await.unwrap(),
+                }
+            }
+        })
+        .await;
+
+        let new_reconnect = || {
+            let mut options = ReconnectOptions::default();
+            options
+                .with_handshake(Arc::new(PusherHandshake::new()))
+                .with_retry_policy_fn(Arc::new(|| {
+                    Box::new(ConstantStrategy::new(Duration::from_millis(10)).into_iter())
+                }));
+            Arc::new(ReconnectT::new(endpoint(&url, "key"), Some(options)))
+        };
+        let reconnect = new_reconnect();
+        let _client = PusherClient::builder().attach(&reconnect).await.unwrap();
+        let result = tokio::time::timeout(Duration::from_secs(5), reconnect.run())
+            .await
+            .unwrap();
+        assert!(matches!(
+            result,
+            Err(ReconnectTError::Stopped(reason))
+                if reason == "pusher error 4009: Connection not authorized"
+        ));
+        assert_eq!(connections.load(Ordering::SeqCst), 2);
+
+        // refused in the handshake
+        let reconnect = new_reconnect();
+        let result = tokio::time::timeout(Duration::from_secs(5), reconnect.run())
+            .await
+            .unwrap();
+        assert!(matches!(
+            result,
+            Err(ReconnectTError::Stopped(reason)) if reason == "pusher error 4001: App does not exist"
+        ));
+        assert_eq!(connections.load(Ordering::SeqCst), 3);
+    }
+}
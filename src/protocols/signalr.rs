@@ -0,0 +1,867 @@
+//! A client of the SignalR JSON hub protocol.
+//!
+//! Connect to the hub URL directly, skipping the HTTP negotiation, e.g.
+//! `ws://host/hubs/chat` or the `url` returned by negotiate with its `id` query.
+
+use crate::errors::ReconnectTError;
+use crate::handshake::{receive_until, HandshakeContext, StreamHandshake};
+use crate::maybe_sender::MaybePSTSender;
+use crate::protocols::{spawn_keep_alive, Driver, KeepAlive, ProtocolClient};
+use crate::tungstenite::{ReconnectT, StopHandle};
+use crate::types::{PSTReceiver, PSTSender};
+use async_trait::async_trait;
+use eyre::{eyre, Result as EResult};
+use futures_util::SinkExt;
+use serde::de::DeserializeOwned;
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot, Mutex};
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use tokio_stream::Stream;
+use tungstenite::client::IntoClientRequest;
+use tungstenite::Message;
+
+/// Terminates every JSON record.
+pub const RECORD_SEPARATOR: char = '\u{1e}';
+
+#[derive(thiserror::Error, Debug)]
+pub enum HubMessageError {
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    #[error("invalid hub message: {0}")]
+    Invalid(String),
+}
+
+/// A message of the hub protocol.
+#[derive(Clone, Debug, PartialEq)]
+pub enum HubMessage {
+    /// Calls `target`. Without an invocation id no completion is expected.
+    Invocation {
+        invocation_id: Option<String>,
+        target: String,
+        arguments: Vec<Value>,
+    },
+    StreamItem {
+        invocation_id: String,
+        item: Value,
+    },
+    Completion {
+        invocation_id: String,
+        result: Option<Value>,
+        error: Option<String>,
+    },
+    StreamInvocation {
+        invocation_id: String,
+        target: String,
+        arguments: Vec<Value>,
+    },
+    CancelInvocation {
+        invocation_id: String,
+    },
+    Ping,
+    Close {
+        error: Option<String>,
+        allow_reconnect: bool,
+    },
+}
+
+impl HubMessage {
+    pub fn to_json(&self) -> Value {
+        match self {
+            HubMessage::Invocation {
+                invocation_id,
+                target,
+                arguments,
+            } => {
+                let mut value = json!({"type": 1, "target": target, "arguments": arguments});
+                if let Some(id) = invocation_id {
+                    value["invocationId"] = json!(id);
+                }
+                value
+            }
+            HubMessage::StreamItem {
+                invocation_id,
+                item,
+            } => json!({"type": 2, "invocationId": invocation_id, "item": item}),
+            HubMessage::Completion {
+                invocation_id,
+                result,
+                error,
+            } => {
+                let mut value = json!({"type": 3, "invocationId": invocation_id});
+                if let Some(error) = error {
+                    value["error"] = json!(error);
+                } else if let Some(result) = result {
+                    value["result"] = result.clone();
+                }
+                value
+            }
+            HubMessage::StreamInvocation {
+                invocation_id,
+                target,
+                arguments,
+            } => json!({
+                "type": 4,
+                "invocationId": invocation_id,
+                "target": target,
+                "arguments": arguments,
+            }),
+            HubMessage::CancelInvocation { invocation_id } => {
+                json!({"type": 5, "invocationId": invocation_id})
+            }
+            HubMessage::Ping => json!({"type": 6}),
+            HubMessage::Close {
+                error,
+                allow_reconnect,
+            } => {
+                let mut value = json!({"type": 7, "allowReconnect": allow_reconnect});
+                if let Some(error) = error {
+                    value["error"] = json!(error);
+                }
+                value
+            }
+        }
+    }
+
+    pub fn from_json(value: &Value) -> Result<Self, HubMessageError> {
+        let invalid = || HubMessageError::Invalid(value.to_string());
+        let object = value.as_object().ok_or_else(invalid)?;
+        let text = |key: &str| object.get(key).and_then(Value::as_str).map(str::to_string);
+        let id = || text("invocationId").ok_or_else(invalid);
+        let arguments = || -> Vec<Value> {
+            object
+                .get("arguments")
+                .and_then(Value::as_array)
+                .cloned()
+                .unwrap_or_default()
+        };
+        Ok(match object.get("type").and_then(Value::as_u64) {
+            Some(1) => HubMessage::Invocation {
+                invocation_id: text("invocationId"),
+                target: text("target").ok_or_else(invalid)?,
+                arguments: arguments(),
+            },
+            Some(2) => HubMessage::StreamItem {
+                invocation_id: id()?,
+                item: object.get("item").cloned().unwrap_or_default(),
+            },
+            Some(3) => HubMessage::Completion {
+                invocation_id: id()?,
+                result: object.get("result").cloned(),
+                error: text("error"),
+            },
+            Some(4) => HubMessage::StreamInvocation {
+                invocation_id: id()?,
+                target: text("target").ok_or_else(invalid)?,
+                arguments: arguments(),
+            },
+            Some(5) => HubMessage::CancelInvocation {
+                invocation_id: id()?,
+            },
+            Some(6) => HubMessage::Ping,
+            Some(7) => HubMessage::Close {
+                error: text("error"),
+                allow_reconnect: object
+                    .get("allowReconnect")
+                    .and_then(Value::as_bool)
+                    .unwrap_or_default(),
+            },
+            _ => return Err(invalid()),
+        })
+    }
+}
+
+/// Encodes `messages` as one text frame.
+pub fn encode(messages: &[HubMessage]) -> Message {
+    let mut text = String::new();
+    for message in messages {
+        text.push_str(&message.to_json().to_string());
+        text.push(RECORD_SEPARATOR);
+    }
+    Message::text(text)
+}
+
+/// Decodes the records of a text frame.
+pub fn decode(msg: &Message) -> Vec<Result<HubMessage, HubMessageError>> {
+    let Ok(text) = msg.to_text() else {
+        return Vec::new();
+    };
+    records(text)
+        .map(|record| {
+            let value = serde_json::from_str(record)?;
+            HubMessage::from_json(&value)
+        })
+        .collect()
+}
+
+fn records(text: &str) -> impl Iterator<Item = &str> {
+    text.split(RECORD_SEPARATOR)
+        .filter(|record| !record.is_empty())
+}
+
+/// Sends the JSON protocol handshake request and waits for the response.
+///
+/// The connection's receive timeout is set to the server timeout, which should be at
+/// least twice the server's keep alive interval.
+#[derive(Clone)]
+pub struct SignalRHandshake {
+    server_timeout: Duration,
+    timeout: Duration,
+}
+
+impl Default for SignalRHandshake {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SignalRHandshake {
+    /// Creates the handshake with a 30s server timeout.
+    pub fn new() -> Self {
+        Self {
+            server_timeout: Duration::from_secs(30),
+            timeout: Duration::from_secs(10),
+        }
+    }
+
+    pub fn with_server_timeout(mut self, server_timeout: Duration) -> Self {
+        self.server_timeout = server_timeout;
+        self
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+}
+
+#[async_trait]
+impl StreamHandshake for SignalRHandshake {
+    async fn handshake(
+        &self,
+        writer: &mut PSTSender,
+        reader: &mut PSTReceiver,
+    ) -> EResult<HandshakeContext> {
+        let request = json!({"protocol": "json", "version": 1});
+        writer
+            .send(Message::text(format!("{request}{RECORD_SEPARATOR}")))
+            .await?;
+
+        let mut ctx = HandshakeContext::new();
+        let rest = receive_until(reader, &mut ctx, self.timeout, |msg| {
+            let text = msg.to_text().ok()?;
+            let (response, rest) = text.split_once(RECORD_SEPARATOR)?;
+            let response: Map<String, Value> = match serde_json::from_str(response) {
+                Ok(response) => response,
+                Err(e) => return Some(Err(e.into())),
+            };
+            match response.get("error") {
+                Some(error) => Some(Err(eyre!("handshake rejected: {error}"))),
+                None => Some(Ok(rest.to_string())),
+            }
+        })
+        .await?;
+        if !rest.is_empty() {
+            // messages the server sent right after the response
+            ctx.forward(Message::text(rest));
+        }
+        ctx.set_receive_timeout(self.server_timeout);
+        Ok(ctx)
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum SignalRError {
+    #[error(transparent)]
+    Transport(#[from] ReconnectTError),
+    /// The hub method failed.
+    #[error("hub error: {0}")]
+    Hub(String),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+}
+
+/// Handles invocations of a client method. The result is returned to the server when it
+/// waits for one.
+pub type MethodHandler = Arc<dyn Fn(Vec<Value>) -> Result<Value, String> + Send + Sync>;
+
+enum Pending {
+    Invoke(oneshot::Sender<Result<Option<Value>, SignalRError>>),
+    Stream(mpsc::UnboundedSender<Result<Value, SignalRError>>),
+}
+
+/// Configures and attaches a [`SignalRClient`].
+pub struct SignalRClientBuilder {
+    keep_alive_interval: Duration,
+    timeout: Duration,
+}
+
+impl SignalRClientBuilder {
+    /// Sets how often a ping is sent. Defaults to 15s.
+    pub fn with_keep_alive_interval(mut self, keep_alive_interval: Duration) -> Self {
+        self.keep_alive_interval = keep_alive_interval;
+        self
+    }
+
+    /// Sets how long [`invoke`](SignalRClient::invoke) waits for the completion.
+    /// Defaults to 30s.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub async fn attach<R: IntoClientRequest + Send + Sync>(
+        self,
+        reconnect: &ReconnectT<R>,
+    ) -> EResult<Arc<SignalRClient>> {
+        let client = Arc::new(SignalRClient {
+            sender: reconnect.sender.clone(),
+            keep_alive_interval: self.keep_alive_interval,
+            timeout: self.timeout,
+            next_id: AtomicU64::new(1),
+            generation: AtomicU64::new(0),
+            pending: Arc::new(Mutex::new(HashMap::new())),
+            handlers: Mutex::new(HashMap::new()),
+            stop: reconnect.stop_handle(),
+        });
+        Driver::attach(&client, reconnect).await?;
+        Ok(client)
+    }
+}
+
+/// A SignalR hub client over a [`ReconnectT`] configured with [`SignalRHandshake`].
+///
+/// Invocations and streams in progress fail when the connection drops; the hub does not
+/// resume them after a reconnect. Client methods registered with [`on`](Self::on) keep
+/// handling invocations on every connection. A close message with `allowReconnect: false`
+/// stops the reconnects.
+pub struct SignalRClient {
+    sender: Arc<MaybePSTSender>,
+    keep_alive_interval: Duration,
+    timeout: Duration,
+    next_id: AtomicU64,
+    generation: AtomicU64,
+    pending: Arc<Mutex<HashMap<String, Pending>>>,
+    handlers: Mutex<HashMap<String, MethodHandler>>,
+    stop: StopHandle,
+}
+
+impl SignalRClient {
+    pub fn builder() -> SignalRClientBuilder {
+        SignalRClientBuilder {
+            keep_alive_interval: Duration::from_secs(15),
+            timeout: Duration::from_secs(30),
+        }
+    }
+
+    fn next_id(&self) -> String {
+        self.next_id.fetch_add(1, Ordering::Relaxed).to_string()
+    }
+
+    async fn send(&self, message: HubMessage) -> Result<(), ReconnectTError> {
+        self.sender.send(encode(&[message])).await
+    }
+
+    /// Invokes the hub method `target` and waits for its result.
+    pub async fn invoke<T: DeserializeOwned>(
+        &self,
+        target: &str,
+        arguments: Vec<Value>,
+    ) -> Result<T, SignalRError> {
+        let id = self.next_id();
+        let (tx, rx) = oneshot::channel();
+        self.pending
+            .lock()
+            .await
+            .insert(id.clone(), Pending::Invoke(tx));
+        let invocation = HubMessage::Invocation {
+            invocation_id: Some(id.clone()),
+            target: target.to_string(),
+            arguments,
+        };
+        if let Err(e) = self.send(invocation).await {
+            self.pending.lock().await.remove(&id);
+            return Err(e.into());
+        }
+        let result = match tokio::time::timeout(self.timeout, rx).await {
+            Ok(Ok(result)) => result?,
+            Ok(Err(_)) => return Err(ReconnectTError::Disconnected.into()),
+            Err(_) => {
+                self.pending.lock().await.remove(&id);
+                return Err(ReconnectTError::RequestTimeout(self.timeout).into());
+            }
+        };
+        Ok(serde_json::from_value(result.unwrap_or_default())?)
+    }
+
+    /// Invokes the hub method `target` without waiting for a result.
+    pub async fn send_invocation(
+        &self,
+        target: &str,
+        arguments: Vec<Value>,
+    ) -> Result<(), SignalRError> {
+        let invocation = HubMessage::Invocation {
+            invocation_id: None,
+            target: target.to_string(),
+            arguments,
+        };
+        Ok(self.send(invocation).await?)
+    }
+
+    /// Invokes the streaming hub method `target`. Dropping the stream before it ends
+    /// cancels the invocation.
+    pub async fn stream(
+        &self,
+        target: &str,
+        arguments: Vec<Value>,
+    ) -> Result<SignalRStream, SignalRError> {
+        let id = self.next_id();
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.pending
+            .lock()
+            .await
+            .insert(id.clone(), Pending::Stream(tx));
+        let invocation = HubMessage::StreamInvocation {
+            invocation_id: id.clone(),
+            target: target.to_string(),
+            arguments,
+        };
+        if let Err(e) = self.send(invocation).await {
+            self.pending.lock().await.remove(&id);
+            return Err(e.into());
+        }
+        Ok(SignalRStream {
+            id,
+            sender: self.sender.clone(),
+            pending: self.pending.clone(),
+            stream: UnboundedReceiverStream::new(rx),
+        })
+    }
+
+    /// Registers the handler of the client method `target`, replacing any previous one.
+    /// Method names are matched case-insensitively.
+    pub async fn on(&self, target: &str, handler: MethodHandler) {
+        self.handlers
+            .lock()
+            .await
+            .insert(target.to_lowercase(), handler);
+    }
+
+    pub async fn off(&self, target: &str) {
+        self.handlers.lock().await.remove(&target.to_lowercase());
+    }
+
+    async fn dispatch(&self, message: HubMessage) {
+        match message {
+            HubMessage::Invocation {
+                invocation_id,
+                target,
+                arguments,
+            } => {
+                let handler = self
+                    .handlers
+                    .lock()
+                    .await
+                    .get(&target.to_lowercase())
+                    .cloned();
+                let result = match handler {
+                    Some(handler) => handler(arguments),
+                    None => {
+                        tracing::debug!(target = target, "signalr::no_handler");
+                        Err(format!("client method '{target}' not found"))
+                    }
+                };
+                let Some(invocation_id) = invocation_id else {
+                    return;
+                };
+                let (result, error) = match result {
+                    Ok(result) => (Some(result), None),
+                    Err(error) => (None, Some(error)),
+                };
+                let completion = HubMessage::Completion {
+                    invocation_id,
+                    result,
+                    error,
+                };
+                if let Err(e) = self.send(completion).await {
+                    tracing::warn!(error=?e, "signalr::completion");
+                }
+            }
+            HubMessage::StreamItem {
+                invocation_id,
+                item,
+            } => {
+                let pending = self.pending.lock().await;
+                if let Some(Pending::Stream(tx)) = pending.get(&invocation_id) {
+                    let _ = tx.send(Ok(item));
+                }
+            }
+            HubMessage::Completion {
+                invocation_id,
+                result,
+                error,
+            } => {
+                let result = match error {
+                    Some(error) => Err(SignalRError::Hub(error)),
+                    None => Ok(result),
+                };
+                match self.pending.lock().await.remove(&invocation_id) {
+                    Some(Pending::Invoke(tx)) => {
+                        let _ = tx.send(result);
+                    }
+                    Some(Pending::Stream(tx)) => {
+                        if let Err(e) = result {
+                            let _ = tx.send(Err(e));
+                        }
+                    }
+                    None => {}
+                }
+            }
+            HubMessage::Close {
+                error,
+                allow_reconnect,
+            } => {
+                tracing::warn!(error=?error, allow_reconnect=allow_reconnect, "signalr::close");
+                if !allow_reconnect {
+                    let reason = error.unwrap_or_else(|| "closed by the hub".to_string());
+                    self.stop.stop(reason);
+                }
+            }
+            HubMessage::Ping => {}
+            message => tracing::debug!(message=?message, "signalr::unhandled"),
+        }
+    }
+}
+
+#[async_trait]
+impl ProtocolClient for SignalRClient {
+    async fn on_message(self: Arc<Self>, msg: Message) {
+        for message in decode(&msg) {
+            match message {
+                Ok(message) => self.dispatch(message).await,
+                Err(e) => tracing::debug!(error=?e, "signalr::invalid_message"),
+            }
+        }
+    }
+
+    async fn on_connected(self: Arc<Self>, ctx: Arc<HandshakeContext>) {
+        self.generation.store(ctx.generation(), Ordering::SeqCst);
+        spawn_keep_alive(
+            &self,
+            ctx.generation(),
+            self.keep_alive_interval,
+            |this| async move { this.send(HubMessage::Ping).await.is_ok() },
+        );
+    }
+
+    async fn on_disconnected(self: Arc<Self>) {
+        for (_, pending) in self.pending.lock().await.drain() {
+            match pending {
+                Pending::Invoke(tx) => {
+                    let _ = tx.send(Err(ReconnectTError::Disconnected.into()));
+                }
+                Pending::Stream(tx) => {
+                    let _ = tx.send(Err(ReconnectTError::Disconnected.into()));
+                }
+            }
+        }
+    }
+}
+
+impl KeepAlive for SignalRClient {
+    fn generation(&self) -> u64 {
+        self.generation.load(Ordering::SeqCst)
+    }
+}
+
+/// The items of a streaming invocation. The stream ends with the completion, after an
+/// error item if the invocation failed.
+pub struct SignalRStream {
+    id: String,
+    sender: Arc<MaybePSTSender>,
+    pending: Arc<Mutex<HashMap<String, Pending>>>,
+    stream: UnboundedReceiverStream<Result<Value, SignalRError>>,
+}
+
+impl SignalRStream {
+    pub fn invocation_id(&self) -> &str {
+        &self.id
+    }
+}
+
+impl Stream for SignalRStream {
+    type Item = Result<Value, SignalRError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.stream).poll_next(cx)
+    }
+}
+
+impl Drop for SignalRStream {
+    fn drop(&mut self) {
+        // the cancel is skipped when dropped outside a runtime
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+        let id = self.id.clone();
+        let sender = self.sender.clone();
+        let pending = self.pending.clone();
+        runtime.spawn(async move {
+            if pending.lock().await.remove(&id).is_some() {
+                let cancel = HubMessage::CancelInvocation { invocation_id: id };
+                let _ = sender.send(encode(&[cancel])).await;
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::prelude::*;
+    use crate::protocols::signalr::{
+        decode, encode, HubMessage, SignalRClient, SignalRError, SignalRHandshake, SignalRStream,
+        RECORD_SEPARATOR,
+    };
+    use crate::test_util::mock_server;
+    use futures_util::{SinkExt, StreamExt};
+    use serde_json::{json, Value};
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::sync::{mpsc, Mutex};
+    use tokio_stream::wrappers::UnboundedReceiverStream;
+    use tungstenite::Message;
+
+    #[test]
+    fn test_hub_message_codec() {
+        let messages = [
+            HubMessage::Invocation {
+                invocation_id: None,
+                target: "send".to_string(),
+                arguments: vec![json!("a")],
+            },
+            HubMessage::Completion {
+                invocation_id: "1".to_string(),
+                result: None,
+                error: Some("boom".to_string()),
+            },
+            HubMessage::Ping,
+        ];
+        let msg = encode(&messages);
+        assert_eq!(msg.to_text().unwrap().matches(RECORD_SEPARATOR).count(), 3);
+        let decoded: Vec<HubMessage> = decode(&msg).into_iter().map(Result::unwrap).collect();
+        assert_eq!(decoded, messages);
+        assert!(decode(&Message::text("{\"type\":99}\u{1e}"))[0].is_err());
+    }
+
+    #[tokio::test]
+    async fn test_signalr() {
+        let (seen_tx, mut seen_rx) = mpsc::unbounded_channel();
+        let url = mock_server(move |mut ws| {
+            let seen_tx = seen_tx.clone();
+            async move {
+                let request = ws.next().await.unwrap().unwrap();
+                assert_eq!(
+                    request.to_text().unwrap(),
+                    "{\"protocol\":\"json\",\"version\":1}\u{1e}"
+                );
+                // the first invocation shares the frame with the response
+                let greet = encode(&[HubMessage::Invocation {
+                    invocation_id: None,
+                    target: "Greet".to_string(),
+                    arguments: vec![json!("hi")],
+                }]);
+                let response = format!("{{}}\u{1e}{}", greet.to_text().unwrap());
+                ws.send(Message::text(response)).await.unwrap();
+
+                while let Some(Ok(msg)) = ws.next().await {
+                    for message in decode(&msg) {
+                        let message = message.unwrap();
+                        seen_tx.send(message.clone()).unwrap();
+                        let replies = match message {
+                            HubMessage::Invocation {
+                                invocation_id,
+                                target,
+                                arguments,
+                            } => match (target.as_str(), invocation_id) {
+                                ("Add", Some(id)) => vec![HubMessage::Completion {
+                                    invocation_id: id,
+                                    result: Some(json!(
+                                        arguments[0].as_i64().unwrap()
+                                            + arguments[1].as_i64().unwrap()
+                                    )),
+                                    error: None,
+                                }],
+                                ("Ask", _) => vec![HubMessage::Invocation {
+                                    invocation_id: Some("s1".to_string()),
+                                    target: "getName".to_string(),
+                                    arguments: vec![],
+                                }],
+                                ("Hang", _) => return,
+                                (_, Some(id)) => vec![HubMessage::Completion {
+                                    invocation_id: id,
+                                    result: None,
+                                    error: Some("no such method".to_string()),
+                                }],
+                                (_, None) => vec![],
+                            },
+                            HubMessage::StreamInvocation { invocation_id, .. } => {
+                                let mut replies: Vec<HubMessage> = (0..3)
+                                    .map(|i| HubMessage::StreamItem {
+                                        invocation_id: invocation_id.clone(),
+                                        item: json!(i),
+                                    })
+                                    .collect();
+                                replies.push(HubMessage::Completion {
+                                    invocation_id,
+                                    result: None,
+                                    error: None,
+                                });
+                                replies
+                            }
+                            _ => vec![],
+                        };
+                        if !replies.is_empty() {
+                            ws.send(encode(&replies)).await.unwrap();
+                        }
+                    }
+                }
+            }
+        })
+        .await;
+
+        let mut options = ReconnectOptions::default();
+        options
+            .with_handshake(Arc::new(SignalRHandshake::new()))
+            .with_retry_policy_fn(Arc::new(|| {
+                Box::new(ConstantStrategy::new(Duration::from_millis(10)).into_iter())
+            }));
+        let reconnect = Arc::new(ReconnectT::new(url, Some(options)));
+        let client = SignalRClient::builder()
+            .with_keep_alive_interval(Duration::from_millis(20))
+            .attach(&reconnect)
+            .await
+            .unwrap();
+        let (greet_tx, mut greet_rx) = mpsc::unbounded_channel();
+        client
+            .on(
+                "greet",
+                Arc::new(move |args| {
+                    greet_tx.send(args).unwrap();
+                    Ok(Value::Null)
+                }),
+            )
+            .await;
+        client.on("getName", Arc::new(|_| Ok(json!("rust")))).await;
+        let mut status = reconnect.create_status_stream().await;
+        reconnect.spawn_run();
+        assert!(matches!(
+            status.next().await,
+            Some(WsStreamStatus::Connected(_))
+        ));
+        assert_eq!(greet_rx.recv().await.unwrap(), vec![json!("hi")]);
+
+        let sum: i64 = client
+            .invoke("Add", vec![json!(1), json!(2)])
+            .await
+            .unwrap();
+        assert_eq!(sum, 3);
+        let failed = client.invoke::<Value>("Missing", vec![]).await;
+        assert!(matches!(failed, Err(SignalRError::Hub(e)) if e == "no such method"));
+
+        let items: Vec<Value> = client
+            .stream("Count", vec![json!(3)])
+            .await
+            .unwrap()
+            .map(Result::unwrap)
+            .collect()
+            .await;
+        assert_eq!(items, [json!(0), json!(1), json!(2)]);
+
+        // the server calls a client method and waits for its result
+        client.send_invocation("Ask", vec![]).await.unwrap();
+        let mut pinged = false;
+        loop {
+            match seen_rx.recv().await.unwrap() {
+                HubMessage::Ping => pinged = true,
+                HubMessage::Completion {
+                    invocation_id,
+                    result,
+                    ..
+                } => {
+                    assert_eq!(invocation_id, "s1");
+                    assert_eq!(result, Some(json!("rust")));
+                    break;
+                }
+                _ => {}
+            }
+        }
+        while !pinged {
+            pinged = seen_rx.recv().await.unwrap() == HubMessage::Ping;
+        }
+
+        let lost = client.invoke::<Value>("Hang", vec![]).await;
+        assert!(matches!(
+            lost,
+            Err(SignalRError::Transport(ReconnectTError::Disconnected))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_close_without_reconnect() {
+        let connections = Arc::new(AtomicUsize::new(0));
+        let url = mock_server({
+            let connections = connections.clone();
+            move |mut ws| {
+                connections.fetch_add(1, Ordering::SeqCst);
+                async move {
+                    ws.next().await.unwrap().unwrap();
+                    ws.send(Message::text("{}\u{1e}")).await.unwrap();
+                    let close = HubMessage::Close {
+                        error: Some("server shutting down".to_string()),
+                        allow_reconnect: false,
+                    };
+                    ws.send(encode(&[close])).await.unwrap();
+                    // left open; the client drops it
+                    while ws.next().await.is_some() {}
+                }
+            }
+        })
+        .await;
+
+        let mut options = ReconnectOptions::default();
+        options
+            .with_handshake(Arc::new(SignalRHandshake::new()))
+            .with_retry_policy_fn(Arc::new(|| {
+                Box::new(ConstantStrategy::new(Duration::from_millis(10)).into_iter())
+            }));
+        let reconnect = Arc::new(ReconnectT::new(url, Some(options)));
+        let _client = SignalRClient::builder().attach(&reconnect).await.unwrap();
+        let result = tokio::time::timeout(Duration::from_secs(5), reconnect.run())
+            .await
+            .unwrap();
+        assert!(matches!(
+            result,
+            Err(ReconnectTError::Stopped(reason)) if reason == "server shutting down"
+        ));
+        assert_eq!(connections.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_drop_stream_outside_runtime() {
+        let (_tx, rx) = mpsc::unbounded_channel();
+        let stream = SignalRStream {
+            id: "1".to_string(),
+            sender: Arc::new(MaybePSTSender::default()),
+            pending: Arc::new(Mutex::new(HashMap::new())),
+            stream: UnboundedReceiverStream::new(rx),
+        };
+        drop(stream);
+    }
+}
@@ -0,0 +1,552 @@
+//! A Socket.IO v5 client over Engine.IO v4.
+
+mod packet;
+
+use crate::errors::ReconnectTError;
+use crate::event_listeners::ShareListener;
+use crate::extension::StreamEvent;
+use crate::handshake::{receive_until, HandshakeContext, StreamHandshake};
+use crate::maybe_sender::{MaybePSTSender, SenderGuard};
+use crate::prelude::WsStreamStatus;
+use crate::tungstenite::ReconnectT;
+use crate::types::{PSTReceiver, PSTSender};
+use async_trait::async_trait;
+use eyre::{eyre, Result as EResult};
+use futures_util::SinkExt;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{oneshot, Mutex};
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use tokio_stream::StreamExt;
+use tungstenite::client::IntoClientRequest;
+use tungstenite::Message;
+
+pub use crate::protocols::socket_io::packet::*;
+
+/// Returns the WebSocket endpoint of the Socket.IO server at `base`, e.g. `ws://host:3000`.
+pub fn endpoint(base: &str) -> String {
+    format!(
+        "{}/socket.io/?EIO=4&transport=websocket",
+        base.trim_end_matches('/')
+    )
+}
+
+fn decode_message(msg: &Message) -> Option<EnginePacket> {
+    EnginePacket::decode(msg.to_text().ok()?).ok()
+}
+
+fn encode_packet(packet: &Packet) -> Message {
+    Message::text(EnginePacket::Message(packet.encode()).encode())
+}
+
+/// The namespaces joined during the handshake and their session ids, available in
+/// the handshake context.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SocketIoSession {
+    pub namespaces: HashMap<String, String>,
+}
+
+/// Waits for the Engine.IO open packet, then joins each namespace in turn.
+///
+/// The connection's receive timeout is set to the server's ping interval plus its
+/// ping timeout. Pings received meanwhile are answered by [`SocketIoClient`] once
+/// connected.
+#[derive(Clone)]
+pub struct SocketIoHandshake {
+    namespaces: Vec<(String, Option<Value>)>,
+    timeout: Duration,
+}
+
+impl Default for SocketIoHandshake {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SocketIoHandshake {
+    /// Creates the handshake joining the `/` namespace, unless others are added.
+    pub fn new() -> Self {
+        Self {
+            namespaces: Vec::new(),
+            timeout: Duration::from_secs(10),
+        }
+    }
+
+    /// Joins `namespace` with the optional `auth` payload.
+    pub fn with_namespace(mut self, namespace: impl Into<String>, auth: Option<Value>) -> Self {
+        self.namespaces.push((namespace.into(), auth));
+        self
+    }
+
+    /// Sets how long to wait for the open packet and for each namespace join.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+}
+
+#[async_trait]
+impl StreamHandshake for SocketIoHandshake {
+    async fn handshake(
+        &self,
+        writer: &mut PSTSender,
+        reader: &mut PSTReceiver,
+    ) -> EResult<HandshakeContext> {
+        let mut ctx = HandshakeContext::new();
+        let open = receive_until(
+            reader,
+            &mut ctx,
+            self.timeout,
+            |msg| match decode_message(msg)? {
+                EnginePacket::Open(data) => {
+                    Some(serde_json::from_str::<EngineIoOpen>(&data).map_err(Into::into))
+                }
+                _ => None,
+            },
+        )
+        .await?;
+
+        let default = [("/".to_string(), None)];
+        let namespaces = if self.namespaces.is_empty() {
+            &default[..]
+        } else {
+            &self.namespaces[..]
+        };
+        let mut session = SocketIoSession::default();
+        for (namespace, auth) in namespaces {
+            let mut connect = Packet::new(PacketType::Connect, namespace);
+            connect.data = auth.clone();
+            writer.send(encode_packet(&connect)).await?;
+
+            let sid = receive_until(reader, &mut ctx, self.timeout, |msg| {
+                let EnginePacket::Message(data) = decode_message(msg)? else {
+                    return None;
+                };
+                let packet = Packet::decode(&data).ok()?;
+                if packet.namespace != *namespace {
+                    return None;
+                }
+                match packet.kind {
+                    PacketType::Connect => Some(Ok(packet
+                        .data
+                        .as_ref()
+                        .and_then(|data| data["sid"].as_str())
+                        .unwrap_or_default()
+                        .to_string())),
+                    PacketType::ConnectError => Some(Err(eyre!(
+                        "joining {namespace} rejected: {}",
+                        packet.data.unwrap_or_default()
+                    ))),
+                    _ => None,
+                }
+            })
+            .await?;
+            session.namespaces.insert(namespace.clone(), sid);
+        }
+
+        ctx.set_receive_timeout(open.receive_timeout());
+        ctx.insert(open);
+        ctx.insert(session);
+        Ok(ctx)
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum SocketIoError {
+    #[error(transparent)]
+    Transport(#[from] ReconnectTError),
+    #[error("event does not expect an ack")]
+    NotAckable,
+}
+
+/// An event emitted by the server.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Event {
+    pub namespace: String,
+    pub name: String,
+    /// The event arguments; binary ones are [`placeholder`]s into `attachments`.
+    pub args: Vec<Value>,
+    pub attachments: Vec<Vec<u8>>,
+    /// Set if the server expects an [`ack`](SocketIoClient::ack).
+    pub ack_id: Option<u64>,
+}
+
+/// The server's acknowledgement of an event.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Ack {
+    pub args: Vec<Value>,
+    pub attachments: Vec<Vec<u8>>,
+}
+
+/// The waiting acks, with the generation of the connection their event was sent on.
+type Acks = Arc<Mutex<HashMap<(String, u64), (u64, oneshot::Sender<Ack>)>>>;
+type Listeners = Arc<Mutex<HashMap<(String, String), Arc<ShareListener<Event>>>>>;
+
+/// A Socket.IO client over a [`ReconnectT`] configured with [`SocketIoHandshake`].
+///
+/// Engine.IO pings are answered with pongs. Acks pending when the connection their event
+/// was sent on drops fail with [`ReconnectTError::Disconnected`].
+pub struct SocketIoClient {
+    sender: Arc<MaybePSTSender>,
+    next_ack: AtomicU64,
+    acks: Acks,
+    listeners: Listeners,
+}
+
+impl SocketIoClient {
+    /// Creates the client and starts handling the events of `reconnect`.
+    pub async fn attach<R: IntoClientRequest + Send + Sync>(
+        reconnect: &ReconnectT<R>,
+    ) -> EResult<Arc<Self>> {
+        let this = Arc::new(Self {
+            sender: reconnect.sender.clone(),
+            next_ack: AtomicU64::new(1),
+            acks: Arc::new(Mutex::new(HashMap::new())),
+            listeners: Arc::new(Mutex::new(HashMap::new())),
+        });
+        this.spawn_receive(reconnect.create_event_stream().await);
+        Ok(this)
+    }
+
+    /// Creates a stream of the `event`s emitted by the server on `namespace`.
+    pub async fn on(&self, namespace: &str, event: &str) -> UnboundedReceiverStream<Event> {
+        let listener = self
+            .listeners
+            .lock()
+            .await
+            .entry((namespace.to_string(), event.to_string()))
+            .or_default()
+            .clone();
+        listener.new_listener().await
+    }
+
+    /// Emits `event` with `args` on `namespace`.
+    pub async fn emit(
+        &self,
+        namespace: &str,
+        event: &str,
+        args: Vec<Value>,
+    ) -> Result<(), SocketIoError> {
+        self.emit_binary(namespace, event, args, Vec::new()).await
+    }
+
+    /// Emits `event` with binary `attachments`, referenced from `args` by [`placeholder`]s.
+    pub async fn emit_binary(
+        &self,
+        namespace: &str,
+        event: &str,
+        args: Vec<Value>,
+        attachments: Vec<Vec<u8>>,
+    ) -> Result<(), SocketIoError> {
+        let packet = Packet::new(PacketType::Event, namespace).with_data(event_data(event, args));
+        self.send(packet, attachments).await
+    }
+
+    /// Emits `event` and waits up to 30s for the server's ack.
+    pub async fn emit_with_ack(
+        &self,
+        namespace: &str,
+        event: &str,
+        args: Vec<Value>,
+    ) -> Result<Ack, SocketIoError> {
+        self.emit_with_ack_timeout(namespace, event, args, Duration::from_secs(30))
+            .await
+    }
+
+    pub async fn emit_with_ack_timeout(
+        &self,
+        namespace: &str,
+        event: &str,
+        args: Vec<Value>,
+        timeout: Duration,
+    ) -> Result<Ack, SocketIoError> {
+        let id = self.next_ack.fetch_add(1, Ordering::Relaxed);
+        let key = (namespace.to_string(), id);
+        let (tx, rx) = oneshot::channel();
+        let packet = Packet::new(PacketType::Event, namespace)
+            .with_id(id)
+            .with_data(event_data(event, args));
+        {
+            let mut sender = self.sender.lock().await;
+            let generation = sender
+                .generation()
+                .ok_or(ReconnectTError::SenderNotConnected)?;
+            self.acks.lock().await.insert(key.clone(), (generation, tx));
+            if let Err(e) = send_packet(&mut sender, packet, Vec::new()).await {
+                self.acks.lock().await.remove(&key);
+                return Err(e);
+            }
+        }
+        match tokio::time::timeout(timeout, rx).await {
+            Ok(Ok(ack)) => Ok(ack),
+            Ok(Err(_)) => Err(ReconnectTError::Disconnected.into()),
+            Err(_) => {
+                self.acks.lock().await.remove(&key);
+                Err(ReconnectTError::RequestTimeout(timeout).into())
+            }
+        }
+    }
+
+    /// Acknowledges a server event that expects it.
+    pub async fn ack(&self, event: &Event, args: Vec<Value>) -> Result<(), SocketIoError> {
+        let id = event.ack_id.ok_or(SocketIoError::NotAckable)?;
+        let packet = Packet::new(PacketType::Ack, &event.namespace)
+            .with_id(id)
+            .with_data(Value::Array(args));
+        self.send(packet, Vec::new()).await
+    }
+
+    async fn send(&self, packet: Packet, attachments: Vec<Vec<u8>>) -> Result<(), SocketIoError> {
+        send_packet(&mut self.sender.lock().await, packet, attachments).await
+    }
+}
+
+/// Sends `packet` followed by its `attachments`; holding the sender keeps the frames together.
+async fn send_packet(
+    sender: &mut SenderGuard<'_>,
+    packet: Packet,
+    attachments: Vec<Vec<u8>>,
+) -> Result<(), SocketIoError> {
+    let packet = packet.with_attachments(attachments.len());
+    sender.send(encode_packet(&packet)).await?;
+    for attachment in attachments {
+        sender.send(Message::binary(attachment)).await?;
+    }
+    Ok(())
+}
+
+fn event_data(event: &str, args: Vec<Value>) -> Value {
+    let mut data = Vec::with_capacity(args.len() + 1);
+    data.push(Value::String(event.to_string()));
+    data.extend(args);
+    Value::Array(data)
+}
+
+/// Delivers a complete packet to the listeners or the pending ack.
+async fn dispatch(packet: Packet, attachments: Vec<Vec<u8>>, acks: &Acks, listeners: &Listeners) {
+    let args = match packet.data {
+        Some(Value::Array(args)) => args,
+        Some(data) => vec![data],
+        None => Vec::new(),
+    };
+    match packet.kind {
+        PacketType::Event | PacketType::BinaryEvent => {
+            let mut args = args.into_iter();
+            let Some(Value::String(name)) = args.next() else {
+                return;
+            };
+            let listener = listeners
+                .lock()
+                .await
+                .get(&(packet.namespace.clone(), name.clone()))
+                .cloned();
+            if let Some(listener) = listener {
+                listener
+                    .notify(Event {
+                        namespace: packet.namespace,
+                        name,
+                        args: args.collect(),
+                        attachments,
+                        ack_id: packet.id,
+                    })
+                    .await;
+            }
+        }
+        PacketType::Ack | PacketType::BinaryAck => {
+            let Some(id) = packet.id else {
+                return;
+            };
+            if let Some((_, ack)) = acks.lock().await.remove(&(packet.namespace, id)) {
+                let _ = ack.send(Ack { args, attachments });
+            }
+        }
+        PacketType::Disconnect => {
+            tracing::warn!(namespace = packet.namespace, "socket_io::disconnected");
+        }
+        _ => {}
+    }
+}
+
+impl SocketIoClient {
+    fn spawn_receive(&self, mut events: UnboundedReceiverStream<StreamEvent>) {
+        let sender = self.sender.clone();
+        let acks = self.acks.clone();
+        let listeners = self.listeners.clone();
+        tokio::spawn(async move {
+            // a binary packet waiting for its attachments
+            let mut partial: Option<(Packet, Vec<Vec<u8>>)> = None;
+            let mut generation = 0;
+            while let Some(event) = events.next().await {
+                let msg = match event {
+                    StreamEvent::Message(msg) => msg,
+                    StreamEvent::Status(WsStreamStatus::Connected(ctx)) => {
+                        generation = ctx.generation();
+                        continue;
+                    }
+                    StreamEvent::Status(WsStreamStatus::Disconnected) => {
+                        partial = None;
+                        // dropping the senders fails the emits sent on the dropped connection
+                        acks.lock().await.retain(|_, (sent, _)| *sent > generation);
+                        continue;
+                    }
+                    StreamEvent::Status(_) => continue,
+                };
+                let (packet, attachments) = match msg {
+                    Message::Binary(data) => {
+                        let Some((packet, mut attachments)) = partial.take() else {
+                            continue;
+                        };
+                        attachments.push(data.to_vec());
+                        if attachments.len() < packet.attachments {
+                            partial = Some((packet, attachments));
+                            continue;
+                        }
+                        (packet, attachments)
+                    }
+                    msg => match decode_message(&msg) {
+                        Some(EnginePacket::Ping(data)) => {
+                            let pong = Message::text(EnginePacket::Pong(data).encode());
+                            if let Err(e) = sender.send(pong).await {
+                                tracing::debug!(error=?e, "socket_io::pong");
+                            }
+                            continue;
+                        }
+                        Some(EnginePacket::Message(data)) => match Packet::decode(&data) {
+                            Ok(packet) if packet.attachments > 0 => {
+                                partial = Some((packet, Vec::new()));
+                                continue;
+                            }
+                            Ok(packet) => (packet, Vec::new()),
+                            Err(e) => {
+                                tracing::debug!(error=?e, "socket_io::invalid_packet");
+                                continue;
+                            }
+                        },
+                        _ => continue,
+                    },
+                };
+                dispatch(packet, attachments, &acks, &listeners).await;
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::prelude::*;
+    use crate::protocols::socket_io::{
+        placeholder, EnginePacket, Packet, PacketType, SocketIoClient, SocketIoHandshake,
+        SocketIoSession,
+    };
+    use crate::test_util::mock_server;
+    use futures_util::{SinkExt, StreamExt};
+    use serde_json::json;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::sync::mpsc;
+    use tungstenite::Message;
+
+    fn message(text: &str) -> Message {
+        Message::text(format!("4{text}"))
+    }
+
+    #[tokio::test]
+    async fn test_socket_io() {
+        let (seen_tx, mut seen_rx) = mpsc::unbounded_channel();
+        let connections = Arc::new(AtomicUsize::new(0));
+        let url = mock_server(move |mut ws| {
+            let seen_tx = seen_tx.clone();
+            let connection = connections.fetch_add(1, Ordering::SeqCst);
+            async move {
+                // the first connection stops pinging and times out after 20ms + 20ms
+                let interval = if connection == 0 { 20 } else { 10_000 };
+                let open = json!({"sid": "e", "upgrades": [], "pingInterval": interval, "pingTimeout": interval, "maxPayload": 1000});
+                ws.send(Message::text(format!("0{open}"))).await.unwrap();
+                let join = ws.next().await.unwrap().unwrap();
+                assert_eq!(join.to_text().unwrap(), "40/chat,{\"token\":\"t\"}");
+                ws.send(message(&format!("0/chat,{{\"sid\":\"s{connection}\"}}")))
+                    .await
+                    .unwrap();
+                ws.send(Message::text("2")).await.unwrap();
+
+                while let Some(Ok(msg)) = ws.next().await {
+                    let text = msg.to_text().unwrap();
+                    let EnginePacket::Message(data) = EnginePacket::decode(text).unwrap() else {
+                        seen_tx.send(text.to_string()).unwrap();
+                        continue;
+                    };
+                    let packet = Packet::decode(&data).unwrap();
+                    match (packet.kind, packet.id) {
+                        (PacketType::Event, Some(id)) => {
+                            let ack = Packet::new(PacketType::Ack, "/chat")
+                                .with_id(id)
+                                .with_data(json!(["ok"]));
+                            ws.send(message(&ack.encode())).await.unwrap();
+                            let file = Packet::new(PacketType::Event, "/chat")
+                                .with_data(json!(["file", placeholder(0)]))
+                                .with_attachments(1);
+                            ws.send(message(&file.encode())).await.unwrap();
+                            ws.send(Message::binary(vec![1, 2, 3])).await.unwrap();
+                            ws.send(message("2/chat,7[\"question\"]")).await.unwrap();
+                        }
+                        _ => seen_tx.send(data).unwrap(),
+                    }
+                }
+            }
+        })
+        .await;
+
+        let mut options = ReconnectOptions::default();
+        options
+            .with_handshake(Arc::new(
+                SocketIoHandshake::new().with_namespace("/chat", Some(json!({"token": "t"}))),
+            ))
+            .with_retry_policy_fn(Arc::new(|| {
+                Box::new(ConstantStrategy::new(Duration::from_millis(10)).into_iter())
+            }));
+        let reconnect = Arc::new(ReconnectT::new(url, Some(options)));
+        let client = SocketIoClient::attach(&reconnect).await.unwrap();
+        let mut files = client.on("/chat", "file").await;
+        let mut questions = client.on("/chat", "question").await;
+        let mut status = reconnect.create_status_stream().await;
+        reconnect.spawn_run();
+
+        assert!(matches!(
+            status.next().await,
+            Some(WsStreamStatus::Connected(_))
+        ));
+        assert!(matches!(
+            status.next().await,
+            Some(WsStreamStatus::Disconnected)
+        ));
+        let Some(WsStreamStatus::Connected(ctx)) = status.next().await else {
+            panic!("not reconnected");
+        };
+        let session = ctx.get::<SocketIoSession>().unwrap();
+        assert_eq!(session.namespaces["/chat"], "s1");
+        assert_eq!(ctx.receive_timeout(), Some(Duration::from_secs(20)));
+
+        let ack = client
+            .emit_with_ack("/chat", "hello", vec![json!(1)])
+            .await
+            .unwrap();
+        assert_eq!(ack.args, [json!("ok")]);
+
+        let file = files.next().await.unwrap();
+        assert_eq!(file.args, [placeholder(0)]);
+        assert_eq!(file.attachments, [vec![1, 2, 3]]);
+
+        let question = questions.next().await.unwrap();
+        client.ack(&question, vec![json!("answer")]).await.unwrap();
+        // pongs from both connections, then the ack
+        let mut seen = Vec::new();
+        while !seen.contains(&"3/chat,7[\"answer\"]".to_string()) {
+            seen.push(seen_rx.recv().await.unwrap());
+        }
+        assert!(seen.contains(&"3".to_string()));
+    }
+}
@@ -0,0 +1,279 @@
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::time::Duration;
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub enum PacketError {
+    #[error("empty packet")]
+    Empty,
+    #[error("unknown packet type: {0}")]
+    UnknownType(char),
+    #[error("malformed packet: {0}")]
+    Malformed(String),
+}
+
+/// An Engine.IO v4 packet sent as a text frame.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum EnginePacket {
+    Open(String),
+    Close,
+    Ping(String),
+    Pong(String),
+    Message(String),
+    Upgrade,
+    Noop,
+}
+
+impl EnginePacket {
+    pub fn decode(text: &str) -> Result<Self, PacketError> {
+        let mut chars = text.chars();
+        let kind = chars.next().ok_or(PacketError::Empty)?;
+        let data = chars.as_str().to_string();
+        Ok(match kind {
+            '0' => EnginePacket::Open(data),
+            '1' => EnginePacket::Close,
+            '2' => EnginePacket::Ping(data),
+            '3' => EnginePacket::Pong(data),
+            '4' => EnginePacket::Message(data),
+            '5' => EnginePacket::Upgrade,
+            '6' => EnginePacket::Noop,
+            kind => return Err(PacketError::UnknownType(kind)),
+        })
+    }
+
+    pub fn encode(&self) -> String {
+        match self {
+            EnginePacket::Open(data) => format!("0{data}"),
+            EnginePacket::Close => "1".to_string(),
+            EnginePacket::Ping(data) => format!("2{data}"),
+            EnginePacket::Pong(data) => format!("3{data}"),
+            EnginePacket::Message(data) => format!("4{data}"),
+            EnginePacket::Upgrade => "5".to_string(),
+            EnginePacket::Noop => "6".to_string(),
+        }
+    }
+}
+
+/// The payload of the Engine.IO open packet, available in the handshake context.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EngineIoOpen {
+    pub sid: String,
+    #[serde(default)]
+    pub upgrades: Vec<String>,
+    /// In milliseconds.
+    pub ping_interval: u64,
+    /// In milliseconds.
+    pub ping_timeout: u64,
+    #[serde(default)]
+    pub max_payload: u64,
+}
+
+impl EngineIoOpen {
+    /// How long the server may stay silent before the connection is considered dead.
+    pub fn receive_timeout(&self) -> Duration {
+        Duration::from_millis(self.ping_interval + self.ping_timeout)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PacketType {
+    Connect,
+    Disconnect,
+    Event,
+    Ack,
+    ConnectError,
+    BinaryEvent,
+    BinaryAck,
+}
+
+impl PacketType {
+    fn from_char(c: char) -> Result<Self, PacketError> {
+        Ok(match c {
+            '0' => PacketType::Connect,
+            '1' => PacketType::Disconnect,
+            '2' => PacketType::Event,
+            '3' => PacketType::Ack,
+            '4' => PacketType::ConnectError,
+            '5' => PacketType::BinaryEvent,
+            '6' => PacketType::BinaryAck,
+            c => return Err(PacketError::UnknownType(c)),
+        })
+    }
+
+    fn as_char(&self) -> char {
+        match self {
+            PacketType::Connect => '0',
+            PacketType::Disconnect => '1',
+            PacketType::Event => '2',
+            PacketType::Ack => '3',
+            PacketType::ConnectError => '4',
+            PacketType::BinaryEvent => '5',
+            PacketType::BinaryAck => '6',
+        }
+    }
+
+    fn is_binary(&self) -> bool {
+        matches!(self, PacketType::BinaryEvent | PacketType::BinaryAck)
+    }
+}
+
+/// A Socket.IO v5 packet, carried in an Engine.IO message.
+///
+/// Binary packets are followed by `attachments` binary frames, referenced from `data`
+/// by [`placeholder`]s.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Packet {
+    pub kind: PacketType,
+    pub namespace: String,
+    pub id: Option<u64>,
+    pub data: Option<Value>,
+    pub attachments: usize,
+}
+
+impl Packet {
+    pub fn new(kind: PacketType, namespace: impl Into<String>) -> Self {
+        Self {
+            kind,
+            namespace: namespace.into(),
+            id: None,
+            data: None,
+            attachments: 0,
+        }
+    }
+
+    pub fn with_id(mut self, id: u64) -> Self {
+        self.id = Some(id);
+        self
+    }
+
+    pub fn with_data(mut self, data: Value) -> Self {
+        self.data = Some(data);
+        self
+    }
+
+    /// Sets the number of binary attachments, making an event or ack binary.
+    pub fn with_attachments(mut self, attachments: usize) -> Self {
+        self.attachments = attachments;
+        self.kind = match (self.kind, attachments) {
+            (PacketType::Event, 1..) => PacketType::BinaryEvent,
+            (PacketType::Ack, 1..) => PacketType::BinaryAck,
+            (kind, _) => kind,
+        };
+        self
+    }
+
+    /// Encodes the packet without the Engine.IO message prefix.
+    pub fn encode(&self) -> String {
+        let mut out = String::new();
+        out.push(self.kind.as_char());
+        if self.kind.is_binary() {
+            out.push_str(&format!("{}-", self.attachments));
+        }
+        if self.namespace != "/" {
+            out.push_str(&self.namespace);
+            out.push(',');
+        }
+        if let Some(id) = self.id {
+            out.push_str(&id.to_string());
+        }
+        if let Some(data) = &self.data {
+            out.push_str(&data.to_string());
+        }
+        out
+    }
+
+    pub fn decode(text: &str) -> Result<Self, PacketError> {
+        let malformed = || PacketError::Malformed(text.to_string());
+        let mut chars = text.chars();
+        let kind = PacketType::from_char(chars.next().ok_or(PacketError::Empty)?)?;
+        let mut rest = chars.as_str();
+
+        let mut attachments = 0;
+        if kind.is_binary() {
+            let (count, tail) = rest.split_once('-').ok_or_else(malformed)?;
+            attachments = count.parse().map_err(|_| malformed())?;
+            rest = tail;
+        }
+
+        let mut namespace = "/".to_string();
+        if rest.starts_with('/') {
+            let end = rest.find(',').unwrap_or(rest.len());
+            namespace = rest[..end].to_string();
+            rest = rest.get(end + 1..).unwrap_or_default();
+        }
+
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let id = match digits {
+            0 => None,
+            _ => Some(rest[..digits].parse().map_err(|_| malformed())?),
+        };
+        rest = &rest[digits..];
+
+        let data = match rest {
+            "" => None,
+            rest => Some(serde_json::from_str(rest).map_err(|_| malformed())?),
+        };
+        Ok(Self {
+            kind,
+            namespace,
+            id,
+            data,
+            attachments,
+        })
+    }
+}
+
+/// The placeholder referencing binary attachment `num` from packet data.
+pub fn placeholder(num: usize) -> Value {
+    json!({"_placeholder": true, "num": num})
+}
+
+#[cfg(test)]
+mod test {
+    use crate::protocols::socket_io::{placeholder, EnginePacket, Packet, PacketType};
+    use serde_json::json;
+
+    #[test]
+    fn test_packet_codec() {
+        assert_eq!(
+            EnginePacket::decode("42[\"a\"]"),
+            Ok(EnginePacket::Message("2[\"a\"]".to_string()))
+        );
+        assert_eq!(EnginePacket::Pong(String::new()).encode(), "3");
+
+        let cases = [
+            ("0", Packet::new(PacketType::Connect, "/")),
+            (
+                "0/admin,{\"token\":\"t\"}",
+                Packet::new(PacketType::Connect, "/admin").with_data(json!({"token": "t"})),
+            ),
+            (
+                "2[\"hello\",1]",
+                Packet::new(PacketType::Event, "/").with_data(json!(["hello", 1])),
+            ),
+            (
+                "3/admin,13[\"ok\"]",
+                Packet::new(PacketType::Ack, "/admin")
+                    .with_id(13)
+                    .with_data(json!(["ok"])),
+            ),
+            (
+                "51-12[\"file\",{\"_placeholder\":true,\"num\":0}]",
+                Packet::new(PacketType::Event, "/")
+                    .with_id(12)
+                    .with_data(json!(["file", placeholder(0)]))
+                    .with_attachments(1),
+            ),
+            ("1/admin,", Packet::new(PacketType::Disconnect, "/admin")),
+        ];
+        for (text, packet) in cases {
+            assert_eq!(Packet::decode(text).unwrap(), packet, "{text}");
+            assert_eq!(packet.encode(), text);
+        }
+        assert!(Packet::decode("5x-[]").is_err());
+        assert!(Packet::decode("9").is_err());
+    }
+}
@@ -0,0 +1,297 @@
+use std::time::Duration;
+use tungstenite::Message;
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub enum FrameError {
+    #[error("frame is missing its command")]
+    MissingCommand,
+    #[error("malformed header: {0}")]
+    MalformedHeader(String),
+    #[error("invalid escape sequence in header: {0}")]
+    InvalidEscape(String),
+    #[error("frame is not terminated by NUL")]
+    Unterminated,
+    #[error("invalid content-length: {0}")]
+    InvalidContentLength(String),
+    #[error("frame is not valid UTF-8")]
+    InvalidUtf8,
+}
+
+/// A STOMP 1.2 frame.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct StompFrame {
+    pub command: String,
+    /// Headers in frame order. Repeated headers are kept; the first one wins.
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl StompFrame {
+    pub fn new(command: impl Into<String>) -> Self {
+        Self {
+            command: command.into(),
+            ..Default::default()
+        }
+    }
+
+    pub fn with_header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    pub fn with_body(mut self, body: impl Into<Vec<u8>>) -> Self {
+        self.body = body.into();
+        self
+    }
+
+    /// Returns the value of the first header called `name`.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn body_text(&self) -> Option<&str> {
+        std::str::from_utf8(&self.body).ok()
+    }
+
+    /// CONNECT and CONNECTED headers are not escaped, for compatibility with STOMP 1.0.
+    fn escapes_headers(command: &str) -> bool {
+        command != "CONNECT" && command != "CONNECTED"
+    }
+
+    /// Encodes the frame, adding a `content-length` header to non-empty bodies.
+    pub fn encode(&self) -> Vec<u8> {
+        let escape = Self::escapes_headers(&self.command);
+        let mut out = Vec::with_capacity(self.body.len() + 64);
+        out.extend_from_slice(self.command.as_bytes());
+        out.push(b'\n');
+        for (name, value) in &self.headers {
+            if escape {
+                out.extend_from_slice(escape_header(name).as_bytes());
+                out.push(b':');
+                out.extend_from_slice(escape_header(value).as_bytes());
+            } else {
+                out.extend_from_slice(format!("{name}:{value}").as_bytes());
+            }
+            out.push(b'\n');
+        }
+        if !self.body.is_empty() && self.header("content-length").is_none() {
+            out.extend_from_slice(format!("content-length:{}\n", self.body.len()).as_bytes());
+        }
+        out.push(b'\n');
+        out.extend_from_slice(&self.body);
+        out.push(0);
+        out
+    }
+
+    /// Decodes one frame. Returns `None` for a heart-beat, which is only end-of-lines.
+    pub fn decode(data: &[u8]) -> Result<Option<Self>, FrameError> {
+        let start = data
+            .iter()
+            .position(|b| *b != b'\n' && *b != b'\r')
+            .unwrap_or(data.len());
+        let data = &data[start..];
+        if data.is_empty() || data == [0] {
+            return Ok(None);
+        }
+
+        let header_end = find(data, b"\n\n")
+            .map(|i| (i, i + 2))
+            .into_iter()
+            .chain(find(data, b"\r\n\r\n").map(|i| (i, i + 4)))
+            .min_by_key(|(i, _)| *i)
+            .ok_or(FrameError::Unterminated)?;
+        let head =
+            std::str::from_utf8(&data[..header_end.0]).map_err(|_| FrameError::InvalidUtf8)?;
+        let mut lines = head.lines();
+        let command = lines
+            .next()
+            .filter(|c| !c.is_empty())
+            .ok_or(FrameError::MissingCommand)?
+            .to_string();
+
+        let escape = Self::escapes_headers(&command);
+        let mut headers = Vec::new();
+        for line in lines {
+            let (name, value) = line
+                .split_once(':')
+                .ok_or_else(|| FrameError::MalformedHeader(line.to_string()))?;
+            if escape {
+                headers.push((unescape_header(name)?, unescape_header(value)?));
+            } else {
+                headers.push((name.to_string(), value.to_string()));
+            }
+        }
+        let mut frame = Self {
+            command,
+            headers,
+            body: Vec::new(),
+        };
+
+        let rest = &data[header_end.1..];
+        let body = match frame.header("content-length") {
+            Some(len) => {
+                let len: usize = len
+                    .trim()
+                    .parse()
+                    .map_err(|_| FrameError::InvalidContentLength(len.to_string()))?;
+                if rest.get(len) != Some(&0) {
+                    return Err(FrameError::Unterminated);
+                }
+                &rest[..len]
+            }
+            None => {
+                let end = rest
+                    .iter()
+                    .position(|b| *b == 0)
+                    .ok_or(FrameError::Unterminated)?;
+                &rest[..end]
+            }
+        };
+        frame.body = body.to_vec();
+        Ok(Some(frame))
+    }
+
+    /// Encodes the frame as a text message, or binary if the body is not UTF-8.
+    pub fn to_message(&self) -> Message {
+        let encoded = self.encode();
+        match String::from_utf8(encoded) {
+            Ok(text) => Message::text(text),
+            Err(e) => Message::binary(e.into_bytes()),
+        }
+    }
+
+    /// Decodes a text or binary message. Returns `None` for heart-beats and other
+    /// message types.
+    pub fn from_message(msg: &Message) -> Result<Option<Self>, FrameError> {
+        match msg {
+            Message::Text(text) => Self::decode(text.as_bytes()),
+            Message::Binary(data) => Self::decode(data),
+            _ => Ok(None),
+        }
+    }
+}
+
+fn find(data: &[u8], needle: &[u8]) -> Option<usize> {
+    data.windows(needle.len()).position(|w| w == needle)
+}
+
+fn escape_header(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            ':' => out.push_str("\\c"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+fn unescape_header(value: &str) -> Result<String, FrameError> {
+    let mut out = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('\\') => out.push('\\'),
+            Some('n') => out.push('\n'),
+            Some('r') => out.push('\r'),
+            Some('c') => out.push(':'),
+            _ => return Err(FrameError::InvalidEscape(value.to_string())),
+        }
+    }
+    Ok(out)
+}
+
+/// Negotiated heart-beat intervals; zero means none.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct HeartBeat {
+    /// How often the client must send.
+    pub outgoing: Duration,
+    /// How often the server will send.
+    pub incoming: Duration,
+}
+
+impl HeartBeat {
+    /// Negotiates from the client's `heart-beat` setting and the server's header value.
+    pub fn negotiate(client: (Duration, Duration), server: Option<&str>) -> Self {
+        let (sx, sy) = server
+            .and_then(|value| value.split_once(','))
+            .and_then(|(sx, sy)| Some((sx.trim().parse().ok()?, sy.trim().parse().ok()?)))
+            .map(|(sx, sy): (u64, u64)| (Duration::from_millis(sx), Duration::from_millis(sy)))
+            .unwrap_or_default();
+        let (cx, cy) = client;
+        let either = |a: Duration, b: Duration| {
+            if a.is_zero() || b.is_zero() {
+                Duration::ZERO
+            } else {
+                a.max(b)
+            }
+        };
+        Self {
+            outgoing: either(cx, sy),
+            incoming: either(cy, sx),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::protocols::stomp::{FrameError, HeartBeat, StompFrame};
+    use std::time::Duration;
+
+    #[test]
+    fn test_frame_codec() {
+        let frame = StompFrame::new("SEND")
+            .with_header("destination", "/queue/a:b")
+            .with_header("note", "line\nbreak\\")
+            .with_body("hello\0world");
+        let encoded = frame.encode();
+        assert_eq!(
+            encoded,
+            b"SEND\ndestination:/queue/a\\cb\nnote:line\\nbreak\\\\\ncontent-length:11\n\nhello\0world\0"
+        );
+        let decoded = StompFrame::decode(&encoded).unwrap().unwrap();
+        assert_eq!(decoded.header("destination"), Some("/queue/a:b"));
+        assert_eq!(decoded.header("note"), Some("line\nbreak\\"));
+        assert_eq!(decoded.body, b"hello\0world");
+
+        let connected =
+            StompFrame::decode(b"\r\nCONNECTED\r\nversion:1.2\r\nserver:a\\c\r\n\r\n\0").unwrap();
+        let connected = connected.unwrap();
+        assert_eq!(connected.header("server"), Some("a\\c"));
+        assert_eq!(connected.body, b"");
+
+        assert_eq!(StompFrame::decode(b"\n"), Ok(None));
+        assert_eq!(
+            StompFrame::decode(b"MESSAGE\nbad\\x:1\n\n\0"),
+            Err(FrameError::InvalidEscape("bad\\x".to_string()))
+        );
+        assert_eq!(
+            StompFrame::decode(b"MESSAGE\n\nbody"),
+            Err(FrameError::Unterminated)
+        );
+    }
+
+    #[test]
+    fn test_heart_beat() {
+        let ms = Duration::from_millis;
+        let heart_beat = HeartBeat::negotiate((ms(1000), ms(2000)), Some("3000,500"));
+        assert_eq!(heart_beat.outgoing, ms(1000));
+        assert_eq!(heart_beat.incoming, ms(3000));
+        let heart_beat = HeartBeat::negotiate((ms(1000), ms(0)), Some("3000,0"));
+        assert_eq!(heart_beat, HeartBeat::default());
+        assert_eq!(
+            HeartBeat::negotiate((ms(1000), ms(1000)), None),
+            HeartBeat::default()
+        );
+    }
+}
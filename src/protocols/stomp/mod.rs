@@ -0,0 +1,549 @@
+//! A STOMP 1.2 client.
+
+mod frame;
+
+use crate::correlation::Correlator;
+use crate::errors::ReconnectTError;
+use crate::event_listeners::ShareListener;
+use crate::handshake::{receive_until, HandshakeContext, StreamHandshake};
+use crate::maybe_sender::MaybePSTSender;
+use crate::protocols::{spawn_keep_alive, Driver, KeepAlive, ProtocolClient};
+use crate::tungstenite::ReconnectT;
+use crate::types::{PSTReceiver, PSTSender};
+use async_trait::async_trait;
+use eyre::{eyre, Result as EResult};
+use futures_util::SinkExt;
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::sync::{mpsc, Mutex};
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use tokio_stream::Stream;
+use tungstenite::client::IntoClientRequest;
+use tungstenite::Message;
+
+pub use crate::protocols::stomp::frame::*;
+
+/// What the server sent in its CONNECTED frame, available in the handshake context.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StompSession {
+    pub version: String,
+    pub session: Option<String>,
+    pub server: Option<String>,
+    pub heart_beat: HeartBeat,
+}
+
+/// Sends CONNECT and waits for CONNECTED.
+///
+/// An ERROR reply fails the handshake. The negotiated [`HeartBeat`] is stored in the
+/// [`StompSession`]; outgoing heart-beats are sent by [`StompClient`], and with incoming
+/// heart-beats the connection's receive timeout is set to one and a half times their
+/// interval.
+#[derive(Clone)]
+pub struct StompHandshake {
+    host: String,
+    login: Option<(String, String)>,
+    heart_beat: (Duration, Duration),
+    headers: Vec<(String, String)>,
+    timeout: Duration,
+}
+
+impl StompHandshake {
+    /// Creates the handshake for the virtual `host`. No heart-beats are requested by default.
+    pub fn new(host: impl Into<String>) -> Self {
+        Self {
+            host: host.into(),
+            login: None,
+            heart_beat: (Duration::ZERO, Duration::ZERO),
+            headers: Vec::new(),
+            timeout: Duration::from_secs(10),
+        }
+    }
+
+    pub fn with_login(mut self, login: impl Into<String>, passcode: impl Into<String>) -> Self {
+        self.login = Some((login.into(), passcode.into()));
+        self
+    }
+
+    /// Sets how often the client can send and wants to receive heart-beats.
+    pub fn with_heart_beat(mut self, outgoing: Duration, incoming: Duration) -> Self {
+        self.heart_beat = (outgoing, incoming);
+        self
+    }
+
+    /// Adds a header to the CONNECT frame.
+    pub fn with_header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    fn connect_frame(&self) -> StompFrame {
+        let (cx, cy) = self.heart_beat;
+        let mut frame = StompFrame::new("CONNECT")
+            .with_header("accept-version", "1.2")
+            .with_header("host", &self.host)
+            .with_header(
+                "heart-beat",
+                format!("{},{}", cx.as_millis(), cy.as_millis()),
+            );
+        if let Some((login, passcode)) = &self.login {
+            frame = frame
+                .with_header("login", login)
+                .with_header("passcode", passcode);
+        }
+        frame.headers.extend(self.headers.iter().cloned());
+        frame
+    }
+}
+
+#[async_trait]
+impl StreamHandshake for StompHandshake {
+    async fn handshake(
+        &self,
+        writer: &mut PSTSender,
+        reader: &mut PSTReceiver,
+    ) -> EResult<HandshakeContext> {
+        writer.send(self.connect_frame().to_message()).await?;
+
+        let mut ctx = HandshakeContext::new();
+        let session = receive_until(reader, &mut ctx, self.timeout, |msg| {
+            let frame = match StompFrame::from_message(msg) {
+                Ok(Some(frame)) => frame,
+                Ok(None) => return None,
+                Err(e) => return Some(Err(e.into())),
+            };
+            match frame.command.as_str() {
+                "CONNECTED" => Some(Ok(StompSession {
+                    version: frame.header("version").unwrap_or("1.0").to_string(),
+                    session: frame.header("session").map(str::to_string),
+                    server: frame.header("server").map(str::to_string),
+                    heart_beat: HeartBeat::negotiate(self.heart_beat, frame.header("heart-beat")),
+                })),
+                "ERROR" => Some(Err(eyre!(
+                    "connect rejected: {}",
+                    frame.header("message").unwrap_or_default()
+                ))),
+                _ => None,
+            }
+        })
+        .await?;
+        if !session.heart_beat.incoming.is_zero() {
+            ctx.set_receive_timeout(session.heart_beat.incoming * 3 / 2);
+        }
+        ctx.insert(session);
+        Ok(ctx)
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum StompError {
+    #[error(transparent)]
+    Transport(#[from] ReconnectTError),
+    #[error(transparent)]
+    Frame(#[from] FrameError),
+    /// The server replied with an ERROR frame.
+    #[error("server error: {}", .0.header("message").unwrap_or_default())]
+    Server(StompFrame),
+    #[error("message has no ack header")]
+    NotAckable,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AckMode {
+    #[default]
+    Auto,
+    Client,
+    ClientIndividual,
+}
+
+impl AckMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AckMode::Auto => "auto",
+            AckMode::Client => "client",
+            AckMode::ClientIndividual => "client-individual",
+        }
+    }
+}
+
+struct Subscription {
+    frame: StompFrame,
+    /// The generation of the connection it was last sent on.
+    sent: Option<u64>,
+    tx: mpsc::UnboundedSender<StompFrame>,
+}
+
+/// A STOMP client over a [`ReconnectT`] configured with [`StompHandshake`].
+///
+/// Subscriptions are re-sent with their id after every reconnect and keep delivering
+/// MESSAGE frames on the same stream. ERROR frames not tied to a receipt are delivered
+/// on [`errors`](Self::errors).
+pub struct StompClient {
+    sender: Arc<MaybePSTSender>,
+    correlator: Arc<Correlator>,
+    next_id: AtomicU64,
+    subscriptions: Mutex<HashMap<String, Subscription>>,
+    errors: ShareListener<StompFrame>,
+    generation: AtomicU64,
+}
+
+impl StompClient {
+    /// Creates the client and starts feeding it the frames of `reconnect`.
+    pub async fn attach<R: IntoClientRequest + Send + Sync>(
+        reconnect: &ReconnectT<R>,
+    ) -> EResult<Arc<Self>> {
+        let correlator = Correlator::new(reconnect.sender.clone(), |msg| {
+            let frame = StompFrame::from_message(msg).ok()??;
+            receipt_id(&frame).map(str::to_string)
+        })
+        .attach(reconnect)
+        .await?;
+        let client = Arc::new(Self {
+            sender: reconnect.sender.clone(),
+            correlator,
+            next_id: AtomicU64::new(1),
+            subscriptions: Mutex::new(HashMap::new()),
+            errors: ShareListener::default(),
+            generation: AtomicU64::new(0),
+        });
+        Driver::attach(&client, reconnect).await?;
+        Ok(client)
+    }
+
+    /// Sends `body` to `destination`.
+    pub async fn send(
+        &self,
+        destination: &str,
+        body: impl Into<Vec<u8>>,
+    ) -> Result<(), StompError> {
+        let frame = StompFrame::new("SEND")
+            .with_header("destination", destination)
+            .with_body(body);
+        self.send_frame(frame).await
+    }
+
+    pub async fn send_frame(&self, frame: StompFrame) -> Result<(), StompError> {
+        self.sender.send(frame.to_message()).await?;
+        Ok(())
+    }
+
+    /// Sends `frame` with a `receipt` header and waits for the RECEIPT.
+    pub async fn send_with_receipt(&self, frame: StompFrame) -> Result<StompFrame, StompError> {
+        let receipt = format!("receipt-{}", self.next_id.fetch_add(1, Ordering::Relaxed));
+        let frame = frame.with_header("receipt", &receipt);
+        let reply = self.correlator.request(frame.to_message(), receipt).await?;
+        let reply = StompFrame::from_message(&reply)?.ok_or(FrameError::MissingCommand)?;
+        match reply.command.as_str() {
+            "ERROR" => Err(StompError::Server(reply)),
+            _ => Ok(reply),
+        }
+    }
+
+    /// Subscribes to `destination`. The subscription is sent once connected and re-sent
+    /// after every reconnect until [`unsubscribe`](Self::unsubscribe) is called.
+    pub async fn subscribe(
+        &self,
+        destination: &str,
+        ack: AckMode,
+    ) -> Result<StompSubscription, StompError> {
+        let id = format!("sub-{}", self.next_id.fetch_add(1, Ordering::Relaxed));
+        let frame = StompFrame::new("SUBSCRIBE")
+            .with_header("id", &id)
+            .with_header("destination", destination)
+            .with_header("ack", ack.as_str());
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.subscriptions.lock().await.insert(
+            id.clone(),
+            Subscription {
+                frame: frame.clone(),
+                sent: None,
+                tx,
+            },
+        );
+
+        // holding the sender keeps a resubscribe from sending the subscription again
+        let mut sender = self.sender.lock().await;
+        let result = match sender.generation() {
+            Some(generation) => sender
+                .send(frame.to_message())
+                .await
+                .map(|()| Some(generation)),
+            None => Err(ReconnectTError::SenderNotConnected),
+        };
+        match result {
+            Ok(sent) => {
+                if let Some(subscription) = self.subscriptions.lock().await.get_mut(&id) {
+                    subscription.sent = sent;
+                }
+            }
+            Err(ReconnectTError::SenderNotConnected) => {}
+            Err(e) => {
+                self.subscriptions.lock().await.remove(&id);
+                return Err(e.into());
+            }
+        }
+        Ok(StompSubscription {
+            id,
+            stream: UnboundedReceiverStream::new(rx),
+        })
+    }
+
+    /// Unsubscribes `id` and ends its stream.
+    pub async fn unsubscribe(&self, id: &str) -> Result<(), StompError> {
+        if self.subscriptions.lock().await.remove(id).is_none() {
+            return Ok(());
+        }
+        let frame = StompFrame::new("UNSUBSCRIBE").with_header("id", id);
+        match self.sender.send(frame.to_message()).await {
+            Ok(()) | Err(ReconnectTError::SenderNotConnected) => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Acknowledges a MESSAGE received on a `client` or `client-individual` subscription.
+    pub async fn ack(&self, message: &StompFrame) -> Result<(), StompError> {
+        self.acknowledge("ACK", message).await
+    }
+
+    pub async fn nack(&self, message: &StompFrame) -> Result<(), StompError> {
+        self.acknowledge("NACK", message).await
+    }
+
+    async fn acknowledge(&self, command: &str, message: &StompFrame) -> Result<(), StompError> {
+        let id = message.header("ack").ok_or(StompError::NotAckable)?;
+        self.send_frame(StompFrame::new(command).with_header("id", id))
+            .await
+    }
+
+    /// Creates a stream of the ERROR frames not answering a receipt.
+    pub async fn errors(&self) -> UnboundedReceiverStream<StompFrame> {
+        self.errors.new_listener().await
+    }
+
+    async fn dispatch(&self, frame: StompFrame) {
+        match frame.command.as_str() {
+            "MESSAGE" => {
+                let subscriptions = self.subscriptions.lock().await;
+                let subscription = frame
+                    .header("subscription")
+                    .and_then(|id| subscriptions.get(id));
+                if let Some(subscription) = subscription {
+                    let _ = subscription.tx.send(frame);
+                }
+            }
+            "ERROR" => self.errors.notify(frame).await,
+            _ => {}
+        }
+    }
+
+    /// Re-sends the subscriptions not yet sent on the connection `generation`.
+    async fn resubscribe(&self, generation: u64) {
+        let mut sender = self.sender.lock().await;
+        if sender.generation() != Some(generation) {
+            // disconnected again
+            return;
+        }
+        let frames: Vec<StompFrame> = {
+            let mut subscriptions = self.subscriptions.lock().await;
+            subscriptions
+                .values_mut()
+                .filter(|subscription| subscription.sent != Some(generation))
+                .map(|subscription| {
+                    subscription.sent = Some(generation);
+                    subscription.frame.clone()
+                })
+                .collect()
+        };
+        for frame in frames {
+            if let Err(e) = sender.send(frame.to_message()).await {
+                tracing::warn!(error=?e, "stomp::resubscribe");
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl ProtocolClient for StompClient {
+    async fn on_message(self: Arc<Self>, msg: Message) {
+        match StompFrame::from_message(&msg) {
+            // receipts and the errors answering them go to the correlator
+            Ok(Some(frame)) if receipt_id(&frame).is_none() => self.dispatch(frame).await,
+            Ok(_) => {}
+            Err(e) => tracing::debug!(error=?e, "stomp::invalid_frame"),
+        }
+    }
+
+    async fn on_connected(self: Arc<Self>, ctx: Arc<HandshakeContext>) {
+        self.generation.store(ctx.generation(), Ordering::SeqCst);
+        if let Some(session) = ctx.get::<StompSession>() {
+            if !session.heart_beat.outgoing.is_zero() {
+                spawn_keep_alive(
+                    &self,
+                    ctx.generation(),
+                    session.heart_beat.outgoing,
+                    |this| async move { this.sender.send(Message::text("\n")).await.is_ok() },
+                );
+            }
+        }
+        self.resubscribe(ctx.generation()).await;
+    }
+
+    async fn on_disconnected(self: Arc<Self>) {}
+}
+
+impl KeepAlive for StompClient {
+    fn generation(&self) -> u64 {
+        self.generation.load(Ordering::SeqCst)
+    }
+}
+
+/// Returns the receipt a RECEIPT or ERROR frame answers.
+fn receipt_id(frame: &StompFrame) -> Option<&str> {
+    match frame.command.as_str() {
+        "RECEIPT" | "ERROR" => frame.header("receipt-id"),
+        _ => None,
+    }
+}
+
+/// The MESSAGE frames of a subscription. The stream ends when it is unsubscribed.
+pub struct StompSubscription {
+    id: String,
+    stream: UnboundedReceiverStream<StompFrame>,
+}
+
+impl StompSubscription {
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+}
+
+impl Stream for StompSubscription {
+    type Item = StompFrame;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<StompFrame>> {
+        Pin::new(&mut self.stream).poll_next(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::prelude::*;
+    use crate::protocols::stomp::{
+        AckMode, StompClient, StompError, StompFrame, StompHandshake, StompSession,
+    };
+    use crate::test_util::mock_server;
+    use futures_util::{SinkExt, StreamExt};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::sync::mpsc;
+
+    #[tokio::test]
+    async fn test_stomp() {
+        let (seen_tx, mut seen_rx) = mpsc::unbounded_channel();
+        let connections = Arc::new(AtomicUsize::new(0));
+        let url = mock_server(move |mut ws| {
+            let seen_tx = seen_tx.clone();
+            let connection = connections.fetch_add(1, Ordering::SeqCst);
+            async move {
+                let connect = ws.next().await.unwrap().unwrap();
+                let connect = StompFrame::from_message(&connect).unwrap().unwrap();
+                assert_eq!(connect.header("login"), Some("guest"));
+                let connected = StompFrame::new("CONNECTED")
+                    .with_header("version", "1.2")
+                    .with_header("heart-beat", "1000,20")
+                    .with_header("session", format!("s{connection}"));
+                ws.send(connected.to_message()).await.unwrap();
+
+                while let Some(Ok(msg)) = ws.next().await {
+                    let Some(frame) = StompFrame::from_message(&msg).unwrap() else {
+                        seen_tx
+                            .send((connection, "heart-beat".to_string()))
+                            .unwrap();
+                        continue;
+                    };
+                    seen_tx.send((connection, frame.command.clone())).unwrap();
+                    let reply = match frame.command.as_str() {
+                        "SUBSCRIBE" => StompFrame::new("MESSAGE")
+                            .with_header("subscription", frame.header("id").unwrap())
+                            .with_header("message-id", "m")
+                            .with_header("ack", format!("a{connection}"))
+                            .with_body(format!("hello {connection}")),
+                        "SEND" if frame.body_text() == Some("bad") => StompFrame::new("ERROR")
+                            .with_header("receipt-id", frame.header("receipt").unwrap())
+                            .with_header("message", "rejected"),
+                        "SEND" => StompFrame::new("RECEIPT")
+                            .with_header("receipt-id", frame.header("receipt").unwrap()),
+                        "ACK" if connection == 0 => return,
+                        _ => continue,
+                    };
+                    ws.send(reply.to_message()).await.unwrap();
+                }
+            }
+        })
+        .await;
+
+        let mut options = ReconnectOptions::default();
+        options
+            .with_handshake(Arc::new(
+                StompHandshake::new("localhost")
+                    .with_login("guest", "guest")
+                    .with_heart_beat(Duration::from_millis(10), Duration::from_millis(500)),
+            ))
+            .with_retry_policy_fn(Arc::new(|| {
+                Box::new(ConstantStrategy::new(Duration::from_millis(10)).into_iter())
+            }));
+        let reconnect = Arc::new(ReconnectT::new(url, Some(options)));
+        let client = StompClient::attach(&reconnect).await.unwrap();
+        let mut messages = client
+            .subscribe("/queue/a", AckMode::ClientIndividual)
+            .await
+            .unwrap();
+        reconnect.spawn_run();
+
+        let message = messages.next().await.unwrap();
+        assert_eq!(message.body_text(), Some("hello 0"));
+        // the server drops the first connection on ACK; the subscription is re-sent
+        client.ack(&message).await.unwrap();
+        let message = messages.next().await.unwrap();
+        assert_eq!(message.body_text(), Some("hello 1"));
+        let ctx = reconnect.handshake_context().await.unwrap();
+        let session = ctx.get::<StompSession>().unwrap();
+        assert_eq!(session.session.as_deref(), Some("s1"));
+        assert_eq!(session.heart_beat.outgoing, Duration::from_millis(20));
+        assert_eq!(session.heart_beat.incoming, Duration::from_secs(1));
+        assert_eq!(ctx.receive_timeout(), Some(Duration::from_millis(1500)));
+
+        let receipt = client
+            .send_with_receipt(
+                StompFrame::new("SEND")
+                    .with_header("destination", "/queue/b")
+                    .with_body("ok"),
+            )
+            .await
+            .unwrap();
+        assert_eq!(receipt.command, "RECEIPT");
+        let error = client
+            .send_with_receipt(
+                StompFrame::new("SEND")
+                    .with_header("destination", "/queue/b")
+                    .with_body("bad"),
+            )
+            .await;
+        assert!(
+            matches!(error, Err(StompError::Server(frame)) if frame.header("message") == Some("rejected"))
+        );
+
+        while seen_rx.recv().await != Some((1, "heart-beat".to_string())) {}
+        client.unsubscribe(messages.id()).await.unwrap();
+        assert!(messages.next().await.is_none());
+    }
+}
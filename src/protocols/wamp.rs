@@ -0,0 +1,1048 @@
+//! A WAMP v2 basic profile client, as caller, subscriber and publisher, with the JSON
+//! serialization.
+
+use crate::errors::ReconnectTError;
+use crate::handshake::{receive_until, HandshakeContext, StreamHandshake};
+use crate::maybe_sender::MaybePSTSender;
+use crate::protocols::{Driver, ProtocolClient};
+use crate::tungstenite::ReconnectT;
+use crate::types::{PSTReceiver, PSTSender};
+use async_trait::async_trait;
+use eyre::{eyre, Result as EResult};
+use futures_util::SinkExt;
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot, Mutex};
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use tokio_stream::Stream;
+use tungstenite::client::IntoClientRequest;
+use tungstenite::handshake::client::Request;
+use tungstenite::http::HeaderValue;
+use tungstenite::Message;
+
+pub const SUBPROTOCOL: &str = "wamp.2.json";
+
+/// Builds the request for `url`, asking for [`SUBPROTOCOL`].
+#[allow(clippy::result_large_err)]
+pub fn request(url: &str) -> Result<Request, tungstenite::Error> {
+    let mut request = url.into_client_request()?;
+    request.headers_mut().insert(
+        "Sec-WebSocket-Protocol",
+        HeaderValue::from_static(SUBPROTOCOL),
+    );
+    Ok(request)
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum WampMessageError {
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    #[error("invalid WAMP message: {0}")]
+    Invalid(String),
+}
+
+/// A message of the basic profile roles the client plays.
+#[derive(Clone, Debug, PartialEq)]
+pub enum WampMessage {
+    Hello {
+        realm: String,
+        details: Value,
+    },
+    Welcome {
+        session: u64,
+        details: Value,
+    },
+    Abort {
+        details: Value,
+        reason: String,
+    },
+    Goodbye {
+        details: Value,
+        reason: String,
+    },
+    Error {
+        request_type: u64,
+        request: u64,
+        details: Value,
+        error: String,
+        args: Vec<Value>,
+        kwargs: Map<String, Value>,
+    },
+    Publish {
+        request: u64,
+        options: Value,
+        topic: String,
+        args: Vec<Value>,
+        kwargs: Map<String, Value>,
+    },
+    Published {
+        request: u64,
+        publication: u64,
+    },
+    Subscribe {
+        request: u64,
+        options: Value,
+        topic: String,
+    },
+    Subscribed {
+        request: u64,
+        subscription: u64,
+    },
+    Unsubscribe {
+        request: u64,
+        subscription: u64,
+    },
+    Unsubscribed {
+        request: u64,
+    },
+    Event {
+        subscription: u64,
+        publication: u64,
+        details: Value,
+        args: Vec<Value>,
+        kwargs: Map<String, Value>,
+    },
+    Call {
+        request: u64,
+        options: Value,
+        procedure: String,
+        args: Vec<Value>,
+        kwargs: Map<String, Value>,
+    },
+    Result {
+        request: u64,
+        details: Value,
+        args: Vec<Value>,
+        kwargs: Map<String, Value>,
+    },
+}
+
+/// Appends the optional arguments, omitting the trailing empty ones.
+fn push_payload(array: &mut Vec<Value>, args: &[Value], kwargs: &Map<String, Value>) {
+    if !args.is_empty() || !kwargs.is_empty() {
+        array.push(json!(args));
+    }
+    if !kwargs.is_empty() {
+        array.push(json!(kwargs));
+    }
+}
+
+impl WampMessage {
+    pub const HELLO: u64 = 1;
+    pub const WELCOME: u64 = 2;
+    pub const ABORT: u64 = 3;
+    pub const GOODBYE: u64 = 6;
+    pub const ERROR: u64 = 8;
+    pub const PUBLISH: u64 = 16;
+    pub const PUBLISHED: u64 = 17;
+    pub const SUBSCRIBE: u64 = 32;
+    pub const SUBSCRIBED: u64 = 33;
+    pub const UNSUBSCRIBE: u64 = 34;
+    pub const UNSUBSCRIBED: u64 = 35;
+    pub const EVENT: u64 = 36;
+    pub const CALL: u64 = 48;
+    pub const RESULT: u64 = 50;
+
+    pub fn to_json(&self) -> Value {
+        let array = match self {
+            WampMessage::Hello { realm, details } => {
+                vec![json!(Self::HELLO), json!(realm), details.clone()]
+            }
+            WampMessage::Welcome { session, details } => {
+                vec![json!(Self::WELCOME), json!(session), details.clone()]
+            }
+            WampMessage::Abort { details, reason } => {
+                vec![json!(Self::ABORT), details.clone(), json!(reason)]
+            }
+            WampMessage::Goodbye { details, reason } => {
+                vec![json!(Self::GOODBYE), details.clone(), json!(reason)]
+            }
+            WampMessage::Error {
+                request_type,
+                request,
+                details,
+                error,
+                args,
+                kwargs,
+            } => {
+                let mut array = vec![
+                    json!(Self::ERROR),
+                    json!(request_type),
+                    json!(request),
+                    details.clone(),
+                    json!(error),
+                ];
+                push_payload(&mut array, args, kwargs);
+                array
+            }
+            WampMessage::Publish {
+                request,
+                options,
+                topic,
+                args,
+                kwargs,
+            } => {
+                let mut array = vec![
+                    json!(Self::PUBLISH),
+                    json!(request),
+                    options.clone(),
+                    json!(topic),
+                ];
+                push_payload(&mut array, args, kwargs);
+                array
+            }
+            WampMessage::Published {
+                request,
+                publication,
+            } => vec![json!(Self::PUBLISHED), json!(request), json!(publication)],
+            WampMessage::Subscribe {
+                request,
+                options,
+                topic,
+            } => vec![
+                json!(Self::SUBSCRIBE),
+                json!(request),
+                options.clone(),
+                json!(topic),
+            ],
+            WampMessage::Subscribed {
+                request,
+                subscription,
+            } => vec![json!(Self::SUBSCRIBED), json!(request), json!(subscription)],
+            WampMessage::Unsubscribe {
+                request,
+                subscription,
+            } => vec![
+                json!(Self::UNSUBSCRIBE),
+                json!(request),
+                json!(subscription),
+            ],
+            WampMessage::Unsubscribed { request } => {
+                vec![json!(Self::UNSUBSCRIBED), json!(request)]
+            }
+            WampMessage::Event {
+                subscription,
+                publication,
+                details,
+                args,
+                kwargs,
+            } => {
+                let mut array = vec![
+                    json!(Self::EVENT),
+                    json!(subscription),
+                    json!(publication),
+                    details.clone(),
+                ];
+                push_payload(&mut array, args, kwargs);
+                array
+            }
+            WampMessage::Call {
+                request,
+                options,
+                procedure,
+                args,
+                kwargs,
+            } => {
+                let mut array = vec![
+                    json!(Self::CALL),
+                    json!(request),
+                    options.clone(),
+                    json!(procedure),
+                ];
+                push_payload(&mut array, args, kwargs);
+                array
+            }
+            WampMessage::Result {
+                request,
+                details,
+                args,
+                kwargs,
+            } => {
+                let mut array = vec![json!(Self::RESULT), json!(request), details.clone()];
+                push_payload(&mut array, args, kwargs);
+                array
+            }
+        };
+        Value::Array(array)
+    }
+
+    pub fn from_json(value: &Value) -> Result<Self, WampMessageError> {
+        let invalid = || WampMessageError::Invalid(value.to_string());
+        let array = value.as_array().ok_or_else(invalid)?;
+        let id = |i: usize| array.get(i).and_then(Value::as_u64).ok_or_else(invalid);
+        let string = |i: usize| {
+            array
+                .get(i)
+                .and_then(Value::as_str)
+                .map(str::to_string)
+                .ok_or_else(invalid)
+        };
+        let dict = |i: usize| {
+            array
+                .get(i)
+                .filter(|v| v.is_object())
+                .cloned()
+                .ok_or_else(invalid)
+        };
+        let args = |i: usize| -> Result<Vec<Value>, WampMessageError> {
+            match array.get(i) {
+                Some(args) => Ok(serde_json::from_value(args.clone())?),
+                None => Ok(Vec::new()),
+            }
+        };
+        let kwargs = |i: usize| -> Result<Map<String, Value>, WampMessageError> {
+            match array.get(i) {
+                Some(kwargs) => Ok(serde_json::from_value(kwargs.clone())?),
+                None => Ok(Map::new()),
+            }
+        };
+
+        Ok(match id(0)? {
+            Self::HELLO => WampMessage::Hello {
+                realm: string(1)?,
+                details: dict(2)?,
+            },
+            Self::WELCOME => WampMessage::Welcome {
+                session: id(1)?,
+                details: dict(2)?,
+            },
+            Self::ABORT => WampMessage::Abort {
+                details: dict(1)?,
+                reason: string(2)?,
+            },
+            Self::GOODBYE => WampMessage::Goodbye {
+                details: dict(1)?,
+                reason: string(2)?,
+            },
+            Self::ERROR => WampMessage::Error {
+                request_type: id(1)?,
+                request: id(2)?,
+                details: dict(3)?,
+                error: string(4)?,
+                args: args(5)?,
+                kwargs: kwargs(6)?,
+            },
+            Self::PUBLISH => WampMessage::Publish {
+                request: id(1)?,
+                options: dict(2)?,
+                topic: string(3)?,
+                args: args(4)?,
+                kwargs: kwargs(5)?,
+            },
+            Self::PUBLISHED => WampMessage::Published {
+                request: id(1)?,
+                publication: id(2)?,
+            },
+            Self::SUBSCRIBE => WampMessage::Subscribe {
+                request: id(1)?,
+                options: dict(2)?,
+                topic: string(3)?,
+            },
+            Self::SUBSCRIBED => WampMessage::Subscribed {
+                request: id(1)?,
+                subscription: id(2)?,
+            },
+            Self::UNSUBSCRIBE => WampMessage::Unsubscribe {
+                request: id(1)?,
+                subscription: id(2)?,
+            },
+            Self::UNSUBSCRIBED => WampMessage::Unsubscribed { request: id(1)? },
+            Self::EVENT => WampMessage::Event {
+                subscription: id(1)?,
+                publication: id(2)?,
+                details: dict(3)?,
+                args: args(4)?,
+                kwargs: kwargs(5)?,
+            },
+            Self::CALL => WampMessage::Call {
+                request: id(1)?,
+                options: dict(2)?,
+                procedure: string(3)?,
+                args: args(4)?,
+                kwargs: kwargs(5)?,
+            },
+            Self::RESULT => WampMessage::Result {
+                request: id(1)?,
+                details: dict(2)?,
+                args: args(3)?,
+                kwargs: kwargs(4)?,
+            },
+            _ => return Err(invalid()),
+        })
+    }
+
+    pub fn decode(msg: &Message) -> Result<Self, WampMessageError> {
+        let text = msg
+            .to_text()
+            .map_err(|_| WampMessageError::Invalid("binary frame".to_string()))?;
+        Self::from_json(&serde_json::from_str(text)?)
+    }
+
+    pub fn to_message(&self) -> Message {
+        Message::text(self.to_json().to_string())
+    }
+}
+
+/// The session the router opened, from its WELCOME.
+#[derive(Clone, Debug, PartialEq)]
+pub struct WampSession {
+    pub id: u64,
+    pub details: Value,
+}
+
+/// Sends HELLO for a realm and stores the [`WampSession`] from the WELCOME in the
+/// context.
+pub struct WampHandshake {
+    realm: String,
+    details: Map<String, Value>,
+    timeout: Duration,
+}
+
+impl WampHandshake {
+    pub fn new(realm: impl Into<String>) -> Self {
+        let roles = json!({"caller": {}, "subscriber": {}, "publisher": {}});
+        let mut details = Map::new();
+        details.insert("roles".to_string(), roles);
+        Self {
+            realm: realm.into(),
+            details,
+            timeout: Duration::from_secs(10),
+        }
+    }
+
+    /// Adds `key` to the HELLO details, e.g. `authid` or `authmethods`.
+    pub fn with_detail(mut self, key: &str, value: Value) -> Self {
+        self.details.insert(key.to_string(), value);
+        self
+    }
+
+    /// Sets how long to wait for the WELCOME. Defaults to 10s.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+}
+
+#[async_trait]
+impl StreamHandshake for WampHandshake {
+    async fn handshake(
+        &self,
+        writer: &mut PSTSender,
+        reader: &mut PSTReceiver,
+    ) -> EResult<HandshakeContext> {
+        let hello = WampMessage::Hello {
+            realm: self.realm.clone(),
+            details: Value::Object(self.details.clone()),
+        };
+        writer.send(hello.to_message()).await?;
+
+        let mut ctx = HandshakeContext::new();
+        let session = receive_until(
+            reader,
+            &mut ctx,
+            self.timeout,
+            |msg| match WampMessage::decode(msg).ok()? {
+                WampMessage::Welcome { session, details } => Some(Ok(WampSession {
+                    id: session,
+                    details,
+                })),
+                WampMessage::Abort { reason, details } => {
+                    Some(Err(eyre!("session aborted: {reason} {details}")))
+                }
+                _ => None,
+            },
+        )
+        .await?;
+        ctx.insert(session);
+        Ok(ctx)
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum WampError {
+    #[error(transparent)]
+    Transport(#[from] ReconnectTError),
+    /// The router or callee answered with an ERROR.
+    #[error("WAMP error {error}")]
+    Error {
+        error: String,
+        args: Vec<Value>,
+        kwargs: Map<String, Value>,
+    },
+    #[error(transparent)]
+    Message(#[from] WampMessageError),
+}
+
+/// The arguments of a RESULT or an EVENT.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Payload {
+    pub args: Vec<Value>,
+    pub kwargs: Map<String, Value>,
+}
+
+struct SubscriptionState {
+    topic: String,
+    options: Value,
+    /// The router's subscription id on the current connection.
+    remote: Option<u64>,
+    tx: mpsc::UnboundedSender<Payload>,
+}
+
+type PendingReply = oneshot::Sender<Result<WampMessage, WampError>>;
+
+/// Configures and attaches a [`WampClient`].
+pub struct WampClientBuilder {
+    timeout: Duration,
+}
+
+impl WampClientBuilder {
+    /// Sets how long a call or subscription may take. Defaults to 10s.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub async fn attach<R: IntoClientRequest + Send + Sync>(
+        self,
+        reconnect: &ReconnectT<R>,
+    ) -> EResult<Arc<WampClient>> {
+        let client = Arc::new(WampClient {
+            sender: reconnect.sender.clone(),
+            timeout: self.timeout,
+            next_request: AtomicU64::new(1),
+            next_local: AtomicU64::new(1),
+            pending: Mutex::new(HashMap::new()),
+            subscribing: Mutex::new(HashMap::new()),
+            subscriptions: Arc::new(Mutex::new(HashMap::new())),
+        });
+        Driver::attach(&client, reconnect).await?;
+        Ok(client)
+    }
+}
+
+/// A WAMP client over a [`ReconnectT`] built with [`request`] and configured with
+/// [`WampHandshake`].
+///
+/// Subscriptions are restored in the new session after a reconnect and their streams
+/// continue. Calls in flight fail with [`ReconnectTError::Disconnected`] instead, as
+/// the router may or may not have executed them.
+pub struct WampClient {
+    sender: Arc<MaybePSTSender>,
+    timeout: Duration,
+    next_request: AtomicU64,
+    next_local: AtomicU64,
+    pending: Mutex<HashMap<u64, PendingReply>>,
+    /// The local ids of the subscriptions waiting for SUBSCRIBED, by request.
+    subscribing: Mutex<HashMap<u64, u64>>,
+    /// By the local id of each [`WampSubscription`].
+    subscriptions: Arc<Mutex<HashMap<u64, SubscriptionState>>>,
+}
+
+impl WampClient {
+    pub fn builder() -> WampClientBuilder {
+        WampClientBuilder {
+            timeout: Duration::from_secs(10),
+        }
+    }
+
+    fn next_request(&self) -> u64 {
+        self.next_request.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Sends `msg` and waits for the reply to `request`.
+    async fn request(&self, request: u64, msg: WampMessage) -> Result<WampMessage, WampError> {
+        let (tx, rx) = oneshot::channel();
+        self.pending.lock().await.insert(request, tx);
+        if let Err(e) = self.sender.send(msg.to_message()).await {
+            self.pending.lock().await.remove(&request);
+            return Err(e.into());
+        }
+        match tokio::time::timeout(self.timeout, rx).await {
+            Ok(Ok(reply)) => reply,
+            Ok(Err(_)) => Err(ReconnectTError::Disconnected.into()),
+            Err(_) => {
+                self.pending.lock().await.remove(&request);
+                Err(ReconnectTError::RequestTimeout(self.timeout).into())
+            }
+        }
+    }
+
+    /// Calls `procedure` and returns its result.
+    pub async fn call(
+        &self,
+        procedure: &str,
+        args: Vec<Value>,
+        kwargs: Map<String, Value>,
+    ) -> Result<Payload, WampError> {
+        let request = self.next_request();
+        let call = WampMessage::Call {
+            request,
+            options: json!({}),
+            procedure: procedure.to_string(),
+            args,
+            kwargs,
+        };
+        match self.request(request, call).await? {
+            WampMessage::Result { args, kwargs, .. } => Ok(Payload { args, kwargs }),
+            reply => Err(WampMessageError::Invalid(reply.to_json().to_string()).into()),
+        }
+    }
+
+    /// Publishes to `topic` without acknowledgement.
+    pub async fn publish(
+        &self,
+        topic: &str,
+        args: Vec<Value>,
+        kwargs: Map<String, Value>,
+    ) -> Result<(), WampError> {
+        let publish = WampMessage::Publish {
+            request: self.next_request(),
+            options: json!({}),
+            topic: topic.to_string(),
+            args,
+            kwargs,
+        };
+        Ok(self.sender.send(publish.to_message()).await?)
+    }
+
+    /// Publishes to `topic` and waits for the router to acknowledge it, returning the
+    /// publication id.
+    pub async fn publish_acknowledged(
+        &self,
+        topic: &str,
+        args: Vec<Value>,
+        kwargs: Map<String, Value>,
+    ) -> Result<u64, WampError> {
+        let request = self.next_request();
+        let publish = WampMessage::Publish {
+            request,
+            options: json!({"acknowledge": true}),
+            topic: topic.to_string(),
+            args,
+            kwargs,
+        };
+        match self.request(request, publish).await? {
+            WampMessage::Published { publication, .. } => Ok(publication),
+            reply => Err(WampMessageError::Invalid(reply.to_json().to_string()).into()),
+        }
+    }
+
+    /// Subscribes to `topic` with the SUBSCRIBE `options`, e.g. `{"match": "prefix"}`,
+    /// waiting for the router when connected.
+    pub async fn subscribe(
+        self: &Arc<Self>,
+        topic: &str,
+        options: Value,
+    ) -> Result<WampSubscription, WampError> {
+        let local = self.next_local.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = mpsc::unbounded_channel();
+        let state = SubscriptionState {
+            topic: topic.to_string(),
+            options,
+            remote: None,
+            tx,
+        };
+        self.subscriptions.lock().await.insert(local, state);
+
+        if self.sender.is_connected() {
+            match self.send_subscribe(local).await {
+                Ok(()) => {}
+                Err(e @ WampError::Error { .. }) => {
+                    self.subscriptions.lock().await.remove(&local);
+                    return Err(e);
+                }
+                // restored once reconnected
+                Err(e) => tracing::warn!(topic=topic, error=?e, "wamp::subscribe"),
+            }
+        }
+        Ok(WampSubscription {
+            local,
+            topic: topic.to_string(),
+            client: self.clone(),
+            stream: UnboundedReceiverStream::new(rx),
+        })
+    }
+
+    async fn send_subscribe(&self, local: u64) -> Result<(), WampError> {
+        let (topic, options) = match self.subscriptions.lock().await.get(&local) {
+            Some(state) => (state.topic.clone(), state.options.clone()),
+            None => return Ok(()),
+        };
+        let request = self.next_request();
+        let subscribe = WampMessage::Subscribe {
+            request,
+            options,
+            topic,
+        };
+        self.subscribing.lock().await.insert(request, local);
+        let reply = self.request(request, subscribe).await;
+        self.subscribing.lock().await.remove(&request);
+        match reply? {
+            WampMessage::Subscribed { .. } => Ok(()),
+            reply => Err(WampMessageError::Invalid(reply.to_json().to_string()).into()),
+        }
+    }
+
+    /// Unsubscribes `local`, telling the router once no other subscription shares its
+    /// router subscription.
+    async fn unsubscribe(&self, local: u64) -> Result<(), WampError> {
+        let remote = {
+            let mut subscriptions = self.subscriptions.lock().await;
+            let Some(remote) = subscriptions.remove(&local).and_then(|state| state.remote) else {
+                return Ok(());
+            };
+            if subscriptions
+                .values()
+                .any(|state| state.remote == Some(remote))
+            {
+                return Ok(());
+            }
+            remote
+        };
+        let request = self.next_request();
+        let unsubscribe = WampMessage::Unsubscribe {
+            request,
+            subscription: remote,
+        };
+        match self.request(request, unsubscribe).await {
+            Ok(_) | Err(WampError::Transport(_)) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn dispatch(&self, msg: WampMessage) {
+        let request = match &msg {
+            WampMessage::Event {
+                subscription,
+                args,
+                kwargs,
+                ..
+            } => {
+                let subscriptions = self.subscriptions.lock().await;
+                for state in subscriptions.values() {
+                    if state.remote == Some(*subscription) {
+                        let payload = Payload {
+                            args: args.clone(),
+                            kwargs: kwargs.clone(),
+                        };
+                        let _ = state.tx.send(payload);
+                    }
+                }
+                return;
+            }
+            WampMessage::Goodbye { reason, .. } => {
+                tracing::warn!(reason = reason, "wamp::goodbye");
+                let goodbye = WampMessage::Goodbye {
+                    details: json!({}),
+                    reason: "wamp.close.goodbye_and_out".to_string(),
+                };
+                let _ = self.sender.send(goodbye.to_message()).await;
+                return;
+            }
+            WampMessage::Subscribed {
+                request,
+                subscription,
+            } => {
+                // set before handling the next message, which may be an EVENT of it
+                if let Some(local) = self.subscribing.lock().await.remove(request) {
+                    if let Some(state) = self.subscriptions.lock().await.get_mut(&local) {
+                        state.remote = Some(*subscription);
+                    }
+                }
+                *request
+            }
+            WampMessage::Result { request, .. }
+            | WampMessage::Published { request, .. }
+            | WampMessage::Unsubscribed { request }
+            | WampMessage::Error { request, .. } => *request,
+            msg => {
+                tracing::debug!(msg=?msg, "wamp::unhandled");
+                return;
+            }
+        };
+        let Some(tx) = self.pending.lock().await.remove(&request) else {
+            return;
+        };
+        let reply = match msg {
+            WampMessage::Error {
+                error,
+                args,
+                kwargs,
+                ..
+            } => Err(WampError::Error {
+                error,
+                args,
+                kwargs,
+            }),
+            msg => Ok(msg),
+        };
+        let _ = tx.send(reply);
+    }
+}
+
+#[async_trait]
+impl ProtocolClient for WampClient {
+    async fn on_message(self: Arc<Self>, msg: Message) {
+        match WampMessage::decode(&msg) {
+            Ok(msg) => self.dispatch(msg).await,
+            Err(e) => tracing::debug!(error=?e, "wamp::invalid_message"),
+        }
+    }
+
+    async fn on_connected(self: Arc<Self>, _ctx: Arc<HandshakeContext>) {
+        let locals: Vec<u64> = self.subscriptions.lock().await.keys().copied().collect();
+        for local in locals {
+            let this = self.clone();
+            tokio::spawn(async move {
+                match this.send_subscribe(local).await {
+                    Ok(()) => {}
+                    Err(e @ WampError::Error { .. }) => {
+                        // refused, which ends the stream
+                        tracing::warn!(error=?e, "wamp::resubscribe");
+                        this.subscriptions.lock().await.remove(&local);
+                    }
+                    Err(e) => tracing::warn!(error=?e, "wamp::resubscribe"),
+                }
+            });
+        }
+    }
+
+    async fn on_disconnected(self: Arc<Self>) {
+        // dropping the senders fails the calls in flight
+        self.pending.lock().await.clear();
+        for state in self.subscriptions.lock().await.values_mut() {
+            state.remote = None;
+        }
+    }
+}
+
+/// The events of a subscription, across reconnects.
+pub struct WampSubscription {
+    local: u64,
+    topic: String,
+    client: Arc<WampClient>,
+    stream: UnboundedReceiverStream<Payload>,
+}
+
+impl WampSubscription {
+    pub fn topic(&self) -> &str {
+        &self.topic
+    }
+
+    /// Unsubscribes and ends the stream.
+    pub async fn unsubscribe(self) -> Result<(), WampError> {
+        self.client.unsubscribe(self.local).await
+    }
+}
+
+impl Stream for WampSubscription {
+    type Item = Payload;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.stream).poll_next(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::prelude::*;
+    use crate::protocols::wamp::{
+        request, WampClient, WampError, WampHandshake, WampMessage, WampSession, SUBPROTOCOL,
+    };
+    use crate::test_util::mock_server_with_subprotocol;
+    use futures_util::{SinkExt, StreamExt};
+    use serde_json::{json, Map, Value};
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::sync::{broadcast, mpsc};
+
+    #[test]
+    fn test_message_codec() {
+        let event = WampMessage::Event {
+            subscription: 5,
+            publication: 6,
+            details: json!({}),
+            args: vec![],
+            kwargs: Map::from_iter([("a".to_string(), json!(1))]),
+        };
+        assert_eq!(event.to_json(), json!([36, 5, 6, {}, [], {"a": 1}]));
+        assert_eq!(WampMessage::from_json(&event.to_json()).unwrap(), event);
+        let call = json!([48, 1, {}, "com.add"]);
+        let WampMessage::Call { args, .. } = WampMessage::from_json(&call).unwrap() else {
+            panic!("not a call");
+        };
+        assert!(args.is_empty());
+        assert!(WampMessage::from_json(&json!([99])).is_err());
+        assert!(WampMessage::from_json(&json!([2, "x", {}])).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_wamp() {
+        let sessions = Arc::new(AtomicU64::new(1));
+        // the publications, sent as EVENTs to the other sessions subscribed to the topic
+        let (broker, _) = broadcast::channel::<(u64, String, Vec<Value>)>(16);
+        let (subscribed_tx, mut subscribed_rx) = mpsc::unbounded_channel();
+        let url = mock_server_with_subprotocol(SUBPROTOCOL, move |mut ws| {
+            let session = sessions.fetch_add(1, Ordering::SeqCst);
+            let broker = broker.clone();
+            let mut publications = broker.subscribe();
+            let subscribed_tx = subscribed_tx.clone();
+            async move {
+                let hello = WampMessage::decode(&ws.next().await.unwrap().unwrap()).unwrap();
+                assert!(matches!(hello, WampMessage::Hello { realm, .. } if realm == "realm1"));
+                let welcome = WampMessage::Welcome {
+                    session,
+                    details: json!({"roles": {"broker": {}, "dealer": {}}}),
+                };
+                ws.send(welcome.to_message()).await.unwrap();
+
+                let mut topics = Vec::new();
+                loop {
+                    let msg = tokio::select! {
+                        msg = ws.next() => match msg {
+                            Some(Ok(msg)) => msg,
+                            _ => return,
+                        },
+                        publication = publications.recv() => {
+                            let (publisher, topic, args) = publication.unwrap();
+                            if publisher != session && topics.contains(&topic) {
+                                let event = WampMessage::Event {
+                                    subscription: 100 + session,
+                                    publication: 7,
+                                    details: json!({}),
+                                    args,
+                                    kwargs: Map::new(),
+                                };
+                                ws.send(event.to_message()).await.unwrap();
+                            }
+                            continue;
+                        }
+                    };
+                    let reply = match WampMessage::decode(&msg).unwrap() {
+                        WampMessage::Call {
+                            request,
+                            procedure,
+                            args,
+                            ..
+                        } => match procedure.as_str() {
+                            "com.add" => WampMessage::Result {
+                                request,
+                                details: json!({}),
+                                args: vec![json!(
+                                    args[0].as_i64().unwrap() + args[1].as_i64().unwrap()
+                                )],
+                                kwargs: Map::new(),
+                            },
+                            // drops the connection with the call in flight
+                            "com.hang" => return,
+                            _ => WampMessage::Error {
+                                request_type: WampMessage::CALL,
+                                request,
+                                details: json!({}),
+                                error: "wamp.error.no_such_procedure".to_string(),
+                                args: vec![],
+                                kwargs: Map::new(),
+                            },
+                        },
+                        WampMessage::Subscribe { request, topic, .. } => {
+                            let subscribed = WampMessage::Subscribed {
+                                request,
+                                subscription: 100 + session,
+                            };
+                            ws.send(subscribed.to_message()).await.unwrap();
+                            topics.push(topic);
+                            subscribed_tx.send(session).unwrap();
+                            continue;
+                        }
+                        WampMessage::Unsubscribe { request, .. } => {
+                            topics.clear();
+                            WampMessage::Unsubscribed { request }
+                        }
+                        WampMessage::Publish {
+                            request,
+                            options,
+                            topic,
+                            args,
+                            ..
+                        } => {
+                            // the publisher is excluded, as with `exclude_me` unset
+                            broker.send((session, topic, args)).unwrap();
+                            if options["acknowledge"] != true {
+                                continue;
+                            }
+                            WampMessage::Published {
+                                request,
+                                publication: 7,
+                            }
+                        }
+                        _ => continue,
+                    };
+                    ws.send(reply.to_message()).await.unwrap();
+                }
+            }
+        })
+        .await;
+
+        let connect = || async {
+            let mut options = ReconnectOptions::default();
+            options
+                .with_handshake(Arc::new(WampHandshake::new("realm1")))
+                .with_retry_policy_fn(Arc::new(|| {
+                    Box::new(ConstantStrategy::new(Duration::from_millis(10)).into_iter())
+                }));
+            let reconnect = Arc::new(ReconnectT::new(request(&url).unwrap(), Some(options)));
+            let client = WampClient::builder().attach(&reconnect).await.unwrap();
+            let mut status = reconnect.create_status_stream().await;
+            reconnect.spawn_run();
+            let Some(WsStreamStatus::Connected(ctx)) = status.next().await else {
+                panic!("not connected");
+            };
+            (client, status, ctx)
+        };
+        let (client, mut status, ctx) = connect().await;
+        assert_eq!(ctx.get::<WampSession>().unwrap().id, 1);
+
+        let sum = client
+            .call("com.add", vec![json!(1), json!(2)], Map::new())
+            .await
+            .unwrap();
+        assert_eq!(sum.args, vec![json!(3)]);
+        assert!(matches!(
+            client.call("com.missing", vec![], Map::new()).await,
+            Err(WampError::Error { error, .. }) if error == "wamp.error.no_such_procedure"
+        ));
+
+        let mut events = client.subscribe("com.news", json!({})).await.unwrap();
+        assert_eq!(subscribed_rx.recv().await, Some(1));
+        let (publisher, _publisher_status, _) = connect().await;
+        publisher
+            .publish("com.news", vec![json!("first")], Map::new())
+            .await
+            .unwrap();
+        assert_eq!(events.next().await.unwrap().args, vec![json!("first")]);
+
+        // the call in flight fails, the subscription is restored in the next session
+        assert!(matches!(
+            client.call("com.hang", vec![], Map::new()).await,
+            Err(WampError::Transport(ReconnectTError::Disconnected))
+        ));
+        assert!(matches!(
+            status.next().await,
+            Some(WsStreamStatus::Disconnected)
+        ));
+        assert_eq!(subscribed_rx.recv().await, Some(3));
+        let publication = publisher
+            .publish_acknowledged("com.news", vec![json!("second")], Map::new())
+            .await
+            .unwrap();
+        assert_eq!(publication, 7);
+        assert_eq!(events.next().await.unwrap().args, vec![json!("second")]);
+        events.unsubscribe().await.unwrap();
+    }
+}
@@ -0,0 +1,140 @@
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tungstenite::http::header::RETRY_AFTER;
+use tungstenite::http::StatusCode;
+use tungstenite::Error as WsError;
+
+/// What to do after a failed connection attempt.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RetryAction {
+    /// Retry after the next delay of the retry strategy.
+    Retry,
+    /// Retry after the given delay, ignoring the retry strategy.
+    RetryAfter(Duration),
+    /// Stop retrying and surface the error to the caller of `run`.
+    GiveUp,
+}
+
+impl RetryAction {
+    /// The default classification: honors `Retry-After` on HTTP 429/503 upgrade
+    /// rejections and retries everything else with the retry strategy.
+    pub fn classify(error: &WsError) -> Self {
+        match retry_after(error) {
+            Some(delay) => RetryAction::RetryAfter(delay),
+            None => RetryAction::Retry,
+        }
+    }
+}
+
+pub type RetryClassifier = Arc<dyn Fn(&WsError) -> RetryAction + Send + Sync>;
+
+/// Returns the delay requested by the server when it rejected the upgrade with
+/// 429 or 503 and a `Retry-After` header (either delay-seconds or an HTTP-date).
+pub fn retry_after(error: &WsError) -> Option<Duration> {
+    let WsError::Http(response) = error else {
+        return None;
+    };
+    if !matches!(
+        response.status(),
+        StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE
+    ) {
+        return None;
+    }
+
+    let value = response.headers().get(RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    let at = httpdate::parse_http_date(value).ok()?;
+    Some(
+        at.duration_since(SystemTime::now())
+            .unwrap_or(Duration::ZERO),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use crate::errors::ReconnectTError;
+    use crate::prelude::*;
+    use crate::retry::{retry_after, RetryAction};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+    use tokio::net::TcpListener;
+    use tungstenite::handshake::server::{ErrorResponse, Request, Response as WsResponse};
+    use tungstenite::http::{Response, StatusCode};
+    use tungstenite::Error as WsError;
+
+    fn http_error(status: u16, retry_after: Option<&str>) -> WsError {
+        let mut builder = Response::builder().status(status);
+        if let Some(value) = retry_after {
+            builder = builder.header("Retry-After", value);
+        }
+        WsError::Http(builder.body(None).unwrap())
+    }
+
+    #[test]
+    fn test_retry_after() {
+        assert_eq!(
+            retry_after(&http_error(429, Some("7"))),
+            Some(Duration::from_secs(7))
+        );
+        assert_eq!(
+            retry_after(&http_error(503, Some("Wed, 21 Oct 2015 07:28:00 GMT"))),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(retry_after(&http_error(503, None)), None);
+        assert_eq!(retry_after(&http_error(401, Some("7"))), None);
+        assert_eq!(
+            RetryAction::classify(&http_error(429, Some("3"))),
+            RetryAction::RetryAfter(Duration::from_secs(3))
+        );
+        assert_eq!(
+            RetryAction::classify(&WsError::ConnectionClosed),
+            RetryAction::Retry
+        );
+    }
+
+    #[tokio::test]
+    async fn test_run_honors_retry_after() {
+        // rejects every upgrade with 429 and `Retry-After: 1`
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let counter = attempts.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                counter.fetch_add(1, Ordering::SeqCst);
+                #[allow(clippy::result_large_err)]
+                let reject = |_: &Request, _: WsResponse| {
+                    let response: ErrorResponse = Response::builder()
+                        .status(StatusCode::TOO_MANY_REQUESTS)
+                        .header("Retry-After", "1")
+                        .body(None)
+                        .unwrap();
+                    Err(response)
+                };
+                let _ = tokio_tungstenite::accept_hdr_async(stream, reject).await;
+            }
+        });
+
+        // honors the first rejection and gives up on the second
+        let classified = AtomicUsize::new(0);
+        let mut options = ReconnectOptions::default();
+        options.with_retry_classifier(Arc::new(move |error| {
+            match classified.fetch_add(1, Ordering::SeqCst) {
+                0 => RetryAction::classify(error),
+                _ => RetryAction::GiveUp,
+            }
+        }));
+        let reconnect = ReconnectT::new(format!("ws://{addr}"), Some(options));
+
+        let start = Instant::now();
+        let result = tokio::time::timeout(Duration::from_secs(5), reconnect.run())
+            .await
+            .unwrap();
+        assert!(matches!(result, Err(ReconnectTError::GaveUp(_))));
+        assert!(start.elapsed() >= Duration::from_secs(1));
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+    }
+}
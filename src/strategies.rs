@@ -1,5 +1,8 @@
+use crate::errors::ReconnectTError;
 use rand::{rngs::StdRng, Rng, SeedableRng};
+use std::sync::Arc;
 use std::time::Duration;
+use tokio::time::Instant;
 
 /// Defines the exponential backoff strategy for retry operations.
 pub struct ExpBackoffStrategy {
@@ -53,10 +56,7 @@ impl IntoIterator for ExpBackoffStrategy {
 
     fn into_iter(self) -> Self::IntoIter {
         let init = self.min.as_secs_f64();
-        let rng = match self.seed {
-            Some(seed) => StdRng::seed_from_u64(seed),
-            None => StdRng::from_entropy(),
-        };
+        let rng = seeded_rng(self.seed);
 
         ExpBackoffIter {
             strategy: self,
@@ -107,9 +107,676 @@ pub type DurationIterator = Box<dyn DurationIteratorExt>;
 
 impl DurationIteratorExt for ExpBackoffIter {}
 
+/// A strategy that can be turned into a resettable iterator of retry delays.
+///
+/// Combinators are provided on the strategy rather than the iterator so they don't
+/// collide with the `Iterator` methods of the same name.
+pub trait BackoffStrategy:
+    IntoIterator<Item = Duration, IntoIter: DurationIteratorExt> + Sized
+{
+    /// Yields at most `n` delays, after which the retry policy gives up.
+    fn take(self, n: usize) -> TakeStrategy<Self> {
+        TakeStrategy { strategy: self, n }
+    }
+
+    /// Yields the delays of `self`, then those of `next` once `self` is exhausted.
+    fn chain<S: BackoffStrategy>(self, next: S) -> ChainStrategy<Self, S> {
+        ChainStrategy {
+            first: self,
+            second: next,
+        }
+    }
+
+    /// Stops yielding delays once `deadline` has passed since the first delay was requested.
+    /// The last delay is shortened so it does not overrun the deadline.
+    fn with_deadline(self, deadline: Duration) -> DeadlineStrategy<Self> {
+        DeadlineStrategy {
+            strategy: self,
+            deadline,
+        }
+    }
+
+    /// Limits every delay to `max`.
+    fn cap(self, max: Duration) -> CapStrategy<Self> {
+        CapStrategy {
+            strategy: self,
+            max,
+        }
+    }
+}
+
+impl BackoffStrategy for ExpBackoffStrategy {}
+
+fn seeded_rng(seed: Option<u64>) -> StdRng {
+    match seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_entropy(),
+    }
+}
+
+fn as_millis_u64(duration: Duration) -> u64 {
+    u64::try_from(duration.as_millis()).unwrap_or(u64::MAX)
+}
+
+/// Waits the same delay between every attempt.
+#[derive(Clone, Debug)]
+pub struct ConstantStrategy {
+    delay: Duration,
+}
+
+impl ConstantStrategy {
+    pub fn new(delay: Duration) -> Self {
+        Self { delay }
+    }
+}
+
+impl IntoIterator for ConstantStrategy {
+    type Item = Duration;
+    type IntoIter = ConstantIter;
+
+    fn into_iter(self) -> Self::IntoIter {
+        ConstantIter { delay: self.delay }
+    }
+}
+
+impl BackoffStrategy for ConstantStrategy {}
+
+pub struct ConstantIter {
+    delay: Duration,
+}
+
+impl Iterator for ConstantIter {
+    type Item = Duration;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        Some(self.delay)
+    }
+}
+
+impl ResetIterExt for ConstantIter {
+    fn reset(&mut self) {}
+}
+
+impl DurationIteratorExt for ConstantIter {}
+
+/// Waits `initial`, `initial + step`, `initial + 2 * step`, ...
+#[derive(Clone, Debug)]
+pub struct LinearStrategy {
+    initial: Duration,
+    step: Duration,
+}
+
+impl LinearStrategy {
+    pub fn new(initial: Duration, step: Duration) -> Self {
+        Self { initial, step }
+    }
+}
+
+impl IntoIterator for LinearStrategy {
+    type Item = Duration;
+    type IntoIter = LinearIter;
+
+    fn into_iter(self) -> Self::IntoIter {
+        LinearIter {
+            strategy: self,
+            n: 0,
+        }
+    }
+}
+
+impl BackoffStrategy for LinearStrategy {}
+
+pub struct LinearIter {
+    strategy: LinearStrategy,
+    n: u32,
+}
+
+impl Iterator for LinearIter {
+    type Item = Duration;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let step = self.strategy.step.saturating_mul(self.n);
+        self.n = self.n.saturating_add(1);
+        Some(self.strategy.initial.saturating_add(step))
+    }
+}
+
+impl ResetIterExt for LinearIter {
+    fn reset(&mut self) {
+        self.n = 0;
+    }
+}
+
+impl DurationIteratorExt for LinearIter {}
+
+/// Waits `unit` multiplied by the Fibonacci sequence: 1, 1, 2, 3, 5, 8, ...
+#[derive(Clone, Debug)]
+pub struct FibonacciStrategy {
+    unit: Duration,
+}
+
+impl FibonacciStrategy {
+    pub fn new(unit: Duration) -> Self {
+        Self { unit }
+    }
+}
+
+impl IntoIterator for FibonacciStrategy {
+    type Item = Duration;
+    type IntoIter = FibonacciIter;
+
+    fn into_iter(self) -> Self::IntoIter {
+        FibonacciIter {
+            current: self.unit,
+            next: self.unit,
+            strategy: self,
+        }
+    }
+}
+
+impl BackoffStrategy for FibonacciStrategy {}
+
+pub struct FibonacciIter {
+    strategy: FibonacciStrategy,
+    current: Duration,
+    next: Duration,
+}
+
+impl Iterator for FibonacciIter {
+    type Item = Duration;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let current = self.current;
+        self.current = self.next;
+        self.next = current.saturating_add(self.next);
+        Some(current)
+    }
+}
+
+impl ResetIterExt for FibonacciIter {
+    fn reset(&mut self) {
+        self.current = self.strategy.unit;
+        self.next = self.strategy.unit;
+    }
+}
+
+impl DurationIteratorExt for FibonacciIter {}
+
+/// AWS-style "decorrelated jitter": each delay is random between `base` and three times
+/// the previous delay, limited to `cap`. Delays have millisecond granularity.
+#[derive(Clone, Debug)]
+pub struct DecorrelatedJitterStrategy {
+    base: Duration,
+    cap: Duration,
+    seed: Option<u64>,
+}
+
+impl DecorrelatedJitterStrategy {
+    pub fn new(base: Duration, cap: Duration) -> Self {
+        Self {
+            base,
+            cap,
+            seed: None,
+        }
+    }
+
+    /// Sets the seed for random jitter generation.
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+}
+
+impl IntoIterator for DecorrelatedJitterStrategy {
+    type Item = Duration;
+    type IntoIter = DecorrelatedJitterIter;
+
+    fn into_iter(self) -> Self::IntoIter {
+        DecorrelatedJitterIter {
+            rng: seeded_rng(self.seed),
+            base: as_millis_u64(self.base),
+            cap: as_millis_u64(self.cap),
+            sleep: as_millis_u64(self.base),
+        }
+    }
+}
+
+impl BackoffStrategy for DecorrelatedJitterStrategy {}
+
+pub struct DecorrelatedJitterIter {
+    rng: StdRng,
+    base: u64,
+    cap: u64,
+    sleep: u64,
+}
+
+impl Iterator for DecorrelatedJitterIter {
+    type Item = Duration;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let upper = self.sleep.saturating_mul(3).max(self.base);
+        self.sleep = self.cap.min(self.rng.gen_range(self.base..=upper));
+        Some(Duration::from_millis(self.sleep))
+    }
+}
+
+impl ResetIterExt for DecorrelatedJitterIter {
+    fn reset(&mut self) {
+        self.sleep = self.base;
+    }
+}
+
+impl DurationIteratorExt for DecorrelatedJitterIter {}
+
+/// AWS-style "full jitter": each delay is random between zero and
+/// `min(cap, base * 2^attempt)`. Delays have millisecond granularity.
+#[derive(Clone, Debug)]
+pub struct FullJitterStrategy {
+    base: Duration,
+    cap: Duration,
+    seed: Option<u64>,
+}
+
+impl FullJitterStrategy {
+    pub fn new(base: Duration, cap: Duration) -> Self {
+        Self {
+            base,
+            cap,
+            seed: None,
+        }
+    }
+
+    /// Sets the seed for random jitter generation.
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+}
+
+impl IntoIterator for FullJitterStrategy {
+    type Item = Duration;
+    type IntoIter = FullJitterIter;
+
+    fn into_iter(self) -> Self::IntoIter {
+        FullJitterIter {
+            rng: seeded_rng(self.seed),
+            base: as_millis_u64(self.base),
+            cap: as_millis_u64(self.cap),
+            pow: 0,
+        }
+    }
+}
+
+impl BackoffStrategy for FullJitterStrategy {}
+
+pub struct FullJitterIter {
+    rng: StdRng,
+    base: u64,
+    cap: u64,
+    pow: u32,
+}
+
+impl Iterator for FullJitterIter {
+    type Item = Duration;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let exp = 1u64.checked_shl(self.pow).unwrap_or(u64::MAX);
+        let upper = self.cap.min(self.base.saturating_mul(exp));
+        self.pow = self.pow.saturating_add(1);
+        Some(Duration::from_millis(self.rng.gen_range(0..=upper)))
+    }
+}
+
+impl ResetIterExt for FullJitterIter {
+    fn reset(&mut self) {
+        self.pow = 0;
+    }
+}
+
+impl DurationIteratorExt for FullJitterIter {}
+
+/// See [`BackoffStrategy::take`].
+#[derive(Clone, Debug)]
+pub struct TakeStrategy<S> {
+    strategy: S,
+    n: usize,
+}
+
+impl<S: BackoffStrategy> IntoIterator for TakeStrategy<S> {
+    type Item = Duration;
+    type IntoIter = TakeIter<S::IntoIter>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        TakeIter {
+            inner: self.strategy.into_iter(),
+            n: self.n,
+            remaining: self.n,
+        }
+    }
+}
+
+impl<S: BackoffStrategy> BackoffStrategy for TakeStrategy<S> {}
+
+pub struct TakeIter<I> {
+    inner: I,
+    n: usize,
+    remaining: usize,
+}
+
+impl<I: DurationIteratorExt> Iterator for TakeIter<I> {
+    type Item = Duration;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        self.inner.next()
+    }
+}
+
+impl<I: DurationIteratorExt> ResetIterExt for TakeIter<I> {
+    fn reset(&mut self) {
+        self.remaining = self.n;
+        self.inner.reset();
+    }
+}
+
+impl<I: DurationIteratorExt> DurationIteratorExt for TakeIter<I> {}
+
+/// See [`BackoffStrategy::chain`].
+#[derive(Clone, Debug)]
+pub struct ChainStrategy<A, B> {
+    first: A,
+    second: B,
+}
+
+impl<A: BackoffStrategy, B: BackoffStrategy> IntoIterator for ChainStrategy<A, B> {
+    type Item = Duration;
+    type IntoIter = ChainIter<A::IntoIter, B::IntoIter>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        ChainIter {
+            first: self.first.into_iter(),
+            second: self.second.into_iter(),
+            first_done: false,
+        }
+    }
+}
+
+impl<A: BackoffStrategy, B: BackoffStrategy> BackoffStrategy for ChainStrategy<A, B> {}
+
+pub struct ChainIter<A, B> {
+    first: A,
+    second: B,
+    first_done: bool,
+}
+
+impl<A: DurationIteratorExt, B: DurationIteratorExt> Iterator for ChainIter<A, B> {
+    type Item = Duration;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if !self.first_done {
+            match self.first.next() {
+                Some(delay) => return Some(delay),
+                None => self.first_done = true,
+            }
+        }
+        self.second.next()
+    }
+}
+
+impl<A: DurationIteratorExt, B: DurationIteratorExt> ResetIterExt for ChainIter<A, B> {
+    fn reset(&mut self) {
+        self.first.reset();
+        self.second.reset();
+        self.first_done = false;
+    }
+}
+
+impl<A: DurationIteratorExt, B: DurationIteratorExt> DurationIteratorExt for ChainIter<A, B> {}
+
+/// See [`BackoffStrategy::with_deadline`].
+#[derive(Clone, Debug)]
+pub struct DeadlineStrategy<S> {
+    strategy: S,
+    deadline: Duration,
+}
+
+impl<S: BackoffStrategy> IntoIterator for DeadlineStrategy<S> {
+    type Item = Duration;
+    type IntoIter = DeadlineIter<S::IntoIter>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        DeadlineIter {
+            inner: self.strategy.into_iter(),
+            deadline: self.deadline,
+            started: None,
+        }
+    }
+}
+
+impl<S: BackoffStrategy> BackoffStrategy for DeadlineStrategy<S> {}
+
+pub struct DeadlineIter<I> {
+    inner: I,
+    deadline: Duration,
+    started: Option<Instant>,
+}
+
+impl<I: DurationIteratorExt> Iterator for DeadlineIter<I> {
+    type Item = Duration;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let started = *self.started.get_or_insert_with(Instant::now);
+        let remaining = self.deadline.checked_sub(started.elapsed())?;
+        if remaining.is_zero() {
+            return None;
+        }
+        self.inner.next().map(|delay| delay.min(remaining))
+    }
+}
+
+impl<I: DurationIteratorExt> ResetIterExt for DeadlineIter<I> {
+    fn reset(&mut self) {
+        self.started = None;
+        self.inner.reset();
+    }
+}
+
+impl<I: DurationIteratorExt> DurationIteratorExt for DeadlineIter<I> {}
+
+/// See [`BackoffStrategy::cap`].
+#[derive(Clone, Debug)]
+pub struct CapStrategy<S> {
+    strategy: S,
+    max: Duration,
+}
+
+impl<S: BackoffStrategy> IntoIterator for CapStrategy<S> {
+    type Item = Duration;
+    type IntoIter = CapIter<S::IntoIter>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        CapIter {
+            inner: self.strategy.into_iter(),
+            max: self.max,
+        }
+    }
+}
+
+impl<S: BackoffStrategy> BackoffStrategy for CapStrategy<S> {}
+
+pub struct CapIter<I> {
+    inner: I,
+    max: Duration,
+}
+
+impl<I: DurationIteratorExt> Iterator for CapIter<I> {
+    type Item = Duration;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next().map(|delay| delay.min(self.max))
+    }
+}
+
+impl<I: DurationIteratorExt> ResetIterExt for CapIter<I> {
+    fn reset(&mut self) {
+        self.inner.reset();
+    }
+}
+
+impl<I: DurationIteratorExt> DurationIteratorExt for CapIter<I> {}
+
+/// The outcome of consulting a [`RetryPolicy`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RetryDecision {
+    /// Reconnect after the given delay.
+    Retry(Duration),
+    /// Stop reconnecting; `run` returns the error.
+    GiveUp,
+}
+
+/// Decides whether and when to reconnect after a failure.
+pub trait RetryPolicy: Send + Sync {
+    /// Called after every failure. `attempt` counts consecutive failures starting at 1,
+    /// `uptime` is how long the connection stayed up if it failed after being established.
+    fn next_retry(
+        &mut self,
+        attempt: u32,
+        error: &ReconnectTError,
+        uptime: Option<Duration>,
+    ) -> RetryDecision;
+
+    /// Called once a connection has been established and the handshake succeeded.
+    fn on_connected(&mut self);
+}
+
+/// Duration iterators reconnect immediately after an established connection drops and
+/// back off on consecutive failures, giving up once the iterator is exhausted.
+impl<T: DurationIteratorExt + ?Sized> RetryPolicy for T {
+    fn next_retry(
+        &mut self,
+        _attempt: u32,
+        _error: &ReconnectTError,
+        uptime: Option<Duration>,
+    ) -> RetryDecision {
+        if uptime.is_some() {
+            return RetryDecision::Retry(Duration::ZERO);
+        }
+        match self.next() {
+            Some(delay) => RetryDecision::Retry(delay),
+            None => RetryDecision::GiveUp,
+        }
+    }
+
+    fn on_connected(&mut self) {
+        self.reset();
+    }
+}
+
+impl RetryPolicy for DurationIterator {
+    fn next_retry(
+        &mut self,
+        attempt: u32,
+        error: &ReconnectTError,
+        uptime: Option<Duration>,
+    ) -> RetryDecision {
+        self.as_mut().next_retry(attempt, error, uptime)
+    }
+
+    fn on_connected(&mut self) {
+        self.as_mut().on_connected()
+    }
+}
+
+pub type RetryPolicyFn = Arc<dyn Fn() -> Box<dyn RetryPolicy> + Send + Sync>;
+
 #[cfg(test)]
 mod test {
-    use crate::strategies::{ExpBackoffStrategy, ResetIterExt};
+    use crate::errors::ReconnectTError;
+    use crate::strategies::{
+        BackoffStrategy, ConstantStrategy, DecorrelatedJitterStrategy, ExpBackoffStrategy,
+        FibonacciStrategy, FullJitterStrategy, LinearStrategy, ResetIterExt, RetryDecision,
+        RetryPolicy,
+    };
+    use std::time::Duration;
+
+    fn millis<S: BackoffStrategy>(strategy: S, n: usize) -> Vec<u128> {
+        strategy
+            .into_iter()
+            .take(n)
+            .map(|d| d.as_millis())
+            .collect()
+    }
+
+    #[test]
+    fn test_deterministic_strategies() {
+        assert_eq!(
+            millis(ConstantStrategy::new(Duration::from_millis(500)), 3),
+            [500, 500, 500]
+        );
+        assert_eq!(
+            millis(
+                LinearStrategy::new(Duration::from_secs(1), Duration::from_millis(500)),
+                4
+            ),
+            [1000, 1500, 2000, 2500]
+        );
+        assert_eq!(
+            millis(FibonacciStrategy::new(Duration::from_millis(100)), 7),
+            [100, 100, 200, 300, 500, 800, 1300]
+        );
+    }
+
+    #[test]
+    fn test_seeded_jitter_strategies() {
+        let decorrelated =
+            DecorrelatedJitterStrategy::new(Duration::from_millis(100), Duration::from_secs(10))
+                .with_seed(42);
+        assert_eq!(
+            millis(decorrelated, 8),
+            [209, 436, 141, 234, 612, 328, 445, 274]
+        );
+
+        let full = FullJitterStrategy::new(Duration::from_millis(100), Duration::from_secs(3))
+            .with_seed(42);
+        assert_eq!(millis(full, 8), [53, 109, 255, 325, 664, 2213, 2548, 2797]);
+    }
+
+    #[test]
+    fn test_combinators() {
+        let strategy = FibonacciStrategy::new(Duration::from_secs(1))
+            .take(4)
+            .chain(ConstantStrategy::new(Duration::from_secs(10)).take(2))
+            .cap(Duration::from_secs(2));
+        let mut iter = strategy.into_iter();
+        let delays: Vec<u64> = iter.by_ref().map(|d| d.as_secs()).collect();
+        assert_eq!(delays, [1, 1, 2, 2, 2, 2]);
+
+        iter.reset();
+        let delays: Vec<u64> = iter.map(|d| d.as_secs()).collect();
+        assert_eq!(delays, [1, 1, 2, 2, 2, 2]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_with_deadline() {
+        let mut iter = ConstantStrategy::new(Duration::from_secs(4))
+            .with_deadline(Duration::from_secs(10))
+            .into_iter();
+
+        assert_eq!(iter.next(), Some(Duration::from_secs(4)));
+        tokio::time::advance(Duration::from_secs(4)).await;
+        assert_eq!(iter.next(), Some(Duration::from_secs(4)));
+        tokio::time::advance(Duration::from_secs(4)).await;
+        // shortened to the time left before the deadline
+        assert_eq!(iter.next(), Some(Duration::from_secs(2)));
+        tokio::time::advance(Duration::from_secs(2)).await;
+        assert_eq!(iter.next(), None);
+
+        iter.reset();
+        assert_eq!(iter.next(), Some(Duration::from_secs(4)));
+    }
 
     #[tokio::test]
     async fn test_reset() {
@@ -129,4 +796,28 @@ mod test {
             println!("{:?}", iter.next());
         }
     }
+
+    #[test]
+    fn test_iterator_retry_policy() {
+        let mut policy = ExpBackoffStrategy::new(Duration::from_secs(1), 2.0, 0.0).into_iter();
+        let error = ReconnectTError::ConnectionClosed;
+        assert_eq!(
+            policy.next_retry(1, &error, None),
+            RetryDecision::Retry(Duration::from_secs(1))
+        );
+        assert_eq!(
+            policy.next_retry(2, &error, None),
+            RetryDecision::Retry(Duration::from_secs(2))
+        );
+        // a dropped connection reconnects immediately
+        assert_eq!(
+            policy.next_retry(1, &error, Some(Duration::from_secs(30))),
+            RetryDecision::Retry(Duration::ZERO)
+        );
+        policy.on_connected();
+        assert_eq!(
+            policy.next_retry(1, &error, None),
+            RetryDecision::Retry(Duration::from_secs(1))
+        );
+    }
 }
@@ -0,0 +1,411 @@
+use crate::errors::ReconnectTError;
+use crate::maybe_sender::MaybePSTSender;
+use eyre::Result as EResult;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{oneshot, Mutex};
+use tungstenite::Message;
+
+/// How a subscription is confirmed by the server.
+#[derive(Clone)]
+pub struct Confirmation {
+    timeout: Duration,
+    matcher: Arc<dyn Fn(&Message) -> bool + Send + Sync>,
+}
+
+impl Confirmation {
+    /// The subscription is confirmed by the first message matching `matcher` within `timeout`.
+    pub fn new<F>(timeout: Duration, matcher: F) -> Self
+    where
+        F: Fn(&Message) -> bool + Send + Sync + 'static,
+    {
+        Self {
+            timeout,
+            matcher: Arc::new(matcher),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SubscriptionStatus {
+    /// Sent, or waiting for a connection, and not confirmed yet.
+    Pending,
+    /// Confirmed by the server, or sent if no confirmation is expected.
+    Active,
+    /// The confirmation did not arrive in time on the current connection.
+    Unconfirmed,
+}
+
+struct Subscription {
+    key: String,
+    msg: Message,
+    confirmation: Option<Confirmation>,
+    status: SubscriptionStatus,
+    /// The generation of the connection the message was last sent on.
+    sent: Option<u64>,
+}
+
+struct PendingConfirmation {
+    matcher: Arc<dyn Fn(&Message) -> bool + Send + Sync>,
+    confirmed: oneshot::Sender<()>,
+    generation: u64,
+}
+
+/// Subscriptions that are re-sent after every successful handshake.
+pub struct SubscriptionManager {
+    sender: Arc<MaybePSTSender>,
+    subscriptions: Mutex<Vec<Subscription>>,
+    pending: Mutex<Vec<PendingConfirmation>>,
+}
+
+impl SubscriptionManager {
+    pub(crate) fn new(sender: Arc<MaybePSTSender>) -> Self {
+        Self {
+            sender,
+            subscriptions: Mutex::new(Vec::new()),
+            pending: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Registers `msg` under `key` and sends it if connected. It is re-sent after every reconnect
+    /// until [`unsubscribe`](Self::unsubscribe) is called. Subscribing to an existing key
+    /// replaces its message.
+    pub async fn subscribe(
+        &self,
+        key: impl Into<String>,
+        msg: Message,
+    ) -> EResult<(), ReconnectTError> {
+        self.insert(key.into(), msg, None).await?;
+        Ok(())
+    }
+
+    /// Like [`subscribe`](Self::subscribe), but waits for the server to confirm it when connected.
+    /// When not connected the subscription is stored and confirmed after the next handshake.
+    pub async fn subscribe_confirmed(
+        &self,
+        key: impl Into<String>,
+        msg: Message,
+        confirmation: Confirmation,
+    ) -> EResult<(), ReconnectTError> {
+        let key = key.into();
+        let timeout = confirmation.timeout;
+        let Some(confirmed) = self.insert(key.clone(), msg, Some(confirmation)).await? else {
+            return Ok(());
+        };
+
+        let status = match tokio::time::timeout(timeout, confirmed).await {
+            Ok(Ok(())) => SubscriptionStatus::Active,
+            _ => SubscriptionStatus::Unconfirmed,
+        };
+        self.set_status(&key, status).await;
+        match status {
+            SubscriptionStatus::Active => Ok(()),
+            _ => Err(ReconnectTError::SubscriptionNotConfirmed(key)),
+        }
+    }
+
+    /// Forgets the subscription under `key` and sends `msg` if connected.
+    pub async fn unsubscribe(&self, key: &str, msg: Message) -> EResult<(), ReconnectTError> {
+        self.subscriptions.lock().await.retain(|sub| sub.key != key);
+        match self.sender.send(msg).await {
+            Err(ReconnectTError::SenderNotConnected) => Ok(()),
+            result => result,
+        }
+    }
+
+    /// Returns the status of the subscription under `key`.
+    pub async fn status(&self, key: &str) -> Option<SubscriptionStatus> {
+        let subscriptions = self.subscriptions.lock().await;
+        subscriptions
+            .iter()
+            .find(|sub| sub.key == key)
+            .map(|sub| sub.status)
+    }
+
+    /// Returns the keys of all subscriptions, in subscription order.
+    pub async fn keys(&self) -> Vec<String> {
+        let subscriptions = self.subscriptions.lock().await;
+        subscriptions.iter().map(|sub| sub.key.clone()).collect()
+    }
+
+    /// Stores the subscription and sends it if connected. Returns the confirmation
+    /// receiver if the message was sent and a confirmation is expected.
+    async fn insert(
+        &self,
+        key: String,
+        msg: Message,
+        confirmation: Option<Confirmation>,
+    ) -> EResult<Option<oneshot::Receiver<()>>, ReconnectTError> {
+        {
+            let mut subscriptions = self.subscriptions.lock().await;
+            let sub = Subscription {
+                key: key.clone(),
+                msg: msg.clone(),
+                confirmation: confirmation.clone(),
+                status: SubscriptionStatus::Pending,
+                sent: None,
+            };
+            match subscriptions.iter_mut().find(|sub| sub.key == key) {
+                Some(existing) => *existing = sub,
+                None => subscriptions.push(sub),
+            }
+        }
+
+        // holding the sender keeps the connection, and so the generation, fixed until the
+        // subscription is marked as sent
+        let mut sender = self.sender.lock().await;
+        let Some(generation) = sender.generation() else {
+            return Ok(None);
+        };
+        let confirmed = match &confirmation {
+            Some(confirmation) => Some(self.expect(confirmation, generation).await),
+            None => None,
+        };
+        sender.send(msg).await?;
+        self.mark_sent(&key, generation, confirmed.is_none()).await;
+        Ok(confirmed)
+    }
+
+    async fn expect(&self, confirmation: &Confirmation, generation: u64) -> oneshot::Receiver<()> {
+        let (tx, rx) = oneshot::channel();
+        self.pending.lock().await.push(PendingConfirmation {
+            matcher: confirmation.matcher.clone(),
+            confirmed: tx,
+            generation,
+        });
+        rx
+    }
+
+    async fn mark_sent(&self, key: &str, generation: u64, active: bool) {
+        let mut subscriptions = self.subscriptions.lock().await;
+        if let Some(sub) = subscriptions.iter_mut().find(|sub| sub.key == key) {
+            sub.sent = Some(generation);
+            if active {
+                sub.status = SubscriptionStatus::Active;
+            }
+        }
+    }
+
+    async fn set_status(&self, key: &str, status: SubscriptionStatus) {
+        let mut subscriptions = self.subscriptions.lock().await;
+        if let Some(sub) = subscriptions.iter_mut().find(|sub| sub.key == key) {
+            sub.status = status;
+        }
+    }
+
+    /// Re-sends the subscriptions not yet sent on the connection `generation` after a
+    /// successful handshake.
+    pub(crate) async fn replay(self: &Arc<Self>, generation: u64) {
+        self.pending
+            .lock()
+            .await
+            .retain(|p| p.generation == generation);
+        let keys: Vec<String> = {
+            let mut subscriptions = self.subscriptions.lock().await;
+            subscriptions
+                .iter_mut()
+                .filter(|sub| sub.sent != Some(generation))
+                .map(|sub| {
+                    sub.status = SubscriptionStatus::Pending;
+                    sub.key.clone()
+                })
+                .collect()
+        };
+
+        for key in keys {
+            let mut sender = self.sender.lock().await;
+            if sender.generation() != Some(generation) {
+                // disconnected again, the next handshake replays
+                return;
+            }
+            let (msg, confirmation) = {
+                let subscriptions = self.subscriptions.lock().await;
+                match subscriptions.iter().find(|sub| sub.key == key) {
+                    Some(sub) if sub.sent != Some(generation) => {
+                        (sub.msg.clone(), sub.confirmation.clone())
+                    }
+                    // unsubscribed or sent by a concurrent subscribe
+                    _ => continue,
+                }
+            };
+            let confirmed = match &confirmation {
+                Some(confirmation) => Some(self.expect(confirmation, generation).await),
+                None => None,
+            };
+            if let Err(e) = sender.send(msg).await {
+                tracing::warn!(key=key, error=?e, "subscriptions::replay");
+                continue;
+            }
+            self.mark_sent(&key, generation, confirmed.is_none()).await;
+            drop(sender);
+
+            if let (Some(confirmation), Some(confirmed)) = (confirmation, confirmed) {
+                let this = self.clone();
+                tokio::spawn(async move {
+                    let status = match tokio::time::timeout(confirmation.timeout, confirmed).await {
+                        Ok(Ok(())) => SubscriptionStatus::Active,
+                        _ => {
+                            tracing::warn!(key = key, "subscriptions::unconfirmed");
+                            SubscriptionStatus::Unconfirmed
+                        }
+                    };
+                    this.set_status(&key, status).await;
+                });
+            }
+        }
+    }
+
+    /// Resolves pending confirmations matching an inbound message.
+    pub(crate) async fn on_message(&self, msg: &Message) {
+        let mut pending = self.pending.lock().await;
+        if pending.is_empty() {
+            return;
+        }
+        if let Some(index) = pending.iter().position(|p| (p.matcher)(msg)) {
+            let _ = pending.swap_remove(index).confirmed.send(());
+        }
+        pending.retain(|p| !p.confirmed.is_closed());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::prelude::*;
+    use crate::test_util::mock_server;
+    use futures_util::{SinkExt, StreamExt};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::sync::mpsc;
+    use tungstenite::Message;
+
+    #[tokio::test]
+    async fn test_replay_after_reconnect() {
+        let (seen_tx, mut seen_rx) = mpsc::unbounded_channel();
+        let connections = Arc::new(AtomicUsize::new(0));
+        let url = mock_server(move |mut ws| {
+            let seen_tx = seen_tx.clone();
+            let connection = connections.fetch_add(1, Ordering::SeqCst);
+            async move {
+                while let Some(Ok(msg)) = ws.next().await {
+                    let text = msg.to_text().unwrap().to_string();
+                    seen_tx.send((connection, text.clone())).unwrap();
+                    if let Some(topic) = text.strip_prefix("sub ") {
+                        ws.send(Message::text(format!("ok {topic}"))).await.unwrap();
+                    }
+                    if text == "sub b" && connection == 0 {
+                        // drop the first connection once both subscriptions arrived
+                        return;
+                    }
+                }
+            }
+        })
+        .await;
+
+        let mut options = ReconnectOptions::default();
+        options.with_retry_policy_fn(Arc::new(|| {
+            Box::new(ConstantStrategy::new(Duration::from_millis(10)).into_iter())
+        }));
+        let reconnect = Arc::new(ReconnectT::new(url, Some(options)));
+        let mut status = reconnect.create_status_stream().await;
+        reconnect.spawn_run();
+        assert!(matches!(
+            status.next().await,
+            Some(WsStreamStatus::Connected(_))
+        ));
+
+        let subscriptions = reconnect.subscriptions();
+        subscriptions
+            .subscribe("a", Message::text("sub a"))
+            .await
+            .unwrap();
+        subscriptions
+            .subscribe_confirmed(
+                "b",
+                Message::text("sub b"),
+                Confirmation::new(Duration::from_secs(1), |msg| {
+                    msg.to_text().is_ok_and(|t| t == "ok b")
+                }),
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            subscriptions.status("b").await,
+            Some(SubscriptionStatus::Active)
+        );
+
+        let mut seen = Vec::new();
+        while seen.len() < 4 {
+            seen.push(seen_rx.recv().await.unwrap());
+        }
+        assert_eq!(
+            seen,
+            [
+                (0, "sub a".to_string()),
+                (0, "sub b".to_string()),
+                (1, "sub a".to_string()),
+                (1, "sub b".to_string()),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_replay_keeps_current_connection() {
+        let (seen_tx, mut seen_rx) = mpsc::unbounded_channel();
+        let url = mock_server(move |mut ws| {
+            let seen_tx = seen_tx.clone();
+            async move {
+                while let Some(Ok(msg)) = ws.next().await {
+                    let text = msg.to_text().unwrap().to_string();
+                    seen_tx.send(text.clone()).unwrap();
+                    if text == "sub b" {
+                        // confirm only after the replay below
+                        tokio::time::sleep(Duration::from_millis(100)).await;
+                        ws.send(Message::text("ok b")).await.unwrap();
+                    }
+                }
+            }
+        })
+        .await;
+
+        let reconnect = Arc::new(ReconnectT::new(url, None));
+        let mut status = reconnect.create_status_stream().await;
+        reconnect.spawn_run();
+        let Some(WsStreamStatus::Connected(ctx)) = status.next().await else {
+            panic!("not connected");
+        };
+
+        let subscriptions = reconnect.subscriptions().clone();
+        subscriptions
+            .subscribe("a", Message::text("sub a"))
+            .await
+            .unwrap();
+        let confirmed = tokio::spawn({
+            let subscriptions = subscriptions.clone();
+            async move {
+                subscriptions
+                    .subscribe_confirmed(
+                        "b",
+                        Message::text("sub b"),
+                        Confirmation::new(Duration::from_secs(1), |msg| {
+                            msg.to_text().is_ok_and(|t| t == "ok b")
+                        }),
+                    )
+                    .await
+            }
+        });
+        assert_eq!(seen_rx.recv().await.unwrap(), "sub a");
+        assert_eq!(seen_rx.recv().await.unwrap(), "sub b");
+
+        // a replay for the connection both were sent on neither re-sends them nor
+        // drops the pending confirmation
+        subscriptions.replay(ctx.generation()).await;
+        confirmed.await.unwrap().unwrap();
+        reconnect.sender.send(Message::text("end")).await.unwrap();
+        assert_eq!(seen_rx.recv().await.unwrap(), "end");
+        assert_eq!(
+            subscriptions.status("a").await,
+            Some(SubscriptionStatus::Active)
+        );
+    }
+}
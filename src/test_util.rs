@@ -0,0 +1,54 @@
+use std::future::Future;
+use tokio::net::{TcpListener, TcpStream};
+use tokio_tungstenite::WebSocketStream;
+use tungstenite::handshake::server::{Request, Response};
+use tungstenite::http::HeaderValue;
+
+/// Starts a local WebSocket server running `handler` for every accepted connection
+/// and returns its `ws://` url.
+pub(crate) async fn mock_server<F, Fut>(handler: F) -> String
+where
+    F: Fn(WebSocketStream<TcpStream>) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    serve(None, handler).await
+}
+
+/// Like [`mock_server`], selecting `subprotocol` in the handshake response.
+pub(crate) async fn mock_server_with_subprotocol<F, Fut>(
+    subprotocol: &'static str,
+    handler: F,
+) -> String
+where
+    F: Fn(WebSocketStream<TcpStream>) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    serve(Some(subprotocol), handler).await
+}
+
+async fn serve<F, Fut>(subprotocol: Option<&'static str>, handler: F) -> String
+where
+    F: Fn(WebSocketStream<TcpStream>) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            #[allow(clippy::result_large_err)]
+            let select = |_: &Request, mut response: Response| {
+                if let Some(subprotocol) = subprotocol {
+                    response.headers_mut().insert(
+                        "Sec-WebSocket-Protocol",
+                        HeaderValue::from_static(subprotocol),
+                    );
+                }
+                Ok(response)
+            };
+            if let Ok(ws) = tokio_tungstenite::accept_hdr_async(stream, select).await {
+                tokio::spawn(handler(ws));
+            }
+        }
+    });
+    format!("ws://{addr}")
+}
@@ -1,12 +1,21 @@
 use crate::config::ReconnectOptions;
+use crate::decompression::DecompressionError;
 use crate::errors::ReconnectTError;
+use crate::extension::StreamEvent;
+use crate::handshake::HandshakeContext;
 use crate::maybe_sender::MaybePSTSender;
 use crate::prelude::{ExtensionType, ShareListener, WsStreamStatus};
+use crate::retry::RetryAction;
+use crate::strategies::{RetryDecision, RetryPolicy};
+use crate::subscriptions::SubscriptionManager;
 use crate::types::{PSTReceiver, PSTSender, WsTcpStream};
 use eyre::Result as EResult;
 use futures_util::StreamExt;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::net::TcpStream;
+use tokio::sync::{watch, RwLock};
 use tokio::time::{interval_at, Instant};
 use tokio_stream::wrappers::UnboundedReceiverStream;
 use tokio_tungstenite::tungstenite::Message;
@@ -18,26 +27,94 @@ use tungstenite::handshake::client::{Request, Response};
 use tungstenite::protocol::WebSocketConfig;
 use tungstenite::Error;
 
+/// Stops a [`ReconnectT`] from reconnecting, e.g. when the server forbids it.
+#[derive(Clone)]
+pub struct StopHandle(Arc<watch::Sender<Option<String>>>);
+
+impl Default for StopHandle {
+    fn default() -> Self {
+        Self(Arc::new(watch::Sender::new(None)))
+    }
+}
+
+impl StopHandle {
+    /// Drops the connection and ends `run` with [`ReconnectTError::Stopped`] instead of
+    /// reconnecting. Later calls keep the first reason.
+    pub fn stop(&self, reason: impl Into<String>) {
+        self.0.send_if_modified(|stopped| {
+            if stopped.is_some() {
+                return false;
+            }
+            *stopped = Some(reason.into());
+            true
+        });
+    }
+
+    /// Returns why reconnecting was stopped, if it was.
+    pub fn reason(&self) -> Option<String> {
+        self.0.borrow().clone()
+    }
+
+    async fn stopped(&self) -> String {
+        let mut stopped = self.0.subscribe();
+        let reason = stopped
+            .wait_for(Option::is_some)
+            .await
+            .map(|reason| reason.clone().unwrap_or_default());
+        match reason {
+            Ok(reason) => reason,
+            // the sender lives as long as `self`
+            Err(_) => std::future::pending().await,
+        }
+    }
+}
+
 pub struct ReconnectT<R> {
     pub request: Box<R>,
     pub option: ReconnectOptions,
     pub sender: Arc<MaybePSTSender>,
     receive_stream: Arc<ShareListener<Message>>,
     status_stream: Arc<ShareListener<WsStreamStatus>>,
+    events: Arc<ShareListener<StreamEvent>>,
+    subscriptions: Arc<SubscriptionManager>,
+    generation: AtomicU64,
+    handshake_context: RwLock<Option<Arc<HandshakeContext>>>,
+    stop: StopHandle,
 }
 
 impl<R: IntoClientRequest + Send + Sync> ReconnectT<R> {
     pub fn new(request: R, option: Option<ReconnectOptions>) -> Self {
         let option = option.unwrap_or_default();
+        let sender = Arc::new(MaybePSTSender::default());
         Self {
             request: Box::new(request),
             option,
-            sender: Arc::new(MaybePSTSender::default()),
+            subscriptions: Arc::new(SubscriptionManager::new(sender.clone())),
+            sender,
             receive_stream: Arc::new(ShareListener::default()),
             status_stream: Arc::new(ShareListener::default()),
+            events: Arc::new(ShareListener::default()),
+            generation: AtomicU64::new(0),
+            handshake_context: RwLock::new(None),
+            stop: StopHandle::default(),
         }
     }
 
+    /// Returns the context of the most recent successful handshake.
+    pub async fn handshake_context(&self) -> Option<Arc<HandshakeContext>> {
+        self.handshake_context.read().await.clone()
+    }
+
+    /// Returns a handle stopping the reconnects.
+    pub fn stop_handle(&self) -> StopHandle {
+        self.stop.clone()
+    }
+
+    /// Returns the subscriptions re-sent after every successful handshake.
+    pub fn subscriptions(&self) -> &Arc<SubscriptionManager> {
+        &self.subscriptions
+    }
+
     pub async fn create_receive_stream(&self) -> UnboundedReceiverStream<Message> {
         self.receive_stream.new_listener().await
     }
@@ -46,6 +123,25 @@ impl<R: IntoClientRequest + Send + Sync> ReconnectT<R> {
         self.status_stream.new_listener().await
     }
 
+    /// Creates a stream of both the messages and the status changes, in the order they
+    /// happened, for clients handling them in one task. E.g. a response received just
+    /// before the connection dropped comes before `Disconnected`.
+    pub(crate) async fn create_event_stream(&self) -> UnboundedReceiverStream<StreamEvent> {
+        self.events.new_listener().await
+    }
+
+    async fn notify_message(&self, msg: Message) {
+        self.events.notify(StreamEvent::Message(msg.clone())).await;
+        self.receive_stream.notify(msg).await;
+    }
+
+    async fn notify_status(&self, status: WsStreamStatus) {
+        self.events
+            .notify(StreamEvent::Status(status.clone()))
+            .await;
+        self.status_stream.notify(status).await;
+    }
+
     pub async fn register_extension(&self, extension: ExtensionType) -> EResult<()> {
         match extension {
             ExtensionType::Msg(extension) => {
@@ -71,41 +167,54 @@ impl<R: IntoClientRequest + Send + Sync> ReconnectT<R> {
 }
 
 impl<R: IntoClientRequest + Send + Sync + Clone> ReconnectT<R> {
-    pub(crate) async fn connect(&self) -> WsTcpStream {
-        let mut retries_to_attempt = self.option.retries_to_attempt_fn()();
-        let mut count = 0;
+    /// Connects and returns the stream with the subprotocol the server selected.
+    pub(crate) async fn connect(&self) -> EResult<(WsTcpStream, Option<String>), ReconnectTError> {
         let request = self
             .request
             .clone()
             .into_client_request()
             .expect("into_client_request");
-        loop {
-            match connect(request.clone(), None, false, None).await {
-                Ok((ws_stream, _)) => return ws_stream,
-                Err(e) => {
-                    count += 1;
-                    tracing::warn!(count=count, error=?e, "reconnect::connect");
-                    tokio::time::sleep(
-                        retries_to_attempt
-                            .next()
-                            .expect("retries_to_attempt_fn::next() should not return None"),
-                    )
-                    .await;
-                }
+        let (ws_stream, response) = connect(request, None, false, None).await?;
+        let subprotocol = response
+            .headers()
+            .get("Sec-WebSocket-Protocol")
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string);
+        Ok((ws_stream, subprotocol))
+    }
+
+    /// Asks the retry classifier, then the retry policy, how to proceed after `error`.
+    pub(crate) fn retry_decision(
+        &self,
+        retry_policy: &mut dyn RetryPolicy,
+        attempt: u32,
+        error: &ReconnectTError,
+        uptime: Option<Duration>,
+    ) -> RetryDecision {
+        if let ReconnectTError::TokioTungsteniteError(e) = error {
+            match (self.option.retry_classifier())(e) {
+                RetryAction::Retry => {}
+                RetryAction::RetryAfter(delay) => return RetryDecision::Retry(delay),
+                RetryAction::GiveUp => return RetryDecision::GiveUp,
             }
         }
+        retry_policy.next_retry(attempt, error, uptime)
     }
 
     pub(crate) async fn handshake(
         &self,
         writer: &mut PSTSender,
         reader: &mut PSTReceiver,
-    ) -> EResult<(), ReconnectTError> {
+    ) -> EResult<HandshakeContext, ReconnectTError> {
         match self.option.handshake().handshake(writer, reader).await {
-            Ok(_) => Ok(()),
+            Ok(ctx) => Ok(ctx),
             Err(e) => {
                 tracing::error!(error=?e, "reconnect::handshake");
-                Err(ReconnectTError::HandshakeFailed)
+                // a handshake refused for good stops the reconnects
+                if let Some(ReconnectTError::Stopped(reason)) = e.downcast_ref() {
+                    self.stop.stop(reason.clone());
+                }
+                Err(ReconnectTError::HandshakeFailed(e))
             }
         }
     }
@@ -113,12 +222,11 @@ impl<R: IntoClientRequest + Send + Sync + Clone> ReconnectT<R> {
     pub(crate) async fn receive_loop(
         &self,
         mut receiver: PSTReceiver,
+        receive_timeout: Duration,
     ) -> EResult<(), ReconnectTError> {
-        let receive_timeout = self.option.receive_timeout();
         let start_time = Instant::now();
         let mut receive_timeout_tick = interval_at(start_time + receive_timeout, receive_timeout);
 
-        let listener = self.receive_stream.clone();
         loop {
             tokio::select! {
                 biased;
@@ -126,7 +234,9 @@ impl<R: IntoClientRequest + Send + Sync + Clone> ReconnectT<R> {
                 msg = receiver.next() => {
                     match msg {
                         Some(Ok(msg)) => {
-                            listener.notify(msg).await;
+                            let msg = self.decompress(msg)?;
+                            self.subscriptions.on_message(&msg).await;
+                            self.notify_message(msg).await;
                             receive_timeout_tick.reset();
                         },
                         Some(Err(e)) => {
@@ -138,32 +248,125 @@ impl<R: IntoClientRequest + Send + Sync + Clone> ReconnectT<R> {
                 _ = receive_timeout_tick.tick() => {
                     return Err(ReconnectTError::ReceiveTimeout(receive_timeout));
                 }
+                reason = self.stop.stopped() => {
+                    return Err(ReconnectTError::Stopped(reason));
+                }
             }
         }
         Ok(())
     }
 
-    pub async fn run(&self) {
+    fn decompress(&self, msg: Message) -> Result<Message, DecompressionError> {
+        match self.option.decompression() {
+            Some(decompression) => decompression.apply(msg),
+            None => Ok(msg),
+        }
+    }
+
+    /// Connects, performs the handshake and receives until the connection fails.
+    /// Returns the failure and, if the handshake succeeded, how long the connection stayed up.
+    pub(crate) async fn session(
+        &self,
+        retry_policy: &mut dyn RetryPolicy,
+    ) -> (ReconnectTError, Option<Duration>) {
+        let permit = match self.option.coordinator() {
+            Some(coordinator) => Some(coordinator.acquire().await),
+            None => None,
+        };
+        let (ws_stream, subprotocol) = match self.connect().await {
+            Ok(connected) => connected,
+            Err(e) => return (e, None),
+        };
+        let (mut sender, mut receiver) = ws_stream.split();
+        let receive_timeout;
+        let mut forwarded_error = None;
+        {
+            // handshake
+            let mut ctx = match self.handshake(&mut sender, &mut receiver).await {
+                Ok(ctx) => ctx,
+                Err(e) => return (e, None),
+            };
+            drop(permit);
+            let forwarded = ctx.take_forwarded();
+            let generation = self.generation.fetch_add(1, Ordering::SeqCst) + 1;
+            ctx.set_generation(generation);
+            ctx.set_subprotocol(subprotocol);
+            receive_timeout = ctx
+                .receive_timeout()
+                .unwrap_or_else(|| self.option.receive_timeout());
+            let ctx = Arc::new(ctx);
+            *self.handshake_context.write().await = Some(ctx.clone());
+
+            self.sender.set_sender(sender, generation).await;
+            self.subscriptions.replay(generation).await;
+            self.notify_status(WsStreamStatus::Connected(ctx)).await;
+            for msg in forwarded {
+                let msg = match self.decompress(msg) {
+                    Ok(msg) => msg,
+                    Err(e) => {
+                        forwarded_error = Some(e.into());
+                        break;
+                    }
+                };
+                self.subscriptions.on_message(&msg).await;
+                self.notify_message(msg).await;
+            }
+            retry_policy.on_connected();
+        }
+
+        // receive loop
+        let connected_at = Instant::now();
+        let result = match forwarded_error {
+            Some(e) => Err(e),
+            None => self.receive_loop(receiver, receive_timeout).await,
+        };
+        let error = match result {
+            Ok(()) => ReconnectTError::ConnectionClosed,
+            Err(e) => {
+                tracing::error!(error=?e, "reconnect::receive_loop");
+                e
+            }
+        };
+        self.sender.reset_sender().await;
+        self.notify_status(WsStreamStatus::Disconnected).await;
+        (error, Some(connected_at.elapsed()))
+    }
+
+    /// Runs the connection until the retry policy gives up.
+    pub async fn run(&self) -> EResult<(), ReconnectTError> {
+        let mut retry_policy = self.option.retry_policy_fn()();
+        let mut circuit_breaker = self.option.circuit_breaker().cloned();
+        let mut attempt = 0;
         loop {
             self.sender.reset_sender().await;
-            let ws_stream = self.connect().await;
-            let (mut sender, mut receiver) = ws_stream.split(); // Removed `mut` from receiver
-            {
-                // handshake
-                if let Err(_) = self.handshake(&mut sender, &mut receiver).await {
-                    continue;
-                }
-                self.sender.set_sender(sender).await;
-                self.status_stream.notify(WsStreamStatus::Connected).await;
+            let (error, uptime) = self.session(retry_policy.as_mut()).await;
+            if let Some(reason) = self.stop.reason() {
+                tracing::warn!(reason = reason, "reconnect::stopped");
+                return Err(ReconnectTError::Stopped(reason));
             }
+            attempt = if uptime.is_some() { 1 } else { attempt + 1 };
+            tracing::warn!(attempt=attempt, error=?error, "reconnect::run");
 
-            // receive loop
-            if let Err(e) = self.receive_loop(receiver).await {
-                tracing::error!(error=?e, "reconnect::receive_loop");
+            let delay = match self.retry_decision(retry_policy.as_mut(), attempt, &error, uptime) {
+                RetryDecision::Retry(delay) => delay,
+                RetryDecision::GiveUp => return Err(ReconnectTError::GaveUp(Box::new(error))),
+            };
+
+            if let Some(circuit_breaker) = circuit_breaker.as_mut() {
+                if circuit_breaker.record_attempt(uptime) {
+                    let cool_down = circuit_breaker.cool_down();
+                    tracing::warn!(cool_down=?cool_down, "reconnect::circuit_open");
+                    self.notify_status(WsStreamStatus::CircuitOpen(cool_down))
+                        .await;
+                    tokio::time::sleep(cool_down).await;
+
+                    // probe right away
+                    circuit_breaker.half_open();
+                    self.notify_status(WsStreamStatus::CircuitHalfOpen).await;
+                    continue;
+                }
             }
-            self.status_stream
-                .notify(WsStreamStatus::Disconnected)
-                .await;
+            tokio::time::sleep(delay).await;
         }
     }
 }
@@ -175,7 +378,11 @@ pub trait ArcReconnectTExt {
 impl<R: IntoClientRequest + Send + Sync + Clone + 'static> ArcReconnectTExt for Arc<ReconnectT<R>> {
     fn spawn_run(&self) {
         let self_clone = self.clone();
-        tokio::spawn(async move { self_clone.run().await });
+        tokio::spawn(async move {
+            if let Err(e) = self_clone.run().await {
+                tracing::error!(error=?e, "reconnect::run");
+            }
+        });
     }
 }
 
@@ -206,6 +413,7 @@ async fn connect(
     client_async_tls_with_config(request, socket, config, connector).await
 }
 
+#[allow(clippy::result_large_err)]
 fn domain(request: &Request) -> Result<String, WsError> {
     match request.uri().host() {
         Some(d) => Ok(d.to_string()),